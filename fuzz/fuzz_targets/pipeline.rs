@@ -0,0 +1,21 @@
+#![no_main]
+
+use compiler::{compiler as codegen, fold, parser, typechecker};
+use libfuzzer_sys::fuzz_target;
+
+// Any stage may reject the input, but none of them may panic, and programs that
+// get as far as running must stop within the step limit.
+fuzz_target!(|source: &str| {
+    let Ok(mut program) = parser::parse(source) else {
+        return;
+    };
+    if typechecker::check(&program).is_err() {
+        return;
+    }
+    fold::fold(&mut program);
+    let Ok(mut vm) = codegen::compile(&program) else {
+        return;
+    };
+    vm.set_step_limit(10_000);
+    let _ = vm.run();
+});
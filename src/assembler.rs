@@ -0,0 +1,466 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::virtual_machine::{Operation, Word};
+
+/// Why `assemble` rejected its input. `line` is 1-based.
+#[derive(Debug, PartialEq)]
+pub enum AsmError {
+    UnknownOperation {
+        line: usize,
+        name: String,
+    },
+    MissingOperand {
+        line: usize,
+    },
+    InvalidOperand {
+        line: usize,
+        operand: String,
+    },
+    /// An operand given to an operation that doesn't take one, or a second operand.
+    UnexpectedOperand {
+        line: usize,
+    },
+    UnknownLabel {
+        line: usize,
+        label: String,
+    },
+    DuplicateLabel {
+        line: usize,
+        label: String,
+    },
+}
+
+/// Names a position in a list of `LabeledOperation`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(pub usize);
+
+/// An operation whose jump target, if any, is a label instead of an index, so that
+/// operations can be inserted or removed without retargeting every jump.
+#[derive(Debug, Clone)]
+pub enum LabeledOperation {
+    Operation(Operation),
+    /// Marks where the operation after it is. Becomes nothing once resolved.
+    Label(Label),
+    /// A jump made by `Operation::Jump`, `JumpIf` or `JumpIfNot` to a label, or
+    /// another operation made from where the label is, like the `AddImmediate` that
+    /// finds an entry in a jump table.
+    Jump(fn(u32) -> Operation, Label),
+}
+
+/// Replaces labels with the indices of the operations they mark.
+///
+/// Panics if a jump targets a label that is never placed.
+pub fn resolve_labels(operations: &[LabeledOperation]) -> Vec<Operation> {
+    let mut positions = HashMap::new();
+    let mut count = 0;
+    for operation in operations {
+        match operation {
+            LabeledOperation::Label(label) => {
+                positions.insert(*label, count);
+            }
+            _ => count += 1,
+        }
+    }
+    operations
+        .iter()
+        .filter_map(|operation| match operation {
+            LabeledOperation::Operation(operation) => Some(operation.clone()),
+            LabeledOperation::Label(_) => None,
+            LabeledOperation::Jump(jump, label) => Some(jump(positions[label])),
+        })
+        .collect()
+}
+
+/// Builds a function's operations from text with one operation per line, named
+/// like the `Operation` variants but in lowercase:
+///
+/// ```text
+/// push 3
+/// loop:          # a label names the operation after it
+///     get 0
+///     jumpifnot end
+///     subimmediate 1
+///     jump loop
+/// end:
+///     return
+/// ```
+///
+/// Jumps take either a label or an operation index. Everything after a `#` is a
+/// comment. Immediates are words, or negative numbers that fit in an `i32`, which
+/// are stored sign extended like `i32` values are.
+pub fn assemble(text: &str) -> Result<Vec<Operation>, AsmError> {
+    // Labels can be used before they are defined, so they are all found first.
+    let mut labels = HashMap::new();
+    for (line, words) in lines(text) {
+        if let [label] = words.as_slice()
+            && let Some(label) = label.strip_suffix(':')
+        {
+            let id = Label(labels.len());
+            if labels.insert(label, id).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    label: label.to_string(),
+                });
+            }
+        }
+    }
+
+    let mut operations = Vec::new();
+    for (line, words) in lines(text) {
+        let (name, operands) = words.split_first().unwrap();
+        if let Some(label) = name.strip_suffix(':')
+            && operands.is_empty()
+        {
+            operations.push(LabeledOperation::Label(labels[label]));
+            continue;
+        }
+        let operand = || match operands {
+            [] => Err(AsmError::MissingOperand { line }),
+            [operand] => Ok(*operand),
+            _ => Err(AsmError::UnexpectedOperand { line }),
+        };
+        let invalid = |operand: &str| AsmError::InvalidOperand {
+            line,
+            operand: operand.to_string(),
+        };
+        let word = || {
+            let operand = operand()?;
+            match operand.parse::<Word>() {
+                Ok(word) => Ok(word),
+                Err(_) if operand.starts_with('-') => operand
+                    .parse::<i32>()
+                    .map(|n| n as i64 as Word)
+                    .map_err(|_| invalid(operand)),
+                Err(_) => Err(invalid(operand)),
+            }
+        };
+        let number = || {
+            let operand = operand()?;
+            operand.parse::<u32>().map_err(|_| invalid(operand))
+        };
+        let none = |operation| match operands {
+            [] => Ok(operation),
+            _ => Err(AsmError::UnexpectedOperand { line }),
+        };
+
+        let name = name.to_lowercase();
+        let jump: Option<fn(u32) -> Operation> = match name.as_str() {
+            "jump" => Some(Operation::Jump),
+            "jumpif" => Some(Operation::JumpIf),
+            "jumpifnot" => Some(Operation::JumpIfNot),
+            _ => None,
+        };
+        if let Some(jump) = jump {
+            let operand = operand()?;
+            operations.push(match labels.get(operand) {
+                Some(&label) => LabeledOperation::Jump(jump, label),
+                None if operand.starts_with(|c: char| c.is_ascii_digit()) => {
+                    LabeledOperation::Operation(jump(
+                        operand.parse::<u32>().map_err(|_| invalid(operand))?,
+                    ))
+                }
+                None => {
+                    return Err(AsmError::UnknownLabel {
+                        line,
+                        label: operand.to_string(),
+                    });
+                }
+            });
+            continue;
+        }
+
+        use Operation::*;
+        operations.push(LabeledOperation::Operation(match name.as_str() {
+            "nop" => none(Nop)?,
+            "push" => Push(word()?),
+            "pushbool" => match operand()? {
+                "true" => PushBool(true),
+                "false" => PushBool(false),
+                operand => return Err(invalid(operand)),
+            },
+            "pushmany" => PushMany(number()?),
+            "pop" => none(Pop)?,
+            "get" => Get(number()?),
+            "put" => Put(number()?),
+            "swap" => none(Swap)?,
+            "store" => Store(number()?),
+            "load" => Load(number()?),
+            "allocate" => Allocate(number()?),
+            "free" => Free(number()?),
+            "storeindirect" => StoreIndirect(number()?),
+            "loadindirect" => LoadIndirect(number()?),
+            "checkindex" => none(CheckIndex)?,
+            "storemany" => StoreMany(number()?),
+            "loadelement" => match operands {
+                [depth, index] => LoadElement(
+                    depth.parse().map_err(|_| invalid(depth))?,
+                    index.parse().map_err(|_| invalid(index))?,
+                ),
+                [] | [_] => return Err(AsmError::MissingOperand { line }),
+                _ => return Err(AsmError::UnexpectedOperand { line }),
+            },
+            "call" => Call(number()?),
+            "callfnpointer" => none(CallFnPointer)?,
+            "pushfunction" => PushFunction(number()?),
+            "return" => none(Return)?,
+            "addimmediate" => AddImmediate(word()?),
+            "add" => none(Add)?,
+            "subimmediate" => SubImmediate(word()?),
+            "subimmediateby" => SubImmediateBy(word()?),
+            "sub" => none(Sub)?,
+            "mulimmediate" => MulImmediate(word()?),
+            "mul" => none(Mul)?,
+            "divimmediate" => DivImmediate(word()?),
+            "divimmediateby" => DivImmediateBy(word()?),
+            "div" => none(Div)?,
+            "modimmediate" => ModImmediate(word()?),
+            "modimmediateby" => ModImmediateBy(word()?),
+            "mod" => none(Mod)?,
+            "addsat" => none(AddSat)?,
+            "subsat" => none(SubSat)?,
+            "mulsat" => none(MulSat)?,
+            "addchecked" => none(AddChecked)?,
+            "subchecked" => none(SubChecked)?,
+            "mulchecked" => none(MulChecked)?,
+            "addcheckedsigned" => none(AddCheckedSigned)?,
+            "subcheckedsigned" => none(SubCheckedSigned)?,
+            "mulcheckedsigned" => none(MulCheckedSigned)?,
+            "divsigned" => none(DivSigned)?,
+            "modsigned" => none(ModSigned)?,
+            "equal" => none(Equal)?,
+            "notequal" => none(NotEqual)?,
+            "less" => none(Less)?,
+            "lessequal" => none(LessEqual)?,
+            "lesssigned" => none(LessSigned)?,
+            "lessequalsigned" => none(LessEqualSigned)?,
+            "goto" => none(Goto)?,
+            "gotoif" => none(GotoIf)?,
+            "gotoifnot" => none(GotoIfNot)?,
+            _ => {
+                return Err(AsmError::UnknownOperation {
+                    line,
+                    name: words[0].to_string(),
+                });
+            }
+        }));
+    }
+    Ok(resolve_labels(&operations))
+}
+
+/// Writes `operations` as text that `assemble` turns back into the same operations.
+/// Immediates are always written as unsigned decimal words, so `Push(u32::MAX)` is
+/// `push 4294967295` rather than `push -1`. Every operation a jump targets gets a
+/// label named after its index, like `l12:`, which the jump refers to.
+pub fn disassemble(operations: &[Operation]) -> String {
+    use Operation::*;
+    let targets: HashSet<u32> = operations
+        .iter()
+        .filter_map(|operation| match operation {
+            Jump(target) | JumpIf(target) | JumpIfNot(target)
+                if *target as usize <= operations.len() =>
+            {
+                Some(*target)
+            }
+            _ => None,
+        })
+        .collect();
+    let target = |target: u32| {
+        if targets.contains(&target) {
+            format!("l{target}")
+        } else {
+            target.to_string()
+        }
+    };
+
+    let mut text = String::new();
+    for (index, operation) in operations.iter().enumerate() {
+        if targets.contains(&(index as u32)) {
+            text.push_str(&format!("l{index}:\n"));
+        }
+        let line = match operation {
+            Nop => "nop".to_string(),
+            Push(n) => format!("push {n}"),
+            PushBool(b) => format!("pushbool {b}"),
+            PushMany(start) => format!("pushmany {start}"),
+            Pop => "pop".to_string(),
+            Get(depth) => format!("get {depth}"),
+            Put(depth) => format!("put {depth}"),
+            Swap => "swap".to_string(),
+            Store(address) => format!("store {address}"),
+            Load(address) => format!("load {address}"),
+            Allocate(size) => format!("allocate {size}"),
+            Free(size) => format!("free {size}"),
+            StoreIndirect(offset) => format!("storeindirect {offset}"),
+            LoadIndirect(offset) => format!("loadindirect {offset}"),
+            CheckIndex => "checkindex".to_string(),
+            LoadElement(depth, index) => format!("loadelement {depth} {index}"),
+            StoreMany(count) => format!("storemany {count}"),
+            Call(id) => format!("call {id}"),
+            CallFnPointer => "callfnpointer".to_string(),
+            PushFunction(id) => format!("pushfunction {id}"),
+            Return => "return".to_string(),
+            AddImmediate(n) => format!("addimmediate {n}"),
+            Add => "add".to_string(),
+            SubImmediate(n) => format!("subimmediate {n}"),
+            SubImmediateBy(n) => format!("subimmediateby {n}"),
+            Sub => "sub".to_string(),
+            MulImmediate(n) => format!("mulimmediate {n}"),
+            Mul => "mul".to_string(),
+            DivImmediate(n) => format!("divimmediate {n}"),
+            DivImmediateBy(n) => format!("divimmediateby {n}"),
+            Div => "div".to_string(),
+            ModImmediate(n) => format!("modimmediate {n}"),
+            ModImmediateBy(n) => format!("modimmediateby {n}"),
+            Mod => "mod".to_string(),
+            AddSat => "addsat".to_string(),
+            SubSat => "subsat".to_string(),
+            MulSat => "mulsat".to_string(),
+            AddChecked => "addchecked".to_string(),
+            SubChecked => "subchecked".to_string(),
+            MulChecked => "mulchecked".to_string(),
+            AddCheckedSigned => "addcheckedsigned".to_string(),
+            SubCheckedSigned => "subcheckedsigned".to_string(),
+            MulCheckedSigned => "mulcheckedsigned".to_string(),
+            DivSigned => "divsigned".to_string(),
+            ModSigned => "modsigned".to_string(),
+            Equal => "equal".to_string(),
+            NotEqual => "notequal".to_string(),
+            Less => "less".to_string(),
+            LessEqual => "lessequal".to_string(),
+            LessSigned => "lesssigned".to_string(),
+            LessEqualSigned => "lessequalsigned".to_string(),
+            Jump(to) => format!("jump {}", target(*to)),
+            JumpIf(to) => format!("jumpif {}", target(*to)),
+            JumpIfNot(to) => format!("jumpifnot {}", target(*to)),
+            Goto => "goto".to_string(),
+            GotoIf => "gotoif".to_string(),
+            GotoIfNot => "gotoifnot".to_string(),
+        };
+        text.push_str("    ");
+        text.push_str(&line);
+        text.push('\n');
+    }
+    // A jump can target the end, just past the last operation.
+    if targets.contains(&(operations.len() as u32)) {
+        text.push_str(&format!("l{}:\n", operations.len()));
+    }
+    text
+}
+
+/// The words on each line that has any once comments are removed, along with the
+/// line's number.
+fn lines(text: &str) -> impl Iterator<Item = (usize, Vec<&str>)> {
+    text.lines().enumerate().filter_map(|(i, line)| {
+        let code = line.split('#').next().unwrap();
+        let words: Vec<_> = code.split_whitespace().collect();
+        (!words.is_empty()).then_some((i + 1, words))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtual_machine::{Function, VirtualMachine};
+
+    #[test]
+    fn assembled_fibonacci_runs() {
+        let operations = assemble(
+            "   push 0
+                push 1
+                push 10         # how many steps to take
+            loop:
+                get 0
+                jumpifnot end
+                get 2
+                get 2
+                add
+                get 2
+                put 3
+                put 1
+                subimmediate 1
+                jump loop
+            end:
+                pop
+                put 3
+                pop
+                return",
+        )
+        .unwrap();
+        assert_eq!(operations[4], Operation::JumpIfNot(13));
+        assert_eq!(operations[12], Operation::Jump(3));
+        let mut vm =
+            VirtualMachine::from_functions(vec![Function::from_operations("main", operations)]);
+        assert_eq!(vm.run().unwrap(), 89);
+
+        assert_eq!(
+            assemble("push\n").unwrap_err(),
+            AsmError::MissingOperand { line: 1 }
+        );
+        assert_eq!(
+            assemble("nop\njump nowhere").unwrap_err(),
+            AsmError::UnknownLabel {
+                line: 2,
+                label: "nowhere".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        use Operation::*;
+        let operations = vec![
+            Push(Word::MAX),
+            Push(u32::MAX as Word),
+            PushBool(true),
+            JumpIfNot(6),
+            AddImmediate(Word::MAX - 1),
+            Jump(9),
+            LoadElement(2, 65535),
+            SubImmediateBy(0),
+            Jump(3),
+            Return,
+            PushFunction(7),
+        ];
+        let text = disassemble(&operations);
+        assert!(text.contains(&format!("push {}", Word::MAX)));
+        assert!(!text.contains('-'));
+        assert!(text.contains("    jumpifnot l6\n"));
+        assert!(text.contains("l6:\n    loadelement 2 65535\n"));
+        assert_eq!(assemble(&text).unwrap(), operations);
+
+        // A jump to the end gets a label after the last operation.
+        let to_end = [Jump(1)];
+        assert_eq!(disassemble(&to_end), "    jump l1\nl1:\n");
+        assert_eq!(assemble(&disassemble(&to_end)).unwrap(), to_end);
+
+        assert_eq!(
+            assemble("push -1\npush -2147483648").unwrap(),
+            [Push(-1i64 as Word), Push(i32::MIN as i64 as Word)]
+        );
+        assert_eq!(
+            assemble("push -2147483649").unwrap_err(),
+            AsmError::InvalidOperand {
+                line: 1,
+                operand: "-2147483649".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn inserting_before_a_label_keeps_jumps_on_target() {
+        let end = Label(0);
+        let mut operations = vec![
+            LabeledOperation::Operation(Operation::PushBool(false)),
+            LabeledOperation::Jump(Operation::JumpIfNot, end),
+            LabeledOperation::Operation(Operation::Push(1)),
+            LabeledOperation::Label(end),
+            LabeledOperation::Operation(Operation::Push(2)),
+        ];
+        assert_eq!(resolve_labels(&operations)[1], Operation::JumpIfNot(3));
+
+        operations.insert(2, LabeledOperation::Operation(Operation::Nop));
+        let resolved = resolve_labels(&operations);
+        assert_eq!(resolved[1], Operation::JumpIfNot(4));
+        assert_eq!(resolved[4], Operation::Push(2));
+    }
+}
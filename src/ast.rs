@@ -1,29 +1,122 @@
-struct Program {}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Declaration {
     Function {
         name: String,
+        doc: Option<String>,
+        /// Set by a `#[wrapping]` or `#[checked]` attribute before the `fn`.
+        arithmetic: Arithmetic,
+        arguments: Vec<(String, Type)>,
+        return_type: Type,
+        body: Vec<Spanned<Statement>>,
+        span: Span,
+    },
+    /// `extern fn name(arguments): return_type;`, a function without a body that
+    /// is provided by a builtin of the same name.
+    Extern {
+        name: String,
+        doc: Option<String>,
         arguments: Vec<(String, Type)>,
         return_type: Type,
-        body: Vec<Statement>,
+        span: Span,
+    },
+    /// `type name = aliased;`. Uses of `name` after it are parsed as a
+    /// `Type::Alias` holding `aliased`.
+    TypeAlias {
+        name: String,
+        doc: Option<String>,
+        aliased: Type,
+        span: Span,
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// What `+`, `-` and `*` do in a function when the result doesn't fit in a word.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Arithmetic {
+    /// Wrap around, unless the VM is in strict mode.
+    #[default]
+    Wrapping,
+    /// Stop with an overflow, whether or not the VM is in strict mode. `i32`s
+    /// overflow at the signed range, including when negated.
+    Checked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Type {
     Fun(Vec<Type>, Box<Type>),
     U32,
+    /// Stored as the two's complement bit pattern, sign extended to a full word.
+    I32,
+    U8,
+    U16,
+    Bool,
+    /// The return type of a function declared without one, which returns nothing
+    /// useful. Its only value is stored as 0, in one slot like any other.
+    Unit,
+    /// Only produced by builtins with several results, and must be destructured
+    /// with `let (a, b) = ...;` straight away.
+    Tuple(Vec<Type>),
+    /// A fixed number of elements stored on the heap. Values are the address of the
+    /// block, whose first slot holds the length.
+    Array(Box<Type>, u32),
+    /// The name of a `type` declaration, with the type it stands for. Kept so the
+    /// formatter can write the name back; everything else looks through it with
+    /// `resolved`. The name is a `Box<str>` to keep `Type` as small as it was.
+    Alias(Box<str>, Box<Type>),
+}
+
+impl Type {
+    /// This type with every alias in it replaced by the type it stands for.
+    pub fn resolved(&self) -> Type {
+        match self {
+            Type::Alias(_, aliased) => aliased.resolved(),
+            Type::Fun(arguments, result) => Type::Fun(
+                arguments.iter().map(Type::resolved).collect(),
+                Box::new(result.resolved()),
+            ),
+            Type::Tuple(types) => Type::Tuple(types.iter().map(Type::resolved).collect()),
+            Type::Array(element, length) => Type::Array(Box::new(element.resolved()), *length),
+            typ => typ.clone(),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Statement {
-    Let(String, Option<Type>, Expression),
+    Let {
+        name: String,
+        /// Declared with `let mut`, so it can be assigned to.
+        mutable: bool,
+        typ: Option<Type>,
+        value: Expression,
+    },
+    /// `const name: typ = value;`, a local whose value is worked out when compiling
+    /// and used in place of each read of it.
+    Const {
+        name: String,
+        typ: Type,
+        value: Expression,
+    },
+    LetTuple(Vec<String>, Expression),
+    /// `name = value;`, replacing the value of a `let mut` variable.
+    Assign(String, Expression),
+    /// `array[index] = value;`. Arrays live on the heap, so their elements can be
+    /// written whether or not the variable holding one is `mut`.
+    IndexAssign(Expression, Expression, Expression),
     Expr(Expression),
     Return(Expression),
+    If(Expression, Vec<Spanned<Statement>>, Vec<Spanned<Statement>>),
+    While(Expression, Vec<Spanned<Statement>>),
+    /// Leaves the innermost `loop` or `while`. A `loop` produces the value given,
+    /// or the unit value if there is none.
+    Break(Option<Expression>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression {
     Call(String, Vec<Expression>),
     Variable(String),
@@ -33,4 +126,321 @@ pub enum Expression {
     Div(Box<Expression>, Box<Expression>),
     Mod(Box<Expression>, Box<Expression>),
     NumLiteral(u32),
+    /// `-n`, an `i32`.
+    NegativeLiteral(u32),
+    /// A literal with its type given by a suffix, like `255u8`.
+    SuffixedLiteral(u32, Type),
+    BoolLiteral(bool),
+    /// `-value`, negating an `i32`.
+    Negate(Box<Expression>),
+    /// `!value`, negating a `bool`.
+    Not(Box<Expression>),
+    /// `"text"`, a `[u32; n]` of the characters' code points. Every use of the same
+    /// text in a program shares one array.
+    StrLiteral(String),
+    ArrayLiteral(Vec<Expression>),
+    /// `array[index]`, reading an element.
+    Index(Box<Expression>, Box<Expression>),
+    Block(Vec<Spanned<Statement>>, Box<Expression>),
+    If(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// `a == b`, `a < b` and so on, comparing two numbers.
+    Compare(Comparison, Box<Expression>, Box<Expression>),
+    /// `a && b`, which only evaluates `b` if `a` is true.
+    And(Box<Expression>, Box<Expression>),
+    /// `a || b`, which only evaluates `b` if `a` is false.
+    Or(Box<Expression>, Box<Expression>),
+    /// `loop { ... }`, repeating its body until a `break`, whose value it takes.
+    Loop(Vec<Spanned<Statement>>),
+    /// `match value { 0 => a, 1 => b, _ => c }`, taking the value of the first arm
+    /// whose number equals `value`, or else of the `_` arm, which the typechecker
+    /// requires.
+    Match(
+        Box<Expression>,
+        Vec<(u32, Expression)>,
+        Option<Box<Expression>>,
+    ),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// A range of byte offsets into the source text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The 1-based line of `source` the span starts on.
+    pub fn line(&self, source: &str) -> usize {
+        source[..self.start].matches('\n').count() + 1
+    }
+}
+
+/// An AST node along with the part of the source it was parsed from.
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+/// Shorthands for building expressions in code instead of parsing source text.
+impl Expression {
+    pub fn num(n: u32) -> Self {
+        Expression::NumLiteral(n)
+    }
+
+    pub fn boolean(b: bool) -> Self {
+        Expression::BoolLiteral(b)
+    }
+
+    pub fn var(name: impl Into<String>) -> Self {
+        Expression::Variable(name.into())
+    }
+
+    pub fn call(function: impl Into<String>, arguments: Vec<Expression>) -> Self {
+        Expression::Call(function.into(), arguments)
+    }
+
+    pub fn compare(comparison: Comparison, a: Expression, b: Expression) -> Self {
+        Expression::Compare(comparison, Box::new(a), Box::new(b))
+    }
+
+    pub fn array(elements: Vec<Expression>) -> Self {
+        Expression::ArrayLiteral(elements)
+    }
+
+    pub fn index(array: Expression, index: Expression) -> Self {
+        Expression::Index(Box::new(array), Box::new(index))
+    }
+
+    pub fn block(body: Vec<Statement>, tail: Expression) -> Self {
+        Expression::Block(unspanned(body), Box::new(tail))
+    }
+
+    pub fn if_else(
+        condition: Expression,
+        then_branch: Expression,
+        else_branch: Expression,
+    ) -> Self {
+        Expression::If(
+            Box::new(condition),
+            Box::new(then_branch),
+            Box::new(else_branch),
+        )
+    }
+}
+
+/// Arithmetic on built expressions, e.g. `Expression::var("x") * Expression::num(2)`.
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $variant:ident) => {
+        impl std::ops::$trait for Expression {
+            type Output = Expression;
+
+            fn $method(self, other: Expression) -> Expression {
+                Expression::$variant(Box::new(self), Box::new(other))
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Add);
+binary_operator!(Sub, sub, Sub);
+binary_operator!(Mul, mul, Mul);
+binary_operator!(Div, div, Div);
+binary_operator!(Rem, rem, Mod);
+
+/// Shorthands for building statements in code. Statements built this way have no
+/// span, so errors found in them have no location.
+impl Statement {
+    pub fn define(name: impl Into<String>, value: Expression) -> Self {
+        Statement::Let {
+            name: name.into(),
+            mutable: false,
+            typ: None,
+            value,
+        }
+    }
+
+    pub fn define_typed(name: impl Into<String>, typ: Type, value: Expression) -> Self {
+        Statement::Let {
+            name: name.into(),
+            mutable: false,
+            typ: Some(typ),
+            value,
+        }
+    }
+
+    pub fn define_mut(name: impl Into<String>, value: Expression) -> Self {
+        Statement::Let {
+            name: name.into(),
+            mutable: true,
+            typ: None,
+            value,
+        }
+    }
+
+    pub fn assign(name: impl Into<String>, value: Expression) -> Self {
+        Statement::Assign(name.into(), value)
+    }
+
+    pub fn assign_index(array: Expression, index: Expression, value: Expression) -> Self {
+        Statement::IndexAssign(array, index, value)
+    }
+
+    pub fn expr(expression: Expression) -> Self {
+        Statement::Expr(expression)
+    }
+
+    pub fn ret(expression: Expression) -> Self {
+        Statement::Return(expression)
+    }
+
+    pub fn if_else(
+        condition: Expression,
+        then_body: Vec<Statement>,
+        else_body: Vec<Statement>,
+    ) -> Self {
+        Statement::If(condition, unspanned(then_body), unspanned(else_body))
+    }
+
+    pub fn while_loop(condition: Expression, body: Vec<Statement>) -> Self {
+        Statement::While(condition, unspanned(body))
+    }
+}
+
+fn unspanned(statements: Vec<Statement>) -> Vec<Spanned<Statement>> {
+    statements
+        .into_iter()
+        .map(|node| Spanned {
+            node,
+            span: Span::default(),
+        })
+        .collect()
+}
+
+/// Serializes a program to JSON, for tools that aren't written in Rust.
+#[cfg(feature = "serde")]
+pub fn to_json(declarations: &[Declaration]) -> String {
+    serde_json::to_string(declarations).expect("the AST only holds JSON friendly values")
+}
+
+/// Builds a `Declaration::Function` one piece at a time:
+///
+/// ```
+/// use compiler::ast::{Expression, FunctionBuilder, Statement, Type};
+///
+/// let double = FunctionBuilder::new("double", Type::U32)
+///     .argument("x", Type::U32)
+///     .statement(Statement::ret(Expression::var("x") * Expression::num(2)))
+///     .build();
+/// ```
+pub struct FunctionBuilder {
+    name: String,
+    doc: Option<String>,
+    arguments: Vec<(String, Type)>,
+    return_type: Type,
+    body: Vec<Statement>,
+}
+
+impl FunctionBuilder {
+    pub fn new(name: impl Into<String>, return_type: Type) -> Self {
+        Self {
+            name: name.into(),
+            doc: None,
+            arguments: Vec::new(),
+            return_type,
+            body: Vec::new(),
+        }
+    }
+
+    pub fn doc(mut self, doc: impl Into<String>) -> Self {
+        self.doc = Some(doc.into());
+        self
+    }
+
+    pub fn argument(mut self, name: impl Into<String>, typ: Type) -> Self {
+        self.arguments.push((name.into(), typ));
+        self
+    }
+
+    pub fn statement(mut self, statement: Statement) -> Self {
+        self.body.push(statement);
+        self
+    }
+
+    pub fn build(self) -> Declaration {
+        Declaration::Function {
+            name: self.name,
+            doc: self.doc,
+            arithmetic: Arithmetic::default(),
+            arguments: self.arguments,
+            return_type: self.return_type,
+            body: unspanned(self.body),
+            span: Span::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler, typechecker};
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trips() {
+        let program = crate::parser::parse(
+            "type Pair = [u32; 2];
+            extern fn print(x: u32);
+            fn main(): u32 {
+                let p: Pair = [1, 2];
+                if p[0] < 2 { print(p[1]); }
+                return loop { break -3; };
+            }",
+        )
+        .unwrap();
+        let json = to_json(&program);
+        let parsed: Vec<Declaration> = serde_json::from_str(&json).unwrap();
+        assert_eq!(to_json(&parsed), json);
+        assert!(json.contains(r#""name":"main""#));
+    }
+
+    #[test]
+    fn program_built_without_parsing_runs() {
+        let program = vec![
+            FunctionBuilder::new("double_plus", Type::U32)
+                .argument("x", Type::U32)
+                .statement(Statement::define(
+                    "y",
+                    Expression::var("x") * Expression::num(2),
+                ))
+                .statement(Statement::ret(Expression::var("y") + Expression::num(1)))
+                .build(),
+            FunctionBuilder::new("main", Type::U32)
+                .statement(Statement::if_else(
+                    Expression::call("lt", vec![Expression::num(1), Expression::num(2)]),
+                    vec![Statement::ret(Expression::call(
+                        "double_plus",
+                        vec![Expression::num(21)],
+                    ))],
+                    vec![],
+                ))
+                .statement(Statement::ret(Expression::num(0)))
+                .build(),
+        ];
+        typechecker::check(&program).unwrap();
+        assert_eq!(compiler::compile(&program).unwrap().run().unwrap(), 43);
+    }
 }
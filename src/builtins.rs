@@ -0,0 +1,429 @@
+use std::rc::Rc;
+
+use crate::{
+    ast, compiler,
+    virtual_machine::{Operation, RuntimeError, VirtualMachine, Word},
+};
+
+/// A function implemented in Rust that programs can call like any other function.
+///
+/// Arguments are pushed left to right, so when the builtin runs the last argument is
+/// on top of the stack. The builtin must pop every argument and push exactly one
+/// result, or else stop the run with `VirtualMachine::fail`.
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: String,
+    pub arguments: Vec<ast::Type>,
+    pub return_type: ast::Type,
+    pub function: Rc<dyn Fn(&mut VirtualMachine)>,
+    /// When set, calls are compiled to these operations instead of a `Call`.
+    pub operations: Option<Vec<Operation>>,
+}
+
+impl Builtin {
+    pub fn new(
+        name: impl Into<String>,
+        arguments: Vec<ast::Type>,
+        return_type: ast::Type,
+        function: impl Fn(&mut VirtualMachine) + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            arguments,
+            return_type,
+            function: Rc::new(function),
+            operations: None,
+        }
+    }
+
+    /// A builtin whose calls compile straight to `operations`, which must consume the
+    /// arguments and leave the result. `function` must do the same thing, for when it
+    /// is called any other way.
+    pub fn inline(
+        name: impl Into<String>,
+        arguments: Vec<ast::Type>,
+        return_type: ast::Type,
+        operations: Vec<Operation>,
+        function: impl Fn(&mut VirtualMachine) + 'static,
+    ) -> Self {
+        Self {
+            operations: Some(operations),
+            ..Self::new(name, arguments, return_type, function)
+        }
+    }
+
+    pub fn signature(&self) -> ast::Type {
+        ast::Type::Fun(self.arguments.clone(), Box::new(self.return_type.clone()))
+    }
+}
+
+/// The builtins available to every program.
+pub fn standard() -> Vec<Builtin> {
+    vec![
+        Builtin::new("exit", vec![ast::Type::U32], ast::Type::U32, |vm| {
+            let code = vm.pop();
+            vm.exit(code);
+        }),
+        Builtin::new("print", vec![ast::Type::U32], ast::Type::Unit, |vm| {
+            let value = vm.pop();
+            vm.write_output(&format!("{value}\n"));
+            vm.push(0);
+        }),
+        Builtin::new("print_hex", vec![ast::Type::U32], ast::Type::Unit, |vm| {
+            let value = vm.pop();
+            vm.write_output(&format!("{value:x}\n"));
+            vm.push(0);
+        }),
+        // Prints the character with the given code point, without a newline, or the
+        // replacement character if there is none.
+        Builtin::new("print_char", vec![ast::Type::U32], ast::Type::Unit, |vm| {
+            let code_point = vm.pop();
+            // Only a no-op when words are 32 bits.
+            #[allow(clippy::useless_conversion)]
+            let c = u32::try_from(code_point)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            vm.write_output(&c.to_string());
+            vm.push(0);
+        }),
+        // Reads up to `max` bytes of input into a new heap block, one byte per
+        // slot, and returns the block's address and how many bytes were read.
+        Builtin::new(
+            "read_bytes",
+            vec![ast::Type::U32],
+            ast::Type::Tuple(vec![ast::Type::U32, ast::Type::U32]),
+            |vm| {
+                let max = vm.pop();
+                let bytes = vm.read_input(max);
+                let address = vm.allocate(bytes.len() as u32);
+                for (i, byte) in bytes.iter().enumerate() {
+                    vm.write_heap(address + i as Word, *byte as Word);
+                }
+                vm.push(address);
+                vm.push(bytes.len() as Word);
+            },
+        ),
+        Builtin::new(
+            "modpow",
+            vec![ast::Type::U32, ast::Type::U32, ast::Type::U32],
+            ast::Type::U32,
+            |vm| {
+                let modulus = vm.pop();
+                let exponent = vm.pop();
+                let base = vm.pop();
+                vm.push(modpow(base, exponent, modulus));
+            },
+        ),
+        binary("min", Word::min),
+        binary("max", Word::max),
+        // Unlike `Ord::clamp`, a `lo` above `hi` doesn't panic but gives `hi`.
+        Builtin::new(
+            "clamp",
+            vec![ast::Type::U32, ast::Type::U32, ast::Type::U32],
+            ast::Type::U32,
+            |vm| {
+                let hi = vm.pop();
+                let lo = vm.pop();
+                let x = vm.pop();
+                vm.push(x.max(lo).min(hi));
+            },
+        ),
+        // For tests written in the language itself.
+        Builtin::new(
+            "assert_eq",
+            vec![ast::Type::U32, ast::Type::U32],
+            ast::Type::Unit,
+            |vm| {
+                let right = vm.pop();
+                let left = vm.pop();
+                if left != right {
+                    vm.fail(RuntimeError::AssertionFailed { left, right });
+                }
+                vm.push(0);
+            },
+        ),
+        checked("add_checked", Word::overflowing_add),
+        checked("sub_checked", Word::overflowing_sub),
+        checked("mul_checked", Word::overflowing_mul),
+        saturating("sat_add", Operation::AddSat, Word::saturating_add),
+        saturating("sat_sub", Operation::SubSat, Word::saturating_sub),
+        saturating("sat_mul", Operation::MulSat, Word::saturating_mul),
+        comparison("eq", ast::Comparison::Equal, |a, b| a == b),
+        comparison("ne", ast::Comparison::NotEqual, |a, b| a != b),
+        comparison("lt", ast::Comparison::Less, |a, b| a < b),
+        comparison("le", ast::Comparison::LessEqual, |a, b| a <= b),
+        comparison("gt", ast::Comparison::Greater, |a, b| a > b),
+        comparison("ge", ast::Comparison::GreaterEqual, |a, b| a >= b),
+    ]
+}
+
+/// `base` to the power of `exponent`, modulo `modulus`, by square-and-multiply.
+/// Like division, a modulus of 0 gives 0.
+#[allow(clippy::unnecessary_cast)]
+fn modpow(base: Word, mut exponent: Word, modulus: Word) -> Word {
+    if modulus == 0 {
+        return 0;
+    }
+    // Products of two numbers below the modulus always fit in 128 bits.
+    let modulus = modulus as u128;
+    let mut base = base as u128 % modulus;
+    let mut result = 1 % modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result as Word
+}
+
+/// A builtin taking two numbers and returning `operation` of them.
+fn binary(name: &str, operation: fn(Word, Word) -> Word) -> Builtin {
+    Builtin::new(
+        name,
+        vec![ast::Type::U32, ast::Type::U32],
+        ast::Type::U32,
+        move |vm| {
+            let b = vm.pop();
+            let a = vm.pop();
+            vm.push(operation(a, b));
+        },
+    )
+}
+
+/// A builtin doing arithmetic that stops at the largest word or 0 instead of
+/// wrapping, compiled to `operation`.
+fn saturating(name: &str, operation: Operation, saturate: fn(Word, Word) -> Word) -> Builtin {
+    Builtin::inline(
+        name,
+        vec![ast::Type::U32, ast::Type::U32],
+        ast::Type::U32,
+        vec![operation],
+        move |vm| {
+            let b = vm.pop();
+            let a = vm.pop();
+            vm.push(saturate(a, b));
+        },
+    )
+}
+
+/// A builtin comparing two numbers, compiled like the matching operator.
+fn comparison(name: &str, comparison: ast::Comparison, compare: fn(Word, Word) -> bool) -> Builtin {
+    Builtin::inline(
+        name,
+        vec![ast::Type::U32, ast::Type::U32],
+        ast::Type::Bool,
+        compiler::comparison_operations(comparison, false),
+        move |vm| {
+            let b = vm.pop();
+            let a = vm.pop();
+            vm.push_bool(compare(a, b));
+        },
+    )
+}
+
+/// A builtin returning both the wrapped result of `operation` and whether it
+/// overflowed.
+fn checked(name: &str, operation: fn(Word, Word) -> (Word, bool)) -> Builtin {
+    Builtin::new(
+        name,
+        vec![ast::Type::U32, ast::Type::U32],
+        ast::Type::Tuple(vec![ast::Type::U32, ast::Type::Bool]),
+        move |vm| {
+            let b = vm.pop();
+            let a = vm.pop();
+            let (result, overflowed) = operation(a, b);
+            vm.push(result);
+            vm.push_bool(overflowed);
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler, parser, typechecker};
+
+    fn run(source: &str) -> Word {
+        let program = parser::parse(source).unwrap();
+        typechecker::check(&program).unwrap();
+        compiler::compile(&program).unwrap().run().unwrap()
+    }
+
+    #[test]
+    fn assert_eq_traps_on_different_values() {
+        assert_eq!(run("fn main(): u32 { assert_eq(2 + 2, 4); return 7; }"), 7);
+
+        let program = parser::parse("fn main(): u32 { assert_eq(1, 2); return 7; }").unwrap();
+        typechecker::check(&program).unwrap();
+        let error = compiler::compile(&program).unwrap().run().unwrap_err();
+        assert!(matches!(
+            error,
+            RuntimeError::AssertionFailed { left: 1, right: 2 }
+        ));
+        let message = format!("{error:?}");
+        assert!(message.contains('1') && message.contains('2'), "{message}");
+    }
+
+    #[test]
+    fn checked_arithmetic_reports_overflow() {
+        let source = |call| {
+            format!(
+                "fn main(): u32 {{
+                    let (value, overflowed) = {call};
+                    if overflowed {{ return value + 1000; }}
+                    return value;
+                }}"
+            )
+        };
+        // `0 - 1` wraps around to the largest word whatever the word size is.
+        assert_eq!(run(&source("add_checked(0 - 1, 1)")), 1000);
+        assert_eq!(run(&source("add_checked(2, 3)")), 5);
+        assert_eq!(run(&source("sub_checked(0, 1)")), 999);
+        assert_eq!(run(&source("mul_checked(0 - 1, 2)")), 998);
+    }
+
+    #[test]
+    fn modpow_matches_repeated_multiplication() {
+        let reference = |base: Word, exponent: Word, modulus: Word| {
+            (0..exponent).fold(1 % modulus, |result, _| result * base % modulus)
+        };
+        assert_eq!(
+            run("fn main(): u32 { return modpow(2, 10, 1000); }"),
+            reference(2, 10, 1000)
+        );
+        assert_eq!(reference(2, 10, 1000), 24);
+        assert_eq!(modpow(7, 13, 1), reference(7, 13, 1));
+        assert_eq!(modpow(3, 0, 5), 1);
+        assert_eq!(modpow(Word::MAX, 2, Word::MAX - 1), 1);
+        assert_eq!(modpow(2, 3, 0), 0);
+    }
+
+    #[test]
+    fn min_max_and_clamp() {
+        let cases = [
+            ("min(3, 7)", 3),
+            ("min(7, 3)", 3),
+            ("max(3, 7)", 7),
+            ("max(7, 7)", 7),
+            ("clamp(5, 2, 9)", 5),
+            ("clamp(1, 2, 9)", 2),
+            ("clamp(12, 2, 9)", 9),
+            ("clamp(5, 9, 2)", 2),
+        ];
+        for (call, expected) in cases {
+            assert_eq!(
+                run(&format!("fn main(): u32 {{ return {call}; }}")),
+                expected,
+                "{call}"
+            );
+        }
+    }
+
+    #[test]
+    fn saturating_arithmetic() {
+        let max = Word::MAX;
+        assert_eq!(run("fn main(): u32 { return sat_add(0 - 1, 1); }"), max);
+        assert_eq!(run("fn main(): u32 { return sat_add(2, 3); }"), 5);
+        assert_eq!(run("fn main(): u32 { return sat_sub(0, 1); }"), 0);
+        assert_eq!(run("fn main(): u32 { return sat_sub(5, 3); }"), 2);
+        assert_eq!(run("fn main(): u32 { return sat_mul(0 - 1, 2); }"), max);
+    }
+
+    #[test]
+    fn comparisons() {
+        let cases = [
+            ("eq(3, 3)", 1),
+            ("eq(3, 4)", 0),
+            ("ne(3, 4)", 1),
+            ("ne(3, 3)", 0),
+            ("lt(3, 4)", 1),
+            ("lt(4, 4)", 0),
+            ("le(4, 4)", 1),
+            ("le(5, 4)", 0),
+            ("gt(5, 4)", 1),
+            ("gt(4, 4)", 0),
+            ("ge(4, 4)", 1),
+            ("ge(3, 4)", 0),
+        ];
+        for (call, expected) in cases {
+            let source = format!("fn main(): u32 {{ if {call} {{ return 1; }} return 0; }}");
+            assert_eq!(run(&source), expected, "{call}");
+        }
+    }
+
+    #[test]
+    fn tuples_must_be_destructured() {
+        let program = parser::parse(
+            "fn main(): u32 { let pair = add_checked(1, 2); add_checked(1, 2); return 0; }",
+        )
+        .unwrap();
+        assert!(matches!(
+            typechecker::check(&program).map_err(|error| error.node),
+            Err(typechecker::Error::UndestructuredTuple(_))
+        ));
+    }
+
+    /// Output written to a buffer that can still be read after the VM has it.
+    #[derive(Clone, Default)]
+    struct Captured(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn printing_goes_to_the_output() {
+        let program = parser::parse(
+            "fn main(): u32 {
+                print_hex(255);
+                print_char(65);
+                print_char(233);
+                print_char(1114112);
+                print(10);
+                return 0;
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        let output = Captured::default();
+        vm.set_output(output.clone());
+        vm.run().unwrap();
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "ff\nAé\u{fffd}10\n"
+        );
+    }
+
+    #[test]
+    fn read_bytes_fills_a_heap_block() {
+        let program = parser::parse(
+            "fn main(): u32 {
+                let (address, length) = read_bytes(4);
+                return address * 100 + length;
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.set_input(&b"hello"[..]);
+        let result = vm.run().unwrap();
+        let (address, length) = (result / 100, result % 100);
+        assert_eq!(length, 4);
+        let bytes: Vec<_> = (0..length)
+            .map(|i| vm.read_heap(address + i) as u8)
+            .collect();
+        assert_eq!(bytes, b"hell");
+        assert_eq!(vm.allocation_size(address), Some(4));
+    }
+}
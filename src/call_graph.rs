@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ast::{Declaration, Expression, Spanned, Statement};
+
+/// Each function declared in a program, mapped to the names of the functions it
+/// calls directly. Callees may be builtins, which don't have entries of their own.
+pub type CallGraph = BTreeMap<String, BTreeSet<String>>;
+
+pub fn call_graph(ast: &[Declaration]) -> CallGraph {
+    let mut graph = CallGraph::new();
+    for declaration in ast {
+        match declaration {
+            Declaration::Function { name, body, .. } => {
+                let mut callees = BTreeSet::new();
+                block_calls(body, &mut callees);
+                graph.insert(name.clone(), callees);
+            }
+            Declaration::Extern { name, .. } => {
+                graph.insert(name.clone(), BTreeSet::new());
+            }
+            Declaration::TypeAlias { .. } => {}
+        }
+    }
+    graph
+}
+
+fn block_calls(body: &[Spanned<Statement>], callees: &mut BTreeSet<String>) {
+    for statement in body {
+        match &statement.node {
+            Statement::Let {
+                value: expression, ..
+            }
+            | Statement::Const {
+                value: expression, ..
+            }
+            | Statement::Assign(_, expression)
+            | Statement::LetTuple(_, expression)
+            | Statement::Expr(expression)
+            | Statement::Return(expression) => expression_calls(expression, callees),
+            Statement::IndexAssign(array, index, value) => {
+                expression_calls(array, callees);
+                expression_calls(index, callees);
+                expression_calls(value, callees);
+            }
+            Statement::If(condition, then_body, else_body) => {
+                expression_calls(condition, callees);
+                block_calls(then_body, callees);
+                block_calls(else_body, callees);
+            }
+            Statement::While(condition, body) => {
+                expression_calls(condition, callees);
+                block_calls(body, callees);
+            }
+            Statement::Break(value) => {
+                if let Some(value) = value {
+                    expression_calls(value, callees);
+                }
+            }
+        }
+    }
+}
+
+fn expression_calls(expression: &Expression, callees: &mut BTreeSet<String>) {
+    match expression {
+        Expression::Call(function, expressions) => {
+            callees.insert(function.clone());
+            for expression in expressions {
+                expression_calls(expression, callees);
+            }
+        }
+        Expression::ArrayLiteral(expressions) => {
+            for expression in expressions {
+                expression_calls(expression, callees);
+            }
+        }
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b)
+        | Expression::And(a, b)
+        | Expression::Or(a, b) => {
+            expression_calls(a, callees);
+            expression_calls(b, callees);
+        }
+        Expression::Block(body, tail) => {
+            block_calls(body, callees);
+            expression_calls(tail, callees);
+        }
+        Expression::If(condition, then_branch, else_branch) => {
+            expression_calls(condition, callees);
+            expression_calls(then_branch, callees);
+            expression_calls(else_branch, callees);
+        }
+        Expression::Loop(body) => block_calls(body, callees),
+        Expression::Negate(a) | Expression::Not(a) => expression_calls(a, callees),
+        Expression::Match(value, arms, wildcard) => {
+            expression_calls(value, callees);
+            for arm in arms.iter().map(|(_, arm)| arm).chain(wildcard.as_deref()) {
+                expression_calls(arm, callees);
+            }
+        }
+        Expression::Variable(_)
+        | Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn mutual_recursion() {
+        let program = parser::parse(
+            "fn is_even(n: u32): bool {
+                if n == 0 { return true; }
+                return is_odd(n - 1);
+            }
+            fn is_odd(n: u32): bool {
+                if n == 0 { return false; }
+                return is_even(n - 1);
+            }
+            fn main(): u32 {
+                if is_even(4) { return add(1, 1); }
+                return 0;
+            }",
+        )
+        .unwrap();
+        let graph = call_graph(&program);
+        let callees = |name: &str| graph[name].iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(graph.len(), 3);
+        assert_eq!(callees("is_even"), ["is_odd"]);
+        assert_eq!(callees("is_odd"), ["is_even"]);
+        assert_eq!(callees("main"), ["add", "is_even"]);
+    }
+}
@@ -1,237 +1,2156 @@
-use crate::{ast, virtual_machine};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+};
 
-pub fn compile(ast: &Vec<ast::Declaration>) -> virtual_machine::VirtualMachine {
+use crate::{
+    assembler::{Label, LabeledOperation, resolve_labels},
+    ast,
+    builtins::{self, Builtin},
+    fold,
+    typechecker::{self, SignedOperations},
+    virtual_machine,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An `extern fn` with no builtin of the same name and signature to provide it.
+    UnboundExtern(String),
+    /// There is no function with the name the program starts from, `main` unless
+    /// another was chosen.
+    MissingEntry(String),
+    /// The code generated for `function` could leave the stack a different height
+    /// than expected at the operation with this index, which is a compiler bug.
+    StackImbalance { function: String, operation: usize },
+    /// The `Get` or `Put` at this index in `function` reaches below the caller's
+    /// result slot, which is a compiler bug.
+    BadLocalOffset { function: String, operation: usize },
+}
+
+pub fn compile(ast: &Vec<ast::Declaration>) -> Result<virtual_machine::VirtualMachine, Error> {
+    compile_with_builtins(ast, &builtins::standard())
+}
+
+pub fn compile_with_builtins(
+    ast: &Vec<ast::Declaration>,
+    builtins: &[Builtin],
+) -> Result<virtual_machine::VirtualMachine, Error> {
+    Ok(build_vm(
+        ast,
+        &compile_functions(ast, builtins, "main")?,
+        builtins,
+    ))
+}
+
+/// Like `compile`, but the program starts from the function named `entry` instead
+/// of `main`.
+pub fn compile_with_entry(
+    ast: &Vec<ast::Declaration>,
+    entry: &str,
+) -> Result<virtual_machine::VirtualMachine, Error> {
+    let builtins = builtins::standard();
+    let functions = compile_functions(ast, &builtins, entry)?;
+    let mut strings = StringPool::default();
+    let mut vm = virtual_machine::VirtualMachine::from_functions_with_entry(
+        build_functions(&functions, &builtins, &mut strings),
+        entry,
+    )
+    .expect("the entry was checked to exist when compiling");
+    strings.load(&mut vm);
+    if let Some(return_type) = return_type_of(ast, entry) {
+        vm.set_return_type(return_type);
+    }
+    Ok(vm)
+}
+
+/// Caches compiled code keyed by a hash of the AST, so compiling an unchanged
+/// program again only has to rebuild the `VirtualMachine` from stored operations.
+/// Each entry keeps its AST, which is compared on a hit so that two programs whose
+/// hashes collide still get their own code.
+#[derive(Default)]
+pub struct CompileCache {
+    entries: HashMap<u64, Vec<CacheEntry>>,
+    compilations: usize,
+}
+
+/// A program and the code compiled from it.
+type CacheEntry = (Vec<ast::Declaration>, Vec<(String, Code)>);
+
+impl CompileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_compile(
+        &mut self,
+        ast: &Vec<ast::Declaration>,
+    ) -> Result<virtual_machine::VirtualMachine, Error> {
+        let mut hasher = DefaultHasher::new();
+        ast.hash(&mut hasher);
+        self.get_or_compile_with_hash(ast, hasher.finish())
+    }
+
+    fn get_or_compile_with_hash(
+        &mut self,
+        ast: &Vec<ast::Declaration>,
+        hash: u64,
+    ) -> Result<virtual_machine::VirtualMachine, Error> {
+        let builtins = builtins::standard();
+        let entries = self.entries.entry(hash).or_default();
+        let index = match entries.iter().position(|(cached, _)| cached == ast) {
+            Some(index) => index,
+            None => {
+                self.compilations += 1;
+                entries.push((ast.clone(), compile_functions(ast, &builtins, "main")?));
+                entries.len() - 1
+            }
+        };
+        Ok(build_vm(ast, &entries[index].1, &builtins))
+    }
+
+    /// Number of times the cache actually had to run the compiler.
+    pub fn compilations(&self) -> usize {
+        self.compilations
+    }
+}
+
+/// Compiles `ast` and loads it into `vm` in place of the functions it had, keeping
+/// its heap, so addresses from earlier runs stay valid.
+pub fn compile_into(
+    ast: &Vec<ast::Declaration>,
+    vm: &mut virtual_machine::VirtualMachine,
+) -> Result<(), Error> {
+    let builtins = builtins::standard();
+    let functions = compile_functions(ast, &builtins, "main")?;
+    let mut strings = StringPool {
+        base: vm.heap_size() as virtual_machine::Word,
+        ..StringPool::default()
+    };
+    vm.replace_functions(build_functions(&functions, &builtins, &mut strings));
+    strings.load(vm);
+    if let Some(return_type) = return_type_of(ast, "main") {
+        vm.set_return_type(return_type);
+    }
+    Ok(())
+}
+
+fn build_vm(
+    ast: &[ast::Declaration],
+    functions: &[(String, Code)],
+    builtins: &[Builtin],
+) -> virtual_machine::VirtualMachine {
+    let mut strings = StringPool::default();
+    let mut vm = virtual_machine::VirtualMachine::from_functions(build_functions(
+        functions,
+        builtins,
+        &mut strings,
+    ));
+    strings.load(&mut vm);
+    if let Some(return_type) = return_type_of(ast, "main") {
+        vm.set_return_type(return_type);
+    }
+    vm
+}
+
+/// The declared return type of the function called `name` in `ast`.
+fn return_type_of(ast: &[ast::Declaration], name: &str) -> Option<ast::Type> {
+    ast.iter().find_map(|declaration| match declaration {
+        ast::Declaration::Function {
+            name: function,
+            return_type,
+            ..
+        } if function == name => Some(return_type.resolved()),
+        _ => None,
+    })
+}
+
+/// Builtins are placed after the compiled functions, matching the indices used by
+/// `compile_functions`.
+fn build_functions(
+    functions: &[(String, Code)],
+    builtins: &[Builtin],
+    strings: &mut StringPool,
+) -> Vec<virtual_machine::Function> {
+    functions
+        .iter()
+        .map(|(name, code)| {
+            let mut operations = resolve_labels(&code.operations);
+            for (index, text) in &code.strings {
+                operations[*index] = virtual_machine::Operation::Push(strings.address(text));
+            }
+            virtual_machine::Function::from_operations(name.clone(), operations)
+                .with_spans(code.spans.clone())
+                .with_constants(code.constants.clone())
+                .with_max_stack(code.max_stack)
+                .with_arguments(code.arguments)
+        })
+        .chain(builtins.iter().map(|builtin| {
+            let function = builtin.function.clone();
+            virtual_machine::Function::from_builtin(builtin.name.clone(), move |vm| function(vm))
+        }))
+        .collect()
+}
+
+/// The string literals of a program, each stored once on the heap as an array of
+/// its characters. They are laid out one after another in a single block, which
+/// must be allocated at `base` with `load`.
+#[derive(Default)]
+struct StringPool {
+    base: virtual_machine::Word,
+    addresses: HashMap<String, virtual_machine::Word>,
+    data: Vec<virtual_machine::Word>,
+}
+
+impl StringPool {
+    /// Where `text` will be, adding it to the pool if it isn't there yet.
+    fn address(&mut self, text: &str) -> virtual_machine::Word {
+        if let Some(address) = self.addresses.get(text) {
+            return *address;
+        }
+        let address = self.base + self.data.len() as virtual_machine::Word;
+        self.data
+            .push(text.chars().count() as virtual_machine::Word);
+        self.data
+            .extend(text.chars().map(|c| c as virtual_machine::Word));
+        self.addresses.insert(text.to_string(), address);
+        address
+    }
+
+    /// Puts the strings on the end of `vm`'s heap, which must have grown to `base`.
+    /// Every use of a string shares its slots, so the program can't write to them.
+    fn load(&self, vm: &mut virtual_machine::VirtualMachine) {
+        if !self.data.is_empty() {
+            let address = vm.allocate_read_only(&self.data);
+            debug_assert_eq!(address, self.base);
+        }
+    }
+}
+
+/// The operations of a function being compiled, along with the span of the
+/// statement each one was generated from.
+#[derive(Clone, Default)]
+struct Code {
+    /// Jumps target labels, which are only turned into indices when the VM is built.
+    operations: Vec<LabeledOperation>,
+    /// The span of each operation, skipping labels.
+    spans: Vec<ast::Span>,
+    /// The span of the statement currently being compiled.
+    span: ast::Span,
+    /// How many labels have been made so far.
+    labels: usize,
+    /// How many arguments the function takes.
+    arguments: usize,
+    /// The loops around the statement being compiled, innermost last.
+    loops: Vec<LoopTarget>,
+    /// The table `PushMany` reads from.
+    constants: Vec<virtual_machine::Word>,
+    /// The most slots the frame holds at once, found by `check_stack_balance`.
+    max_stack: Option<usize>,
+    /// The `const` locals in scope, innermost last, with their values and how many
+    /// slots the frame had when each was declared.
+    const_locals: Vec<(String, virtual_machine::Word, usize)>,
+    /// The operations that push the address of a string literal, by their index
+    /// once labels are resolved, which are filled in when the string pool is laid
+    /// out.
+    strings: Vec<(usize, String)>,
+    /// The `Goto` of each jump table, by its index once labels are resolved, and
+    /// how many `Jump` entries follow it.
+    jump_tables: HashMap<usize, usize>,
+    /// Whether the function is `#[checked]`, so `+`, `-` and `*` trap on overflow.
+    checked: bool,
+}
+
+/// A `loop` or `while` that a `break` inside it jumps out of.
+#[derive(Clone, Copy)]
+struct LoopTarget {
+    end: Label,
+    /// How many slots of the frame there were when the body started, all of which
+    /// are still there after the loop.
+    depth: usize,
+    /// Whether the slot just below `depth` holds the loop's value, as for a `loop`.
+    has_value: bool,
+}
+
+impl Code {
+    fn push(&mut self, operation: virtual_machine::Operation) {
+        self.operations.push(LabeledOperation::Operation(operation));
+        self.spans.push(self.span);
+    }
+
+    /// Emits a `PushMany` of `values`, adding them to the constants.
+    fn push_many(&mut self, values: &[virtual_machine::Word]) {
+        let start = self.constants.len() as u32;
+        self.constants.push(values.len() as virtual_machine::Word);
+        self.constants.extend_from_slice(values);
+        self.push(virtual_machine::Operation::PushMany(start));
+    }
+
+    /// A new label, not yet placed anywhere.
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    /// The value of the `const` that `name` refers to, unless there is none or a
+    /// local declared after it shadows it.
+    fn constant(&self, name: &str, local_vars: &[Option<String>]) -> Option<virtual_machine::Word> {
+        let (_, value, height) = self
+            .const_locals
+            .iter()
+            .rev()
+            .find(|(constant, ..)| constant == name)?;
+        let shadowed = local_vars
+            .iter()
+            .skip(*height)
+            .any(|local| local.as_deref() == Some(name));
+        (!shadowed).then_some(*value)
+    }
+
+    /// Makes `label` point at the next operation to be emitted.
+    fn place(&mut self, label: Label) {
+        self.operations.push(LabeledOperation::Label(label));
+    }
+
+    /// Emits a jump made by `jump` to wherever `label` is placed.
+    fn jump(&mut self, jump: fn(u32) -> virtual_machine::Operation, label: Label) {
+        self.operations.push(LabeledOperation::Jump(jump, label));
+        self.spans.push(self.span);
+    }
+}
+
+struct Callable {
+    name: String,
+    /// How many arguments it is declared with.
+    arity: usize,
+    builtin: bool,
+    result_size: u32,
+    operations: Option<Vec<virtual_machine::Operation>>,
+}
+
+/// Compiles every function in `ast`, checking that there is one named `entry` to
+/// start from.
+fn compile_functions(
+    ast: &Vec<ast::Declaration>,
+    builtins: &[Builtin],
+    entry: &str,
+) -> Result<Vec<(String, Code)>, Error> {
     let mut functions = vec![];
 
-    let function_names: Vec<String> = ast
+    if !ast.iter().any(
+        |declaration| matches!(declaration, ast::Declaration::Function { name, .. } if name == entry),
+    ) {
+        return Err(Error::MissingEntry(entry.to_string()));
+    }
+
+    for declaration in ast {
+        if let ast::Declaration::Extern {
+            name,
+            arguments,
+            return_type,
+            ..
+        } = declaration
+        {
+            let signature = ast::Type::Fun(
+                arguments.iter().map(|(_, t)| t.resolved()).collect(),
+                Box::new(return_type.resolved()),
+            );
+            if !builtins
+                .iter()
+                .any(|builtin| &builtin.name == name && builtin.signature() == signature)
+            {
+                return Err(Error::UnboundExtern(name.clone()));
+            }
+        }
+    }
+
+    let callables: Vec<Callable> = ast
         .iter()
         .flat_map(|decl| match decl {
-            ast::Declaration::Function { name, .. } => Some(name.clone()),
+            ast::Declaration::Function {
+                name, arguments, ..
+            } => Some(Callable {
+                name: name.clone(),
+                arity: arguments.len(),
+                builtin: false,
+                result_size: 1,
+                operations: None,
+            }),
+            // Calls resolve to the builtin of the same name.
+            ast::Declaration::Extern { .. } => None,
+            ast::Declaration::TypeAlias { .. } => None,
         })
+        .chain(builtins.iter().map(|builtin| Callable {
+            name: builtin.name.clone(),
+            arity: builtin.arguments.len(),
+            builtin: true,
+            result_size: size_of(&builtin.return_type),
+            operations: builtin.operations.clone(),
+        }))
         .collect();
 
+    let signed = typechecker::signed_operations(ast, builtins, entry);
     for declaration in ast {
         match declaration {
             ast::Declaration::Function {
                 name,
+                doc: _,
+                arithmetic,
                 arguments,
                 return_type,
                 body,
+                span: _,
             } => {
                 let mut local_vars = Vec::new();
-                let mut operations = Vec::new();
+                let mut operations = Code {
+                    arguments: arguments.len(),
+                    checked: *arithmetic == ast::Arithmetic::Checked,
+                    ..Code::default()
+                };
                 for statement in body {
                     compile_statement(
                         statement,
                         &mut operations,
                         &mut local_vars,
                         arguments,
-                        &function_names,
+                        &callables,
+                        &signed,
                     );
                 }
-                functions.push(virtual_machine::Function::from_operations(
-                    name.clone(),
-                    operations,
-                ))
+                // A unit function may run off the end of its body, and returns there.
+                // The caller's result slot already holds 0, the unit value.
+                if return_type.resolved() == ast::Type::Unit
+                    && !matches!(
+                        body.last(),
+                        Some(ast::Spanned {
+                            node: ast::Statement::Return(_),
+                            ..
+                        })
+                    )
+                {
+                    for _ in 0..local_vars.len() {
+                        operations.push(virtual_machine::Operation::Pop);
+                    }
+                    operations.push(virtual_machine::Operation::Return);
+                }
+                operations.max_stack = check_stack_balance(
+                    name,
+                    &resolve_labels(&operations.operations),
+                    &operations.constants,
+                    &operations.jump_tables,
+                    operations.arguments,
+                    &callables,
+                )?;
+                functions.push((name.clone(), operations))
             }
+            ast::Declaration::Extern { .. } | ast::Declaration::TypeAlias { .. } => {}
         }
     }
 
-    virtual_machine::VirtualMachine::from_functions(functions)
+    Ok(functions)
+}
+
+/// The operations comparing the top two values, leaving a bool. `>` and `>=` are
+/// `<` and `<=` with the operands swapped; they are still evaluated left to right,
+/// and exchanged afterwards. `signed` compares them as signed numbers.
+pub(crate) fn comparison_operations(
+    comparison: ast::Comparison,
+    signed: bool,
+) -> Vec<virtual_machine::Operation> {
+    use virtual_machine::Operation::*;
+    let (less, less_equal) = if signed {
+        (LessSigned, LessEqualSigned)
+    } else {
+        (Less, LessEqual)
+    };
+    match comparison {
+        ast::Comparison::Equal => vec![Equal],
+        ast::Comparison::NotEqual => vec![NotEqual],
+        ast::Comparison::Less => vec![less],
+        ast::Comparison::LessEqual => vec![less_equal],
+        ast::Comparison::Greater => vec![Swap, less],
+        ast::Comparison::GreaterEqual => vec![Swap, less_equal],
+    }
 }
 
-fn size_of(t: &ast::Type) -> u32 {
+/// How many stack slots a value of type `t` takes.
+pub(crate) fn size_of(t: &ast::Type) -> u32 {
     match t {
-        ast::Type::Fun(items, _) => 1,
+        ast::Type::Fun(_, _) => 1,
         ast::Type::U32 => 1,
+        ast::Type::I32 => 1,
+        ast::Type::U8 => 1,
+        ast::Type::U16 => 1,
+        ast::Type::Bool => 1,
+        ast::Type::Unit => 1,
+        ast::Type::Tuple(items) => items.iter().map(size_of).sum(),
+        ast::Type::Array(_, _) => 1,
+        ast::Type::Alias(_, aliased) => size_of(aliased),
     }
 }
 
+/// What `sizeof(t)` evaluates to: the number of slots a value of type `t` takes.
+/// For an array that is the slots of its elements, not counting the header or the
+/// one slot the array itself takes.
+pub(crate) fn sizeof_value(t: &ast::Type) -> u32 {
+    match t.resolved() {
+        ast::Type::Array(element, length) => length.saturating_mul(size_of(&element)),
+        typ => size_of(&typ),
+    }
+}
+
+/// Follows every path through a function's `operations`, tracking how many slots
+/// its frame has, and checks that paths meeting at an operation agree on the height
+/// and that every `Return` is reached with an empty frame, the result having been
+/// put in the caller's slot. Also checks that no `Get` or `Put` reaches past the
+/// `arguments` to below that slot. A `Goto` in `jump_tables` is followed to each
+/// entry of its table. Returns the most slots the frame ever holds, or `None` if
+/// another computed jump stopped the check before every path was followed.
+fn check_stack_balance(
+    function: &str,
+    operations: &[virtual_machine::Operation],
+    constants: &[virtual_machine::Word],
+    jump_tables: &HashMap<usize, usize>,
+    arguments: usize,
+    callables: &[Callable],
+) -> Result<Option<usize>, Error> {
+    use virtual_machine::Operation::*;
+
+    let imbalance = |operation| Error::StackImbalance {
+        function: function.to_string(),
+        operation,
+    };
+    let bad_offset = |operation| Error::BadLocalOffset {
+        function: function.to_string(),
+        operation,
+    };
+    let mut heights: Vec<Option<usize>> = vec![None; operations.len()];
+    let mut max_height = 0;
+    let mut pending = vec![(0, 0)];
+    while let Some((index, height)) = pending.pop() {
+        // Running off the end is a missing return, which the VM reports.
+        let Some(operation) = operations.get(index) else {
+            continue;
+        };
+        match heights[index] {
+            Some(seen) if seen == height => continue,
+            Some(_) => return Err(imbalance(index)),
+            None => heights[index] = Some(height),
+        }
+        // Below the frame are the return address, the caller's function id, the
+        // arguments and the result slot, the deepest slot either may touch.
+        let deepest = height + 2 + arguments;
+        match *operation {
+            Get(depth) if depth as usize > deepest => return Err(bad_offset(index)),
+            LoadElement(depth, _) if depth as usize > deepest => return Err(bad_offset(index)),
+            // `Put` counts its depth after popping the value.
+            Put(depth) if height > 0 && depth as usize >= deepest => {
+                return Err(bad_offset(index));
+            }
+            _ => {}
+        }
+        let (popped, pushed) = match *operation {
+            Nop | Swap | Jump(_) | LoadIndirect(_) => (0, 0),
+            CheckIndex => (2, 2),
+            Push(_) | PushBool(_) | PushFunction(_) | Get(_) | Load(_) | Allocate(_)
+            | LoadElement(..) => (0, 1),
+            // `Put` may write to the caller's result slot, below the frame.
+            Pop | Put(_) | Store(_) | Free(_) | JumpIf(_) | JumpIfNot(_) => (1, 0),
+            StoreIndirect(_) => (2, 0),
+            PushMany(start) => (0, virtual_machine::constant_run(constants, start).len()),
+            StoreMany(count) => (count as usize + 1, 1),
+            AddImmediate(_) | SubImmediate(_) | SubImmediateBy(_) | MulImmediate(_)
+            | DivImmediate(_) | DivImmediateBy(_) | ModImmediate(_) | ModImmediateBy(_) => (1, 1),
+            Add | Sub | Mul | Div | Mod | AddSat | SubSat | MulSat | AddChecked | SubChecked
+            | MulChecked | AddCheckedSigned | SubCheckedSigned | MulCheckedSigned | DivSigned
+            | ModSigned | Equal | NotEqual | Less | LessEqual | LessSigned | LessEqualSigned => {
+                (2, 1)
+            }
+            // Compiled functions put their result in a slot the caller pushed.
+            Call(id) => match callables.get(id as usize) {
+                Some(callable) if callable.builtin => {
+                    (callable.arity, callable.result_size as usize)
+                }
+                _ => (0, 0),
+            },
+            CallFnPointer => (1, 0),
+            Return if height == 0 => continue,
+            Return => return Err(imbalance(index)),
+            Goto if jump_tables.contains_key(&index) => (1, 0),
+            // Other computed jumps can't be followed.
+            Goto | GotoIf | GotoIfNot => return Ok(None),
+        };
+        let Some(height) = height.checked_sub(popped) else {
+            return Err(imbalance(index));
+        };
+        let height = height + pushed;
+        max_height = max_height.max(height);
+        match *operation {
+            Jump(target) => pending.push((target as usize, height)),
+            Goto => {
+                let entries = index + 1..index + 1 + jump_tables[&index];
+                pending.extend(entries.map(|entry| (entry, height)));
+            }
+            JumpIf(target) | JumpIfNot(target) => {
+                pending.push((target as usize, height));
+                pending.push((index + 1, height));
+            }
+            _ => pending.push((index + 1, height)),
+        }
+    }
+    Ok(Some(max_height))
+}
+
+/// How far below the top of the stack the variable `name` is.
+fn variable_depth(
+    name: &String,
+    local_vars: &[Option<String>],
+    arguments: &[(String, ast::Type)],
+) -> u32 {
+    local_vars
+        .iter()
+        .rev()
+        .position(|var_name| var_name.as_ref() == Some(name))
+        .or_else(|| {
+            arguments
+                .iter()
+                .rev()
+                .position(|var_name| &var_name.0 == name)
+                .map(|i| i + local_vars.len() + 2)
+        })
+        .unwrap() as u32
+}
+
+fn compile_block(
+    body: &[ast::Spanned<ast::Statement>],
+    operations: &mut Code,
+    local_vars: &mut Vec<Option<String>>,
+    arguments: &Vec<(String, ast::Type)>,
+    functions: &Vec<Callable>,
+    signed: &SignedOperations,
+) {
+    let scope_start = local_vars.len();
+    let constants_start = operations.const_locals.len();
+    for statement in body {
+        compile_statement(
+            statement, operations, local_vars, arguments, functions, signed,
+        );
+    }
+    // Locals declared inside the block go out of scope here.
+    for _ in scope_start..local_vars.len() {
+        operations.push(virtual_machine::Operation::Pop);
+    }
+    local_vars.truncate(scope_start);
+    operations.const_locals.truncate(constants_start);
+}
+
 fn compile_statement(
-    statement: &ast::Statement,
-    operations: &mut Vec<virtual_machine::Operation>,
+    statement: &ast::Spanned<ast::Statement>,
+    operations: &mut Code,
     local_vars: &mut Vec<Option<String>>,
     arguments: &Vec<(String, ast::Type)>,
-    function_names: &Vec<String>,
+    functions: &Vec<Callable>,
+    signed: &SignedOperations,
 ) {
-    match statement {
-        ast::Statement::Let(name, var_type, expression) => {
-            let var_size = size_of(var_type.as_ref().unwrap());
+    // Operations of nested statements get their own spans, so restore ours after.
+    let outer_span = std::mem::replace(&mut operations.span, statement.span);
+    match &statement.node {
+        ast::Statement::Let {
+            name,
+            typ: var_type,
+            value: expression,
+            ..
+        } => {
+            // Unannotated bindings take the single slot their initializer pushed.
+            let var_size = var_type.as_ref().map_or(1, size_of);
+            compile_expression(
+                expression, operations, local_vars, arguments, functions, signed,
+            );
+            local_vars.pop(); // Expressions add their own None instance, which becomes the variable.
             if var_size > 0 {
                 local_vars.push(Some(name.clone()));
                 for _ in 1..var_size {
                     local_vars.push(None);
                 }
             }
+        }
+        // Takes no slot, as reads of it push the value instead.
+        ast::Statement::Const { name, value, .. } => {
+            let constant = |name: &str| operations.constant(name, local_vars);
+            let value = fold::evaluate(value, &constant, signed)
+                .expect("the typechecker checks that constants can be evaluated");
+            operations
+                .const_locals
+                .push((name.clone(), value, local_vars.len()));
+        }
+        ast::Statement::LetTuple(names, expression) => {
             compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                expression, operations, local_vars, arguments, functions, signed,
             );
-            local_vars.pop(); // Expressions add their own None instance, so we must remove it.
+            // The call pushed one slot per element, in order.
+            local_vars.truncate(local_vars.len() - names.len());
+            local_vars.extend(names.iter().cloned().map(Some));
         }
-        ast::Statement::Expr(expression) => {
+        ast::Statement::Assign(name, expression) => {
             compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                expression, operations, local_vars, arguments, functions, signed,
             );
-            operations.push(virtual_machine::Operation::Pop);
             local_vars.pop();
+            let depth = variable_depth(name, local_vars, arguments);
+            operations.push(virtual_machine::Operation::Put(depth));
+        }
+        ast::Statement::IndexAssign(array, index, value) => {
+            compile_element_address(
+                array, index, operations, local_vars, arguments, functions, signed,
+            );
+            compile_expression(value, operations, local_vars, arguments, functions, signed);
+            operations.push(virtual_machine::Operation::StoreIndirect(1));
+            local_vars.truncate(local_vars.len() - 2);
+        }
+        ast::Statement::Expr(expression) => {
+            let stack_size = local_vars.len();
+            compile_expression(
+                expression, operations, local_vars, arguments, functions, signed,
+            );
+            for _ in stack_size..local_vars.len() {
+                operations.push(virtual_machine::Operation::Pop);
+            }
+            local_vars.truncate(stack_size);
         }
         ast::Statement::Return(expression) => {
             compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                expression, operations, local_vars, arguments, functions, signed,
             );
             local_vars.pop();
-            operations.push(virtual_machine::Operation::Put(local_vars.len() as u32 + 2));
+            // `local_vars` holds every slot of the function's frame, including those of
+            // the blocks and half-evaluated expressions enclosing this statement, so
+            // all of them are dropped however deeply the return is nested.
+            // A call to another function leaves its result on top like any other
+            // expression, so `return f(...)` is just this, with no special frame.
+            operations.push(virtual_machine::Operation::Put(
+                (local_vars.len() + 2 + arguments.len()) as u32,
+            ));
             for _ in 0..local_vars.len() {
                 operations.push(virtual_machine::Operation::Pop);
             }
             operations.push(virtual_machine::Operation::Return);
         }
+        ast::Statement::If(condition, then_body, else_body) => {
+            compile_expression(
+                condition, operations, local_vars, arguments, functions, signed,
+            );
+            local_vars.pop(); // JumpIfNot consumes the condition.
+            let else_start = operations.label();
+            operations.jump(virtual_machine::Operation::JumpIfNot, else_start);
+            compile_block(
+                then_body, operations, local_vars, arguments, functions, signed,
+            );
+            if else_body.is_empty() {
+                operations.place(else_start);
+            } else {
+                let end = operations.label();
+                operations.jump(virtual_machine::Operation::Jump, end);
+                operations.place(else_start);
+                compile_block(
+                    else_body, operations, local_vars, arguments, functions, signed,
+                );
+                operations.place(end);
+            }
+        }
+        ast::Statement::While(condition, body) => {
+            let loop_start = operations.label();
+            let end = operations.label();
+            operations.place(loop_start);
+            compile_expression(
+                condition, operations, local_vars, arguments, functions, signed,
+            );
+            local_vars.pop();
+            operations.jump(virtual_machine::Operation::JumpIfNot, end);
+            operations.loops.push(LoopTarget {
+                end,
+                depth: local_vars.len(),
+                has_value: false,
+            });
+            compile_block(body, operations, local_vars, arguments, functions, signed);
+            operations.loops.pop();
+            operations.jump(virtual_machine::Operation::Jump, loop_start);
+            operations.place(end);
+        }
+        ast::Statement::Break(value) => {
+            let target = *operations.loops.last().expect("break outside of a loop");
+            if let Some(value) = value {
+                compile_expression(value, operations, local_vars, arguments, functions, signed);
+                local_vars.pop();
+                if target.has_value {
+                    operations.push(virtual_machine::Operation::Put(
+                        (local_vars.len() - target.depth) as u32,
+                    ));
+                } else {
+                    operations.push(virtual_machine::Operation::Pop);
+                }
+            }
+            // Like a return, this leaves every block and expression inside the loop.
+            for _ in target.depth..local_vars.len() {
+                operations.push(virtual_machine::Operation::Pop);
+            }
+            operations.jump(virtual_machine::Operation::Jump, target.end);
+        }
+    }
+    operations.span = outer_span;
+}
+
+/// Pushes the address of `array[index]`, less the one slot of the array's header,
+/// once the index is checked to be in bounds.
+fn compile_element_address(
+    array: &ast::Expression,
+    index: &ast::Expression,
+    operations: &mut Code,
+    local_vars: &mut Vec<Option<String>>,
+    arguments: &Vec<(String, ast::Type)>,
+    functions: &Vec<Callable>,
+    signed: &SignedOperations,
+) {
+    compile_expression(array, operations, local_vars, arguments, functions, signed);
+    compile_expression(index, operations, local_vars, arguments, functions, signed);
+    operations.push(virtual_machine::Operation::CheckIndex);
+    operations.push(virtual_machine::Operation::Add);
+    local_vars.pop();
+}
+
+/// The fewest arms a `match` needs before it is worth building a jump table for.
+const MIN_JUMP_TABLE_ARMS: usize = 3;
+
+/// If the numbers of a `match`'s arms are a contiguous range, the smallest of them
+/// and, for each number from it up, the index of the first arm with that number.
+fn jump_table(arms: &[(u32, ast::Expression)]) -> Option<(u32, Vec<usize>)> {
+    let mut first_arm = BTreeMap::new();
+    for (index, (n, _)) in arms.iter().enumerate() {
+        first_arm.entry(*n).or_insert(index);
+    }
+    let (&min, _) = first_arm.first_key_value()?;
+    let (&max, _) = first_arm.last_key_value()?;
+    if first_arm.len() < MIN_JUMP_TABLE_ARMS || (max - min) as usize + 1 != first_arm.len() {
+        return None;
+    }
+    Some((min, first_arm.into_values().collect()))
+}
+
+/// The word a literal compiles to, or `None` if `expression` isn't one.
+fn constant_value(expression: &ast::Expression) -> Option<virtual_machine::Word> {
+    match expression {
+        ast::Expression::NumLiteral(n) | ast::Expression::SuffixedLiteral(n, _) => {
+            Some(*n as virtual_machine::Word)
+        }
+        ast::Expression::NegativeLiteral(n) => {
+            Some((*n as i64).wrapping_neg() as virtual_machine::Word)
+        }
+        ast::Expression::BoolLiteral(b) => Some(*b as virtual_machine::Word),
+        _ => None,
     }
 }
 
 fn compile_expression(
     expression: &ast::Expression,
-    operations: &mut Vec<virtual_machine::Operation>,
+    operations: &mut Code,
     local_vars: &mut Vec<Option<String>>,
     arguments: &Vec<(String, ast::Type)>,
-    function_names: &Vec<String>,
+    functions: &Vec<Callable>,
+    signed: &SignedOperations,
 ) {
     match expression {
+        // Arguments are evaluated and pushed left to right, so their side effects
+        // happen in source order; this is a guarantee, and reordering them for
+        // speed would break it. A compiled function sees
+        // `[result slot, arguments..., return pc, return function id]`, writes its
+        // result into the slot and returns, after which the caller pops the arguments.
+        // Builtins pop their own arguments and push the result.
+        ast::Expression::Call(fn_name, expressions) if fn_name == "len" => {
+            // Read the length from the array's header.
+            compile_expression(
+                &expressions[0],
+                operations,
+                local_vars,
+                arguments,
+                functions,
+                signed,
+            );
+            operations.push(virtual_machine::Operation::LoadIndirect(0));
+        }
         ast::Expression::Call(fn_name, expressions) => {
+            let index = functions.iter().position(|f| &f.name == fn_name).unwrap();
+            // The callee finds its arguments by assuming this many were pushed, so a
+            // mismatch would shift every one of them.
+            debug_assert_eq!(
+                expressions.len(),
+                functions[index].arity,
+                "call to {fn_name} lays out the wrong number of arguments"
+            );
+            // Inline builtins consume their arguments and leave one value, like `Add`.
+            if let Some(inline) = &functions[index].operations {
+                for expression in expressions {
+                    compile_expression(
+                        expression, operations, local_vars, arguments, functions, signed,
+                    );
+                }
+                for operation in inline {
+                    operations.push(operation.clone());
+                }
+                local_vars.truncate(local_vars.len() - expressions.len());
+                local_vars.push(None);
+                return;
+            }
+            let Callable {
+                builtin,
+                result_size,
+                ..
+            } = functions[index];
+            if !builtin {
+                operations.push(virtual_machine::Operation::Push(0));
+                local_vars.push(None);
+            }
             for expression in expressions {
                 compile_expression(
-                    expression,
-                    operations,
-                    local_vars,
-                    arguments,
-                    function_names,
+                    expression, operations, local_vars, arguments, functions, signed,
                 );
             }
-            operations.push(virtual_machine::Operation::Call(
-                function_names.iter().position(|s| s == fn_name).unwrap() as u32,
-            ))
+            operations.push(virtual_machine::Operation::Call(index as u32));
+            for _ in expressions {
+                if !builtin {
+                    operations.push(virtual_machine::Operation::Pop);
+                }
+                local_vars.pop();
+            }
+            if builtin {
+                for _ in 0..result_size {
+                    local_vars.push(None);
+                }
+            }
         }
         ast::Expression::NumLiteral(n) => {
-            operations.push(virtual_machine::Operation::Push(*n));
+            operations.push(virtual_machine::Operation::Push(
+                *n as virtual_machine::Word,
+            ));
+            local_vars.push(None);
+        }
+        ast::Expression::SuffixedLiteral(n, _) => {
+            operations.push(virtual_machine::Operation::Push(
+                *n as virtual_machine::Word,
+            ));
+            local_vars.push(None);
+        }
+        ast::Expression::NegativeLiteral(n) => {
+            // Truncating or sign extending the i64 gives the i32's pattern at any word size.
+            operations.push(virtual_machine::Operation::Push(
+                (*n as i64).wrapping_neg() as virtual_machine::Word
+            ));
+            local_vars.push(None);
+        }
+        ast::Expression::StrLiteral(text) => {
+            // Labels take no index, so this is the index the `Push` ends up at.
+            operations
+                .strings
+                .push((operations.spans.len(), text.clone()));
+            operations.push(virtual_machine::Operation::Push(0));
+            local_vars.push(None);
+        }
+        ast::Expression::BoolLiteral(b) => {
+            operations.push(virtual_machine::Operation::PushBool(*b));
             local_vars.push(None);
         }
         ast::Expression::Variable(name) => {
-            let index = local_vars
-                .iter()
-                .rev()
-                .position(|var_name| var_name.as_ref() == Some(name))
-                .or_else(|| {
-                    arguments
-                        .iter()
-                        .rev()
-                        .position(|var_name| &var_name.0 == name)
-                        .map(|i| i + local_vars.len() + 2)
-                })
-                .unwrap();
-            operations.push(virtual_machine::Operation::Get(index as u32));
+            let operation = match operations.constant(name, local_vars) {
+                Some(value) => virtual_machine::Operation::Push(value),
+                None => {
+                    virtual_machine::Operation::Get(variable_depth(name, local_vars, arguments))
+                }
+            };
+            operations.push(operation);
             local_vars.push(None);
         }
-        ast::Expression::Add(expression, expression1) => {
+        ast::Expression::Add(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.push(match (operations.checked, signed.contains(expression)) {
+                (true, true) => virtual_machine::Operation::AddCheckedSigned,
+                (true, false) => virtual_machine::Operation::AddChecked,
+                (false, _) => virtual_machine::Operation::Add,
+            });
+            local_vars.pop();
+        }
+        ast::Expression::Sub(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.push(match (operations.checked, signed.contains(expression)) {
+                (true, true) => virtual_machine::Operation::SubCheckedSigned,
+                (true, false) => virtual_machine::Operation::SubChecked,
+                (false, _) => virtual_machine::Operation::Sub,
+            });
+            local_vars.pop();
+        }
+        ast::Expression::Mul(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.push(match (operations.checked, signed.contains(expression)) {
+                (true, true) => virtual_machine::Operation::MulCheckedSigned,
+                (true, false) => virtual_machine::Operation::MulChecked,
+                (false, _) => virtual_machine::Operation::Mul,
+            });
+            local_vars.pop();
+        }
+        ast::Expression::Negate(expression) => {
             compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                expression, operations, local_vars, arguments, functions, signed,
             );
+            // Only `i32`s are negated, and negating the smallest one overflows.
+            if operations.checked {
+                operations.push(virtual_machine::Operation::Push(0));
+                operations.push(virtual_machine::Operation::Swap);
+                operations.push(virtual_machine::Operation::SubCheckedSigned);
+            } else {
+                operations.push(virtual_machine::Operation::SubImmediateBy(0));
+            }
+        }
+        ast::Expression::Not(expression) => {
             compile_expression(
-                expression1,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                expression, operations, local_vars, arguments, functions, signed,
             );
-            operations.push(virtual_machine::Operation::Add);
+            operations.push(virtual_machine::Operation::PushBool(false));
+            operations.push(virtual_machine::Operation::Equal);
+        }
+        ast::Expression::Div(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.push(if signed.contains(expression) {
+                virtual_machine::Operation::DivSigned
+            } else {
+                virtual_machine::Operation::Div
+            });
             local_vars.pop();
         }
-        ast::Expression::Sub(expression, expression1) => {
-            compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+        ast::Expression::Mod(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.push(if signed.contains(expression) {
+                virtual_machine::Operation::ModSigned
+            } else {
+                virtual_machine::Operation::Mod
+            });
+            local_vars.pop();
+        }
+        ast::Expression::ArrayLiteral(elements) => {
+            // Constant elements are pushed along with the header in one go and copied in.
+            // Bools are stored one at a time, as type tags count what `PushMany` pushes
+            // as numbers.
+            let bools = elements
+                .iter()
+                .any(|element| matches!(element, ast::Expression::BoolLiteral(_)));
+            if !bools
+                && let Some(values) = elements
+                    .iter()
+                    .map(constant_value)
+                    .collect::<Option<Vec<_>>>()
+            {
+                let mut block = vec![elements.len() as virtual_machine::Word];
+                block.extend(values);
+                operations.push_many(&block);
+                operations.push(virtual_machine::Operation::Allocate(block.len() as u32));
+                operations.push(virtual_machine::Operation::StoreMany(block.len() as u32));
+                local_vars.push(None);
+                return;
+            }
+            // The length goes in a header slot before the elements.
+            operations.push(virtual_machine::Operation::Allocate(
+                elements.len() as u32 + 1,
+            ));
+            local_vars.push(None);
+            operations.push(virtual_machine::Operation::Get(0));
+            operations.push(virtual_machine::Operation::Push(
+                elements.len() as virtual_machine::Word
+            ));
+            operations.push(virtual_machine::Operation::StoreIndirect(0));
+            for (i, element) in elements.iter().enumerate() {
+                operations.push(virtual_machine::Operation::Get(0));
+                local_vars.push(None);
+                compile_expression(
+                    element, operations, local_vars, arguments, functions, signed,
+                );
+                operations.push(virtual_machine::Operation::StoreIndirect(i as u32 + 1));
+                local_vars.truncate(local_vars.len() - 2);
+            }
+        }
+        ast::Expression::Index(array, index) => {
+            // A variable indexed by a literal needs neither on the stack.
+            if let ast::Expression::Variable(name) = &**array
+                && let ast::Expression::NumLiteral(index)
+                | ast::Expression::SuffixedLiteral(index, _) = &**index
+                && let Ok(depth) = u16::try_from(variable_depth(name, local_vars, arguments))
+                && let Ok(index) = u16::try_from(*index)
+            {
+                operations.push(virtual_machine::Operation::LoadElement(depth, index));
+                local_vars.push(None);
+                return;
+            }
+            compile_element_address(
+                array, index, operations, local_vars, arguments, functions, signed,
             );
+            operations.push(virtual_machine::Operation::LoadIndirect(1));
+        }
+        ast::Expression::Block(body, tail) => {
+            let scope_start = local_vars.len();
+            let constants_start = operations.const_locals.len();
+            for statement in body {
+                compile_statement(
+                    statement, operations, local_vars, arguments, functions, signed,
+                );
+            }
+            compile_expression(tail, operations, local_vars, arguments, functions, signed);
+            // Move the value into the first slot of the block and drop the block's locals.
+            let block_size = local_vars.len() - scope_start;
+            if block_size > 1 {
+                operations.push(virtual_machine::Operation::Put(block_size as u32 - 2));
+                for _ in 2..block_size {
+                    operations.push(virtual_machine::Operation::Pop);
+                }
+            }
+            local_vars.truncate(scope_start);
+            local_vars.push(None);
+            operations.const_locals.truncate(constants_start);
+        }
+        ast::Expression::Compare(comparison, a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            for operation in comparison_operations(*comparison, signed.contains(expression)) {
+                operations.push(operation);
+            }
+            local_vars.pop();
+        }
+        ast::Expression::If(condition, then_branch, else_branch) => {
             compile_expression(
-                expression1,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+                condition, operations, local_vars, arguments, functions, signed,
             );
-            operations.push(virtual_machine::Operation::Sub);
             local_vars.pop();
-        }
-        ast::Expression::Mul(expression, expression1) => {
+            let else_start = operations.label();
+            let end = operations.label();
+            operations.jump(virtual_machine::Operation::JumpIfNot, else_start);
             compile_expression(
-                expression,
+                then_branch,
                 operations,
                 local_vars,
                 arguments,
-                function_names,
+                functions,
+                signed,
             );
+            local_vars.pop(); // Only one of the branches' values ends up on the stack.
+            operations.jump(virtual_machine::Operation::Jump, end);
+            operations.place(else_start);
             compile_expression(
-                expression1,
+                else_branch,
                 operations,
                 local_vars,
                 arguments,
-                function_names,
+                functions,
+                signed,
             );
-            operations.push(virtual_machine::Operation::Mul);
+            operations.place(end);
+        }
+        // The value of `a` is kept as the result when it decides it, and otherwise
+        // replaced by the value of `b`.
+        ast::Expression::And(a, b) | ast::Expression::Or(a, b) => {
+            compile_expression(a, operations, local_vars, arguments, functions, signed);
+            let end = operations.label();
+            operations.push(virtual_machine::Operation::Get(0));
+            if matches!(expression, ast::Expression::And(..)) {
+                operations.jump(virtual_machine::Operation::JumpIfNot, end);
+            } else {
+                operations.jump(virtual_machine::Operation::JumpIf, end);
+            }
+            operations.push(virtual_machine::Operation::Pop);
             local_vars.pop();
+            compile_expression(b, operations, local_vars, arguments, functions, signed);
+            operations.place(end);
         }
-        ast::Expression::Div(expression, expression1) => {
-            compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+        // Arms covering a contiguous range are found with a `Goto` into a table of
+        // jumps, and others by comparing the value with each arm's number in turn.
+        // The arm taken puts its value in the slot the matched value was in.
+        ast::Expression::Match(value, arms, wildcard) => {
+            use virtual_machine::Operation::*;
+
+            compile_expression(value, operations, local_vars, arguments, functions, signed);
+            let end = operations.label();
+            let default = operations.label();
+            let arm_labels: Vec<Label> = arms.iter().map(|_| operations.label()).collect();
+            match jump_table(arms) {
+                Some((min, entries)) => {
+                    let max = min + (entries.len() - 1) as u32;
+                    if min > 0 {
+                        operations.push(Get(0));
+                        operations.push(Push(min as virtual_machine::Word));
+                        operations.push(Less);
+                        operations.jump(JumpIf, default);
+                    }
+                    operations.push(Get(0));
+                    operations.push(Push(max as virtual_machine::Word));
+                    operations.push(LessEqual);
+                    operations.jump(JumpIfNot, default);
+                    operations.push(Get(0));
+                    if min > 0 {
+                        operations.push(SubImmediate(min as virtual_machine::Word));
+                    }
+                    let table = operations.label();
+                    operations.jump(|table| AddImmediate(table as virtual_machine::Word), table);
+                    // Labels take no index, so this is the index the `Goto` ends up at.
+                    operations
+                        .jump_tables
+                        .insert(operations.spans.len(), entries.len());
+                    operations.push(Goto);
+                    operations.place(table);
+                    for entry in entries {
+                        operations.jump(Jump, arm_labels[entry]);
+                    }
+                }
+                None => {
+                    for ((n, _), &label) in arms.iter().zip(&arm_labels) {
+                        operations.push(Get(0));
+                        operations.push(Push(*n as virtual_machine::Word));
+                        operations.push(Equal);
+                        operations.jump(JumpIf, label);
+                    }
+                    operations.jump(Jump, default);
+                }
+            }
+            let wildcard = wildcard
+                .as_ref()
+                .expect("the typechecker requires a `_` arm");
+            let bodies = arms.iter().map(|(_, arm)| arm).zip(arm_labels);
+            for (arm, label) in bodies.chain([(&**wildcard, default)]) {
+                operations.place(label);
+                compile_expression(arm, operations, local_vars, arguments, functions, signed);
+                operations.push(Put(0));
+                local_vars.pop();
+                operations.jump(Jump, end);
+            }
+            operations.place(end);
+        }
+        ast::Expression::Loop(body) => {
+            // The value the loop breaks with is put in this slot. A `break` without
+            // one leaves the 0 of the unit value.
+            operations.push(virtual_machine::Operation::Push(0));
+            local_vars.push(None);
+            let loop_start = operations.label();
+            let end = operations.label();
+            operations.place(loop_start);
+            operations.loops.push(LoopTarget {
+                end,
+                depth: local_vars.len(),
+                has_value: true,
+            });
+            compile_block(body, operations, local_vars, arguments, functions, signed);
+            operations.loops.pop();
+            operations.jump(virtual_machine::Operation::Jump, loop_start);
+            operations.place(end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser, typechecker};
+
+    fn run(source: &str) -> virtual_machine::Word {
+        let program = parser::parse(source).unwrap();
+        compile(&program).unwrap().run().unwrap()
+    }
+
+    #[test]
+    fn conditionals_select_a_value() {
+        let source = "fn size(n: u32): u32 { return n == 0 ? 0 : n < 10 ? 1 : 2; }
+            fn main(): u32 {
+                let big = 7 > 3;
+                return (big ? 10 : 20) + size(4) * 100 + size(0) * 1000 + size(99) * 10000;
+            }";
+        let program = parser::parse(source).unwrap();
+        typechecker::check(&program).unwrap();
+        assert_eq!(run(source), 20110);
+
+        let mismatched = parser::parse("fn main(): u32 { return true ? 1 : false; }").unwrap();
+        assert!(typechecker::check(&mismatched).is_err());
+        let not_bool = parser::parse("fn main(): u32 { return 1 ? 1 : 2; }").unwrap();
+        assert!(typechecker::check(&not_bool).is_err());
+    }
+
+    #[test]
+    fn checked_functions_trap_on_overflow() {
+        let program = |main: &str| {
+            format!(
+                "#[checked]
+                fn checked_cube(a: u32): u32 {{ return a * a * a; }}
+                #[wrapping]
+                fn wrapping_cube(a: u32): u32 {{ return a * a * a; }}
+                fn cube(a: u32): u32 {{ return a * a * a; }}
+                fn main(): u32 {{ return {main}; }}"
+            )
+        };
+        // Big enough to overflow a 64 bit word too.
+        let big: virtual_machine::Word = 4000000000;
+        let wrapped = big.wrapping_mul(big).wrapping_mul(big);
+        assert_eq!(run(&program("cube(4000000000)")), wrapped);
+        assert_eq!(run(&program("wrapping_cube(4000000000)")), wrapped);
+        assert_eq!(run(&program("checked_cube(1000)")), 1000000000);
+
+        let ast = parser::parse(&program("checked_cube(4000000000)")).unwrap();
+        assert!(matches!(
+            compile(&ast).unwrap().run(),
+            Err(virtual_machine::RuntimeError::Overflow)
+        ));
+
+        // `i32`s overflow at the signed range, and crossing zero is fine.
+        let signed = |main: &str| {
+            let source = format!(
+                "#[checked]
+                fn checked_cube(a: i32): i32 {{ return a * a * a; }}
+                #[checked]
+                fn checked_sum(a: i32, b: i32): i32 {{ return a + b - -a; }}
+                fn main(): i32 {{ return {main}; }}"
             );
-            compile_expression(
-                expression1,
+            let ast = parser::parse(&source).unwrap();
+            typechecker::check(&ast).unwrap();
+            compile(&ast).unwrap().run()
+        };
+        assert_eq!(
+            signed("checked_sum(-5, 7)").unwrap(),
+            -3i64 as virtual_machine::Word
+        );
+        assert_eq!(
+            signed("checked_cube(-1000)").unwrap(),
+            -1000000000i64 as virtual_machine::Word
+        );
+        assert!(matches!(
+            signed("checked_cube(-2000000000)"),
+            Err(virtual_machine::RuntimeError::Overflow)
+        ));
+        #[cfg(not(feature = "word64"))]
+        assert!(matches!(
+            signed("checked_sum(-2147483648, 0)"),
+            Err(virtual_machine::RuntimeError::Overflow)
+        ));
+
+        assert!(matches!(
+            parser::parse("#[fast] fn main(): u32 { return 0; }").map_err(|error| error.node),
+            Err(parser::Error::UnknownAttribute(name)) if name == "fast"
+        ));
+        assert!(matches!(
+            parser::parse("#[checked] type A = u32;").map_err(|error| error.node),
+            Err(parser::Error::MisplacedAttribute)
+        ));
+    }
+
+    #[test]
+    fn negative_literals_are_sign_extended() {
+        let main = |body: &str| {
+            let source = format!("fn main(): i32 {{ let x: i32 = -5; {body} }}");
+            let program = parser::parse(&source).unwrap();
+            typechecker::check(&program).map(|_| compile(&program).unwrap().run().unwrap())
+        };
+        assert_eq!(main("return x;").unwrap(), -5i64 as virtual_machine::Word);
+        assert_eq!(
+            main("return x + 3;").unwrap(),
+            -2i64 as virtual_machine::Word
+        );
+        assert_eq!(main("if x == -5 { return 1; } return 0;").unwrap(), 1);
+        assert_eq!(main("if x != 5 { return 1; } return 0;").unwrap(), 1);
+        assert_eq!(main("if x < 0 { return 1; } return 0;").unwrap(), 1);
+        assert_eq!(main("if -5 < 3 { return 1; } return 0;").unwrap(), 1);
+        assert_eq!(
+            main("if x >= -5 { if x > -6 { return 1; } } return 0;").unwrap(),
+            1
+        );
+        assert_eq!(
+            main("return x / 2;").unwrap(),
+            -2i64 as virtual_machine::Word
+        );
+        assert_eq!(
+            main("return x % 2;").unwrap(),
+            -1i64 as virtual_machine::Word
+        );
+        assert_eq!(
+            main("let y: i32 = (0 - 7) / 2; return y;").unwrap(),
+            -3i64 as virtual_machine::Word
+        );
+    }
+
+    #[test]
+    fn boolean_operators_short_circuit() {
+        let main = |condition: &str| {
+            let source =
+                format!("fn main(): u32 {{ let a = 1; if {condition} {{ return 1; }} return 2; }}");
+            let program = parser::parse(&source).unwrap();
+            typechecker::check(&program).unwrap();
+            compile(&program).unwrap().run().unwrap()
+        };
+        // The right side would stop the program with 7 if it ran.
+        assert_eq!(main("a == 2 && exit(7) == 7"), 2);
+        assert_eq!(main("a == 1 || exit(7) == 7"), 1);
+        assert_eq!(main("a == 1 && exit(7) == 7"), 7);
+        assert_eq!(main("a == 2 || exit(7) == 7"), 7);
+        assert_eq!(main("a == 1 && a < 2 || a > 5"), 1);
+        assert_eq!(main("a > 5 || a == 1 && a == 2"), 2);
+
+        let program = parser::parse("fn main(): bool { return 1 && true; }").unwrap();
+        assert!(matches!(
+            typechecker::check(&program).map_err(|error| error.node),
+            Err(typechecker::Error::NonMatchingTypes(
+                ast::Type::Bool,
+                ast::Type::U32
+            ))
+        ));
+    }
+
+    #[test]
+    fn function_body_can_end_in_its_value() {
+        let source = "fn main(): u32 { let x = 5; x + 1 }";
+        let program = parser::parse(source).unwrap();
+        typechecker::check(&program).unwrap();
+        assert_eq!(run(source), 6);
+
+        let program = parser::parse("fn main(): u32 { let x = 5; x == 1 }").unwrap();
+        assert!(matches!(
+            typechecker::check(&program).map_err(|error| error.node),
+            Err(typechecker::Error::NonMatchingTypes(..))
+        ));
+    }
+
+    #[test]
+    fn returning_another_functions_result() {
+        let source = "fn f(x: u32): u32 { let y = x * 10; return y + 1; }
+            fn g(a: u32): u32 {
+                let b = a + 1;
+                if a > 5 { let z = 2; return f(z); }
+                let c = 5;
+                return f(b + c);
+            }
+            fn main(): u32 { let before = 7; let r = g(2); return before * 1000 + r + g(6); }";
+        assert_eq!(run(source), 7081 + 21);
+    }
+
+    #[test]
+    fn unbalanced_code_is_caught() {
+        use virtual_machine::Operation::*;
+
+        let check = |operations: &[virtual_machine::Operation]| match check_stack_balance(
+            "main",
+            operations,
+            &[],
+            &HashMap::new(),
+            0,
+            &[],
+        ) {
+            Ok(_) => None,
+            Err(Error::StackImbalance { operation, .. }) => Some(operation),
+            Err(error) => panic!("unexpected {error:?}"),
+        };
+        assert_eq!(check(&[Push(1), Put(2), Return]), None);
+        // A local left behind when returning.
+        assert_eq!(check(&[Push(1), Push(2), Put(3), Return]), Some(3));
+        // One branch pushes a value that the other doesn't, but both pop it.
+        assert_eq!(
+            check(&[
+                PushBool(true),
+                JumpIfNot(3),
+                Push(1),
+                Pop,
+                Push(2),
+                Put(2),
+                Return
+            ]),
+            Some(3)
+        );
+        // Popping more than the frame holds.
+        assert_eq!(check(&[Pop, Return]), Some(0));
+        // The second entry of a jump table leaves a local behind.
+        let table = [
+            Push(0),
+            Goto,
+            Jump(4),
+            Jump(7),
+            Push(1),
+            Put(2),
+            Return,
+            Push(1),
+            Push(2),
+            Put(3),
+            Return,
+        ];
+        assert!(matches!(
+            check_stack_balance("main", &table, &[], &HashMap::from([(1, 2)]), 0, &[]),
+            Err(Error::StackImbalance { operation: 10, .. })
+        ));
+
+        let offsets =
+            |operations: &[virtual_machine::Operation], arguments| match check_stack_balance(
+                "main",
                 operations,
-                local_vars,
+                &[],
+                &HashMap::new(),
                 arguments,
-                function_names,
+                &[],
+            ) {
+                Ok(_) => None,
+                Err(Error::BadLocalOffset { operation, .. }) => Some(operation),
+                Err(error) => panic!("unexpected {error:?}"),
+            };
+        // The deepest slots `Get` and `Put` can reach are the result slot.
+        assert_eq!(offsets(&[Get(3), Put(3), Push(1), Put(3), Return], 1), None);
+        assert_eq!(offsets(&[Get(3), Pop, Push(1), Put(2), Return], 0), Some(0));
+        assert_eq!(offsets(&[Push(1), Put(3), Return], 0), Some(1));
+    }
+
+    #[test]
+    fn many_locals_are_within_reach() {
+        let count = 2000;
+        let lets: String = (0..count)
+            .map(|i| format!("let v{i}: u32 = a + {i};"))
+            .collect();
+        let source = format!(
+            "fn f(a: u32, b: u32): u32 {{ {lets} return v0 + v{} * b + a; }}
+            fn main(): u32 {{ return f(1, 2); }}",
+            count - 1
+        );
+        let program = parser::parse(&source).unwrap();
+        let functions = compile_functions(&program, &builtins::standard(), "main").unwrap();
+        let deepest = resolve_labels(&functions[0].1.operations)
+            .iter()
+            .filter_map(|operation| match operation {
+                virtual_machine::Operation::Get(depth) => Some(*depth),
+                _ => None,
+            })
+            .max();
+        // Reading `a` from under every local, the sum so far, the return address and `b`.
+        assert_eq!(deepest, Some(count + 4));
+        assert_eq!(run(&source), 1 + 2000 * 2 + 1);
+    }
+
+    #[test]
+    fn arguments_are_evaluated_left_to_right() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // Records each value it is called with, and returns it.
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = Builtin::new("record", vec![ast::Type::U32], ast::Type::U32, {
+            let seen = Rc::clone(&seen);
+            move |vm| {
+                let value = vm.pop();
+                seen.borrow_mut().push(value);
+                vm.push(value);
+            }
+        });
+        let program = parser::parse(
+            "fn three(a: u32, b: u32, c: u32): u32 { return a * 100 + b * 10 + c; }
+            fn main(): u32 {
+                return three(record(1), record(2), record(3)) + max(record(4), record(5));
+            }",
+        )
+        .unwrap();
+        let mut builtins = builtins::standard();
+        builtins.push(record);
+        let mut vm = compile_with_builtins(&program, &builtins).unwrap();
+        assert_eq!(vm.run().unwrap(), 128);
+        assert_eq!(*seen.borrow(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn entry_can_be_named_anything() {
+        let program = parser::parse(
+            "fn main(): u32 { return 1; }
+            fn start(): u32 { return 2; }",
+        )
+        .unwrap();
+        assert_eq!(
+            compile_with_entry(&program, "start")
+                .unwrap()
+                .run()
+                .unwrap(),
+            2
+        );
+        assert_eq!(compile(&program).unwrap().run().unwrap(), 1);
+        assert!(matches!(
+            compile_with_entry(&program, "begin"),
+            Err(Error::MissingEntry(name)) if name == "begin"
+        ));
+
+        // `main` is an ordinary name when the program starts elsewhere, so the
+        // signed comparison in `start` is still found.
+        let program = parser::parse(
+            "fn start(): u32 { let main: i32 = -5; if main < 0 { return 1; } return 0; }",
+        )
+        .unwrap();
+        typechecker::check_with_entry(&program, "start").unwrap();
+        assert_eq!(
+            compile_with_entry(&program, "start")
+                .unwrap()
+                .run()
+                .unwrap(),
+            1
+        );
+
+        let functions = || {
+            vec![
+                virtual_machine::Function::from_operations("main", vec![]),
+                virtual_machine::Function::from_operations(
+                    "start",
+                    vec![
+                        virtual_machine::Operation::Push(3),
+                        virtual_machine::Operation::Put(2),
+                        virtual_machine::Operation::Return,
+                    ],
+                ),
+            ]
+        };
+        let mut vm =
+            virtual_machine::VirtualMachine::from_functions_with_entry(functions(), "start")
+                .unwrap();
+        assert_eq!(vm.run().unwrap(), 3);
+        assert!(matches!(
+            virtual_machine::VirtualMachine::from_functions_with_entry(functions(), "begin"),
+            Err(virtual_machine::RuntimeError::UnknownFunction(_))
+        ));
+    }
+
+    #[test]
+    fn if_and_while() {
+        assert_eq!(
+            run("fn main(): u32 { if false { return 1; } else { return 2; } }"),
+            2
+        );
+        assert_eq!(
+            run("fn main(): u32 {
+                let n: u32 = 0;
+                let keep_going: bool = true;
+                while keep_going {
+                    let step: u32 = 5;
+                    if true { return n + step; }
+                }
+                return 7;
+            }"),
+            5
+        );
+    }
+
+    #[test]
+    fn jumps_are_aimed_at_their_labels() {
+        use virtual_machine::Operation::*;
+
+        let program = parser::parse(
+            "fn main(): u32 {
+                if true { return 1; } else { return 2; }
+                while false {}
+                return 3;
+            }",
+        )
+        .unwrap();
+        let functions = compile_functions(&program, &[], "main").unwrap();
+        assert_eq!(
+            resolve_labels(&functions[0].1.operations),
+            vec![
+                PushBool(true),  // 0
+                JumpIfNot(6),    // 1
+                Push(1),         // 2
+                Put(2),          // 3
+                Return,          // 4
+                Jump(9),         // 5
+                Push(2),         // 6
+                Put(2),          // 7
+                Return,          // 8
+                PushBool(false), // 9
+                JumpIfNot(12),   // 10
+                Jump(9),         // 11
+                Push(3),         // 12
+                Put(2),          // 13
+                Return,          // 14
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_arrays_are_pushed_at_once() {
+        use virtual_machine::Operation::*;
+
+        let program = parser::parse("fn main(): u32 { let a = [3, 4, 5]; return a[2]; }").unwrap();
+        let functions = compile_functions(&program, &builtins::standard(), "main").unwrap();
+        let code = &functions[0].1;
+        assert_eq!(
+            resolve_labels(&code.operations)[..3],
+            [PushMany(0), Allocate(4), StoreMany(4)]
+        );
+        assert_eq!(code.constants, vec![4, 3, 3, 4, 5]);
+        let mut vm = compile(&program).unwrap();
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 5);
+
+        let mut vm = virtual_machine::VirtualMachine::from_functions(vec![
+            virtual_machine::Function::from_operations(
+                "main",
+                vec![PushMany(2), Add, Add, Put(2), Return],
+            )
+            .with_constants(vec![1, 9, 3, 10, 20, 30]),
+        ]);
+        assert_eq!(vm.run().unwrap(), 60);
+    }
+
+    #[test]
+    fn array_elements_keep_their_tags() {
+        let program = parser::parse(
+            "fn main(): bool {
+                let a = [true, false];
+                let mut b = [false, false];
+                b[1] = a[0];
+                return a[1] == false && b[1] == true;
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compile(&program).unwrap();
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 1);
+    }
+
+    #[test]
+    fn constant_index_loads_at_an_offset() {
+        use virtual_machine::Operation::*;
+
+        let source =
+            "fn main(): u32 { let arr = [10, 20, 30, 40]; let i = 1; return arr[2] + arr[i]; }";
+        let program = parser::parse(source).unwrap();
+        let functions = compile_functions(&program, &builtins::standard(), "main").unwrap();
+        let operations = resolve_labels(&functions[0].1.operations);
+        // `arr` is under `i` when it is read.
+        assert!(operations.contains(&LoadElement(1, 2)));
+        // The variable index still goes through an address on the stack.
+        assert_eq!(
+            operations
+                .iter()
+                .filter(|operation| matches!(operation, LoadIndirect(_)))
+                .count(),
+            1
+        );
+        let mut vm = compile(&program).unwrap();
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 50);
+    }
+
+    #[test]
+    fn builtin_arguments_are_popped_in_order() {
+        let subtract = Builtin::new(
+            "subtract",
+            vec![ast::Type::U32, ast::Type::U32],
+            ast::Type::U32,
+            |vm| {
+                let b = vm.pop();
+                let a = vm.pop();
+                vm.push(a.wrapping_sub(b));
+            },
+        );
+        let builtins = [subtract];
+        let program = parser::parse("fn main(): u32 { return subtract(10, 3); }").unwrap();
+        typechecker::check_with_builtins(&program, &builtins).unwrap();
+        assert_eq!(
+            compile_with_builtins(&program, &builtins)
+                .unwrap()
+                .run()
+                .unwrap(),
+            7
+        );
+    }
+
+    #[test]
+    fn extern_is_bound_to_builtin() {
+        let double = Builtin::new("double", vec![ast::Type::U32], ast::Type::U32, |vm| {
+            let a = vm.pop();
+            vm.push(a * 2);
+        });
+        let builtins = [double];
+        let program = parser::parse(
+            "extern fn double(x: u32): u32;
+            fn main(): u32 { return double(21); }",
+        )
+        .unwrap();
+        typechecker::check_with_builtins(&program, &builtins).unwrap();
+        assert_eq!(
+            compile_with_builtins(&program, &builtins)
+                .unwrap()
+                .run()
+                .unwrap(),
+            42
+        );
+
+        let program = parser::parse(
+            "extern fn double(x: bool): u32;
+            fn main(): u32 { return 0; }",
+        )
+        .unwrap();
+        assert!(matches!(
+            compile_with_builtins(&program, &builtins),
+            Err(Error::UnboundExtern(name)) if name == "double"
+        ));
+    }
+
+    #[test]
+    fn calls_leave_one_result() {
+        assert_eq!(
+            run("fn seven(): u32 { return 7; }
+            fn sub(a: u32, b: u32): u32 { return a - b; }
+            fn main(): u32 {
+                let x: u32 = seven();
+                let y: u32 = x + 1;
+                return sub(y, seven()) * 10 + x;
+            }"),
+            17
+        );
+    }
+
+    #[test]
+    fn let_with_block_and_if_initializers() {
+        let source = "fn f(): u32 { return 4; }
+            fn main(): u32 {
+                let x = { let t = f(); t + 1 };
+                let c = false;
+                let y = if c { 1 } else { let z = 2; z };
+                return x * 10 + y;
+            }";
+        typechecker::check(&parser::parse(source).unwrap()).unwrap();
+        assert_eq!(run(source), 52);
+    }
+
+    #[test]
+    fn exit_halts_from_nested_call() {
+        let source = "fn inner(): u32 { exit(42); return 1; }
+            fn main(): u32 { let x = inner(); return x + 1; }";
+        typechecker::check(&parser::parse(source).unwrap()).unwrap();
+        assert_eq!(run(source), 42);
+    }
+
+    #[test]
+    fn multiply_uses_the_full_word() {
+        let result = run("fn main(): u32 { return 100000 * 100000; }");
+        #[cfg(feature = "word64")]
+        assert_eq!(result, 10_000_000_000);
+        #[cfg(not(feature = "word64"))]
+        assert_eq!(result, 1_410_065_408);
+    }
+
+    #[test]
+    fn division_by_zero_reports_source_line() {
+        let source = "fn divide(a: u32, b: u32): u32 {
+                let q = a / b;
+                return q;
+            }
+            fn main(): u32 { return divide(1, 0); }";
+        let program = parser::parse(source).unwrap();
+
+        assert_eq!(compile(&program).unwrap().run().unwrap(), 0);
+
+        let mut vm = compile(&program).unwrap();
+        vm.enable_strict_arithmetic();
+        assert!(matches!(
+            vm.run(),
+            Err(virtual_machine::RuntimeError::DivideByZero)
+        ));
+        assert_eq!(vm.current_span().unwrap().line(source), 2);
+    }
+
+    #[test]
+    fn array_length_is_stored_in_the_header() {
+        let source = "fn main(): u32 {
+                let a = [4, 5, 6, 7];
+                let b: [bool; 2] = [true, false];
+                return len(a) * 10 + len(b);
+            }";
+        typechecker::check(&parser::parse(source).unwrap()).unwrap();
+        assert_eq!(run(source), 42);
+    }
+
+    #[test]
+    fn greater_is_less_with_swapped_operands() {
+        let holds = |condition: String| {
+            run(&format!(
+                "fn main(): u32 {{ if {condition} {{ return 1; }} return 0; }}"
+            ))
+        };
+        for (a, b) in [(1, 2), (2, 1), (3, 3), (0, 4000000)] {
+            assert_eq!(holds(format!("{a} > {b}")), holds(format!("{b} < {a}")));
+            assert_eq!(holds(format!("{a} >= {b}")), holds(format!("{b} <= {a}")));
+            assert_eq!(
+                holds(format!("{a} > {b}")),
+                (a > b) as virtual_machine::Word
             );
-            operations.push(virtual_machine::Operation::Div);
-            local_vars.pop();
         }
-        ast::Expression::Mod(expression, expression1) => {
-            compile_expression(
-                expression,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+        // The left operand is still evaluated first.
+        assert_eq!(
+            run("fn main(): u32 { if exit(1) > exit(2) { return 3; } return 4; }"),
+            1
+        );
+    }
+
+    #[test]
+    fn compile_cache_hit() {
+        let mut cache = CompileCache::new();
+        let program = parser::parse("fn main(): u32 { return 1 + 2; }").unwrap();
+
+        assert_eq!(cache.get_or_compile(&program).unwrap().run().unwrap(), 3);
+        assert_eq!(cache.get_or_compile(&program).unwrap().run().unwrap(), 3);
+        assert_eq!(cache.compilations(), 1);
+
+        let changed = parser::parse("fn main(): u32 { return 1 + 3; }").unwrap();
+        assert_eq!(cache.get_or_compile(&changed).unwrap().run().unwrap(), 4);
+        assert_eq!(cache.compilations(), 2);
+
+        // Programs whose hashes collide are told apart by their ASTs.
+        let mut cache = CompileCache::new();
+        let run = |cache: &mut CompileCache, program| {
+            cache
+                .get_or_compile_with_hash(program, 7)
+                .unwrap()
+                .run()
+                .unwrap()
+        };
+        assert_eq!(run(&mut cache, &program), 3);
+        assert_eq!(run(&mut cache, &changed), 4);
+        assert_eq!(run(&mut cache, &program), 3);
+        assert_eq!(cache.compilations(), 2);
+    }
+
+    #[test]
+    fn unit_function_returns_at_the_end_of_its_body() {
+        let program = parser::parse(
+            "fn show(x: u32) {
+                let doubled = x * 2;
+                print(doubled);
+            }
+            fn main(): u32 {
+                let kept = 7;
+                show(1);
+                show(2);
+                return kept;
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        assert_eq!(compile(&program).unwrap().run().unwrap(), 7);
+    }
+
+    #[test]
+    fn array_elements_are_written_and_read_back() {
+        let program = parser::parse(
+            "fn main(): u32 {
+                let mut values = [1, 2, 3];
+                let mut i = 0;
+                while i < len(values) {
+                    values[i] = values[i] * 10;
+                    i = i + 1;
+                }
+                values[1] = values[1] + 5;
+                return values[0] + values[1] + values[2];
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compile(&program).unwrap();
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 65);
+
+        let program =
+            parser::parse("fn main(): u32 { let values = [1, 2]; values[0] = true; return 0; }")
+                .unwrap();
+        assert!(matches!(
+            typechecker::check(&program).map_err(|error| error.node),
+            Err(typechecker::Error::NonMatchingTypes(
+                ast::Type::U32,
+                ast::Type::Bool
+            ))
+        ));
+    }
+
+    #[test]
+    fn indexing_past_the_end_stops_the_program() {
+        let main = |body: &str| {
+            let source = format!("fn main(): u32 {{ let mut a = [1, 2, 3]; {body} }}");
+            let program = parser::parse(&source).unwrap();
+            typechecker::check(&program).unwrap();
+            compile(&program).unwrap().run()
+        };
+        for body in [
+            "return a[5];",
+            "let i = 3; return a[i];",
+            "a[7] = 1; return 0;",
+        ] {
+            assert!(
+                matches!(
+                    main(body),
+                    Err(virtual_machine::RuntimeError::IndexOutOfBounds { length: 3, .. })
+                ),
+                "{body}"
             );
-            compile_expression(
-                expression1,
-                operations,
-                local_vars,
-                arguments,
-                function_names,
+        }
+        assert_eq!(main("let i = 2; a[i] = 4; return a[2];").unwrap(), 4);
+    }
+
+    #[test]
+    fn match_takes_the_arm_with_the_value() {
+        let functions = "fn pick(x: u32): u32 {
+                let base = 100;
+                return base + match x { 0 => 10, 1 => base * 2, 2 => { let y = x; y * 3 }, _ => x };
+            }";
+        for (argument, expected) in [(0, 110), (1, 300), (2, 106), (7, 107)] {
+            let source = format!("{functions} fn main(): u32 {{ return pick({argument}); }}");
+            let program = parser::parse(&source).unwrap();
+            typechecker::check(&program).unwrap();
+            assert_eq!(run(&source), expected, "pick({argument})");
+        }
+    }
+
+    #[test]
+    fn dense_matches_use_a_jump_table() {
+        let compile_pick = |arms: &str| {
+            let source = format!(
+                "fn pick(x: u32): u32 {{ return match x {{ {arms}, _ => 99 }}; }}
+                fn main(): u32 {{ return pick(0); }}"
             );
-            operations.push(virtual_machine::Operation::Mod);
-            local_vars.pop();
+            let program = parser::parse(&source).unwrap();
+            let functions = compile_functions(&program, &[], "main").unwrap();
+            let operations = resolve_labels(&functions[0].1.operations);
+            (program, operations)
+        };
+
+        let (program, operations) =
+            compile_pick("3 => 30, 1 => 10, 4 => 40, 0 => 0, 2 => 20, 1 => 11");
+        assert!(operations.contains(&virtual_machine::Operation::Goto));
+        // The stack check follows the table instead of giving up at the `Goto`.
+        assert!(compile(&program).unwrap().max_stack_of("pick").is_some());
+        for (argument, expected) in [(0, 0), (1, 10), (2, 20), (3, 30), (4, 40), (5, 99)] {
+            let mut vm = compile_with_entry(&program, "pick").unwrap();
+            vm.set_main_arguments(&[argument]).unwrap();
+            assert_eq!(vm.run().unwrap(), expected, "pick({argument})");
+        }
+
+        // Below the range is checked separately when it doesn't start at 0.
+        let (program, _) = compile_pick("2 => 20, 3 => 30, 4 => 40");
+        for (argument, expected) in [(1, 99), (2, 20), (4, 40), (5, 99)] {
+            let mut vm = compile_with_entry(&program, "pick").unwrap();
+            vm.set_main_arguments(&[argument]).unwrap();
+            assert_eq!(vm.run().unwrap(), expected, "pick({argument})");
+        }
+
+        let (_, operations) = compile_pick("0 => 0, 10 => 1, 20 => 2");
+        assert!(!operations.contains(&virtual_machine::Operation::Goto));
+    }
+
+    #[test]
+    fn const_locals_are_pushed_as_values() {
+        use virtual_machine::Operation::*;
+
+        let source = "fn main(): u32 {
+                const N: u32 = 2 + 3;
+                const M: u32 = N * 4;
+                let x = 1;
+                let y = { let N = 100; N + M };
+                return x + M + y;
+            }";
+        let program = parser::parse(source).unwrap();
+        typechecker::check(&program).unwrap();
+        let functions = compile_functions(&program, &[], "main").unwrap();
+        let operations = resolve_labels(&functions[0].1.operations);
+        assert_eq!(operations[..2], [Push(1), Push(100)]);
+        assert_eq!(operations.iter().filter(|op| **op == Push(20)).count(), 2);
+        assert_eq!(run(source), 141);
+    }
+
+    #[test]
+    fn program_hash_follows_the_code() {
+        let hash = |source: &str| {
+            let builtins = builtins::standard();
+            let functions = compile_functions(&parser::parse(source).unwrap(), &builtins, "main");
+            let functions =
+                build_functions(&functions.unwrap(), &builtins, &mut StringPool::default());
+            virtual_machine::program_hash(&functions)
+        };
+        let source = "fn main(): u32 { let a = [1, 2]; return a[0] + 3; }";
+        assert_eq!(hash(source), hash(source));
+        assert_ne!(
+            hash(source),
+            hash("fn main(): u32 { let a = [1, 2]; return a[0] + 4; }")
+        );
+        assert_ne!(
+            hash(source),
+            hash("fn main(): u32 { let a = [1, 5]; return a[0] + 3; }")
+        );
+    }
+
+    #[test]
+    fn identical_strings_share_an_address() {
+        let program = parser::parse(
+            r#"fn one(): [u32; 2] { return "hi"; }
+            fn two(): [u32; 2] { let s = "hi"; return s; }
+            fn other(): [u32; 2] { return "ho"; }
+            fn main(): u32 { let s = "hi"; return s[0] + len(s); }"#,
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compile(&program).unwrap();
+        // "hi" and "ho", each a length and two characters.
+        assert_eq!(vm.heap_size(), 6);
+        assert_eq!(vm.run().unwrap(), 'h' as virtual_machine::Word + 2);
+        let address = vm.call("one", &[]).unwrap();
+        assert_eq!(vm.call("two", &[]).unwrap(), address);
+        assert_ne!(vm.call("other", &[]).unwrap(), address);
+        assert_eq!(vm.read_heap(address + 2), 'i' as virtual_machine::Word);
+    }
+
+    #[test]
+    fn string_literals_cant_be_written_to() {
+        let program = parser::parse(
+            r#"fn main(): u32 { let mut s = "hi"; s[0] = 120; let t = "hi"; return t[0]; }"#,
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        assert!(matches!(
+            compile(&program).unwrap().run(),
+            Err(virtual_machine::RuntimeError::WriteToReadOnly(_))
+        ));
+
+        // Each call would otherwise see the changes the calls before it made.
+        let program = parser::parse(
+            r#"fn first(): u32 { let mut s = "ab"; let c = s[0]; s[0] = c + 1; return c; }
+            fn main(): u32 { return first(); }"#,
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compile(&program).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                vm.call("first", &[]),
+                Err(virtual_machine::RuntimeError::WriteToReadOnly(_))
+            ));
+        }
+        assert_eq!(vm.read_heap(1), 'a' as virtual_machine::Word);
+    }
+
+    #[test]
+    fn arguments_read_back_what_was_passed() {
+        // Arguments are combined as digits, so one read from the wrong slot shows.
+        let functions = "fn none(): u32 { return 9; }
+            fn one(a: u32): u32 { return a; }
+            fn two(a: u32, b: u32): u32 { return a * 10 + b; }
+            fn five(a: u32, b: u32, c: u32, d: u32, e: u32): u32 {
+                let local = a * 10000 + b * 1000 + c * 100;
+                return local + d * 10 + e;
+            }";
+        for (call, expected) in [
+            ("none()", 9),
+            ("one(7)", 7),
+            ("two(1, 2)", 12),
+            ("five(5, 4, 3, 2, 1)", 54321),
+        ] {
+            let source = format!("{functions} fn main(): u32 {{ let x = 0; return x + {call}; }}");
+            assert_eq!(run(&source), expected, "{call}");
         }
     }
 }
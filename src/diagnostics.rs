@@ -0,0 +1,83 @@
+use crate::{ast::Spanned, parser, typechecker};
+
+/// An error found before the program runs, along with where in the source it is.
+#[derive(Debug)]
+pub enum CompilerError {
+    Parse(Spanned<parser::Error>),
+    Type(Spanned<typechecker::Error>),
+}
+
+impl From<Spanned<parser::Error>> for CompilerError {
+    fn from(error: Spanned<parser::Error>) -> Self {
+        CompilerError::Parse(error)
+    }
+}
+
+impl From<Spanned<typechecker::Error>> for CompilerError {
+    fn from(error: Spanned<typechecker::Error>) -> Self {
+        CompilerError::Type(error)
+    }
+}
+
+/// Formats `error` with the line of `source` it was found on and a `^` caret under
+/// the offending span:
+///
+/// ```text
+/// error: UndeclaredVariable("y")
+///  --> 2:12
+///   |
+/// 2 |     return y;
+///   |            ^
+/// ```
+pub fn render_error(source: &str, error: &CompilerError) -> String {
+    let (message, span) = match error {
+        CompilerError::Parse(error) => (format!("{:?}", error.node), error.span),
+        CompilerError::Type(error) => (format!("{:?}", error.node), error.span),
+    };
+
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let line_text = &source[line_start..line_end];
+    let line_number = span.line(source).to_string();
+
+    let column = source[line_start..span.start].chars().count();
+    // Spans running over several lines are only underlined up to the end of the first.
+    let width = source[span.start..span.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    let gutter = " ".repeat(line_number.len());
+    format!(
+        "error: {message}\n{gutter}--> {line_number}:{}\n{gutter} |\n{line_number} | {line_text}\n{gutter} | {}{}\n",
+        column + 1,
+        " ".repeat(column),
+        "^".repeat(width),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn caret_points_at_the_error() {
+        let source = "fn main(): u32 {\n    let x = 1;\n    return x + true;\n}";
+        let program = parser::parse(source).unwrap();
+        let error = typechecker::check(&program).unwrap_err().into();
+
+        let rendered = render_error(source, &error);
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[0], "error: ArithmeticOnNonNumber(Bool)");
+        assert_eq!(lines[1], " --> 3:5");
+        assert_eq!(lines[3], "3 |     return x + true;");
+        assert_eq!(lines[4], "  |     ^^^^^^^^^^^^^^^^");
+
+        let source = "fn main(): u32 {\n    return (1;\n}";
+        let error = parser::parse(source).unwrap_err().into();
+        let rendered = render_error(source, &error);
+        assert!(rendered.contains("2 |     return (1;\n  |              ^\n"));
+    }
+}
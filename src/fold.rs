@@ -0,0 +1,344 @@
+use crate::{
+    ast::{Comparison, Declaration, Expression, Spanned, Statement},
+    typechecker::SignedOperations,
+    virtual_machine::{SignedWord, Word, signed_div, signed_rem},
+};
+
+/// Replaces expressions whose value is known at compile time with literals. Only
+/// meaningful on programs that typecheck.
+pub fn fold(ast: &mut [Declaration]) {
+    for declaration in ast {
+        if let Declaration::Function { body, .. } = declaration {
+            fold_block(body);
+        }
+    }
+}
+
+fn fold_block(body: &mut Vec<Spanned<Statement>>) {
+    for statement in body.iter_mut() {
+        fold_statement(&mut statement.node);
+    }
+    // Statements that can be seen to do nothing are dropped.
+    body.retain(|statement| {
+        !matches!(
+            &statement.node,
+            Statement::If(Expression::BoolLiteral(false), _, else_body) if else_body.is_empty()
+        ) && !matches!(
+            statement.node,
+            Statement::While(Expression::BoolLiteral(false), _)
+        )
+    });
+}
+
+fn fold_statement(statement: &mut Statement) {
+    match statement {
+        Statement::Let {
+            value: expression, ..
+        }
+        | Statement::Const {
+            value: expression, ..
+        }
+        | Statement::Assign(_, expression)
+        | Statement::LetTuple(_, expression)
+        | Statement::Expr(expression)
+        | Statement::Return(expression) => fold_expression(expression),
+        Statement::IndexAssign(array, index, value) => {
+            fold_expression(array);
+            fold_expression(index);
+            fold_expression(value);
+        }
+        Statement::If(condition, then_body, else_body) => {
+            fold_expression(condition);
+            fold_block(then_body);
+            fold_block(else_body);
+            // Only the branch that is taken is kept, as the body of an `if true`.
+            match condition {
+                Expression::BoolLiteral(true) => else_body.clear(),
+                Expression::BoolLiteral(false) => {
+                    *then_body = std::mem::take(else_body);
+                    *condition = Expression::BoolLiteral(true);
+                }
+                _ => {}
+            }
+        }
+        Statement::While(condition, body) => {
+            fold_expression(condition);
+            fold_block(body);
+        }
+        Statement::Break(value) => {
+            if let Some(value) = value {
+                fold_expression(value);
+            }
+        }
+    }
+}
+
+fn fold_expression(expression: &mut Expression) {
+    match expression {
+        Expression::Call(_, expressions) | Expression::ArrayLiteral(expressions) => {
+            expressions.iter_mut().for_each(fold_expression);
+        }
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b)
+        | Expression::And(a, b)
+        | Expression::Or(a, b) => {
+            fold_expression(a);
+            fold_expression(b);
+        }
+        Expression::Block(body, tail) => {
+            fold_block(body);
+            fold_expression(tail);
+        }
+        Expression::If(condition, then_branch, else_branch) => {
+            fold_expression(condition);
+            fold_expression(then_branch);
+            fold_expression(else_branch);
+        }
+        Expression::Loop(body) => fold_block(body),
+        Expression::Negate(a) | Expression::Not(a) => fold_expression(a),
+        Expression::Match(value, arms, wildcard) => {
+            fold_expression(value);
+            for (_, arm) in arms {
+                fold_expression(arm);
+            }
+            if let Some(wildcard) = wildcard {
+                fold_expression(wildcard);
+            }
+        }
+        Expression::Variable(_)
+        | Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => {}
+    }
+
+    let folded = match expression {
+        Expression::Compare(comparison, a, b) => {
+            constant_comparison(*comparison, a, b).map(Expression::BoolLiteral)
+        }
+        // Only the branch that is taken is kept. The condition is a literal, so
+        // dropping it loses no side effects.
+        Expression::If(condition, then_branch, else_branch) => match **condition {
+            Expression::BoolLiteral(true) => Some(take(then_branch)),
+            Expression::BoolLiteral(false) => Some(take(else_branch)),
+            _ => None,
+        },
+        Expression::Block(body, tail) if body.is_empty() => Some(take(tail)),
+        Expression::Not(value) => match **value {
+            Expression::BoolLiteral(b) => Some(Expression::BoolLiteral(!b)),
+            _ => None,
+        },
+        // A literal on the left decides the value or hands it to the right, which
+        // would not have been evaluated in the first case. A literal on the right
+        // that doesn't decide the value leaves the left's.
+        Expression::And(a, b) => match (&**a, &**b) {
+            (Expression::BoolLiteral(false), _) => Some(Expression::BoolLiteral(false)),
+            (Expression::BoolLiteral(true), _) => Some(take(b)),
+            (_, Expression::BoolLiteral(true)) => Some(take(a)),
+            _ => None,
+        },
+        Expression::Or(a, b) => match (&**a, &**b) {
+            (Expression::BoolLiteral(true), _) => Some(Expression::BoolLiteral(true)),
+            (Expression::BoolLiteral(false), _) => Some(take(b)),
+            (_, Expression::BoolLiteral(false)) => Some(take(a)),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(folded) = folded {
+        *expression = folded;
+    }
+
+    // The length of an array literal is known, but its elements still have to be
+    // evaluated if that could have side effects.
+    if let Expression::Call(function, expressions) = expression
+        && function == "len"
+        && let [Expression::ArrayLiteral(elements)] = expressions.as_slice()
+        && elements.iter().all(|element| {
+            matches!(
+                element,
+                Expression::NumLiteral(_)
+                    | Expression::NegativeLiteral(_)
+                    | Expression::SuffixedLiteral(..)
+                    | Expression::BoolLiteral(_)
+                    | Expression::Variable(_)
+            )
+        })
+    {
+        *expression = Expression::NumLiteral(elements.len() as u32);
+    }
+}
+
+/// The word `expression` evaluates to, if it is made only of literals, arithmetic,
+/// comparisons and variables `constant` gives the values of. Arithmetic wraps like
+/// the VM's does outside of strict mode, and dividing by zero has no value. The
+/// operations in `signed` divide and compare as signed numbers.
+pub(crate) fn evaluate(
+    expression: &Expression,
+    constant: &dyn Fn(&str) -> Option<Word>,
+    signed: &SignedOperations,
+) -> Option<Word> {
+    let operands = |a, b| {
+        Some((
+            evaluate(a, constant, signed)?,
+            evaluate(b, constant, signed)?,
+        ))
+    };
+    let is_signed = signed.contains(expression);
+    match expression {
+        Expression::NumLiteral(n) | Expression::SuffixedLiteral(n, _) => Some(*n as Word),
+        Expression::NegativeLiteral(n) => Some((*n as i64).wrapping_neg() as Word),
+        Expression::BoolLiteral(b) => Some(*b as Word),
+        Expression::Variable(name) => constant(name),
+        Expression::Add(a, b) => operands(a, b).map(|(a, b)| a.wrapping_add(b)),
+        Expression::Sub(a, b) => operands(a, b).map(|(a, b)| a.wrapping_sub(b)),
+        Expression::Mul(a, b) => operands(a, b).map(|(a, b)| a.wrapping_mul(b)),
+        Expression::Div(a, b) if is_signed => operands(a, b).and_then(|(a, b)| signed_div(a, b)),
+        Expression::Mod(a, b) if is_signed => operands(a, b).and_then(|(a, b)| signed_rem(a, b)),
+        Expression::Div(a, b) => operands(a, b).and_then(|(a, b)| a.checked_div(b)),
+        Expression::Mod(a, b) => operands(a, b).and_then(|(a, b)| a.checked_rem(b)),
+        Expression::Negate(a) => evaluate(a, constant, signed).map(|a| a.wrapping_neg()),
+        Expression::Not(a) => evaluate(a, constant, signed).map(|a| (a == 0) as Word),
+        Expression::And(a, b) => match evaluate(a, constant, signed)? {
+            0 => Some(0),
+            _ => evaluate(b, constant, signed),
+        },
+        Expression::Or(a, b) => match evaluate(a, constant, signed)? {
+            0 => evaluate(b, constant, signed),
+            a => Some(a),
+        },
+        Expression::Compare(comparison, a, b) => {
+            let (a, b) = operands(a, b)?;
+            let ordering = if is_signed {
+                (a as SignedWord).cmp(&(b as SignedWord))
+            } else {
+                a.cmp(&b)
+            };
+            Some(match comparison {
+                Comparison::Equal => ordering.is_eq(),
+                Comparison::NotEqual => ordering.is_ne(),
+                Comparison::Less => ordering.is_lt(),
+                Comparison::LessEqual => ordering.is_le(),
+                Comparison::Greater => ordering.is_gt(),
+                Comparison::GreaterEqual => ordering.is_ge(),
+            } as Word)
+        }
+        _ => None,
+    }
+}
+
+/// The result of comparing `a` and `b`, if both are literals.
+fn constant_comparison(comparison: Comparison, a: &Expression, b: &Expression) -> Option<bool> {
+    let value = |expression: &Expression| match expression {
+        Expression::NumLiteral(n) | Expression::SuffixedLiteral(n, _) => Some(*n),
+        Expression::BoolLiteral(b) => Some(*b as u32),
+        _ => None,
+    };
+    let (a, b) = (value(a)?, value(b)?);
+    Some(match comparison {
+        Comparison::Equal => a == b,
+        Comparison::NotEqual => a != b,
+        Comparison::Less => a < b,
+        Comparison::LessEqual => a <= b,
+        Comparison::Greater => a > b,
+        Comparison::GreaterEqual => a >= b,
+    })
+}
+
+/// Moves `expression` out, leaving a placeholder that is about to be overwritten.
+fn take(expression: &mut Expression) -> Expression {
+    std::mem::replace(expression, Expression::BoolLiteral(false))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler, parser, virtual_machine::Operation};
+
+    fn folded_return(source: &str) -> Expression {
+        let mut program = parser::parse(source).unwrap();
+        fold(&mut program);
+        match &program[0] {
+            Declaration::Function { body, .. } => match &body[0].node {
+                Statement::Return(expression) => expression.clone(),
+                statement => panic!("unexpected statement {statement:?}"),
+            },
+            declaration => panic!("unexpected declaration {declaration:?}"),
+        }
+    }
+
+    #[test]
+    fn len_of_array_literal() {
+        assert!(matches!(
+            folded_return("fn main(): u32 { return len([1, 2, 3]); }"),
+            Expression::NumLiteral(3)
+        ));
+        assert!(matches!(
+            folded_return("fn main(): u32 { return len([1, exit(2)]); }"),
+            Expression::Call(..)
+        ));
+    }
+
+    #[test]
+    fn constant_conditions_keep_one_branch() {
+        assert!(matches!(
+            folded_return("fn main(): u32 { return if true { 1 } else { 2 }; }"),
+            Expression::NumLiteral(1)
+        ));
+        assert!(matches!(
+            folded_return("fn main(): u32 { return if 3 < 5 { 1 } else { 2 }; }"),
+            Expression::NumLiteral(1)
+        ));
+        assert!(matches!(
+            folded_return("fn main(): bool { return 4u8 == 5u8; }"),
+            Expression::BoolLiteral(false)
+        ));
+
+        let mut program =
+            parser::parse("fn main(): u32 { if 2 > 3 { return 7; } else { return 8; } }").unwrap();
+        fold(&mut program);
+        let vm = compiler::compile(&program).unwrap();
+        let (_, operations) = vm.iter_code().next().unwrap();
+        assert!(!operations.contains(&Operation::Push(7)));
+        assert!(operations.contains(&Operation::Push(8)));
+    }
+
+    #[test]
+    fn constant_booleans_short_circuit() {
+        let folded = |expression: &str| {
+            let source = format!("fn main(a: bool): bool {{ return {expression}; }}");
+            format!("{:?}", folded_return(&source))
+        };
+        let a = format!("{:?}", Expression::var("a"));
+        assert_eq!(folded("true && false"), "BoolLiteral(false)");
+        assert_eq!(folded("1 < 2 || a"), "BoolLiteral(true)");
+        assert_eq!(folded("false && exit(1) == 1"), "BoolLiteral(false)");
+        assert_eq!(folded("true && a"), a);
+        assert_eq!(folded("false || a"), a);
+        assert_eq!(folded("a && true"), a);
+        assert_eq!(folded("a || 2 < 1"), a);
+        // The left side has to be evaluated even when the right decides the value.
+        assert!(folded("exit(1) == 1 && false").starts_with("And("));
+        assert!(folded("a || true").starts_with("Or("));
+
+        let constant = |expression: &str| {
+            let program = parser::parse(&format!("fn main(): bool {{ return {expression}; }}"));
+            let Declaration::Function { body, .. } = &program.unwrap()[0] else {
+                panic!("expected a function");
+            };
+            let Statement::Return(expression) = &body[0].node else {
+                panic!("expected a return");
+            };
+            evaluate(expression, &|_| None, &SignedOperations::default())
+        };
+        assert_eq!(constant("false || 3 > 2 && true"), Some(1));
+        assert_eq!(constant("false && exit(1) == 1"), Some(0));
+        assert_eq!(constant("true && exit(1) == 1"), None);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::{
+    ast::{Arithmetic, Comparison, Declaration, Expression, Spanned, Statement, Type},
+    parser,
+};
+
+/// How `format_source` lays out code.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatOptions {
+    /// Spaces per level of indentation. Ignored when `use_tabs` is set.
+    pub indent: usize,
+    /// Indent with one tab per level instead of spaces.
+    pub use_tabs: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            use_tabs: false,
+        }
+    }
+}
+
+/// Reformats `source` with consistent indentation and spacing. Comments are kept
+/// roughly where they were: on their own line before the code following them, or
+/// after the statement they share a line with.
+pub fn format_source(
+    source: &str,
+    options: FormatOptions,
+) -> Result<String, Spanned<parser::Error>> {
+    let program = parser::parse(source)?;
+    let mut formatter = Formatter {
+        source,
+        comments: parser::comments(source)?,
+        next_comment: 0,
+        output: String::new(),
+        indent: 0,
+        options,
+    };
+    for (i, declaration) in program.iter().enumerate() {
+        if i > 0 {
+            formatter.output.push('\n');
+        }
+        formatter.declaration(declaration);
+    }
+    formatter.comments_before(source.len());
+    Ok(formatter.output)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Spanned<String>>,
+    /// Comments before this one have already been written.
+    next_comment: usize,
+    output: String,
+    /// How many levels deep the code being written is.
+    indent: usize,
+    options: FormatOptions,
+}
+
+impl Formatter<'_> {
+    fn indentation(&mut self) {
+        for _ in 0..self.indent {
+            if self.options.use_tabs {
+                self.output.push('\t');
+            } else {
+                self.output.push_str(&" ".repeat(self.options.indent));
+            }
+        }
+    }
+
+    /// Writes the comments starting before `position` on lines of their own.
+    fn comments_before(&mut self, position: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && comment.span.start < position
+        {
+            let text = comment.node.clone();
+            self.indentation();
+            self.output.push_str(&text);
+            self.output.push('\n');
+            self.next_comment += 1;
+        }
+    }
+
+    /// Moves comments from inside the code ending at `end`, or after it on the same
+    /// line, to the end of the line just written.
+    fn trailing_comments(&mut self, end: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && (comment.span.start < end || !self.source[end..comment.span.start].contains('\n'))
+        {
+            let text = comment.node.clone();
+            self.output.pop();
+            self.output.push(' ');
+            self.output.push_str(&text);
+            self.output.push('\n');
+            self.next_comment += 1;
+        }
+    }
+
+    fn declaration(&mut self, declaration: &Declaration) {
+        match declaration {
+            Declaration::Function {
+                name,
+                arithmetic,
+                arguments,
+                return_type,
+                body,
+                span,
+                ..
+            } => {
+                self.comments_before(span.start);
+                if *arithmetic == Arithmetic::Checked {
+                    self.output.push_str("#[checked]\n");
+                }
+                self.signature("fn", name, arguments, return_type);
+                self.output.push(' ');
+                self.block(body, span.end);
+                self.output.push('\n');
+                self.trailing_comments(span.end);
+            }
+            Declaration::Extern {
+                name,
+                arguments,
+                return_type,
+                span,
+                ..
+            } => {
+                self.comments_before(span.start);
+                self.signature("extern fn", name, arguments, return_type);
+                self.output.push_str(";\n");
+                self.trailing_comments(span.end);
+            }
+            Declaration::TypeAlias {
+                name,
+                aliased,
+                span,
+                ..
+            } => {
+                self.comments_before(span.start);
+                self.output
+                    .push_str(&format!("type {name} = {};\n", type_name(aliased)));
+                self.trailing_comments(span.end);
+            }
+        }
+    }
+
+    fn signature(
+        &mut self,
+        keyword: &str,
+        name: &str,
+        arguments: &[(String, Type)],
+        return_type: &Type,
+    ) {
+        let arguments: Vec<_> = arguments
+            .iter()
+            .map(|(name, typ)| format!("{name}: {}", type_name(typ)))
+            .collect();
+        self.output
+            .push_str(&format!("{keyword} {name}({})", arguments.join(", ")));
+        if return_type != &Type::Unit {
+            self.output
+                .push_str(&format!(": {}", type_name(return_type)));
+        }
+    }
+
+    /// Writes `{ body }`, with any comments left before `end` inside the braces.
+    fn block(&mut self, body: &[Spanned<Statement>], end: usize) {
+        let has_comments = self
+            .comments
+            .get(self.next_comment)
+            .is_some_and(|comment| comment.span.start < end);
+        if body.is_empty() && !has_comments {
+            self.output.push_str("{}");
+            return;
+        }
+        self.output.push_str("{\n");
+        self.indent += 1;
+        for statement in body {
+            self.statement(statement);
+        }
+        self.comments_before(end);
+        self.indent -= 1;
+        self.indentation();
+        self.output.push('}');
+    }
+
+    fn statement(&mut self, statement: &Spanned<Statement>) {
+        self.comments_before(statement.span.start);
+        self.indentation();
+        self.statement_inline(&statement.node, statement.span.end);
+        self.output.push('\n');
+        self.trailing_comments(statement.span.end);
+    }
+
+    /// Writes `statement` without indentation or a trailing newline. `end` is where
+    /// it ends in the source.
+    fn statement_inline(&mut self, statement: &Statement, end: usize) {
+        match statement {
+            Statement::Let {
+                name,
+                mutable,
+                typ,
+                value: expression,
+            } => {
+                self.output
+                    .push_str(if *mutable { "let mut " } else { "let " });
+                self.output.push_str(name);
+                if let Some(typ) = typ {
+                    self.output.push_str(": ");
+                    self.output.push_str(&type_name(typ));
+                }
+                self.output.push_str(" = ");
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Statement::Const { name, typ, value } => {
+                self.output
+                    .push_str(&format!("const {name}: {} = ", type_name(typ)));
+                self.expression(value);
+                self.output.push(';');
+            }
+            Statement::LetTuple(names, expression) => {
+                self.output
+                    .push_str(&format!("let ({}) = ", names.join(", ")));
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Statement::Assign(name, expression) => {
+                self.output.push_str(name);
+                self.output.push_str(" = ");
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Statement::IndexAssign(array, index, value) => {
+                self.index(array, index);
+                self.output.push_str(" = ");
+                self.expression(value);
+                self.output.push(';');
+            }
+            Statement::Expr(expression) => {
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Statement::Return(expression) => {
+                self.output.push_str("return ");
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Statement::If(condition, then_body, else_body) => {
+                self.output.push_str("if ");
+                self.expression(condition);
+                self.output.push(' ');
+                let then_end = else_body
+                    .first()
+                    .map_or(end, |statement| statement.span.start);
+                self.block(then_body, then_end);
+                match else_body.as_slice() {
+                    [] => {}
+                    [
+                        Spanned {
+                            node: else_if @ Statement::If(..),
+                            span,
+                        },
+                    ] => {
+                        self.output.push_str(" else ");
+                        self.statement_inline(else_if, span.end);
+                    }
+                    _ => {
+                        self.output.push_str(" else ");
+                        self.block(else_body, end);
+                    }
+                }
+            }
+            Statement::While(condition, body) => {
+                self.output.push_str("while ");
+                self.expression(condition);
+                self.output.push(' ');
+                self.block(body, end);
+            }
+            Statement::Break(value) => {
+                self.output.push_str("break");
+                if let Some(value) = value {
+                    self.output.push(' ');
+                    self.expression(value);
+                }
+                self.output.push(';');
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Call(function, expressions) => {
+                self.output.push_str(function);
+                self.output.push('(');
+                self.list(expressions);
+                self.output.push(')');
+            }
+            Expression::Variable(name) => self.output.push_str(name),
+            Expression::NumLiteral(n) => self.output.push_str(&n.to_string()),
+            Expression::NegativeLiteral(n) => self.output.push_str(&format!("-{n}")),
+            Expression::SuffixedLiteral(n, typ) => {
+                self.output.push_str(&format!("{n}{}", type_name(typ)))
+            }
+            Expression::BoolLiteral(b) => self.output.push_str(&b.to_string()),
+            // `-5` would read back as a negative literal.
+            Expression::Negate(a) => {
+                self.output.push('-');
+                self.operand(
+                    a,
+                    precedence(a) < 5 || matches!(**a, Expression::NumLiteral(_)),
+                );
+            }
+            Expression::Not(a) => {
+                self.output.push('!');
+                self.operand(a, precedence(a) < 5);
+            }
+            Expression::StrLiteral(text) => {
+                let escaped = text
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                self.output.push_str(&format!("\"{escaped}\""));
+            }
+            Expression::ArrayLiteral(elements) => {
+                self.output.push('[');
+                self.list(elements);
+                self.output.push(']');
+            }
+            Expression::Index(array, index) => self.index(array, index),
+            Expression::Add(a, b) => self.binary(a, "+", b, precedence(expression)),
+            Expression::Sub(a, b) => self.binary(a, "-", b, precedence(expression)),
+            Expression::Mul(a, b) => self.binary(a, "*", b, precedence(expression)),
+            Expression::Div(a, b) => self.binary(a, "/", b, precedence(expression)),
+            Expression::Mod(a, b) => self.binary(a, "%", b, precedence(expression)),
+            Expression::And(a, b) => self.binary(a, "&&", b, precedence(expression)),
+            Expression::Or(a, b) => self.binary(a, "||", b, precedence(expression)),
+            Expression::Block(body, tail) if body.is_empty() => {
+                self.output.push_str("{ ");
+                self.expression(tail);
+                self.output.push_str(" }");
+            }
+            Expression::Block(body, tail) => {
+                self.output.push_str("{\n");
+                self.indent += 1;
+                for statement in body {
+                    self.statement(statement);
+                }
+                self.indentation();
+                self.expression(tail);
+                self.output.push('\n');
+                self.indent -= 1;
+                self.indentation();
+                self.output.push('}');
+            }
+            Expression::Compare(comparison, a, b) => {
+                let operator = match comparison {
+                    Comparison::Equal => "==",
+                    Comparison::NotEqual => "!=",
+                    Comparison::Less => "<",
+                    Comparison::LessEqual => "<=",
+                    Comparison::Greater => ">",
+                    Comparison::GreaterEqual => ">=",
+                };
+                // Comparisons don't chain, so operands that are comparisons themselves
+                // always need parentheses.
+                self.operand(a, precedence(a) <= 2);
+                self.output.push_str(&format!(" {operator} "));
+                self.operand(b, precedence(b) <= 2);
+            }
+            Expression::If(condition, then_branch, else_branch) => {
+                self.output.push_str("if ");
+                self.expression(condition);
+                self.output.push(' ');
+                self.expression(then_branch);
+                self.output.push_str(" else ");
+                self.expression(else_branch);
+            }
+            Expression::Loop(body) => {
+                self.output.push_str("loop ");
+                self.block(body, body.last().map_or(0, |statement| statement.span.end));
+            }
+            Expression::Match(value, arms, wildcard) => {
+                self.output.push_str("match ");
+                self.expression(value);
+                self.output.push_str(" {\n");
+                self.indent += 1;
+                let patterns = arms.iter().map(|(n, arm)| (n.to_string(), arm));
+                for (pattern, arm) in
+                    patterns.chain(wildcard.iter().map(|arm| ("_".to_string(), &**arm)))
+                {
+                    self.indentation();
+                    self.output.push_str(&format!("{pattern} => "));
+                    self.expression(arm);
+                    self.output.push_str(",\n");
+                }
+                self.indent -= 1;
+                self.indentation();
+                self.output.push('}');
+            }
+        }
+    }
+
+    fn list(&mut self, expressions: &[Expression]) {
+        for (i, expression) in expressions.iter().enumerate() {
+            if i > 0 {
+                self.output.push_str(", ");
+            }
+            self.expression(expression);
+        }
+    }
+
+    /// Writes `a operator b`, adding the parentheses needed to keep the same tree.
+    /// Operators are left associative, so the right operand also needs them at the
+    /// same precedence.
+    fn binary(&mut self, a: &Expression, operator: &str, b: &Expression, level: u8) {
+        self.operand(a, precedence(a) < level);
+        self.output.push_str(&format!(" {operator} "));
+        self.operand(b, precedence(b) <= level);
+    }
+
+    /// Writes `array[index]`. Indexing binds tighter than any operator, prefixes too.
+    fn index(&mut self, array: &Expression, index: &Expression) {
+        self.operand(array, precedence(array) < 6);
+        self.output.push('[');
+        self.expression(index);
+        self.output.push(']');
+    }
+
+    fn operand(&mut self, expression: &Expression, parenthesize: bool) {
+        if parenthesize {
+            self.output.push('(');
+            self.expression(expression);
+            self.output.push(')');
+        } else {
+            self.expression(expression);
+        }
+    }
+}
+
+/// How tightly an expression binds; higher binds tighter.
+fn precedence(expression: &Expression) -> u8 {
+    match expression {
+        Expression::Or(..) => 0,
+        Expression::And(..) => 1,
+        Expression::Compare(..) => 2,
+        Expression::Add(..) | Expression::Sub(..) => 3,
+        Expression::Mul(..) | Expression::Div(..) | Expression::Mod(..) => 4,
+        Expression::Negate(_) | Expression::Not(_) => 5,
+        _ => 6,
+    }
+}
+
+fn type_name(typ: &Type) -> String {
+    match typ {
+        Type::U32 => "u32".to_string(),
+        Type::I32 => "i32".to_string(),
+        Type::U8 => "u8".to_string(),
+        Type::U16 => "u16".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::Unit => "()".to_string(),
+        Type::Array(element, length) => format!("[{}; {length}]", type_name(element)),
+        Type::Alias(name, _) => name.to_string(),
+        Type::Tuple(types) => format!(
+            "({})",
+            types.iter().map(type_name).collect::<Vec<_>>().join(", ")
+        ),
+        Type::Fun(arguments, result) => format!(
+            "fn({}): {}",
+            arguments
+                .iter()
+                .map(type_name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            type_name(result)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn comments_are_preserved() {
+        let source = "/// Doubles x.
+fn double(x:u32):u32{return x*2;}
+
+
+fn main(): u32 {
+  let y = double( 2 );   // four
+// now add
+    if true { return (y+1)*2 /* odd */; }
+  return y - (1 - 1);
+}";
+        let expected = "/// Doubles x.
+fn double(x: u32): u32 {
+    return x * 2;
+}
+
+fn main(): u32 {
+    let y = double(2); // four
+    // now add
+    if true {
+        return (y + 1) * 2; /* odd */
+    }
+    return y - (1 - 1);
+}
+";
+        let formatted = format_source(source, FormatOptions::default()).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(
+            format_source(&formatted, FormatOptions::default()).unwrap(),
+            formatted
+        );
+    }
+
+    #[test]
+    fn boolean_operators_keep_their_grouping() {
+        let source = "fn main(a: bool, b: bool): bool {
+    return (a || b) && !(a && b) || a == (b || a);
+}
+";
+        assert_eq!(
+            format_source(source, FormatOptions::default()).unwrap(),
+            source
+        );
+    }
+
+    #[test]
+    fn type_aliases_are_kept() {
+        let source = "type Count = u32;
+
+type Counts = [Count; 2];
+
+fn main(counts: Counts): Count {
+    let first: Count = counts[0];
+    return first;
+}
+";
+        assert_eq!(
+            format_source(source, FormatOptions::default()).unwrap(),
+            source
+        );
+    }
+
+    #[test]
+    fn indentation_is_configurable() {
+        let source = "fn main(): u32 { while true { return 1; } return 0; }";
+        let format =
+            |indent, use_tabs| format_source(source, FormatOptions { indent, use_tabs }).unwrap();
+        assert_eq!(
+            format(2, false),
+            "fn main(): u32 {\n  while true {\n    return 1;\n  }\n  return 0;\n}\n"
+        );
+        assert_eq!(
+            format(4, false),
+            "fn main(): u32 {\n    while true {\n        return 1;\n    }\n    return 0;\n}\n"
+        );
+        assert_eq!(
+            format(2, true),
+            "fn main(): u32 {\n\twhile true {\n\t\treturn 1;\n\t}\n\treturn 0;\n}\n"
+        );
+    }
+}
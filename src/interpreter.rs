@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Arithmetic, Comparison, Declaration, Expression, Spanned, Statement, Type},
+    builtins::{self, Builtin},
+    compiler,
+    typechecker::{self, SignedOperations},
+    virtual_machine::{
+        RuntimeError, SignedWord, VirtualMachine, Word, signed_checked_add, signed_checked_mul,
+        signed_checked_sub, signed_div, signed_rem,
+    },
+};
+
+/// Runs `main` by walking the AST, without compiling it. Values, arrays and
+/// arithmetic behave as they do in the VM outside of strict mode, so the two can be
+/// checked against each other. Only meaningful on programs that typecheck.
+///
+/// Builtins still run against a `VirtualMachine`, which only provides the heap and
+/// the program's input and output. Each call is a call in Rust, so deep recursion
+/// overflows the stack instead of being limited.
+pub fn interpret(ast: &[Declaration]) -> Result<Word, RuntimeError> {
+    let mut interpreter = Interpreter {
+        functions: ast
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::Function {
+                    name,
+                    arithmetic,
+                    arguments,
+                    return_type,
+                    body,
+                    ..
+                } => Some((
+                    name.as_str(),
+                    (*arithmetic, arguments.as_slice(), return_type, body),
+                )),
+                _ => None,
+            })
+            .collect(),
+        builtins: builtins::standard(),
+        signed: typechecker::signed_operations(ast, &builtins::standard(), "main"),
+        host: VirtualMachine::from_functions(Vec::new()),
+        strings: HashMap::new(),
+        locals: Vec::new(),
+        arithmetic: Arithmetic::default(),
+    };
+    match interpreter.call("main", Vec::new()) {
+        Ok(value) | Err(Unwind::Exit(value)) => Ok(value),
+        Err(Unwind::Error(error)) => Err(error),
+        Err(Unwind::Return(_) | Unwind::Break(_)) => {
+            unreachable!("calls and loops catch their own returns and breaks")
+        }
+    }
+}
+
+type Function<'a> = (
+    Arithmetic,
+    &'a [(String, Type)],
+    &'a Type,
+    &'a Vec<Spanned<Statement>>,
+);
+
+struct Interpreter<'a> {
+    functions: HashMap<&'a str, Function<'a>>,
+    builtins: Vec<Builtin>,
+    signed: SignedOperations,
+    /// Holds the heap that arrays live on, and runs builtins.
+    host: VirtualMachine,
+    /// The address of each string literal's array, shared like the compiler's pool.
+    strings: HashMap<&'a str, Word>,
+    /// The variables of the function being run, innermost last.
+    locals: Vec<(&'a str, Word)>,
+    /// How the function being run does arithmetic.
+    arithmetic: Arithmetic,
+}
+
+/// Why evaluation stopped before reaching the end of what it was running.
+enum Unwind {
+    Return(Word),
+    Break(Word),
+    /// The program called `exit`.
+    Exit(Word),
+    Error(RuntimeError),
+}
+
+impl<'a> Interpreter<'a> {
+    fn call(&mut self, name: &str, arguments: Vec<Word>) -> Result<Word, Unwind> {
+        if name == "exit" {
+            return Err(Unwind::Exit(arguments[0]));
+        }
+        let Some(&(arithmetic, parameters, return_type, body)) = self.functions.get(name) else {
+            return self
+                .call_builtin(name, arguments)?
+                .pop()
+                .ok_or_else(|| Unwind::Error(RuntimeError::UnknownFunction(name.to_string())));
+        };
+        let bound = parameters.iter().map(|(name, _)| name.as_str());
+        let caller = std::mem::replace(&mut self.locals, bound.zip(arguments).collect());
+        let caller_arithmetic = std::mem::replace(&mut self.arithmetic, arithmetic);
+        let result = match self.block(body) {
+            Ok(()) if return_type.resolved() == Type::Unit => Ok(0),
+            Ok(()) => Err(Unwind::Error(RuntimeError::MissingReturn)),
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(unwind) => Err(unwind),
+        };
+        self.locals = caller;
+        self.arithmetic = caller_arithmetic;
+        result
+    }
+
+    /// Runs the builtin called `name` on the host VM, returning every slot of its
+    /// result.
+    fn call_builtin(&mut self, name: &str, arguments: Vec<Word>) -> Result<Vec<Word>, Unwind> {
+        let Some(builtin) = self.builtins.iter().find(|builtin| builtin.name == name) else {
+            return Err(Unwind::Error(RuntimeError::UnknownFunction(
+                name.to_string(),
+            )));
+        };
+        for argument in arguments {
+            self.host.push(argument);
+        }
+        (builtin.function)(&mut self.host);
+        if let Some(error) = self.host.take_failure() {
+            return Err(Unwind::Error(error));
+        }
+        let mut results: Vec<Word> = (0..compiler::size_of(&builtin.return_type))
+            .map(|_| self.host.pop())
+            .collect();
+        results.reverse();
+        Ok(results)
+    }
+
+    /// Runs `body` in a scope of its own.
+    fn block(&mut self, body: &'a [Spanned<Statement>]) -> Result<(), Unwind> {
+        let scope_start = self.locals.len();
+        let result = body
+            .iter()
+            .try_for_each(|statement| self.statement(&statement.node));
+        self.locals.truncate(scope_start);
+        result
+    }
+
+    fn statement(&mut self, statement: &'a Statement) -> Result<(), Unwind> {
+        match statement {
+            Statement::Let { name, value, .. } | Statement::Const { name, value, .. } => {
+                let value = self.expression(value)?;
+                self.locals.push((name, value));
+            }
+            Statement::LetTuple(names, value) => {
+                let Expression::Call(function, arguments) = value else {
+                    unreachable!("the typechecker only allows destructuring calls");
+                };
+                let arguments = self.arguments(arguments)?;
+                let values = self.call_builtin(function, arguments)?;
+                self.locals
+                    .extend(names.iter().map(String::as_str).zip(values));
+            }
+            Statement::Assign(name, value) => {
+                let value = self.expression(value)?;
+                *self.local(name) = value;
+            }
+            Statement::IndexAssign(array, index, value) => {
+                let address = self.element_address(array, index)?;
+                let value = self.expression(value)?;
+                if self.host.is_read_only(address) {
+                    return Err(Unwind::Error(RuntimeError::WriteToReadOnly(address)));
+                }
+                self.host.write_heap(address, value);
+            }
+            Statement::Expr(expression) => {
+                self.expression(expression)?;
+            }
+            Statement::Return(value) => return Err(Unwind::Return(self.expression(value)?)),
+            Statement::If(condition, then_body, else_body) => {
+                if self.expression(condition)? != 0 {
+                    self.block(then_body)?;
+                } else {
+                    self.block(else_body)?;
+                }
+            }
+            Statement::While(condition, body) => {
+                while self.expression(condition)? != 0 {
+                    match self.block(body) {
+                        Ok(()) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(unwind) => return Err(unwind),
+                    }
+                }
+            }
+            Statement::Break(value) => {
+                let value = match value {
+                    Some(value) => self.expression(value)?,
+                    None => 0,
+                };
+                return Err(Unwind::Break(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &'a Expression) -> Result<Word, Unwind> {
+        Ok(match expression {
+            Expression::Call(function, arguments) if function == "len" => {
+                let array = self.expression(&arguments[0])?;
+                self.host.read_heap(array)
+            }
+            Expression::Call(function, arguments) => {
+                let arguments = self.arguments(arguments)?;
+                self.call(function, arguments)?
+            }
+            Expression::Variable(name) => *self.local(name),
+            Expression::Add(a, b) => {
+                let checked = if self.signed.contains(expression) {
+                    signed_checked_add
+                } else {
+                    Word::checked_add
+                };
+                self.arithmetic(a, b, Word::wrapping_add, checked)?
+            }
+            Expression::Sub(a, b) => {
+                let checked = if self.signed.contains(expression) {
+                    signed_checked_sub
+                } else {
+                    Word::checked_sub
+                };
+                self.arithmetic(a, b, Word::wrapping_sub, checked)?
+            }
+            Expression::Mul(a, b) => {
+                let checked = if self.signed.contains(expression) {
+                    signed_checked_mul
+                } else {
+                    Word::checked_mul
+                };
+                self.arithmetic(a, b, Word::wrapping_mul, checked)?
+            }
+            Expression::Div(a, b) => {
+                let (a, b) = (self.expression(a)?, self.expression(b)?);
+                if self.signed.contains(expression) {
+                    signed_div(a, b).unwrap_or(0)
+                } else {
+                    a.checked_div(b).unwrap_or(0)
+                }
+            }
+            Expression::Mod(a, b) => {
+                let (a, b) = (self.expression(a)?, self.expression(b)?);
+                if self.signed.contains(expression) {
+                    signed_rem(a, b).unwrap_or(0)
+                } else {
+                    a.checked_rem(b).unwrap_or(0)
+                }
+            }
+            Expression::NumLiteral(n) | Expression::SuffixedLiteral(n, _) => *n as Word,
+            Expression::NegativeLiteral(n) => (*n as i64).wrapping_neg() as Word,
+            Expression::BoolLiteral(b) => *b as Word,
+            Expression::Negate(a) => {
+                let a = self.expression(a)?;
+                match self.arithmetic {
+                    Arithmetic::Wrapping => a.wrapping_neg(),
+                    Arithmetic::Checked => {
+                        signed_checked_sub(0, a).ok_or(Unwind::Error(RuntimeError::Overflow))?
+                    }
+                }
+            }
+            Expression::Not(a) => (self.expression(a)? == 0) as Word,
+            Expression::StrLiteral(text) => match self.strings.get(text.as_str()) {
+                Some(address) => *address,
+                None => {
+                    // Like the compiler's string pool, every use shares the slots,
+                    // so they are read only.
+                    let mut slots = vec![text.chars().count() as Word];
+                    slots.extend(text.chars().map(|c| c as Word));
+                    let address = self.host.allocate_read_only(&slots);
+                    self.strings.insert(text, address);
+                    address
+                }
+            },
+            Expression::ArrayLiteral(elements) => {
+                let elements = self.arguments(elements)?;
+                self.array(&elements)
+            }
+            Expression::Index(array, index) => {
+                let address = self.element_address(array, index)?;
+                self.host.read_heap(address)
+            }
+            Expression::Block(body, tail) => {
+                let scope_start = self.locals.len();
+                let result = body
+                    .iter()
+                    .try_for_each(|statement| self.statement(&statement.node))
+                    .and_then(|()| self.expression(tail));
+                self.locals.truncate(scope_start);
+                result?
+            }
+            Expression::If(condition, then_branch, else_branch) => {
+                if self.expression(condition)? != 0 {
+                    self.expression(then_branch)?
+                } else {
+                    self.expression(else_branch)?
+                }
+            }
+            Expression::And(a, b) => match self.expression(a)? {
+                0 => 0,
+                _ => self.expression(b)?,
+            },
+            Expression::Or(a, b) => match self.expression(a)? {
+                0 => self.expression(b)?,
+                a => a,
+            },
+            Expression::Compare(comparison, a, b) => {
+                let (a, b) = (self.expression(a)?, self.expression(b)?);
+                let ordering = if self.signed.contains(expression) {
+                    (a as SignedWord).cmp(&(b as SignedWord))
+                } else {
+                    a.cmp(&b)
+                };
+                let result = match comparison {
+                    Comparison::Equal => ordering.is_eq(),
+                    Comparison::NotEqual => ordering.is_ne(),
+                    Comparison::Less => ordering.is_lt(),
+                    Comparison::LessEqual => ordering.is_le(),
+                    Comparison::Greater => ordering.is_gt(),
+                    Comparison::GreaterEqual => ordering.is_ge(),
+                };
+                result as Word
+            }
+            Expression::Loop(body) => loop {
+                match self.block(body) {
+                    Ok(()) => {}
+                    Err(Unwind::Break(value)) => break value,
+                    Err(unwind) => return Err(unwind),
+                }
+            },
+            Expression::Match(value, arms, wildcard) => {
+                let value = self.expression(value)?;
+                let arm = arms
+                    .iter()
+                    .find(|(n, _)| *n as Word == value)
+                    .map(|(_, arm)| arm)
+                    .or(wildcard.as_deref())
+                    .expect("the typechecker requires a `_` arm");
+                self.expression(arm)?
+            }
+        })
+    }
+
+    /// `a` and `b` combined with `wrapping`, or in a `#[checked]` function with
+    /// `checked`, stopping if that overflows.
+    fn arithmetic(
+        &mut self,
+        a: &'a Expression,
+        b: &'a Expression,
+        wrapping: fn(Word, Word) -> Word,
+        checked: fn(Word, Word) -> Option<Word>,
+    ) -> Result<Word, Unwind> {
+        let (a, b) = (self.expression(a)?, self.expression(b)?);
+        match self.arithmetic {
+            Arithmetic::Wrapping => Ok(wrapping(a, b)),
+            Arithmetic::Checked => checked(a, b).ok_or(Unwind::Error(RuntimeError::Overflow)),
+        }
+    }
+
+    /// Evaluates `expressions` left to right, as arguments are.
+    fn arguments(&mut self, expressions: &'a [Expression]) -> Result<Vec<Word>, Unwind> {
+        expressions
+            .iter()
+            .map(|expression| self.expression(expression))
+            .collect()
+    }
+
+    fn element_address(
+        &mut self,
+        array: &'a Expression,
+        index: &'a Expression,
+    ) -> Result<Word, Unwind> {
+        let array = self.expression(array)?;
+        let index = self.expression(index)?;
+        // The first slot holds the length.
+        let length = self.host.read_heap(array);
+        if index >= length {
+            return Err(Unwind::Error(RuntimeError::IndexOutOfBounds {
+                index,
+                length,
+            }));
+        }
+        Ok(array + index + 1)
+    }
+
+    /// Allocates an array holding `elements`, laid out as the compiler lays them out.
+    fn array(&mut self, elements: &[Word]) -> Word {
+        let address = self.host.allocate(elements.len() as u32 + 1);
+        self.host.write_heap(address, elements.len() as Word);
+        for (i, element) in elements.iter().enumerate() {
+            self.host.write_heap(address + 1 + i as Word, *element);
+        }
+        address
+    }
+
+    /// The innermost variable called `name`.
+    fn local(&mut self, name: &str) -> &mut Word {
+        let (_, value) = self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|(local, _)| *local == name)
+            .expect("the typechecker rejects undeclared variables");
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser, typechecker};
+
+    /// Programs that the interpreter and the compiled VM must agree on, in their
+    /// results and in how they fail.
+    const CORPUS: &[&str] = &[
+        include_str!("../examples/function.prog"),
+        include_str!("../examples/math.prog"),
+        include_str!("../examples/return_var.prog"),
+        // Arithmetic wraps, and dividing by zero gives zero.
+        "fn main(): u32 {
+            let big = 4000000000;
+            return big + big * 3 - 7 / 0 + 9 % 0 + 17 / 5 * 5 + 17 % 5;
+        }",
+        "fn main(): i32 { return -5 + 2; }",
+        "fn check(n: u32): bool { print(n); return n > 1; }
+        fn main(): u32 {
+            let a = check(1) && check(2) || check(3) && !check(0);
+            if a || check(4) { return 1; }
+            return 0;
+        }",
+        "fn main(): u32 { let a = [1, 2, 3]; return a[5]; }",
+        "fn main(): u32 {
+            let mut a = [1, 2, 3];
+            let i = 7;
+            a[i - 5] = 9;
+            a[i] = 1;
+            return a[2];
+        }",
+        "#[checked]
+        fn shift(a: i32): i32 { return a * 3 - -a + 7; }
+        fn main(): i32 { return shift(-5); }",
+        "fn main(): i32 {
+            let a: i32 = -7;
+            if a < 0 { if -1 >= a { return a / 2 + a % 2 * 10 + (0 - 9) / 4; } }
+            return 0;
+        }",
+        "fn main(): u32 { return sat_add(4000000000, 4000000000) - sat_sub(3, 5); }",
+        "fn main(): u32 {
+            let (a, carry) = mul_checked(70000, 70000);
+            if carry { return a + 1; }
+            return a;
+        }",
+        "fn main(): i32 {
+            let a: i32 = 7;
+            let flag = !(a == 7);
+            if !flag { return -a * 3 - -a + --a; }
+            return a;
+        }",
+        "#[checked]
+        fn grow(a: u32): u32 { return a * 70000; }
+        fn main(): u32 { return 70000 * 70000 + grow(5) + grow(70000); }",
+        // Calls, arguments in order, and recursion.
+        "fn sub(a: u32, b: u32): u32 { return a - b; }
+        fn twice(a: u32): u32 { return sub(a * 3, a); }
+        fn main(): u32 { return sub(twice(sub(50, 8)), twice(5)) + modpow(3, 200, 1000); }",
+        "fn ackermann(m: u32, n: u32): u32 {
+            if m == 0 { return n + 1; }
+            if n == 0 { return ackermann(m - 1, 1); }
+            return ackermann(m - 1, ackermann(m, n - 1));
+        }
+        fn main(): u32 { return ackermann(2, 3); }",
+        "fn nothing(a: u32): () { let b = a; }
+        fn main(): u32 { nothing(3); return clamp(99, 10, 20); }",
+        // Control flow.
+        "fn main(): u32 {
+            let mut total = 0;
+            let mut i = 0;
+            while i < 10 {
+                i = i + 1;
+                if i % 3 == 0 { total = total + i; } else { total = total + 100; }
+            }
+            let found = loop {
+                total = total + 1;
+                if total % 7 == 0 { break total; }
+            };
+            return found + if total > 500 { 1 } else { 2 };
+        }",
+        "fn name(n: u32): u32 {
+            return match n { 0 => 10, 1 => 11, 2 => 12, 3 => 13, _ => 99 };
+        }
+        fn sparse(n: u32): u32 {
+            return match n { 5 => 1, 500 => 2, _ => 3 };
+        }
+        fn main(): u32 {
+            return name(0) + name(3) * 10 + name(4) * 100 + sparse(500) * 1000 + sparse(6);
+        }",
+        "fn main(): u32 {
+            let x = 1;
+            let y = { let x = 20; x + 1 };
+            const Z: u32 = 300;
+            return x + y + Z;
+        }",
+        // Arrays and strings on the heap.
+        "fn sum(values: [u32; 4]): u32 {
+            let mut total = 0;
+            let mut i = 0;
+            while i < len(values) {
+                total = total + values[i];
+                i = i + 1;
+            }
+            return total;
+        }
+        fn main(): u32 {
+            let mut values = [1, 2, 3, 4];
+            values[2] = 30;
+            let text = \"hi\";
+            return sum(values) + text[0] + text[1] + len(\"hi\");
+        }",
+        "fn double(x: u32): u32 { return x * 2; }
+        fn main(): u32 {
+            let (block, n) = 0 |> read_bytes;
+            return n + (3 |> double |> double) + ([4, 5] |> len) + (n == 0 ? 10 : 20);
+        }",
+        // Ways of stopping early.
+        "fn deep(n: u32): u32 {
+            if n == 0 { return exit(42); }
+            return deep(n - 1) + 1;
+        }
+        fn main(): u32 { return deep(20); }",
+        "fn main(): u32 { assert_eq(2 + 2, 4); assert_eq(3, 2 * 2); return 1; }",
+        "fn main(): u32 { let mut s = \"hi\"; s[0] = 120; return s[0]; }",
+    ];
+
+    /// Runs `source` both compiled and interpreted, and checks that the two agree.
+    fn assert_agrees(source: &str) {
+        let program = parser::parse(source).unwrap();
+        typechecker::check(&program).unwrap();
+        let compiled = compiler::compile(&program).unwrap().run();
+        let interpreted = interpret(&program);
+        assert_eq!(
+            format!("{interpreted:?}"),
+            format!("{compiled:?}"),
+            "in\n{source}"
+        );
+    }
+
+    #[test]
+    fn corpus_agrees_with_the_compiled_programs() {
+        for source in CORPUS {
+            assert_agrees(source);
+        }
+    }
+
+    #[test]
+    fn agrees_with_the_compiled_program() {
+        let program = parser::parse(
+            "fn fib(n: u32): u32 {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn fib_loop(n: u32): u32 {
+                let mut a = 0;
+                let mut b = 1;
+                let mut i = 0;
+                while i < n {
+                    let next = a + b;
+                    a = b;
+                    b = next;
+                    i = i + 1;
+                }
+                return a;
+            }
+            fn main(): u32 {
+                const N: u32 = 15;
+                let mut table = [0, 0, 0, 0, 0];
+                let mut i = 0;
+                loop {
+                    if i == len(table) { break; }
+                    table[i] = fib(i + N);
+                    i = i + 1;
+                };
+                let check = match fib_loop(N + 4) - table[4] { 0 => 1000, _ => 0 };
+                let (sum, overflowed) = add_checked(table[1], table[2]);
+                return check + sum % 9973 + max(table[0] / 7, 3);
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let compiled = compiler::compile(&program).unwrap().run().unwrap();
+        assert_eq!(interpret(&program).unwrap(), compiled);
+        assert!(compiled > 1000);
+    }
+
+    #[test]
+    fn exits_and_failures_stop_the_program() {
+        let run = |source| interpret(&parser::parse(source).unwrap());
+        assert_eq!(
+            run("fn f(): u32 { return exit(4); } fn main(): u32 { f(); return 1; }").unwrap(),
+            4
+        );
+        assert!(matches!(
+            run("fn main(): u32 { assert_eq(1, 2); return 0; }"),
+            Err(RuntimeError::AssertionFailed { left: 1, right: 2 })
+        ));
+    }
+}
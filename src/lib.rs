@@ -0,0 +1,14 @@
+pub mod assembler;
+pub mod ast;
+pub mod builtins;
+pub mod call_graph;
+pub mod compiler;
+pub mod diagnostics;
+pub mod fold;
+pub mod formatter;
+pub mod interpreter;
+pub mod lint;
+pub mod parser;
+pub mod repl;
+pub mod typechecker;
+pub mod virtual_machine;
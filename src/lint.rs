@@ -0,0 +1,552 @@
+use crate::{
+    ast::{Declaration, Expression, Span, Spanned, Statement},
+    builtins,
+    call_graph::call_graph,
+    fold,
+    typechecker::{self, SignedOperations},
+};
+
+/// Something that is allowed but probably a mistake.
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// An expression statement whose value is thrown away and that has no effects,
+    /// like `a + b;`.
+    UnusedValue,
+    /// A function that calls itself on every path before it can return, and so
+    /// never returns at all.
+    UnconditionalRecursion(String),
+    /// A parameter that the function's body never reads, which usually means the
+    /// signature is out of date. Names starting with `_` are left alone.
+    UnusedParameter { function: String, name: String },
+    /// An `if` or `while` whose condition is made only of literals, so it always
+    /// has the value given, like `if 1 == 1`. A literal `while true` is left alone,
+    /// as a way of writing a loop, and so are conditions reading `const`s.
+    ConstantCondition(bool),
+}
+
+/// Looks for likely mistakes in a program that typechecks.
+pub fn lint(ast: &[Declaration]) -> Vec<Spanned<Warning>> {
+    let mut warnings = Vec::new();
+    let graph = call_graph(ast);
+    let signed = typechecker::signed_operations(ast, &builtins::standard(), "main");
+    for declaration in ast {
+        if let Declaration::Function {
+            name,
+            arguments,
+            body,
+            span,
+            ..
+        } = declaration
+        {
+            for (argument, _) in arguments {
+                if !argument.starts_with('_') && !block_reads(body, argument) {
+                    warnings.push(Spanned {
+                        node: Warning::UnusedParameter {
+                            function: name.clone(),
+                            name: argument.clone(),
+                        },
+                        span: *span,
+                    });
+                }
+            }
+            if graph[name].contains(name) && block_flow(body, name) == Flow::Recurses {
+                warnings.push(Spanned {
+                    node: Warning::UnconditionalRecursion(name.clone()),
+                    span: *span,
+                });
+            }
+            lint_block(body, &signed, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// What running a piece of code is sure to do, as far as recursion is concerned.
+#[derive(Debug, PartialEq)]
+enum Flow {
+    /// Every path calls the function being linted.
+    Recurses,
+    /// Some path may return before calling it.
+    MayReturn,
+    /// Neither, so the code after this decides.
+    Continues,
+}
+
+/// The flow of `body`, a block in the function `name`.
+fn block_flow(body: &[Spanned<Statement>], name: &str) -> Flow {
+    for statement in body {
+        let flow = match &statement.node {
+            Statement::Let {
+                value: expression, ..
+            }
+            | Statement::Const {
+                value: expression, ..
+            }
+            | Statement::Assign(_, expression)
+            | Statement::LetTuple(_, expression)
+            | Statement::Expr(expression) => expression_flow(expression, name),
+            Statement::IndexAssign(array, index, value) => {
+                sequence_flow([array, index, value], name)
+            }
+            Statement::Return(expression) => match expression_flow(expression, name) {
+                Flow::Continues => Flow::MayReturn,
+                flow => flow,
+            },
+            Statement::If(condition, then_body, else_body) => {
+                match expression_flow(condition, name) {
+                    Flow::Continues => {
+                        branches_flow(block_flow(then_body, name), block_flow(else_body, name))
+                    }
+                    flow => flow,
+                }
+            }
+            // The body may not run at all, so only a return from it matters.
+            Statement::While(condition, body) => match expression_flow(condition, name) {
+                Flow::Continues if block_flow(body, name) == Flow::MayReturn => Flow::MayReturn,
+                flow => flow,
+            },
+            // Leaving the loop may skip the code after it that recurses, so the rest
+            // of the block can't be relied on.
+            Statement::Break(value) => match sequence_flow(value, name) {
+                Flow::Continues => Flow::MayReturn,
+                flow => flow,
+            },
+        };
+        if flow != Flow::Continues {
+            return flow;
+        }
+    }
+    Flow::Continues
+}
+
+fn expression_flow(expression: &Expression, name: &str) -> Flow {
+    match expression {
+        Expression::Call(function, expressions) => match sequence_flow(expressions, name) {
+            Flow::Continues if function == name => Flow::Recurses,
+            flow => flow,
+        },
+        Expression::ArrayLiteral(expressions) => sequence_flow(expressions, name),
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b) => match expression_flow(a, name) {
+            Flow::Continues => expression_flow(b, name),
+            flow => flow,
+        },
+        // `b` is only evaluated on some paths, like one branch of an `if`.
+        Expression::And(a, b) | Expression::Or(a, b) => match expression_flow(a, name) {
+            Flow::Continues => branches_flow(expression_flow(b, name), Flow::Continues),
+            flow => flow,
+        },
+        Expression::Block(body, tail) => match block_flow(body, name) {
+            Flow::Continues => expression_flow(tail, name),
+            flow => flow,
+        },
+        Expression::If(condition, then_branch, else_branch) => {
+            match expression_flow(condition, name) {
+                Flow::Continues => branches_flow(
+                    expression_flow(then_branch, name),
+                    expression_flow(else_branch, name),
+                ),
+                flow => flow,
+            }
+        }
+        // The body runs at least once.
+        Expression::Loop(body) => block_flow(body, name),
+        Expression::Negate(a) | Expression::Not(a) => expression_flow(a, name),
+        Expression::Match(value, arms, wildcard) => match expression_flow(value, name) {
+            Flow::Continues => arms
+                .iter()
+                .map(|(_, arm)| arm)
+                .chain(wildcard.as_deref())
+                .map(|arm| expression_flow(arm, name))
+                .reduce(branches_flow)
+                .unwrap_or(Flow::Continues),
+            flow => flow,
+        },
+        Expression::Variable(_)
+        | Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => Flow::Continues,
+    }
+}
+
+/// The flow of expressions evaluated one after another.
+fn sequence_flow<'a>(expressions: impl IntoIterator<Item = &'a Expression>, name: &str) -> Flow {
+    for expression in expressions {
+        let flow = expression_flow(expression, name);
+        if flow != Flow::Continues {
+            return flow;
+        }
+    }
+    Flow::Continues
+}
+
+/// The flow of an `if` whose branches have the flows `a` and `b`.
+fn branches_flow(a: Flow, b: Flow) -> Flow {
+    match (a, b) {
+        (Flow::Recurses, Flow::Recurses) => Flow::Recurses,
+        (Flow::MayReturn, _) | (_, Flow::MayReturn) => Flow::MayReturn,
+        _ => Flow::Continues,
+    }
+}
+
+/// Whether `body` reads the variable `name` before a `let` in it shadows it.
+fn block_reads(body: &[Spanned<Statement>], name: &str) -> bool {
+    for statement in body {
+        let reads = match &statement.node {
+            Statement::Let {
+                name: bound, value, ..
+            }
+            | Statement::Const {
+                name: bound, value, ..
+            } => {
+                if expression_reads(value, name) {
+                    return true;
+                }
+                if bound == name {
+                    return false;
+                }
+                false
+            }
+            Statement::LetTuple(bound, value) => {
+                if expression_reads(value, name) {
+                    return true;
+                }
+                if bound.iter().any(|bound| bound == name) {
+                    return false;
+                }
+                false
+            }
+            Statement::Assign(_, expression)
+            | Statement::Expr(expression)
+            | Statement::Return(expression) => expression_reads(expression, name),
+            Statement::IndexAssign(array, index, value) => [array, index, value]
+                .into_iter()
+                .any(|expression| expression_reads(expression, name)),
+            Statement::If(condition, then_body, else_body) => {
+                expression_reads(condition, name)
+                    || block_reads(then_body, name)
+                    || block_reads(else_body, name)
+            }
+            Statement::While(condition, body) => {
+                expression_reads(condition, name) || block_reads(body, name)
+            }
+            Statement::Break(value) => value
+                .as_ref()
+                .is_some_and(|value| expression_reads(value, name)),
+        };
+        if reads {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether `expression` reads the variable `name`, including calling it if it
+/// holds a function.
+fn expression_reads(expression: &Expression, name: &str) -> bool {
+    match expression {
+        Expression::Variable(variable) => variable == name,
+        Expression::Call(function, expressions) => {
+            function == name
+                || expressions
+                    .iter()
+                    .any(|expression| expression_reads(expression, name))
+        }
+        Expression::ArrayLiteral(expressions) => expressions
+            .iter()
+            .any(|expression| expression_reads(expression, name)),
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b)
+        | Expression::And(a, b)
+        | Expression::Or(a, b) => expression_reads(a, name) || expression_reads(b, name),
+        Expression::Block(body, tail) => {
+            // A `let` in the block shadows `name` for the tail too.
+            let shadowed = body.iter().any(|statement| match &statement.node {
+                Statement::Let { name: bound, .. } => bound == name,
+                Statement::LetTuple(bound, _) => bound.iter().any(|bound| bound == name),
+                _ => false,
+            });
+            block_reads(body, name) || (!shadowed && expression_reads(tail, name))
+        }
+        Expression::If(condition, then_branch, else_branch) => {
+            expression_reads(condition, name)
+                || expression_reads(then_branch, name)
+                || expression_reads(else_branch, name)
+        }
+        Expression::Loop(body) => block_reads(body, name),
+        Expression::Negate(a) | Expression::Not(a) => expression_reads(a, name),
+        Expression::Match(value, arms, wildcard) => {
+            expression_reads(value, name)
+                || arms
+                    .iter()
+                    .map(|(_, arm)| arm)
+                    .chain(wildcard.as_deref())
+                    .any(|arm| expression_reads(arm, name))
+        }
+        Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => false,
+    }
+}
+
+fn lint_block(
+    body: &[Spanned<Statement>],
+    signed: &SignedOperations,
+    warnings: &mut Vec<Spanned<Warning>>,
+) {
+    for statement in body {
+        match &statement.node {
+            Statement::Expr(expression) => {
+                if is_pure(expression) {
+                    warnings.push(Spanned {
+                        node: Warning::UnusedValue,
+                        span: statement.span,
+                    });
+                }
+                lint_expression(expression, signed, warnings);
+            }
+            Statement::Let {
+                value: expression, ..
+            }
+            | Statement::Const {
+                value: expression, ..
+            }
+            | Statement::Assign(_, expression)
+            | Statement::LetTuple(_, expression)
+            | Statement::Return(expression) => lint_expression(expression, signed, warnings),
+            Statement::IndexAssign(array, index, value) => {
+                lint_expression(array, signed, warnings);
+                lint_expression(index, signed, warnings);
+                lint_expression(value, signed, warnings);
+            }
+            Statement::If(condition, then_body, else_body) => {
+                lint_condition(condition, statement.span, signed, warnings);
+                lint_expression(condition, signed, warnings);
+                lint_block(then_body, signed, warnings);
+                lint_block(else_body, signed, warnings);
+            }
+            Statement::While(Expression::BoolLiteral(true), body) => {
+                lint_block(body, signed, warnings)
+            }
+            Statement::While(condition, body) => {
+                lint_condition(condition, statement.span, signed, warnings);
+                lint_expression(condition, signed, warnings);
+                lint_block(body, signed, warnings);
+            }
+            Statement::Break(value) => {
+                if let Some(value) = value {
+                    lint_expression(value, signed, warnings);
+                }
+            }
+        }
+    }
+}
+
+/// Warns if `condition`, of the statement at `span`, folds to a constant.
+fn lint_condition(
+    condition: &Expression,
+    span: Span,
+    signed: &SignedOperations,
+    warnings: &mut Vec<Spanned<Warning>>,
+) {
+    if let Some(value) = fold::evaluate(condition, &|_| None, signed) {
+        warnings.push(Spanned {
+            node: Warning::ConstantCondition(value != 0),
+            span,
+        });
+    }
+}
+
+/// Lints the statements of block expressions nested in `expression`.
+fn lint_expression(
+    expression: &Expression,
+    signed: &SignedOperations,
+    warnings: &mut Vec<Spanned<Warning>>,
+) {
+    match expression {
+        Expression::Call(_, expressions) | Expression::ArrayLiteral(expressions) => {
+            for expression in expressions {
+                lint_expression(expression, signed, warnings);
+            }
+        }
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b)
+        | Expression::And(a, b)
+        | Expression::Or(a, b) => {
+            lint_expression(a, signed, warnings);
+            lint_expression(b, signed, warnings);
+        }
+        Expression::Block(body, tail) => {
+            lint_block(body, signed, warnings);
+            lint_expression(tail, signed, warnings);
+        }
+        Expression::If(condition, then_branch, else_branch) => {
+            lint_expression(condition, signed, warnings);
+            lint_expression(then_branch, signed, warnings);
+            lint_expression(else_branch, signed, warnings);
+        }
+        Expression::Loop(body) => lint_block(body, signed, warnings),
+        Expression::Negate(a) | Expression::Not(a) => lint_expression(a, signed, warnings),
+        Expression::Match(value, arms, wildcard) => {
+            lint_expression(value, signed, warnings);
+            for arm in arms.iter().map(|(_, arm)| arm).chain(wildcard.as_deref()) {
+                lint_expression(arm, signed, warnings);
+            }
+        }
+        Expression::Variable(_)
+        | Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => {}
+    }
+}
+
+/// Whether evaluating `expression` does nothing but produce its value. Calls may
+/// have effects, and so may the statements in a block, so those are never pure.
+fn is_pure(expression: &Expression) -> bool {
+    match expression {
+        Expression::Call(..) | Expression::Loop(_) => false,
+        Expression::Variable(_)
+        | Expression::NumLiteral(_)
+        | Expression::NegativeLiteral(_)
+        | Expression::SuffixedLiteral(..)
+        | Expression::BoolLiteral(_)
+        | Expression::StrLiteral(_) => true,
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::Index(a, b)
+        | Expression::Compare(_, a, b)
+        | Expression::And(a, b)
+        | Expression::Or(a, b) => is_pure(a) && is_pure(b),
+        Expression::Negate(a) | Expression::Not(a) => is_pure(a),
+        Expression::ArrayLiteral(elements) => elements.iter().all(is_pure),
+        Expression::Block(body, tail) => body.is_empty() && is_pure(tail),
+        Expression::If(condition, then_branch, else_branch) => {
+            is_pure(condition) && is_pure(then_branch) && is_pure(else_branch)
+        }
+        Expression::Match(value, arms, wildcard) => {
+            is_pure(value)
+                && arms
+                    .iter()
+                    .map(|(_, arm)| arm)
+                    .chain(wildcard.as_deref())
+                    .all(is_pure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn unused_pure_value_warns() {
+        let source = "fn f(): u32 { return 1; }
+            fn main(): u32 {
+                1 + 2;
+                f();
+                if f() == 1 { let x = 3; x; }
+                return 0;
+            }";
+        let warnings = lint(&parser::parse(source).unwrap());
+        let warned: Vec<_> = warnings
+            .iter()
+            .map(|warning| {
+                assert_eq!(warning.node, Warning::UnusedValue);
+                &source[warning.span.start..warning.span.end]
+            })
+            .collect();
+        assert_eq!(warned, ["1 + 2;", "x;"]);
+    }
+
+    #[test]
+    fn recursion_without_base_case_warns() {
+        let source = "fn forever(): u32 { return forever(); }
+            fn factorial(n: u32): u32 {
+                if n == 0 { return 1; }
+                return n * factorial(n - 1);
+            }
+            fn main(): u32 { return factorial(3); }";
+        let warnings: Vec<_> = lint(&parser::parse(source).unwrap())
+            .into_iter()
+            .map(|warning| warning.node)
+            .collect();
+        assert_eq!(
+            warnings,
+            [Warning::UnconditionalRecursion("forever".to_string())]
+        );
+    }
+
+    #[test]
+    fn constant_condition_warns() {
+        let source = "fn main(n: u32): u32 {
+                if 1 == 1 { }
+                if n == 1 { }
+                while 2 * 3 < 5 { }
+                while true { return n; }
+                return 0;
+            }";
+        let warnings = lint(&parser::parse(source).unwrap());
+        let warned: Vec<_> = warnings
+            .iter()
+            .map(|warning| (&warning.node, &source[warning.span.start..warning.span.end]))
+            .collect();
+        assert_eq!(
+            warned,
+            [
+                (&Warning::ConstantCondition(true), "if 1 == 1 { }"),
+                (&Warning::ConstantCondition(false), "while 2 * 3 < 5 { }"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unused_parameter_warns() {
+        let source = "fn first(a: u32, b: u32): u32 { return a; }
+            fn shadowed(c: u32): u32 { let c = 1; return c; }
+            fn ignored(_d: u32): u32 { return 0; }
+            fn apply(f: fn(u32): u32, e: u32): u32 { return { let x = e; f(x) }; }
+            fn main(): u32 { return first(1, 2); }";
+        let warnings: Vec<_> = lint(&parser::parse(source).unwrap())
+            .into_iter()
+            .map(|warning| warning.node)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                Warning::UnusedParameter {
+                    function: "first".to_string(),
+                    name: "b".to_string()
+                },
+                Warning::UnusedParameter {
+                    function: "shadowed".to_string(),
+                    name: "c".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -1,38 +1,223 @@
-mod ast;
-mod compiler;
-mod parser;
-mod typechecker;
-mod virtual_machine;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use compiler::{
+    ast, compiler as codegen, diagnostics, fold, lint, parser, repl, typechecker,
+    virtual_machine::Word,
+};
 
 fn main() {
-    let Some(file_name) = std::env::args().skip(1).next() else {
-        eprintln!("Usage: {} [filename]", std::env::args().next().unwrap());
+    let mut args: Vec<String> = std::env::args().collect();
+    let program_name = args.remove(0);
+    let no_run = take_flag(&mut args, "--no-run");
+    let time = take_flag(&mut args, "--time");
+    let trace = take_flag(&mut args, "--trace");
+
+    let Some(file_name) = args.first() else {
+        eprintln!(
+            "Usage: {program_name} [--no-run] [--time] [--trace] [filename | --repl | --eval expression]"
+        );
         return;
     };
 
-    let Ok(input) = std::fs::read_to_string(&file_name) else {
-        eprintln!("Error opening file {:?}", file_name);
+    if file_name == "--repl" {
+        run_repl();
         return;
-    };
+    }
+
+    let mut timings = Timings::default();
+    if file_name == "--eval" {
+        let Some(expression) = args.get(1) else {
+            eprintln!("Usage: {program_name} --eval [expression]");
+            return;
+        };
+        run(
+            expression,
+            parser::parse_expression_program,
+            no_run,
+            trace,
+            0,
+            &mut timings,
+        );
+    } else {
+        let Ok(input) = std::fs::read_to_string(file_name) else {
+            eprintln!("Error opening file {:?}", file_name);
+            return;
+        };
+        // Like a C program's argc, but only counting the arguments after the file.
+        let argc = (args.len() - 1) as Word;
+        run(&input, parser::parse, no_run, trace, argc, &mut timings);
+    }
+
+    if time {
+        for (phase, duration) in &timings.0 {
+            eprintln!("{phase}: {duration:?}");
+        }
+    }
+}
+
+/// Removes `flag` from `args`, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
+}
+
+/// How long each phase of the pipeline took, in the order they ran.
+#[derive(Default)]
+struct Timings(Vec<(&'static str, Duration)>);
 
-    let program = match parser::parse(&input) {
+impl Timings {
+    fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.push((phase, start.elapsed()));
+        result
+    }
+}
+
+/// Parses `input` with `parse`, then checks, compiles and, unless `no_run` is set,
+/// runs it, reporting any errors. `trace` prints every step as it runs. `argc` is
+/// passed to `main` if it takes an argument. Returns the program's exit code if it
+/// ran.
+fn run(
+    input: &str,
+    parse: fn(&str) -> Result<Vec<ast::Declaration>, ast::Spanned<parser::Error>>,
+    no_run: bool,
+    trace: bool,
+    argc: Word,
+    timings: &mut Timings,
+) -> Option<Word> {
+    let mut program = match timings.time("parse", || parse(input)) {
         Ok(program) => program,
         Err(e) => {
             eprintln!("Error parsing file");
-            eprintln!("{e:?}");
-            return;
+            eprint!("{}", diagnostics::render_error(input, &e.into()));
+            return None;
         }
     };
 
-    if let Err(e) = typechecker::check(&program) {
+    if let Err(errors) = timings.time("typecheck", || typechecker::check_all(&program)) {
         eprintln!("Typechecking error");
-        eprintln!("{e:?}");
-        return;
+        for e in errors {
+            eprint!("{}", diagnostics::render_error(input, &e.into()));
+        }
+        return None;
     };
 
+    for warning in lint::lint(&program) {
+        eprintln!(
+            "warning: {:?} on line {}",
+            warning.node,
+            warning.span.line(input)
+        );
+    }
+
+    let compiled = timings.time("compile", || {
+        fold::fold(&mut program);
+        codegen::compile(&program)
+    });
+
     println!("{:?}", program);
 
-    let mut vm = compiler::compile(&program);
-    let result = vm.run();
-    println!("Program exited with code {result}");
+    let mut vm = match compiled {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("Compilation error");
+            eprintln!("{e:?}");
+            return None;
+        }
+    };
+    if no_run {
+        return None;
+    }
+    if trace {
+        vm.enable_tracing();
+    }
+    let takes_argc = program.iter().any(|declaration| {
+        matches!(declaration, ast::Declaration::Function { name, arguments, .. }
+            if name == "main" && !arguments.is_empty())
+    });
+    let main_arguments = if takes_argc { vec![argc] } else { vec![] };
+    if let Err(e) = vm.set_main_arguments(&main_arguments) {
+        eprintln!("Runtime error");
+        eprintln!("{e:?}");
+        return None;
+    }
+    match timings.time("run", || vm.run()) {
+        Ok(result) => {
+            println!("Program exited with code {result}");
+            Some(result)
+        }
+        Err(e) => {
+            match vm.current_span() {
+                Some(span) => eprintln!("Runtime error on line {}", span.line(input)),
+                None => eprintln!("Runtime error"),
+            }
+            eprintln!("{e:?}");
+            for frame in vm.backtrace() {
+                eprintln!("  in {} at {}", frame.function_name, frame.pc);
+            }
+            None
+        }
+    }
+}
+
+fn run_repl() {
+    let mut repl = repl::Repl::new();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().unwrap();
+        line.clear();
+        if std::io::stdin().read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match repl.eval(&line) {
+            Ok(Some(value)) => println!("{value}"),
+            Ok(None) => {}
+            Err(repl::Error::Parse(e)) => {
+                eprint!("{}", diagnostics::render_error(&line, &e.into()))
+            }
+            Err(repl::Error::Type(e)) => eprint!("{}", diagnostics::render_error(&line, &e.into())),
+            Err(e) => eprintln!("{e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_phase_is_timed() {
+        let source = "fn main(): u32 { let a = 2; return a + 3; }";
+        let mut timings = Timings::default();
+        assert_eq!(
+            run(source, parser::parse, false, false, 0, &mut timings),
+            Some(5)
+        );
+        let phases: Vec<_> = timings.0.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(phases, ["parse", "typecheck", "compile", "run"]);
+        assert!(
+            timings
+                .0
+                .iter()
+                .map(|(_, duration)| *duration)
+                .sum::<Duration>()
+                > Duration::ZERO
+        );
+
+        let mut timings = Timings::default();
+        assert_eq!(
+            run(source, parser::parse, true, false, 0, &mut timings),
+            None
+        );
+        assert_eq!(timings.0.len(), 3);
+    }
 }
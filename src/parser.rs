@@ -1,12 +1,33 @@
-use crate::ast::{Declaration, Expression, Statement, Type};
+use std::collections::HashMap;
 
-#[derive(Debug)]
+use crate::{
+    ast::{Arithmetic, Comparison, Declaration, Expression, Span, Spanned, Statement, Type},
+    compiler,
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Comment,
+    DocComment(String),
     KwFn,
+    KwExtern,
+    KwType,
     KwLet,
+    KwConst,
     KwReturn,
+    KwIf,
+    KwElse,
+    KwWhile,
+    KwLoop,
+    KwBreak,
+    KwMatch,
+    KwSizeof,
+    KwMut,
+    KwTrue,
+    KwFalse,
     Number(u32),
+    /// A number with a type suffix, like `255u8`.
+    SuffixedNumber(u32, Type),
     Identifier(String),
     StringLiteral(String),
     OpenParen,
@@ -19,6 +40,11 @@ pub enum Token {
     Colon,
     Semicolon,
     Equals,
+    EqualEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
     Add,
     Minus,
     Mod,
@@ -26,177 +52,1153 @@ pub enum Token {
     Multiply,
     Bang,
     BangEquals,
+    /// `|>`
+    Pipe,
+    /// `&&`
+    AndAnd,
+    /// `||`
+    OrOr,
+    /// `=>`
+    FatArrow,
+    /// `#`, starting an attribute like `#[checked]`.
+    Hash,
+    /// `?`, between the condition and the first value of `cond ? a : b`.
+    Question,
+}
+
+/// Every reserved word and the token it is lexed as. None of these can be used as
+/// a name.
+pub const KEYWORDS: &[(&str, Token)] = &[
+    ("fn", Token::KwFn),
+    ("extern", Token::KwExtern),
+    ("type", Token::KwType),
+    ("let", Token::KwLet),
+    ("const", Token::KwConst),
+    ("return", Token::KwReturn),
+    ("if", Token::KwIf),
+    ("else", Token::KwElse),
+    ("while", Token::KwWhile),
+    ("loop", Token::KwLoop),
+    ("break", Token::KwBreak),
+    ("match", Token::KwMatch),
+    ("sizeof", Token::KwSizeof),
+    ("mut", Token::KwMut),
+    ("true", Token::KwTrue),
+    ("false", Token::KwFalse),
+];
+
+/// The word `token` was lexed from, if it is a keyword.
+fn keyword(token: &Token) -> Option<&'static str> {
+    KEYWORDS
+        .iter()
+        .find(|(_, keyword)| keyword == token)
+        .map(|(word, _)| *word)
 }
 
-fn get_tokens(input: &str) -> Vec<Token> {
+fn get_tokens(input: &str) -> Result<Vec<(Token, Span)>, Spanned<Error>> {
     let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        match c {
-            '(' => tokens.push(Token::OpenParen),
-            ')' => tokens.push(Token::CloseParen),
-            '[' => tokens.push(Token::OpenBracket),
-            ']' => tokens.push(Token::CloseBracket),
-            '{' => tokens.push(Token::OpenBrace),
-            '}' => tokens.push(Token::CloseBrace),
-            '=' => tokens.push(Token::Equals),
-            ':' => tokens.push(Token::Colon),
-            ';' => tokens.push(Token::Semicolon),
-            ',' => tokens.push(Token::Comma),
-            '+' => tokens.push(Token::Add),
-            '-' => tokens.push(Token::Minus),
-            '*' => tokens.push(Token::Multiply),
+    // Lexing errors run from the start of the token to wherever lexing stopped.
+    let error = |node, start, chars: &mut std::iter::Peekable<std::str::CharIndices>| {
+        let end = chars.peek().map_or(input.len(), |(i, _)| *i);
+        Err(Spanned {
+            node,
+            span: Span { start, end },
+        })
+    };
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            '{' => Token::OpenBrace,
+            '}' => Token::CloseBrace,
+            '=' => {
+                if chars.next_if(|(_, c)| *c == '=').is_some() {
+                    Token::EqualEquals
+                } else if chars.next_if(|(_, c)| *c == '>').is_some() {
+                    Token::FatArrow
+                } else {
+                    Token::Equals
+                }
+            }
+            '<' => {
+                if chars.next_if(|(_, c)| *c == '=').is_some() {
+                    Token::LessEquals
+                } else {
+                    Token::Less
+                }
+            }
+            '>' => {
+                if chars.next_if(|(_, c)| *c == '=').is_some() {
+                    Token::GreaterEquals
+                } else {
+                    Token::Greater
+                }
+            }
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            ',' => Token::Comma,
+            '+' => Token::Add,
+            '-' => Token::Minus,
+            '*' => Token::Multiply,
             '/' => {
-                if chars.next_if(|c| *c == '/').is_some() {
-                    while chars.next_if(|c| *c != '\n').is_some() {}
-                    tokens.push(Token::Comment)
+                if chars.next_if(|(_, c)| *c == '/').is_some() {
+                    if chars.next_if(|(_, c)| *c == '/').is_some() {
+                        let mut text = String::new();
+                        while let Some((_, c)) = chars.next_if(|(_, c)| *c != '\n') {
+                            text.push(c);
+                        }
+                        let text = text.strip_prefix(' ').unwrap_or(&text).to_string();
+                        Token::DocComment(text)
+                    } else {
+                        while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                        Token::Comment
+                    }
+                } else if chars.next_if(|(_, c)| *c == '*').is_some() {
+                    let mut last = ' ';
+                    loop {
+                        match chars.next() {
+                            Some((_, '/')) if last == '*' => break,
+                            Some((_, c)) => last = c,
+                            None => return error(Error::UnterminatedComment, start, &mut chars),
+                        }
+                    }
+                    Token::Comment
                 } else {
-                    tokens.push(Token::Divide)
+                    Token::Divide
                 }
             }
-            '%' => tokens.push(Token::Mod),
+            '%' => Token::Mod,
+            '#' => Token::Hash,
+            '?' => Token::Question,
+            '|' => {
+                if chars.next_if(|(_, c)| *c == '|').is_some() {
+                    Token::OrOr
+                } else if chars.next_if(|(_, c)| *c == '>').is_some() {
+                    Token::Pipe
+                } else {
+                    return error(Error::UnexpectedCharacter('|'), start, &mut chars);
+                }
+            }
+            '&' => {
+                if chars.next_if(|(_, c)| *c == '&').is_none() {
+                    return error(Error::UnexpectedCharacter('&'), start, &mut chars);
+                }
+                Token::AndAnd
+            }
             '!' => {
-                if chars.next_if(|c| *c == '=').is_some() {
-                    tokens.push(Token::BangEquals)
+                if chars.next_if(|(_, c)| *c == '=').is_some() {
+                    Token::BangEquals
                 } else {
-                    tokens.push(Token::Bang)
+                    Token::Bang
                 }
             }
             '"' => {
                 let mut string = String::new();
                 loop {
-                    match chars.next() {
-                        Some('\\') => match chars.next() {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('\\') => match chars.next().map(|(_, c)| c) {
                             Some('\\') => string.push('\\'),
                             Some('n') => string.push('\n'),
                             Some('"') => string.push('"'),
-                            Some(c) => panic!("Unknown escape char {c:?}"),
-                            None => panic!("Reached eof while parsing string"),
+                            Some(c) => return error(Error::UnknownEscape(c), start, &mut chars),
+                            None => return error(Error::UnterminatedString, start, &mut chars),
                         },
                         Some('"') => break,
                         Some(c) => string.push(c),
-                        None => panic!("Reached eof while parsing string"),
+                        None => return error(Error::UnterminatedString, start, &mut chars),
                     }
                 }
-                tokens.push(Token::StringLiteral(string))
+                Token::StringLiteral(string)
             }
             '0'..='9' => {
-                let mut n = c as u64 as u32 - 48;
-                while let Some(d) = chars.next_if(|n| matches!(n, '0'..='9')) {
-                    n *= 10;
-                    n += d as u64 as u32 - 48;
+                let hex = c == '0' && chars.next_if(|(_, c)| *c == 'x').is_some();
+                let radix = if hex { 16 } else { 10 };
+                let mut digits = if hex { String::new() } else { c.to_string() };
+                while let Some((_, d)) = chars.next_if(|(_, d)| d.is_digit(radix)) {
+                    digits.push(d);
+                }
+                let mut suffix = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    suffix.push(c);
+                }
+                // `0x` on its own is treated as the number 0 with the suffix `x`.
+                if digits.is_empty() {
+                    return error(
+                        Error::UnknownSuffix(format!("x{suffix}")),
+                        start,
+                        &mut chars,
+                    );
+                }
+                let Ok(n) = u32::from_str_radix(&digits, radix) else {
+                    return error(Error::NumberTooLarge, start, &mut chars);
+                };
+                match suffix.as_str() {
+                    "" => Token::Number(n),
+                    "u8" => Token::SuffixedNumber(n, Type::U8),
+                    "u16" => Token::SuffixedNumber(n, Type::U16),
+                    "u32" => Token::SuffixedNumber(n, Type::U32),
+                    _ => return error(Error::UnknownSuffix(suffix), start, &mut chars),
                 }
-                tokens.push(Token::Number(n))
             }
             'a'..='z' | 'A'..='Z' | '_' => {
                 let mut ident = String::new();
                 ident.push(c);
-                while let Some(c) =
-                    chars.next_if(|c| matches!(c, '0'..='9'|'a'..='z'|'A'..='Z'|'_'))
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| matches!(c, '0'..='9'|'a'..='z'|'A'..='Z'|'_'))
                 {
                     ident.push(c);
                 }
-                tokens.push(match ident.as_str() {
-                    "fn" => Token::KwFn,
-                    "let" => Token::KwLet,
-                    "return" => Token::KwReturn,
-                    _ => Token::Identifier(ident),
-                })
+                match KEYWORDS.iter().find(|(word, _)| *word == ident) {
+                    Some((_, keyword)) => keyword.clone(),
+                    None => Token::Identifier(ident),
+                }
             }
-            ' ' | '\t' | '\n' | '\r' => (),
-            c => panic!("Unknown start of token {c:?}"),
-        }
+            ' ' | '\t' | '\n' | '\r' => continue,
+            c => return error(Error::UnexpectedCharacter(c), start, &mut chars),
+        };
+        let end = chars.peek().map_or(input.len(), |(i, _)| *i);
+        tokens.push((token, Span { start, end }));
     }
-    tokens
+    Ok(tokens)
 }
 
 #[derive(Debug)]
 pub enum Error {
     UnexpectedToken(Token),
     UnexpectedEof,
+    NestingTooDeep,
+    /// A keyword was used where a name was expected, e.g. `let fn = 1;`.
+    KeywordAsIdentifier(String),
+    UnexpectedCharacter(char),
+    UnterminatedComment,
+    UnterminatedString,
+    UnknownEscape(char),
+    /// A number literal that doesn't fit in a `u32`.
+    NumberTooLarge,
+    /// A number followed by letters other than a type, like `5abc`.
+    UnknownSuffix(String),
+    /// The input ended before the `{` at `opened_at` was closed.
+    UnbalancedBrace {
+        opened_at: Span,
+    },
+    /// `let a, b = ...;` with a different number of values than names.
+    BindingCountMismatch {
+        names: usize,
+        values: usize,
+    },
+    /// `let a, b = 1, 2;` where only a single statement is allowed.
+    MultipleBindings,
+    /// `type A = A;`, an alias defined in terms of itself.
+    CyclicTypeAlias(String),
+    /// `#[name]` where `name` isn't `wrapping` or `checked`.
+    UnknownAttribute(String),
+    /// An attribute before something other than a `fn`.
+    MisplacedAttribute,
+}
+
+/// How deeply expressions and blocks may nest before parsing gives up, so that
+/// pathological input can't overflow the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// The token stream being parsed, along with how deeply nested the parser currently is.
+struct Tokens {
+    tokens: Peekable2<std::vec::IntoIter<(Token, Span)>>,
+    depth: usize,
+    max_depth: usize,
+    /// The most recently consumed token.
+    last: Span,
+    /// Where each `{` that hasn't been closed yet is, innermost last.
+    open_braces: Vec<Span>,
+    /// The types named by the `type` declarations parsed so far.
+    aliases: HashMap<String, Type>,
+    /// The errors recovered from so far, if parsing carries on after errors.
+    errors: Option<Vec<Spanned<Error>>>,
+}
+
+/// Where parsing can carry on from after an error inside a block.
+#[derive(Clone, Copy)]
+struct RecoveryPoint {
+    depth: usize,
+    open_braces: usize,
+}
+
+impl Tokens {
+    fn new(tokens: Vec<(Token, Span)>, max_depth: usize) -> Self {
+        Self {
+            tokens: Peekable2::new(tokens.into_iter()),
+            depth: 0,
+            max_depth,
+            last: Span::default(),
+            open_braces: Vec::new(),
+            aliases: HashMap::new(),
+            errors: None,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let (token, span) = self.tokens.next()?;
+        self.consumed(&token, span);
+        Some(token)
+    }
+
+    fn first(&mut self) -> Option<&Token> {
+        self.tokens.first().as_ref().map(|(token, _)| token)
+    }
+
+    fn second(&mut self) -> Option<&Token> {
+        self.tokens.second().as_ref().map(|(token, _)| token)
+    }
+
+    fn next_if(&mut self, predicate: impl Fn(&Token) -> bool) -> Option<Token> {
+        let (token, span) = self.tokens.next_if(|(token, _)| predicate(token))?;
+        self.consumed(&token, span);
+        Some(token)
+    }
+
+    fn consumed(&mut self, token: &Token, span: Span) {
+        self.last = span;
+        match token {
+            Token::OpenBrace => self.open_braces.push(span),
+            Token::CloseBrace => {
+                self.open_braces.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Where the next token starts.
+    fn start(&mut self) -> usize {
+        let end = self.last.end;
+        self.tokens
+            .first()
+            .as_ref()
+            .map_or(end, |(_, span)| span.start)
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(Error::NestingTooDeep);
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Gives `error` the span of where it was found: the token just consumed, or
+    /// after the last one at the end of the input.
+    fn spanned(&self, error: Error) -> Spanned<Error> {
+        // Running out of input inside a block most likely means a `}` is missing.
+        let error = match (error, self.open_braces.last()) {
+            (Error::UnexpectedEof, Some(&opened_at)) => Error::UnbalancedBrace { opened_at },
+            (error, _) => error,
+        };
+        let span = match error {
+            Error::UnexpectedEof | Error::UnbalancedBrace { .. } => Span {
+                start: self.last.end,
+                end: self.last.end,
+            },
+            _ => self.last,
+        };
+        Spanned { node: error, span }
+    }
+
+    /// Where to carry on from after an error in the statements of the block being
+    /// parsed.
+    fn recovery_point(&self) -> RecoveryPoint {
+        RecoveryPoint {
+            depth: self.depth,
+            open_braces: self.open_braces.len(),
+        }
+    }
+
+    /// When recovering from errors, records `error` and skips past the next `;` of
+    /// the block that `point` is in, or up to its `}`, so the next statement can be
+    /// parsed. Returns whether the error already consumed the `}`, ending the block.
+    /// Otherwise, or if there is nothing left to parse, gives `error` back.
+    fn recover(&mut self, error: Error, point: RecoveryPoint) -> Result<bool, Error> {
+        if self.errors.is_none() || matches!(error, Error::UnexpectedEof | Error::NestingTooDeep) {
+            return Err(error);
+        }
+        let error = self.spanned(error);
+        self.errors.get_or_insert_default().push(error);
+        self.depth = point.depth;
+        while self.open_braces.len() >= point.open_braces {
+            let in_block = self.open_braces.len() == point.open_braces;
+            match self.first() {
+                None => break,
+                Some(Token::CloseBrace) if in_block => break,
+                Some(Token::Semicolon) if in_block => {
+                    self.next();
+                    break;
+                }
+                Some(_) => {
+                    self.next();
+                }
+            }
+        }
+        Ok(self.open_braces.len() < point.open_braces)
+    }
+
+    /// When recovering from errors, records `error` and skips to the next `fn`,
+    /// `extern` or `type`, where the next declaration starts. Otherwise gives
+    /// `error` back.
+    fn recover_declaration(&mut self, error: Error) -> Result<(), Error> {
+        if self.errors.is_none() {
+            return Err(error);
+        }
+        let error = self.spanned(error);
+        self.errors.get_or_insert_default().push(error);
+        while self
+            .next_if(|t| {
+                !matches!(
+                    t,
+                    Token::KwFn | Token::KwExtern | Token::KwType | Token::Hash
+                )
+            })
+            .is_some()
+        {}
+        self.depth = 0;
+        self.open_braces.clear();
+        Ok(())
+    }
+}
+
+fn parse_identifier(tokens: &mut Tokens) -> Result<String, Error> {
+    match tokens.next() {
+        Some(Token::Identifier(name)) => Ok(name),
+        Some(t) => Err(expected_identifier(t)),
+        None => Err(Error::UnexpectedEof),
+    }
 }
 
-fn parse_type<T: Iterator<Item = Token>>(tokens: &mut Peekable2<T>) -> Result<Type, Error> {
+/// The error for finding `token` where a name was expected.
+fn expected_identifier(token: Token) -> Error {
+    match keyword(&token) {
+        Some(word) => Error::KeywordAsIdentifier(word.to_string()),
+        None => Error::UnexpectedToken(token),
+    }
+}
+
+/// Types nest, so they count towards the same depth limit as expressions.
+fn parse_type(tokens: &mut Tokens) -> Result<Type, Error> {
+    tokens.enter()?;
+    let typ = parse_type_contents(tokens)?;
+    tokens.leave();
+    Ok(typ)
+}
+
+fn parse_type_contents(tokens: &mut Tokens) -> Result<Type, Error> {
     match tokens.next().ok_or(Error::UnexpectedEof)? {
         Token::Identifier(s) if s == "u32" => Ok(Type::U32),
+        Token::Identifier(s) if s == "i32" => Ok(Type::I32),
+        Token::Identifier(s) if s == "u8" => Ok(Type::U8),
+        Token::Identifier(s) if s == "u16" => Ok(Type::U16),
+        Token::Identifier(s) if s == "bool" => Ok(Type::Bool),
+        Token::Identifier(s) if tokens.aliases.contains_key(&s) => {
+            let aliased = tokens.aliases[&s].clone();
+            Ok(Type::Alias(s.into(), Box::new(aliased)))
+        }
+        // `()` is the unit type and `(t)` is just `t`, which helps when nesting
+        // function types. Anything longer is a tuple.
+        Token::OpenParen => {
+            let mut types = parse_type_list(tokens)?;
+            match types.len() {
+                0 => Ok(Type::Unit),
+                1 => Ok(types.remove(0)),
+                _ => Ok(Type::Tuple(types)),
+            }
+        }
+        // `fn(arguments): result`, written like a signature without names.
+        Token::KwFn => {
+            match tokens.next() {
+                Some(Token::OpenParen) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+            let arguments = parse_type_list(tokens)?;
+            let result = if tokens.next_if(|t| matches!(t, Token::Colon)).is_some() {
+                parse_type(tokens)?
+            } else {
+                Type::Unit
+            };
+            Ok(Type::Fun(arguments, Box::new(result)))
+        }
+        Token::OpenBracket => {
+            let element = parse_type(tokens)?;
+            match tokens.next() {
+                Some(Token::Semicolon) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+            let length = match tokens.next() {
+                Some(Token::Number(n)) => n,
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            };
+            match tokens.next() {
+                Some(Token::CloseBracket) => Ok(Type::Array(Box::new(element), length)),
+                Some(t) => Err(Error::UnexpectedToken(t)),
+                None => Err(Error::UnexpectedEof),
+            }
+        }
         t => Err(Error::UnexpectedToken(t)),
     }
 }
 
-fn parse_statement<T: Iterator<Item = Token>>(
-    tokens: &mut Peekable2<T>,
-) -> Result<Statement, Error> {
-    match tokens.first().as_ref().ok_or(Error::UnexpectedEof)? {
+/// Comma separated types up to and including a `)`, after the `(` has been taken.
+fn parse_type_list(tokens: &mut Tokens) -> Result<Vec<Type>, Error> {
+    let mut types = Vec::new();
+    while tokens.next_if(|t| matches!(t, Token::CloseParen)).is_none() {
+        types.push(parse_type(tokens)?);
+        match tokens.next() {
+            Some(Token::Comma) => {}
+            Some(Token::CloseParen) => break,
+            Some(t) => return Err(Error::UnexpectedToken(t)),
+            None => return Err(Error::UnexpectedEof),
+        }
+    }
+    Ok(types)
+}
+
+fn parse_statement(tokens: &mut Tokens) -> Result<Statement, Error> {
+    if at_assignment(tokens) {
+        return parse_assignment(tokens);
+    }
+    match tokens.first().ok_or(Error::UnexpectedEof)? {
         Token::KwLet => {
             tokens.next();
-            let name = match tokens.next() {
-                Some(Token::Identifier(name)) => name,
+            if tokens.next_if(|t| matches!(t, Token::OpenParen)).is_some() {
+                return parse_let_tuple(tokens);
+            }
+            let mut lets = parse_let(tokens)?;
+            if lets.len() > 1 {
+                return Err(Error::MultipleBindings);
+            }
+            Ok(lets.remove(0))
+        }
+        Token::KwConst => {
+            tokens.next();
+            parse_const(tokens)
+        }
+        Token::KwReturn => {
+            tokens.next();
+            let expression = parse_expression(tokens)?;
+
+            match tokens.next() {
+                Some(Token::Semicolon) => {}
                 Some(t) => {
                     return Err(Error::UnexpectedToken(t));
                 }
                 None => return Err(Error::UnexpectedEof),
+            }
+
+            Ok(Statement::Return(expression))
+        }
+        Token::KwIf => {
+            tokens.next();
+            let condition = parse_expression(tokens)?;
+            let then_body = parse_block(tokens)?;
+
+            let else_body = if tokens.next_if(|t| matches!(t, Token::KwElse)).is_some() {
+                if matches!(tokens.first(), Some(Token::KwIf)) {
+                    vec![parse_spanned_statement(tokens)?]
+                } else {
+                    parse_block(tokens)?
+                }
+            } else {
+                Vec::new()
             };
+
+            Ok(Statement::If(condition, then_body, else_body))
+        }
+        Token::KwWhile => {
+            tokens.next();
+            let condition = parse_expression(tokens)?;
+            let body = parse_block(tokens)?;
+
+            Ok(Statement::While(condition, body))
+        }
+        Token::KwBreak => {
+            tokens.next();
+            let value = if matches!(tokens.first(), Some(Token::Semicolon)) {
+                None
+            } else {
+                Some(parse_expression(tokens)?)
+            };
+
             match tokens.next() {
-                Some(Token::Colon) => {}
-                Some(t) => {
-                    return Err(Error::UnexpectedToken(t));
-                }
+                Some(Token::Semicolon) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
                 None => return Err(Error::UnexpectedEof),
             }
-            let value_type = parse_type(tokens)?;
+
+            Ok(Statement::Break(value))
+        }
+        _ => {
+            let expression = parse_expression(tokens)?;
+            if at_index_assignment(&expression, tokens) {
+                return parse_index_assignment(expression, tokens);
+            }
 
             match tokens.next() {
-                Some(Token::Equals) => {}
+                Some(Token::Semicolon) => {}
                 Some(t) => {
                     return Err(Error::UnexpectedToken(t));
                 }
                 None => return Err(Error::UnexpectedEof),
             }
 
-            let value = parse_expression(tokens)?;
+            Ok(Statement::Expr(expression))
+        }
+    }
+}
+
+/// Whether the next tokens are `name =`, starting an assignment.
+fn at_assignment(tokens: &mut Tokens) -> bool {
+    matches!(tokens.first(), Some(Token::Identifier(_)))
+        && matches!(tokens.second(), Some(Token::Equals))
+}
+
+fn parse_assignment(tokens: &mut Tokens) -> Result<Statement, Error> {
+    let name = parse_identifier(tokens)?;
+    tokens.next();
+    let value = parse_expression(tokens)?;
 
-            Ok(Statement::Let(name, Some(value_type), value))
+    match tokens.next() {
+        Some(Token::Semicolon) => {}
+        Some(t) => {
+            return Err(Error::UnexpectedToken(t));
         }
-        Token::KwReturn => {
-            tokens.next();
-            let expression = parse_expression(tokens)?;
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    Ok(Statement::Assign(name, value))
+}
+
+/// Whether `target`, just parsed, is the `array[index]` of `array[index] = value;`.
+fn at_index_assignment(target: &Expression, tokens: &mut Tokens) -> bool {
+    matches!(target, Expression::Index(..)) && matches!(tokens.first(), Some(Token::Equals))
+}
+
+/// Parses the rest of an index assignment after its `target`.
+fn parse_index_assignment(target: Expression, tokens: &mut Tokens) -> Result<Statement, Error> {
+    let Expression::Index(array, index) = target else {
+        unreachable!()
+    };
+    tokens.next();
+    let value = parse_expression(tokens)?;
+
+    match tokens.next() {
+        Some(Token::Semicolon) => {}
+        Some(t) => {
+            return Err(Error::UnexpectedToken(t));
+        }
+        None => return Err(Error::UnexpectedEof),
+    }
 
+    Ok(Statement::IndexAssign(*array, *index, value))
+}
+
+/// Parses the rest of `const N: u32 = 2 + 3;` after `const`. Unlike a `let`, the
+/// type is required.
+fn parse_const(tokens: &mut Tokens) -> Result<Statement, Error> {
+    let name = parse_identifier(tokens)?;
+    match tokens.next() {
+        Some(Token::Colon) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    let typ = parse_type(tokens)?;
+    match tokens.next() {
+        Some(Token::Equals) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    let value = parse_expression(tokens)?;
+    match tokens.next() {
+        Some(Token::Semicolon) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    Ok(Statement::Const { name, typ, value })
+}
+
+/// Parses the rest of `let a: u32, mut b = 1, 2;` after `let`, as a `let` for each
+/// name. They are made one after another, so later values can use earlier names.
+fn parse_let(tokens: &mut Tokens) -> Result<Vec<Statement>, Error> {
+    let mut bindings = Vec::new();
+    loop {
+        let mutable = tokens.next_if(|t| matches!(t, Token::KwMut)).is_some();
+        let name = parse_identifier(tokens)?;
+        let value_type = if tokens.next_if(|t| matches!(t, Token::Colon)).is_some() {
+            Some(parse_type(tokens)?)
+        } else {
+            None
+        };
+        bindings.push((name, mutable, value_type));
+        if tokens.next_if(|t| matches!(t, Token::Comma)).is_none() {
+            break;
+        }
+    }
+
+    match tokens.next() {
+        Some(Token::Equals) => {}
+        Some(t) => {
+            return Err(Error::UnexpectedToken(t));
+        }
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    let mut values = vec![parse_expression(tokens)?];
+    while tokens.next_if(|t| matches!(t, Token::Comma)).is_some() {
+        values.push(parse_expression(tokens)?);
+    }
+
+    match tokens.next() {
+        Some(Token::Semicolon) => {}
+        Some(t) => {
+            return Err(Error::UnexpectedToken(t));
+        }
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    if bindings.len() != values.len() {
+        return Err(Error::BindingCountMismatch {
+            names: bindings.len(),
+            values: values.len(),
+        });
+    }
+    Ok(bindings
+        .into_iter()
+        .zip(values)
+        .map(|((name, mutable, typ), value)| Statement::Let {
+            name,
+            mutable,
+            typ,
+            value,
+        })
+        .collect())
+}
+
+/// Parses a statement, which is several when it is a `let` declaring several
+/// variables. Each gets the span of the whole statement.
+fn parse_spanned_statements(tokens: &mut Tokens) -> Result<Vec<Spanned<Statement>>, Error> {
+    if !matches!(tokens.first(), Some(Token::KwLet))
+        || matches!(tokens.second(), Some(Token::OpenParen))
+    {
+        return Ok(vec![parse_spanned_statement(tokens)?]);
+    }
+    let start = tokens.start();
+    tokens.next();
+    let lets = parse_let(tokens)?;
+    let span = Span {
+        start,
+        end: tokens.last.end,
+    };
+    Ok(lets
+        .into_iter()
+        .map(|node| Spanned { node, span })
+        .collect())
+}
+
+fn parse_spanned_statement(tokens: &mut Tokens) -> Result<Spanned<Statement>, Error> {
+    let start = tokens.start();
+    let node = parse_statement(tokens)?;
+    Ok(Spanned {
+        node,
+        span: Span {
+            start,
+            end: tokens.last.end,
+        },
+    })
+}
+
+fn parse_block(tokens: &mut Tokens) -> Result<Vec<Spanned<Statement>>, Error> {
+    match tokens.next() {
+        Some(Token::OpenBrace) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    tokens.enter()?;
+    let mut body = Vec::new();
+    let point = tokens.recovery_point();
+
+    while tokens
+        .next_if(|token| matches!(token, Token::CloseBrace))
+        .is_none()
+    {
+        // A lone `;` is an empty statement.
+        if tokens.next_if(|t| matches!(t, Token::Semicolon)).is_none() {
+            match parse_spanned_statements(tokens) {
+                Ok(statements) => body.extend(statements),
+                Err(error) => {
+                    if tokens.recover(error, point)? {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    tokens.leave();
+
+    Ok(body)
+}
+
+/// Parses the rest of `let (a, b) = expression;` after the opening parenthesis.
+fn parse_let_tuple(tokens: &mut Tokens) -> Result<Statement, Error> {
+    let mut names = Vec::new();
+    while tokens.next_if(|t| matches!(t, Token::CloseParen)).is_none() {
+        names.push(parse_identifier(tokens)?);
+        if !(tokens.next_if(|t| matches!(t, Token::Comma)).is_some()
+            || matches!(tokens.first(), Some(Token::CloseParen)))
+        {
+            match tokens.next() {
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+        }
+    }
+
+    match tokens.next() {
+        Some(Token::Equals) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    let value = parse_expression(tokens)?;
+
+    match tokens.next() {
+        Some(Token::Semicolon) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    Ok(Statement::LetTuple(names, value))
+}
+
+/// Parses `{ statements; tail }`, a block whose value is the trailing expression.
+fn parse_block_expression(tokens: &mut Tokens) -> Result<Expression, Error> {
+    match tokens.next() {
+        Some(Token::OpenBrace) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    match parse_block_contents(tokens)? {
+        (body, Some(tail)) => Ok(Expression::Block(body, Box::new(tail.node))),
+        (_, None) => Err(Error::UnexpectedToken(Token::CloseBrace)),
+    }
+}
+
+/// A block that is a function's body. It may end in an expression, which is
+/// returned as if it were `return expression;`.
+fn parse_function_body(tokens: &mut Tokens) -> Result<Vec<Spanned<Statement>>, Error> {
+    match tokens.next() {
+        // `= value;` is short for `{ return value; }`.
+        Some(Token::Equals) => {
+            let start = tokens.start();
+            let value = parse_expression(tokens)?;
             match tokens.next() {
                 Some(Token::Semicolon) => {}
-                Some(t) => {
-                    return Err(Error::UnexpectedToken(t));
-                }
+                Some(t) => return Err(Error::UnexpectedToken(t)),
                 None => return Err(Error::UnexpectedEof),
             }
+            return Ok(vec![Spanned {
+                node: Statement::Return(value),
+                span: Span {
+                    start,
+                    end: tokens.last.end,
+                },
+            }]);
+        }
+        Some(Token::OpenBrace) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
 
-            Ok(Statement::Return(expression))
+    tokens.enter()?;
+    let (mut body, tail) = parse_block_contents(tokens)?;
+    tokens.leave();
+    if let Some(tail) = tail {
+        body.push(Spanned {
+            node: Statement::Return(tail.node),
+            span: tail.span,
+        });
+    }
+    Ok(body)
+}
+
+/// A block's statements, and the expression it ends in without a `;`, if any.
+type BlockContents = (Vec<Spanned<Statement>>, Option<Spanned<Expression>>);
+
+/// Parses the rest of a block after its `{`, up to and including the `}`.
+fn parse_block_contents(tokens: &mut Tokens) -> Result<BlockContents, Error> {
+    let mut body = Vec::new();
+    let point = tokens.recovery_point();
+
+    loop {
+        match parse_block_item(tokens, &mut body) {
+            Ok(Some(tail)) => return Ok((body, tail)),
+            Ok(None) => {}
+            Err(error) => {
+                if tokens.recover(error, point)? {
+                    return Ok((body, None));
+                }
+            }
+        }
+    }
+}
+
+/// Parses the next statement of a block into `body`. Once the block's `}` has
+/// been consumed, returns the expression it ended in, if any.
+fn parse_block_item(
+    tokens: &mut Tokens,
+    body: &mut Vec<Spanned<Statement>>,
+) -> Result<Option<Option<Spanned<Expression>>>, Error> {
+    if at_assignment(tokens) {
+        body.push(parse_spanned_statement(tokens)?);
+        return Ok(None);
+    }
+    match tokens.first().ok_or(Error::UnexpectedEof)? {
+        Token::Semicolon => {
+            tokens.next();
         }
+        Token::CloseBrace => {
+            tokens.next();
+            return Ok(Some(None));
+        }
+        Token::KwLet
+        | Token::KwConst
+        | Token::KwReturn
+        | Token::KwIf
+        | Token::KwWhile
+        | Token::KwBreak => body.extend(parse_spanned_statements(tokens)?),
         _ => {
+            let start = tokens.start();
             let expression = parse_expression(tokens)?;
+            if at_index_assignment(&expression, tokens) {
+                let node = parse_index_assignment(expression, tokens)?;
+                body.push(Spanned {
+                    node,
+                    span: Span {
+                        start,
+                        end: tokens.last.end,
+                    },
+                });
+                return Ok(None);
+            }
+            let span = Span {
+                start,
+                end: tokens.last.end,
+            };
+            match tokens.next() {
+                Some(Token::Semicolon) => body.push(Spanned {
+                    node: Statement::Expr(expression),
+                    span: Span {
+                        start,
+                        end: tokens.last.end,
+                    },
+                }),
+                Some(Token::CloseBrace) => {
+                    let tail = Spanned {
+                        node: expression,
+                        span,
+                    };
+                    return Ok(Some(Some(tail)));
+                }
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Parses the body of a `loop` expression after the `loop` keyword.
+fn parse_loop(tokens: &mut Tokens) -> Result<Expression, Error> {
+    Ok(Expression::Loop(parse_block(tokens)?))
+}
+
+/// Parses the rest of a `match` expression after the `match` keyword. Each arm is
+/// a number or `_` followed by `=>` and its value, and arms are separated by
+/// commas. Nothing may follow the `_` arm, as it would never be chosen.
+fn parse_match(tokens: &mut Tokens) -> Result<Expression, Error> {
+    let value = parse_expression(tokens)?;
+    match tokens.next() {
+        Some(Token::OpenBrace) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
 
+    let mut arms = Vec::new();
+    let mut wildcard = None;
+    while tokens.next_if(|t| matches!(t, Token::CloseBrace)).is_none() {
+        let pattern = match tokens.next() {
+            Some(Token::Number(n)) if wildcard.is_none() => Some(n),
+            Some(Token::Identifier(name)) if name == "_" && wildcard.is_none() => None,
+            Some(t) => return Err(Error::UnexpectedToken(t)),
+            None => return Err(Error::UnexpectedEof),
+        };
+        match tokens.next() {
+            Some(Token::FatArrow) => {}
+            Some(t) => return Err(Error::UnexpectedToken(t)),
+            None => return Err(Error::UnexpectedEof),
+        }
+        let arm = parse_expression(tokens)?;
+        match pattern {
+            Some(n) => arms.push((n, arm)),
+            None => wildcard = Some(Box::new(arm)),
+        }
+        if tokens.next_if(|t| matches!(t, Token::Comma)).is_none() {
             match tokens.next() {
-                Some(Token::Semicolon) => {}
-                Some(t) => {
-                    return Err(Error::UnexpectedToken(t));
-                }
+                Some(Token::CloseBrace) => break,
+                Some(t) => return Err(Error::UnexpectedToken(t)),
                 None => return Err(Error::UnexpectedEof),
             }
-
-            Ok(Statement::Expr(expression))
         }
     }
+    Ok(Expression::Match(Box::new(value), arms, wildcard))
+}
+
+/// Parses the rest of an `if` expression after the `if` keyword. Unlike the `if`
+/// statement, the `else` branch is required as both branches produce the value.
+fn parse_if_expression(tokens: &mut Tokens) -> Result<Expression, Error> {
+    let condition = parse_expression(tokens)?;
+    let then_branch = parse_block_expression(tokens)?;
+
+    match tokens.next() {
+        Some(Token::KwElse) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+
+    let else_branch = if tokens.next_if(|t| matches!(t, Token::KwIf)).is_some() {
+        parse_if_expression(tokens)?
+    } else {
+        parse_block_expression(tokens)?
+    };
+
+    Ok(Expression::If(
+        Box::new(condition),
+        Box::new(then_branch),
+        Box::new(else_branch),
+    ))
+}
+
+fn parse_expression(tokens: &mut Tokens) -> Result<Expression, Error> {
+    tokens.enter()?;
+    let mut expression = parse_comparison(tokens)?;
+    if matches!(
+        tokens.first(),
+        Some(Token::AndAnd | Token::OrOr | Token::Question | Token::Pipe)
+    ) {
+        expression = parse_pipes(expression, tokens)?;
+    }
+    tokens.leave();
+    Ok(expression)
+}
+
+/// Parses the `&& b`s and `|| b`s following `expression`. `&&` binds tighter than
+/// `||`, and both bind looser than comparisons but tighter than `?`, so
+/// `a < b || c && d` is `(a < b) || (c && d)`. Only called when there is one, like
+/// `parse_pipes`.
+fn parse_logical(expression: Expression, tokens: &mut Tokens) -> Result<Expression, Error> {
+    let mut expression = parse_and(expression, tokens)?;
+    while tokens.next_if(|t| matches!(t, Token::OrOr)).is_some() {
+        let other = parse_comparison(tokens)?;
+        let other = parse_and(other, tokens)?;
+        expression = Expression::Or(Box::new(expression), Box::new(other));
+    }
+    Ok(expression)
+}
+
+/// Parses the `&& b`s following `expression`.
+fn parse_and(mut expression: Expression, tokens: &mut Tokens) -> Result<Expression, Error> {
+    while tokens.next_if(|t| matches!(t, Token::AndAnd)).is_some() {
+        let other = parse_comparison(tokens)?;
+        expression = Expression::And(Box::new(expression), Box::new(other));
+    }
+    Ok(expression)
+}
+
+/// Parses the `|> f`s following `expression`, as calls: `x |> f |> g` is `g(f(x))`.
+/// Pipes bind the loosest of all, so a conditional before them is parsed first.
+/// Like `parse_indexes`, this is only called when there is a pipe, a `?`, a `&&` or
+/// a `||` to keep the stack used per level of nesting down.
+fn parse_pipes(mut expression: Expression, tokens: &mut Tokens) -> Result<Expression, Error> {
+    if matches!(tokens.first(), Some(Token::AndAnd | Token::OrOr)) {
+        expression = parse_logical(expression, tokens)?;
+    }
+    if matches!(tokens.first(), Some(Token::Question)) {
+        expression = parse_conditional(expression, tokens)?;
+    }
+    while tokens.next_if(|t| matches!(t, Token::Pipe)).is_some() {
+        let function = parse_identifier(tokens)?;
+        expression = Expression::Call(function, vec![expression]);
+    }
+    Ok(expression)
+}
+
+/// Parses the `? a : b` following `condition` into the `if` expression it stands
+/// for. It binds looser than `||` but tighter than pipes, and nests to the right:
+/// `a ? b : c ? d : e` is `a ? b : (c ? d : e)`.
+fn parse_conditional(condition: Expression, tokens: &mut Tokens) -> Result<Expression, Error> {
+    tokens.enter()?;
+    tokens.next();
+    let then_branch = parse_expression(tokens)?;
+    match tokens.next() {
+        Some(Token::Colon) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    let mut else_branch = parse_comparison(tokens)?;
+    if matches!(tokens.first(), Some(Token::AndAnd | Token::OrOr)) {
+        else_branch = parse_logical(else_branch, tokens)?;
+    }
+    if matches!(tokens.first(), Some(Token::Question)) {
+        else_branch = parse_conditional(else_branch, tokens)?;
+    }
+    tokens.leave();
+    // Each branch is a block, as if it had been written `if c { a } else { b }`.
+    Ok(Expression::If(
+        Box::new(condition),
+        Box::new(Expression::Block(Vec::new(), Box::new(then_branch))),
+        Box::new(Expression::Block(Vec::new(), Box::new(else_branch))),
+    ))
 }
 
-fn parse_expression<T: Iterator<Item = Token>>(
-    tokens: &mut Peekable2<T>,
-) -> Result<Expression, Error> {
-    parse_additive(tokens)
+/// Comparisons don't chain, so `a < b < c` is an error.
+fn parse_comparison(tokens: &mut Tokens) -> Result<Expression, Error> {
+    let expression = parse_additive(tokens)?;
+    let comparison = match tokens.first() {
+        Some(Token::EqualEquals) => Comparison::Equal,
+        Some(Token::BangEquals) => Comparison::NotEqual,
+        Some(Token::Less) => Comparison::Less,
+        Some(Token::LessEquals) => Comparison::LessEqual,
+        Some(Token::Greater) => Comparison::Greater,
+        Some(Token::GreaterEquals) => Comparison::GreaterEqual,
+        _ => return Ok(expression),
+    };
+    tokens.next();
+    let other = parse_additive(tokens)?;
+    Ok(Expression::Compare(
+        comparison,
+        Box::new(expression),
+        Box::new(other),
+    ))
 }
 
-fn parse_additive<T: Iterator<Item = Token>>(
-    tokens: &mut Peekable2<T>,
-) -> Result<Expression, Error> {
+fn parse_additive(tokens: &mut Tokens) -> Result<Expression, Error> {
     let mut expression = parse_multiplicative(tokens)?;
     while let Some(token) = tokens.next_if(|t| matches!(t, Token::Add | Token::Minus)) {
         let next_expression = parse_multiplicative(tokens)?;
@@ -209,9 +1211,7 @@ fn parse_additive<T: Iterator<Item = Token>>(
     Ok(expression)
 }
 
-fn parse_multiplicative<T: Iterator<Item = Token>>(
-    tokens: &mut Peekable2<T>,
-) -> Result<Expression, Error> {
+fn parse_multiplicative(tokens: &mut Tokens) -> Result<Expression, Error> {
     let mut expression = parse_unary(tokens)?;
     while let Some(token) =
         tokens.next_if(|t| matches!(t, Token::Multiply | Token::Divide | Token::Mod))
@@ -227,15 +1227,95 @@ fn parse_multiplicative<T: Iterator<Item = Token>>(
     Ok(expression)
 }
 
-fn parse_unary<T: Iterator<Item = Token>>(tokens: &mut Peekable2<T>) -> Result<Expression, Error> {
-    parse_primary(tokens)
+fn parse_unary(tokens: &mut Tokens) -> Result<Expression, Error> {
+    if matches!(tokens.first(), Some(Token::Minus | Token::Bang)) {
+        return parse_prefix(tokens);
+    }
+    let expression = parse_primary(tokens)?;
+    if matches!(tokens.first(), Some(Token::OpenBracket)) {
+        return parse_indexes(expression, tokens);
+    }
+    Ok(expression)
+}
+
+/// Parses a `-` or `!` and the unary expression it applies to, so prefixes bind
+/// tighter than any binary operator: `-a * b` is `(-a) * b`. A `-` directly before a
+/// number is a negative literal instead.
+fn parse_prefix(tokens: &mut Tokens) -> Result<Expression, Error> {
+    let negate = matches!(tokens.next(), Some(Token::Minus));
+    if negate && let Some(Token::Number(n)) = tokens.next_if(|t| matches!(t, Token::Number(_))) {
+        return Ok(Expression::NegativeLiteral(n));
+    }
+    tokens.enter()?;
+    let operand = Box::new(parse_unary(tokens)?);
+    tokens.leave();
+    Ok(if negate {
+        Expression::Negate(operand)
+    } else {
+        Expression::Not(operand)
+    })
+}
+
+/// Parses the `[index]`es following `expression`. Kept out of `parse_unary` so that
+/// its locals don't take up stack in every level of nested parentheses.
+fn parse_indexes(mut expression: Expression, tokens: &mut Tokens) -> Result<Expression, Error> {
+    while tokens
+        .next_if(|t| matches!(t, Token::OpenBracket))
+        .is_some()
+    {
+        let index = parse_expression(tokens)?;
+        match tokens.next() {
+            Some(Token::CloseBracket) => {}
+            Some(t) => return Err(Error::UnexpectedToken(t)),
+            None => return Err(Error::UnexpectedEof),
+        }
+        expression = Expression::Index(Box::new(expression), Box::new(index));
+    }
+    Ok(expression)
+}
+
+/// Parses the rest of `sizeof(type)` after the keyword, which is replaced by the
+/// number it stands for. Aliases are known by now, so their sizes are too.
+fn parse_sizeof(tokens: &mut Tokens) -> Result<Expression, Error> {
+    match tokens.next() {
+        Some(Token::OpenParen) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    let typ = parse_type(tokens)?;
+    match tokens.next() {
+        Some(Token::CloseParen) => Ok(Expression::NumLiteral(compiler::sizeof_value(&typ))),
+        Some(t) => Err(Error::UnexpectedToken(t)),
+        None => Err(Error::UnexpectedEof),
+    }
+}
+
+fn parse_string_literal(tokens: &mut Tokens) -> Result<Expression, Error> {
+    match tokens.next() {
+        Some(Token::StringLiteral(text)) => Ok(Expression::StrLiteral(text)),
+        Some(t) => Err(Error::UnexpectedToken(t)),
+        None => Err(Error::UnexpectedEof),
+    }
 }
 
-fn parse_primary<T: Iterator<Item = Token>>(
-    tokens: &mut Peekable2<T>,
-) -> Result<Expression, Error> {
+fn parse_primary(tokens: &mut Tokens) -> Result<Expression, Error> {
+    if matches!(tokens.first(), Some(Token::OpenBrace)) {
+        return parse_block_expression(tokens);
+    }
+    // Kept out of the `match` below, which every level of nesting goes through, as
+    // its `String` makes each of those frames bigger.
+    if matches!(tokens.first(), Some(Token::StringLiteral(_))) {
+        return parse_string_literal(tokens);
+    }
     match tokens.next() {
-        Some(Token::Number(n)) => return Ok(Expression::NumLiteral(n)),
+        Some(Token::Number(n)) => Ok(Expression::NumLiteral(n)),
+        Some(Token::SuffixedNumber(n, typ)) => Ok(Expression::SuffixedLiteral(n, typ)),
+        Some(Token::KwTrue) => Ok(Expression::BoolLiteral(true)),
+        Some(Token::KwFalse) => Ok(Expression::BoolLiteral(false)),
+        Some(Token::KwIf) => parse_if_expression(tokens),
+        Some(Token::KwLoop) => parse_loop(tokens),
+        Some(Token::KwSizeof) => parse_sizeof(tokens),
+        Some(Token::KwMatch) => parse_match(tokens),
         Some(Token::Identifier(name)) => {
             if tokens.next_if(|t| matches!(t, Token::OpenParen)).is_some() {
                 let mut args = Vec::new();
@@ -257,6 +1337,22 @@ fn parse_primary<T: Iterator<Item = Token>>(
                 Ok(Expression::Variable(name))
             }
         }
+        // Array literals need at least one element to infer their type from.
+        Some(Token::OpenBracket) => {
+            let mut elements = vec![parse_expression(tokens)?];
+            while tokens
+                .next_if(|t| matches!(t, Token::CloseBracket))
+                .is_none()
+            {
+                match tokens.next() {
+                    Some(Token::Comma) => {}
+                    Some(t) => return Err(Error::UnexpectedToken(t)),
+                    None => return Err(Error::UnexpectedEof),
+                }
+                elements.push(parse_expression(tokens)?);
+            }
+            Ok(Expression::ArrayLiteral(elements))
+        }
         Some(Token::OpenParen) => {
             let expression = parse_expression(tokens)?;
             match tokens.next() {
@@ -265,129 +1361,346 @@ fn parse_primary<T: Iterator<Item = Token>>(
                 None => Err(Error::UnexpectedEof),
             }
         }
-        Some(t) => return Err(Error::UnexpectedToken(t)),
-        None => return Err(Error::UnexpectedEof),
+        Some(t) => Err(Error::UnexpectedToken(t)),
+        None => Err(Error::UnexpectedEof),
     }
 }
 
-pub fn parse(input: &str) -> Result<Vec<Declaration>, Error> {
-    let tokens = get_tokens(input);
+/// A function's name, arguments and return type.
+type Signature = (String, Vec<(String, Type)>, Type);
 
-    let mut iter = Peekable2::new(tokens.into_iter());
+/// Parses `name(arguments): return_type`, the part of a function declaration
+/// after `fn`. Without `: return_type`, the function returns `()`.
+fn parse_signature(tokens: &mut Tokens) -> Result<Signature, Error> {
+    let name = parse_identifier(tokens)?;
 
-    let mut result = Vec::new();
+    match tokens.next() {
+        Some(Token::OpenParen) => {}
+        Some(t) => {
+            return Err(Error::UnexpectedToken(t));
+        }
+        None => return Err(Error::UnexpectedEof),
+    }
 
-    while let Some(token) = iter.next() {
-        match token {
-            Token::KwFn => {
-                let name = match iter.next() {
-                    Some(Token::Identifier(name)) => name,
-                    Some(t) => {
-                        return Err(Error::UnexpectedToken(t));
-                    }
-                    None => return Err(Error::UnexpectedEof),
-                };
+    let mut arguments = Vec::new();
 
-                match iter.next() {
-                    Some(Token::OpenParen) => {}
+    while let Some(token) = tokens.next()
+        && !matches!(token, Token::CloseParen)
+    {
+        match token {
+            Token::Identifier(arg_name) => {
+                match tokens.next() {
+                    Some(Token::Colon) => {}
                     Some(t) => {
                         return Err(Error::UnexpectedToken(t));
                     }
                     None => return Err(Error::UnexpectedEof),
                 }
 
-                let mut arguments = Vec::new();
+                let arg_type = parse_type(tokens)?;
 
-                while let Some(token) = iter.next()
-                    && !matches!(token, Token::CloseParen)
+                if !(tokens
+                    .next_if(|token| matches!(token, Token::Comma))
+                    .is_some()
+                    || tokens
+                        .first()
+                        .as_ref()
+                        .is_some_and(|v| matches!(v, Token::CloseParen)))
                 {
-                    match token {
-                        Token::Identifier(arg_name) => {
-                            match iter.next() {
-                                Some(Token::Colon) => {}
-                                Some(t) => {
-                                    return Err(Error::UnexpectedToken(t));
-                                }
-                                None => return Err(Error::UnexpectedEof),
-                            }
-
-                            let arg_type = parse_type(&mut iter)?;
-
-                            if !(iter
-                                .next_if(|token| matches!(token, Token::Comma))
-                                .is_some()
-                                || iter
-                                    .first()
-                                    .as_ref()
-                                    .is_some_and(|v| matches!(v, Token::CloseParen)))
-                            {
-                                match iter.next() {
-                                    Some(t) => {
-                                        return Err(Error::UnexpectedToken(t));
-                                    }
-                                    None => return Err(Error::UnexpectedEof),
-                                }
-                            }
-                            arguments.push((arg_name, arg_type))
+                    match tokens.next() {
+                        Some(t) => {
+                            return Err(Error::UnexpectedToken(t));
                         }
-                        t => return Err(Error::UnexpectedToken(t)),
+                        None => return Err(Error::UnexpectedEof),
                     }
                 }
+                arguments.push((arg_name, arg_type))
+            }
+            t => return Err(expected_identifier(t)),
+        }
+    }
 
-                match iter.next() {
-                    Some(Token::Colon) => {}
-                    Some(t) => {
-                        return Err(Error::UnexpectedToken(t));
-                    }
-                    None => return Err(Error::UnexpectedEof),
-                }
+    let return_type = if tokens.next_if(|t| matches!(t, Token::Colon)).is_some() {
+        parse_type(tokens)?
+    } else {
+        Type::Unit
+    };
 
-                let return_type = parse_type(&mut iter)?;
+    Ok((name, arguments, return_type))
+}
 
-                match iter.next() {
-                    Some(Token::OpenBrace) => {}
-                    Some(t) => {
-                        return Err(Error::UnexpectedToken(t));
-                    }
-                    None => return Err(Error::UnexpectedEof),
-                }
+/// Every comment in `input`, including doc comments, with its text as written.
+pub fn comments(input: &str) -> Result<Vec<Spanned<String>>, Spanned<Error>> {
+    Ok(get_tokens(input)?
+        .into_iter()
+        .filter(|(token, _)| matches!(token, Token::Comment | Token::DocComment(_)))
+        .map(|(_, span)| Spanned {
+            node: input[span.start..span.end].to_string(),
+            span,
+        })
+        .collect())
+}
 
-                let mut body = Vec::new();
+pub fn parse(input: &str) -> Result<Vec<Declaration>, Spanned<Error>> {
+    parse_tokens(
+        get_tokens(input)?,
+        false,
+        DEFAULT_MAX_DEPTH,
+        parse_declarations,
+    )
+}
 
-                while iter
-                    .next_if(|token| matches!(token, Token::CloseBrace))
-                    .is_none()
-                {
-                    body.push(parse_statement(&mut iter)?);
-                    match iter.next_if(|t| !matches!(t, Token::CloseBrace)) {
-                        Some(Token::Semicolon) => {}
-                        Some(t) => {
-                            return Err(Error::UnexpectedToken(t));
-                        }
-                        None => {
-                            if iter.first().is_none() {
-                                return Err(Error::UnexpectedEof);
-                            }
-                        }
-                    }
-                }
+/// Like `parse`, but carries on after syntax errors, returning every declaration
+/// that parsed along with every error. A statement with an error is skipped up to
+/// its `;`, or the end of its block, and the statements after it are still
+/// parsed. An error outside of a function body skips to the next `fn`, `extern` or
+/// `type`. Errors found while splitting the input into tokens still stop parsing.
+pub fn parse_recovering(input: &str) -> (Vec<Declaration>, Vec<Spanned<Error>>) {
+    let tokens = match get_tokens(input) {
+        Ok(tokens) => tokens,
+        Err(error) => return (Vec::new(), vec![error]),
+    };
+    let parsed = parse_tokens(tokens, false, DEFAULT_MAX_DEPTH, |tokens| {
+        tokens.errors = Some(Vec::new());
+        let declarations = parse_declarations(tokens)?;
+        Ok((declarations, tokens.errors.take().unwrap_or_default()))
+    });
+    match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => (Vec::new(), vec![error]),
+    }
+}
 
-                result.push(Declaration::Function {
-                    name,
-                    arguments,
-                    return_type,
-                    body,
-                })
-            }
-            t => {
-                return Err(Error::UnexpectedToken(t));
+/// Like `parse`, but keeps `///` comments and attaches them to the declaration
+/// that follows them.
+pub fn parse_with_docs(input: &str) -> Result<Vec<Declaration>, Spanned<Error>> {
+    parse_tokens(
+        get_tokens(input)?,
+        true,
+        DEFAULT_MAX_DEPTH,
+        parse_declarations,
+    )
+}
+
+/// Like `parse`, but fails with `Error::NestingTooDeep` once expressions or blocks
+/// nest deeper than `max_depth`.
+pub fn parse_with_max_depth(
+    input: &str,
+    max_depth: usize,
+) -> Result<Vec<Declaration>, Spanned<Error>> {
+    parse_tokens(get_tokens(input)?, false, max_depth, parse_declarations)
+}
+
+/// A single statement on its own, such as a line typed into the REPL.
+pub fn parse_single_statement(input: &str) -> Result<Spanned<Statement>, Spanned<Error>> {
+    parse_tokens(get_tokens(input)?, false, DEFAULT_MAX_DEPTH, |tokens| {
+        let statement = parse_spanned_statement(tokens)?;
+        match tokens.next() {
+            Some(token) => Err(Error::UnexpectedToken(token)),
+            None => Ok(statement),
+        }
+    })
+}
+
+/// A program made of a single expression, such as `2 + 3 * 4`. It becomes a `main`
+/// returning the expression's value, which must be a `u32`.
+pub fn parse_expression_program(input: &str) -> Result<Vec<Declaration>, Spanned<Error>> {
+    parse_tokens(get_tokens(input)?, false, DEFAULT_MAX_DEPTH, |tokens| {
+        let start = tokens.start();
+        let expression = parse_expression(tokens)?;
+        if let Some(token) = tokens.next() {
+            return Err(Error::UnexpectedToken(token));
+        }
+        let span = Span {
+            start,
+            end: tokens.last.end,
+        };
+        Ok(vec![Declaration::Function {
+            name: "main".to_string(),
+            doc: None,
+            arithmetic: Arithmetic::default(),
+            arguments: Vec::new(),
+            return_type: Type::U32,
+            body: vec![Spanned {
+                node: Statement::Return(expression),
+                span,
+            }],
+            span,
+        }])
+    })
+}
+
+fn parse_tokens<T>(
+    tokens: Vec<(Token, Span)>,
+    keep_docs: bool,
+    max_depth: usize,
+    parse: impl FnOnce(&mut Tokens) -> Result<T, Error>,
+) -> Result<T, Spanned<Error>> {
+    // Doc comments are only meaningful in front of declarations, so ones inside
+    // function bodies are dropped along with ordinary comments.
+    let mut depth = 0;
+    let tokens = tokens.into_iter().filter(|(token, _)| match token {
+        Token::Comment => false,
+        Token::DocComment(_) => keep_docs && depth == 0,
+        Token::OpenBrace => {
+            depth += 1;
+            true
+        }
+        Token::CloseBrace => {
+            depth -= 1;
+            true
+        }
+        _ => true,
+    });
+
+    let mut tokens = Tokens::new(tokens.collect(), max_depth);
+    parse(&mut tokens).map_err(|error| tokens.spanned(error))
+}
+
+fn parse_declarations(tokens: &mut Tokens) -> Result<Vec<Declaration>, Error> {
+    let mut result = Vec::new();
+    let mut doc: Option<String> = None;
+    let mut arithmetic = None;
+
+    while let Some(token) = tokens.next() {
+        if let Token::DocComment(text) = token {
+            doc = Some(match doc.take() {
+                Some(doc) => doc + "\n" + &text,
+                None => text,
+            });
+            continue;
+        }
+        if token == Token::Hash {
+            match parse_attribute(tokens) {
+                Ok(attribute) => arithmetic = Some(attribute),
+                Err(error) => tokens.recover_declaration(error)?,
             }
+            continue;
+        }
+        match parse_declaration(token, doc.take(), arithmetic.take(), tokens) {
+            Ok(declaration) => result.push(declaration),
+            Err(error) => tokens.recover_declaration(error)?,
         }
     }
 
     Ok(result)
 }
 
+/// Parses the rest of an attribute after the `#`, which sets how a function does
+/// arithmetic.
+fn parse_attribute(tokens: &mut Tokens) -> Result<Arithmetic, Error> {
+    match tokens.next() {
+        Some(Token::OpenBracket) => {}
+        Some(t) => return Err(Error::UnexpectedToken(t)),
+        None => return Err(Error::UnexpectedEof),
+    }
+    let arithmetic = match parse_identifier(tokens)?.as_str() {
+        "wrapping" => Arithmetic::Wrapping,
+        "checked" => Arithmetic::Checked,
+        name => return Err(Error::UnknownAttribute(name.to_string())),
+    };
+    match tokens.next() {
+        Some(Token::CloseBracket) => Ok(arithmetic),
+        Some(t) => Err(Error::UnexpectedToken(t)),
+        None => Err(Error::UnexpectedEof),
+    }
+}
+
+/// Parses the rest of the declaration that starts with `token`. Only functions can
+/// have an attribute.
+fn parse_declaration(
+    token: Token,
+    doc: Option<String>,
+    arithmetic: Option<Arithmetic>,
+    tokens: &mut Tokens,
+) -> Result<Declaration, Error> {
+    if arithmetic.is_some() && token != Token::KwFn {
+        return Err(Error::MisplacedAttribute);
+    }
+    match token {
+        Token::KwFn => {
+            let start = tokens.last.start;
+            let (name, arguments, return_type) = parse_signature(tokens)?;
+
+            let body = parse_function_body(tokens)?;
+
+            Ok(Declaration::Function {
+                name,
+                doc,
+                arithmetic: arithmetic.unwrap_or_default(),
+                arguments,
+                return_type,
+                body,
+                span: Span {
+                    start,
+                    end: tokens.last.end,
+                },
+            })
+        }
+        Token::KwExtern => {
+            let start = tokens.last.start;
+            match tokens.next() {
+                Some(Token::KwFn) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+
+            let (name, arguments, return_type) = parse_signature(tokens)?;
+
+            match tokens.next() {
+                Some(Token::Semicolon) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+
+            Ok(Declaration::Extern {
+                name,
+                doc,
+                arguments,
+                return_type,
+                span: Span {
+                    start,
+                    end: tokens.last.end,
+                },
+            })
+        }
+        Token::KwType => {
+            let start = tokens.last.start;
+            let name = parse_identifier(tokens)?;
+            match tokens.next() {
+                Some(Token::Equals) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+            // Aliases must be declared before they are used, so the only way
+            // to form a cycle is to use the alias in its own definition.
+            let aliased = parse_type(tokens).map_err(|error| match error {
+                Error::UnexpectedToken(Token::Identifier(used)) if used == name => {
+                    Error::CyclicTypeAlias(name.clone())
+                }
+                error => error,
+            })?;
+            match tokens.next() {
+                Some(Token::Semicolon) => {}
+                Some(t) => return Err(Error::UnexpectedToken(t)),
+                None => return Err(Error::UnexpectedEof),
+            }
+
+            tokens.aliases.insert(name.clone(), aliased.clone());
+            Ok(Declaration::TypeAlias {
+                name,
+                doc,
+                aliased,
+                span: Span {
+                    start,
+                    end: tokens.last.end,
+                },
+            })
+        }
+        t => Err(Error::UnexpectedToken(t)),
+    }
+}
+
 enum Peeked2<T> {
     None,
     One(T),
@@ -431,9 +1744,8 @@ impl<T: Iterator> Peekable2<T> {
     }
 
     fn first(&mut self) -> &Option<T::Item> {
-        match &self.peeked {
-            Peeked2::None => self.peeked = Peeked2::One(self.iter.next()),
-            _ => (),
+        if let Peeked2::None = &self.peeked {
+            self.peeked = Peeked2::One(self.iter.next());
         }
         match &self.peeked {
             Peeked2::None => unreachable!(),
@@ -460,3 +1772,564 @@ impl<T: Iterator> Peekable2<T> {
         if predicate(a) { self.next() } else { None }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn doc_comment_is_attached() {
+        let source = "/// Adds two numbers.
+            /// Wraps on overflow.
+            fn add(a: u32, b: u32): u32 {
+                /// Not a declaration comment.
+                return a + b; // trailing comment
+            }
+            fn main(): u32 { return add(1, 2); }";
+
+        let program = parse_with_docs(source).unwrap();
+        let docs: Vec<_> = program
+            .iter()
+            .map(|declaration| match declaration {
+                Declaration::Function { doc, .. }
+                | Declaration::Extern { doc, .. }
+                | Declaration::TypeAlias { doc, .. } => doc.clone(),
+            })
+            .collect();
+        assert_eq!(
+            docs,
+            vec![
+                Some("Adds two numbers.\nWraps on overflow.".to_string()),
+                None
+            ]
+        );
+
+        let program = parse(source).unwrap();
+        assert!(
+            program
+                .iter()
+                .all(|declaration| matches!(declaration, Declaration::Function { doc: None, .. }))
+        );
+    }
+
+    #[test]
+    fn empty_statements_are_ignored() {
+        let program = parse("fn main(): u32 { ; let a = 1;; ; return { a;; a }; ; }").unwrap();
+        let Declaration::Function { body, .. } = &program[0] else {
+            panic!("expected a function");
+        };
+        assert!(matches!(
+            body.as_slice(),
+            [
+                Spanned { node: Statement::Let { .. }, .. },
+                Spanned { node: Statement::Return(Expression::Block(block, _)), .. }
+            ] if matches!(block.as_slice(), [Spanned { node: Statement::Expr(_), .. }])
+        ));
+    }
+
+    #[test]
+    fn missing_closing_brace_points_at_its_opening() {
+        let source = "fn main(): u32 {
+            while true { let a = 1; }
+            return 0;
+        ";
+        match parse(source) {
+            Err(Spanned {
+                node: Error::UnbalancedBrace { opened_at },
+                ..
+            }) => assert_eq!(opened_at.start, source.find('{').unwrap()),
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    #[test]
+    fn keywords_are_not_identifiers() {
+        for (word, keyword) in KEYWORDS {
+            assert_eq!(
+                get_tokens(word).unwrap(),
+                vec![(
+                    keyword.clone(),
+                    Span {
+                        start: 0,
+                        end: word.len()
+                    }
+                )]
+            );
+        }
+
+        match parse("fn main(): u32 { let fn = 1; return 0; }") {
+            Err(Spanned {
+                node: Error::KeywordAsIdentifier(word),
+                span,
+            }) => {
+                assert_eq!(word, "fn");
+                assert_eq!(span, Span { start: 21, end: 23 });
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert!(matches!(
+            parse("fn f(while: u32): u32 { return 0; }"),
+            Err(Spanned {
+                node: Error::KeywordAsIdentifier(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let nested = |depth| {
+            format!(
+                "fn main(): u32 {{ return {}1{}; }}",
+                "(".repeat(depth),
+                ")".repeat(depth)
+            )
+        };
+
+        assert!(parse(&nested(100)).is_ok());
+        assert!(matches!(
+            parse(&nested(100_000)),
+            Err(Spanned {
+                node: Error::NestingTooDeep,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_with_max_depth(&nested(100), 50),
+            Err(Spanned {
+                node: Error::NestingTooDeep,
+                ..
+            })
+        ));
+
+        let nested_types = |open: &str, close: &str, depth| {
+            format!(
+                "fn f(a: {}u32{}): u32 {{ return 0; }}",
+                open.repeat(depth),
+                close.repeat(depth)
+            )
+        };
+        assert!(parse(&nested_types("(", ")", 100)).is_ok());
+        assert!(parse(&nested_types("[", "; 1]", 100)).is_ok());
+        for (open, close) in [("(", ")"), ("[", "; 1]"), ("fn(", "): u32")] {
+            assert!(matches!(
+                parse(&nested_types(open, close, 50_000)),
+                Err(Spanned {
+                    node: Error::NestingTooDeep,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn expression_program_returns_its_value() {
+        let program = parse_expression_program("2 + 3 * 4").unwrap();
+        crate::typechecker::check(&program).unwrap();
+        let result = crate::compiler::compile(&program).unwrap().run().unwrap();
+        assert_eq!(result, 14);
+
+        assert!(parse_expression_program("1 + 2; 3").is_err());
+    }
+
+    #[test]
+    fn lexing_errors_are_reported() {
+        let error = parse("fn main(): u32 { return 1 @ 2; }").unwrap_err();
+        assert!(matches!(error.node, Error::UnexpectedCharacter('@')));
+        assert_eq!(error.span, Span { start: 26, end: 27 });
+        assert!(matches!(
+            parse("fn main(): u32 { return 1; } /* open")
+                .unwrap_err()
+                .node,
+            Error::UnterminatedComment
+        ));
+        assert!(matches!(
+            parse("fn main(): u32 { return 4294967296; }")
+                .unwrap_err()
+                .node,
+            Error::NumberTooLarge
+        ));
+        assert!(matches!(
+            parse("fn main(): bool { return true & false; }")
+                .unwrap_err()
+                .node,
+            Error::UnexpectedCharacter('&')
+        ));
+    }
+
+    #[test]
+    fn hex_and_suffixed_numbers() {
+        let tokens: Vec<_> = get_tokens("0xff 255u8 0x10u16 42u32 7")
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Number(255),
+                Token::SuffixedNumber(255, Type::U8),
+                Token::SuffixedNumber(16, Type::U16),
+                Token::SuffixedNumber(42, Type::U32),
+                Token::Number(7),
+            ]
+        );
+        assert!(matches!(
+            get_tokens("5abc").unwrap_err().node,
+            Error::UnknownSuffix(suffix) if suffix == "abc"
+        ));
+    }
+
+    #[test]
+    fn comments_inside_expressions() {
+        let returned = |source: &str| {
+            let program = parse(source).unwrap();
+            let Declaration::Function { body, .. } = &program[1] else {
+                panic!("expected a function");
+            };
+            match &body[0].node {
+                Statement::Return(expression) => format!("{expression:?}"),
+                statement => panic!("unexpected statement {statement:?}"),
+            }
+        };
+        let program = |expression: &str| {
+            format!(
+                "fn f(a: u32, b: u32): u32 {{ return a; }} fn main(): u32 {{ return {expression}; }}"
+            )
+        };
+        for (commented, plain) in [
+            ("f(1, /* b */ 2)", "f(1, 2)"),
+            ("f(/* a */ 1 /* , 3 */, 2 /* b */)", "f(1, 2)"),
+            ("1 /* plus */ + /* two */ 2 * 3", "1 + 2 * 3"),
+            ("(/* inner */ 1 + 2 // trailing\n) * 3", "(1 + 2) * 3"),
+            ("-/* negative */5 == 4", "-5 == 4"),
+        ] {
+            assert_eq!(returned(&program(commented)), returned(&program(plain)));
+        }
+    }
+
+    #[test]
+    fn conditionals_desugar_to_if_expressions() {
+        let returned = |expression: &str| {
+            let program = parse(&format!(
+                "fn main(a: bool, b: bool): u32 {{ return {expression}; }}"
+            ))
+            .unwrap();
+            let Declaration::Function { body, .. } = &program[0] else {
+                panic!("expected a function");
+            };
+            match &body[0].node {
+                Statement::Return(expression) => format!("{expression:?}"),
+                statement => panic!("unexpected statement {statement:?}"),
+            }
+        };
+        assert_eq!(
+            returned("a ? 1 : 2"),
+            format!(
+                "{:?}",
+                Expression::if_else(
+                    Expression::var("a"),
+                    Expression::block(vec![], Expression::num(1)),
+                    Expression::block(vec![], Expression::num(2)),
+                )
+            )
+        );
+        for (conditional, grouped) in [
+            ("1 < 2 ? 1 + 2 : 3 * 4", "(1 < 2) ? (1 + 2) : (3 * 4)"),
+            ("a || b ? 1 : 2", "(a || b) ? 1 : 2"),
+            ("a ? 1 : b ? 2 : 3", "a ? 1 : (b ? 2 : 3)"),
+            ("a ? b ? 1 : 2 : 3", "a ? (b ? 1 : 2) : 3"),
+            ("a ? 1 : 2 |> double", "double(if a { 1 } else { 2 })"),
+        ] {
+            assert_eq!(returned(conditional), returned(grouped), "{conditional}");
+        }
+        assert!(matches!(
+            parse("fn main(): u32 { return true ? 1; }").map_err(|error| error.node),
+            Err(Error::UnexpectedToken(Token::Semicolon))
+        ));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let returned = |expression: &str| {
+            let program = parse(&format!(
+                "fn main(a: bool, b: bool, c: bool): bool {{ return {expression}; }}"
+            ))
+            .unwrap();
+            let Declaration::Function { body, .. } = &program[0] else {
+                panic!("expected a function");
+            };
+            match &body[0].node {
+                Statement::Return(expression) => format!("{expression:?}"),
+                statement => panic!("unexpected statement {statement:?}"),
+            }
+        };
+        for (source, grouped) in [
+            ("a || b && c", "a || (b && c)"),
+            ("a && b || c", "(a && b) || c"),
+            ("a && b && c", "(a && b) && c"),
+            ("a || b || c", "(a || b) || c"),
+            ("1 < 2 && a == b", "(1 < 2) && (a == b)"),
+            ("a && b ? 1 < 2 : b || c", "(a && b) ? (1 < 2) : (b || c)"),
+        ] {
+            assert_eq!(returned(source), returned(grouped), "{source}");
+        }
+    }
+
+    #[test]
+    fn prefixes_bind_tighter_than_binary_operators() {
+        let returned = |expression: &str| {
+            let program = parse(&format!("fn main(): i32 {{ return {expression}; }}")).unwrap();
+            let Declaration::Function { body, .. } = &program[0] else {
+                panic!("expected a function");
+            };
+            match &body[0].node {
+                Statement::Return(expression) => format!("{expression:?}"),
+                statement => panic!("unexpected statement {statement:?}"),
+            }
+        };
+        let a = || Box::new(Expression::Variable("a".to_string()));
+        let b = || Box::new(Expression::Variable("b".to_string()));
+        let negate = |e| Box::new(Expression::Negate(e));
+        let not = |e| Box::new(Expression::Not(e));
+        for (source, tree) in [
+            ("-a * b", Expression::Mul(negate(a()), b())),
+            ("-a + b", Expression::Add(negate(a()), b())),
+            ("-a - b", Expression::Sub(negate(a()), b())),
+            ("a * -b", Expression::Mul(a(), negate(b()))),
+            ("--a", *negate(negate(a()))),
+            ("!!a", *not(not(a()))),
+            ("-!a", *negate(not(a()))),
+            ("-(a - b)", *negate(Box::new(Expression::Sub(a(), b())))),
+            ("-a[b]", *negate(Box::new(Expression::Index(a(), b())))),
+            (
+                "!a == b",
+                Expression::Compare(Comparison::Equal, not(a()), b()),
+            ),
+            (
+                "-5 * a",
+                Expression::Mul(Box::new(Expression::NegativeLiteral(5)), a()),
+            ),
+            ("--5", *negate(Box::new(Expression::NegativeLiteral(5)))),
+            ("!a && b", Expression::And(not(a()), b())),
+        ] {
+            assert_eq!(returned(source), format!("{tree:?}"), "{source}");
+        }
+    }
+
+    #[test]
+    fn pipes_desugar_to_nested_calls() {
+        let program = parse("fn main(): u32 { return 1 + 2 |> double |> inc; }").unwrap();
+        let Declaration::Function { body, .. } = &program[0] else {
+            panic!("expected a function");
+        };
+        let Statement::Return(Expression::Call(outer, arguments)) = &body[0].node else {
+            panic!("unexpected statement {:?}", body[0].node);
+        };
+        assert_eq!(outer, "inc");
+        let [Expression::Call(inner, arguments)] = arguments.as_slice() else {
+            panic!("unexpected arguments {arguments:?}");
+        };
+        assert_eq!(inner, "double");
+        assert!(matches!(arguments.as_slice(), [Expression::Add(..)]));
+
+        assert!(matches!(
+            parse("fn main(): u32 { return 1 | 2; }").map_err(|error| error.node),
+            Err(Error::UnexpectedCharacter('|'))
+        ));
+    }
+
+    #[test]
+    fn let_declares_several_variables() {
+        let statements = |source: &str| {
+            let program = parse(source).unwrap();
+            let Declaration::Function { body, .. } = &program[0] else {
+                panic!("expected a function");
+            };
+            body.iter()
+                .map(|statement| format!("{:?}", statement.node))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            statements("fn main(): u32 { let a: u32, mut b = 1, a + 1; return b; }"),
+            statements("fn main(): u32 { let a: u32 = 1; let mut b = a + 1; return b; }")
+        );
+        assert!(matches!(
+            parse("fn main(): u32 { let a, b = 1; return a; }").map_err(|error| error.node),
+            Err(Error::BindingCountMismatch {
+                names: 2,
+                values: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn sizeof_is_a_constant() {
+        let value = |source: &str| {
+            let program = parse(&format!(
+                "type Pair = [u32; 2]; fn main(): u32 {{ return {source}; }}"
+            ))
+            .unwrap();
+            let Declaration::Function { body, .. } = &program[1] else {
+                panic!("expected a function");
+            };
+            match &body[0].node {
+                Statement::Return(Expression::NumLiteral(n)) => *n,
+                statement => panic!("expected a number, got {statement:?}"),
+            }
+        };
+        assert_eq!(value("sizeof(u32)"), 1);
+        assert_eq!(value("sizeof(bool)"), 1);
+        assert_eq!(value("sizeof([u32; 3])"), 3);
+        assert_eq!(value("sizeof(Pair)"), 2);
+        // The inner arrays are each one slot, pointing to their own elements.
+        assert_eq!(value("sizeof([[u8; 4]; 2])"), 2);
+        assert!(parse("fn main(): u32 { return sizeof(1); }").is_err());
+    }
+
+    #[test]
+    fn expression_bodied_functions() {
+        // Only the spans differ between the two ways of writing a function.
+        let function = |source: &str| {
+            let mut function = parse(source).unwrap().remove(0);
+            let Declaration::Function { body, span, .. } = &mut function else {
+                panic!("expected a function, got {function:?}");
+            };
+            *span = Span::default();
+            for statement in body {
+                statement.span = Span::default();
+            }
+            function
+        };
+        assert_eq!(
+            function("fn double(x: u32): u32 = x * 2;"),
+            function("fn double(x: u32): u32 { return x * 2; }")
+        );
+        assert_eq!(
+            function("fn f(): u32 = f();"),
+            function("fn f(): u32 { return f(); }")
+        );
+        assert!(matches!(
+            parse("fn double(x: u32): u32 = x * 2").map_err(|error| error.node),
+            Err(Error::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn type_aliases_are_resolved() {
+        let program = parse(
+            "type Count = u32;
+            type Counts = [Count; 2];
+            fn first(counts: Counts): Count { return counts[0]; }",
+        )
+        .unwrap();
+        let Declaration::Function {
+            arguments,
+            return_type,
+            ..
+        } = &program[2]
+        else {
+            panic!("expected a function");
+        };
+        let count = Type::Alias("Count".into(), Box::new(Type::U32));
+        assert_eq!(
+            arguments[0].1,
+            Type::Alias(
+                "Counts".into(),
+                Box::new(Type::Array(Box::new(count.clone()), 2))
+            )
+        );
+        assert_eq!(
+            arguments[0].1.resolved(),
+            Type::Array(Box::new(Type::U32), 2)
+        );
+        assert_eq!(return_type, &count);
+        assert!(crate::typechecker::check(&program).is_ok());
+
+        assert!(matches!(
+            parse("type A = A;").map_err(|error| error.node),
+            Err(Error::CyclicTypeAlias(name)) if name == "A"
+        ));
+        assert!(matches!(
+            parse("type A = B; type B = u32;").map_err(|error| error.node),
+            Err(Error::UnexpectedToken(Token::Identifier(name))) if name == "B"
+        ));
+    }
+
+    #[test]
+    fn parenthesized_and_function_types() {
+        let aliased = |source: &str| match parse(source).unwrap().remove(0) {
+            Declaration::TypeAlias { aliased, .. } => aliased,
+            declaration => panic!("expected a type alias, got {declaration:?}"),
+        };
+        let fun = |arguments, result| Type::Fun(arguments, Box::new(result));
+
+        assert_eq!(aliased("type A = (u32);"), Type::U32);
+        assert_eq!(aliased("type A = ((bool));"), Type::Bool);
+        assert_eq!(
+            aliased("type A = fn((u32, u32)): u32;"),
+            fun(vec![Type::Tuple(vec![Type::U32, Type::U32])], Type::U32)
+        );
+        assert_eq!(
+            aliased("type A = fn(fn(u32): bool, u8): (fn(): u16);"),
+            fun(
+                vec![fun(vec![Type::U32], Type::Bool), Type::U8],
+                fun(vec![], Type::U16)
+            )
+        );
+        assert_eq!(
+            aliased("type A = fn(u32);"),
+            fun(vec![Type::U32], Type::Unit)
+        );
+        assert!(parse("type A = (u32;").is_err());
+    }
+
+    #[test]
+    fn parsing_recovers_after_errors() {
+        let names = |declarations: &[Declaration]| {
+            declarations
+                .iter()
+                .map(|declaration| match declaration {
+                    Declaration::Function { name, .. } => name.clone(),
+                    _ => panic!("expected a function"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A bad signature skips the whole function.
+        let (declarations, errors) =
+            parse_recovering("fn broken(: u32 { return 1; }\nfn main(): u32 { return 2; }");
+        assert_eq!(names(&declarations), ["main"]);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].node,
+            Error::UnexpectedToken(Token::Colon)
+        ));
+
+        // A bad statement only skips to its `;`.
+        let source = "fn broken(): u32 { let = 1; if true { 5 + ; } return 2; }
+            fn main(): u32 { return 3; }";
+        let (declarations, errors) = parse_recovering(source);
+        assert_eq!(names(&declarations), ["broken", "main"]);
+        let Declaration::Function { body, .. } = &declarations[0] else {
+            unreachable!()
+        };
+        assert!(matches!(
+            body.as_slice(),
+            [
+                _,
+                Spanned {
+                    node: Statement::Return(_),
+                    ..
+                }
+            ]
+        ));
+        let found: Vec<_> = errors
+            .iter()
+            .map(|error| &source[error.span.start..error.span.end])
+            .collect();
+        assert_eq!(found, ["=", ";"]);
+
+        assert!(parse(source).is_err());
+    }
+}
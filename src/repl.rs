@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast, builtins, compiler, parser, typechecker,
+    virtual_machine::{RuntimeError, VirtualMachine, Word},
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Parse(ast::Spanned<parser::Error>),
+    Type(ast::Spanned<typechecker::Error>),
+    Compile(compiler::Error),
+    Runtime(RuntimeError),
+    /// `let (a, b) = ...`, whose values can't be kept between lines yet.
+    TupleBinding,
+}
+
+/// Runs statements one at a time, keeping the variables they bind and the heap
+/// between them.
+///
+/// Each statement is compiled into a `main` taking every variable bound so far as
+/// an argument, so the usual typechecker and compiler can be reused unchanged.
+/// Arguments can't be assigned to, so a `let mut` variable is passed in a heap
+/// cell instead, copied into a local of the same name that the statement can
+/// assign to, and copied back into the cell before `main` returns.
+pub struct Repl {
+    vm: VirtualMachine,
+    /// Variables bound by earlier statements, in order.
+    variables: Vec<Variable>,
+}
+
+struct Variable {
+    name: String,
+    typ: ast::Type,
+    /// Declared with `let mut`, so later statements can assign to it.
+    mutable: bool,
+    value: Word,
+}
+
+/// The name of the argument holding the cell of the mutable variable `name`, which
+/// no variable in the source can have.
+fn cell(name: &str) -> String {
+    format!("{name}'")
+}
+
+/// The local holding the value of an expression statement while the cells are
+/// written back, named so that it can't clash with a variable in the source.
+const RESULT: &str = "'result";
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            vm: VirtualMachine::from_functions(Vec::new()),
+            variables: Vec::new(),
+        }
+    }
+
+    /// Runs one line of input and returns the value it produced: the value bound by
+    /// a `let`, or the value of an expression. The trailing `;` may be left out.
+    /// After an error the session carries on as if the line was never entered.
+    pub fn eval(&mut self, line: &str) -> Result<Option<Word>, Error> {
+        let line = line.trim_end();
+        let source = if line.ends_with(';') || line.ends_with('}') {
+            line.to_string()
+        } else {
+            format!("{line};")
+        };
+        let statement = parser::parse_single_statement(&source).map_err(Error::Parse)?;
+        let span = statement.span;
+        let returning = |expression| ast::Spanned {
+            node: ast::Statement::Return(expression),
+            span,
+        };
+
+        // The statement, the variable it binds and whether that is mutable, and
+        // what `main` returns after it.
+        let (statement_body, binding, return_type, result) = match &statement.node {
+            ast::Statement::Let {
+                name,
+                mutable,
+                typ,
+                value: expression,
+            } => {
+                let typ = match typ {
+                    Some(typ) => typ.clone(),
+                    None => self.infer(expression, span)?,
+                };
+                let result = ast::Expression::var(name);
+                (
+                    statement.clone(),
+                    Some((name.clone(), *mutable)),
+                    typ,
+                    result,
+                )
+            }
+            ast::Statement::Expr(expression) | ast::Statement::Return(expression) => {
+                let typ = self.infer(expression, span)?;
+                let statement = ast::Spanned {
+                    node: ast::Statement::define_typed(RESULT, typ.clone(), expression.clone()),
+                    span,
+                };
+                (statement, None, typ, ast::Expression::var(RESULT))
+            }
+            ast::Statement::Const { name, typ, .. } => {
+                let result = ast::Expression::var(name);
+                (
+                    statement.clone(),
+                    Some((name.clone(), false)),
+                    typ.clone(),
+                    result,
+                )
+            }
+            ast::Statement::LetTuple(..) => return Err(Error::TupleBinding),
+            ast::Statement::If(..)
+            | ast::Statement::While(..)
+            | ast::Statement::Break(..)
+            | ast::Statement::Assign(..)
+            | ast::Statement::IndexAssign(..) => (
+                statement.clone(),
+                None,
+                ast::Type::U32,
+                ast::Expression::num(0),
+            ),
+        };
+
+        let mut body = Vec::new();
+        for variable in self.variables.iter().filter(|variable| variable.mutable) {
+            // Arguments can't be `mut`, so the cell is bound again to be written to.
+            body.push(ast::Spanned {
+                node: ast::Statement::Let {
+                    name: cell(&variable.name),
+                    mutable: true,
+                    typ: None,
+                    value: ast::Expression::var(cell(&variable.name)),
+                },
+                span,
+            });
+            body.push(ast::Spanned {
+                node: ast::Statement::Let {
+                    name: variable.name.clone(),
+                    mutable: true,
+                    typ: Some(variable.typ.clone()),
+                    value: ast::Expression::index(
+                        ast::Expression::var(cell(&variable.name)),
+                        ast::Expression::num(0),
+                    ),
+                },
+                span,
+            });
+        }
+        body.push(statement_body);
+        // A variable the statement binds again is replaced rather than updated.
+        let rebound = binding.as_ref().map(|(name, _)| name);
+        for variable in &self.variables {
+            if variable.mutable && rebound != Some(&variable.name) {
+                body.push(ast::Spanned {
+                    node: ast::Statement::assign_index(
+                        ast::Expression::var(cell(&variable.name)),
+                        ast::Expression::num(0),
+                        ast::Expression::var(&variable.name),
+                    ),
+                    span,
+                });
+            }
+        }
+        body.push(returning(result));
+
+        let program = vec![ast::Declaration::Function {
+            name: "main".to_string(),
+            doc: None,
+            arithmetic: ast::Arithmetic::default(),
+            arguments: self
+                .variables
+                .iter()
+                .map(|variable| {
+                    if variable.mutable {
+                        let typ = ast::Type::Array(Box::new(variable.typ.clone()), 1);
+                        (cell(&variable.name), typ)
+                    } else {
+                        (variable.name.clone(), variable.typ.clone())
+                    }
+                })
+                .collect(),
+            return_type: return_type.clone(),
+            body,
+            span,
+        }];
+        typechecker::check(&program).map_err(Error::Type)?;
+        compiler::compile_into(&program, &mut self.vm).map_err(Error::Compile)?;
+        let values: Vec<Word> = self
+            .variables
+            .iter()
+            .map(|variable| {
+                if !variable.mutable {
+                    return variable.value;
+                }
+                // Laid out like an array of one element.
+                let cell = self.vm.allocate(2);
+                self.vm.write_heap(cell, 1);
+                self.vm.write_heap(cell + 1, variable.value);
+                cell
+            })
+            .collect();
+        let result = self.vm.call("main", &values);
+        for (variable, value) in self.variables.iter_mut().zip(values) {
+            if variable.mutable {
+                if result.is_ok() {
+                    variable.value = self.vm.read_heap(value + 1);
+                }
+                self.vm.free(value).map_err(Error::Runtime)?;
+            }
+        }
+        let result = result.map_err(Error::Runtime)?;
+
+        match binding {
+            Some((name, mutable)) => {
+                self.variables.retain(|variable| variable.name != name);
+                self.variables.push(Variable {
+                    name,
+                    typ: return_type,
+                    mutable,
+                    value: result,
+                });
+                Ok(Some(result))
+            }
+            None if matches!(
+                statement.node,
+                ast::Statement::If(..)
+                    | ast::Statement::While(..)
+                    | ast::Statement::Assign(..)
+                    | ast::Statement::IndexAssign(..)
+            ) =>
+            {
+                Ok(None)
+            }
+            None => Ok(Some(result)),
+        }
+    }
+
+    fn infer(&self, expression: &ast::Expression, span: ast::Span) -> Result<ast::Type, Error> {
+        let builtins = builtins::standard();
+        let mut env = HashMap::new();
+        for builtin in &builtins {
+            env.insert(&builtin.name, builtin.signature());
+        }
+        for variable in &self.variables {
+            env.insert(&variable.name, variable.typ.clone());
+        }
+        typechecker::infer_expression(expression, &ast::Type::U32, &env.into()).map_err(|error| {
+            Error::Type(ast::Spanned {
+                node: error.node,
+                span,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_session() {
+        let mut repl = Repl::new();
+        assert!(matches!(repl.eval("let x = 2;"), Ok(Some(2))));
+        assert!(matches!(repl.eval("let y = x * 3"), Ok(Some(6))));
+        assert!(matches!(repl.eval("y + 1"), Ok(Some(7))));
+        assert!(matches!(repl.eval("let z = (;"), Err(Error::Parse(_))));
+        assert!(matches!(repl.eval("z"), Err(Error::Type(_))));
+        assert!(matches!(repl.eval("let x = x + y;"), Ok(Some(8))));
+        assert!(matches!(repl.eval("if lt(x, y) { exit(1); }"), Ok(None)));
+        // The array lives on the heap, which is kept between lines.
+        assert!(matches!(repl.eval("let a = [x, y, 1];"), Ok(Some(_))));
+        assert!(matches!(repl.eval("len(a) + x"), Ok(Some(11))));
+
+        // Assignments to `let mut` variables last past the line that made them.
+        assert!(matches!(repl.eval("let mut m = 1;"), Ok(Some(1))));
+        assert!(matches!(repl.eval("m = 5;"), Ok(None)));
+        assert!(matches!(repl.eval("m + x"), Ok(Some(13))));
+        assert!(matches!(repl.eval("let mut i = 0"), Ok(Some(0))));
+        assert!(matches!(
+            repl.eval("while i < 4 { i = i + 1; m = m * 2; }"),
+            Ok(None)
+        ));
+        assert!(matches!(repl.eval("i"), Ok(Some(4))));
+        assert!(matches!(repl.eval("m"), Ok(Some(80))));
+        assert!(matches!(repl.eval("{ m = 3; m + i };"), Ok(Some(7))));
+        assert!(matches!(repl.eval("m"), Ok(Some(3))));
+        assert!(matches!(repl.eval("x = 1;"), Err(Error::Type(_))));
+        // A line that fails changes nothing.
+        assert!(matches!(
+            repl.eval("{ m = 9; assert_eq(m, 1); m };"),
+            Err(Error::Runtime(_))
+        ));
+        assert!(matches!(repl.eval("m"), Ok(Some(3))));
+    }
+}
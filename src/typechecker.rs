@@ -1,122 +1,1461 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
 
-use crate::ast;
+use crate::{
+    ast,
+    builtins::{self, Builtin},
+    fold,
+    virtual_machine::Word,
+};
 
 #[derive(Debug)]
 pub enum Error {
     UndeclaredVariable(String),
     CallingNonFunction(String, ast::Type),
     NonMatchingTypes(ast::Type, ast::Type),
+    ArgumentTypeMismatch {
+        function: String,
+        index: usize,
+        expected: ast::Type,
+        found: ast::Type,
+    },
+    UndestructuredTuple(ast::Type),
+    CannotDestructure(usize, ast::Type),
+    ArgumentCountMismatch {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    NotAnArray(ast::Type),
+    ArithmeticOnNonNumber(ast::Type),
+    /// A literal too big or too small for the type it is used as.
+    LiteralOutOfRange(ast::Type),
+    /// Assigning to a variable that wasn't declared with `let mut`, or to an element
+    /// of the array in one.
+    AssignToImmutable(String),
+    /// Assigning to an element of an array that isn't in a variable, such as one a
+    /// call returned, which could be shared with a variable that isn't `mut`.
+    AssignToTemporary,
+    /// Arithmetic on `u8` or `u16`, which the VM can't wrap to the narrower width.
+    NarrowArithmetic(ast::Type),
+    /// `break` outside of any `loop` or `while`.
+    BreakOutsideLoop,
+    /// A `const` whose value can't be worked out when compiling, as it reads a
+    /// variable that isn't a `const`, calls a function or divides by zero.
+    NotConstant(String),
+    /// A `match` without a `_` arm, which could find no arm to take.
+    NonExhaustiveMatch,
+    /// A parameter or local named after the entry point, `main` unless the program
+    /// is checked with another one. Calls are resolved against variables here
+    /// but against functions when compiling, so the two would disagree about it.
+    ShadowsEntryPoint,
 }
 
-pub fn check(ast: &Vec<ast::Declaration>) -> Result<(), Error> {
+/// Where the type of the values the innermost loop breaks with is kept in the
+/// environment. No variable can have an empty name.
+static BREAK_TYPE: String = String::new();
+
+/// The arithmetic operators and comparisons of a program that work on `i32`s, which
+/// divide, compare and overflow differently from `u32`s but look the same in the
+/// AST. They are told apart by address, so this only applies to the AST it was
+/// found in.
+#[derive(Debug, Default)]
+pub struct SignedOperations(HashSet<*const ast::Expression>);
+
+impl SignedOperations {
+    pub fn contains(&self, expression: &ast::Expression) -> bool {
+        self.0.contains(&(expression as *const _))
+    }
+}
+
+/// The variables in scope and their types. Clones share the `SignedOperations`
+/// found so far, which outlive the scopes they were found in.
+#[derive(Clone)]
+pub struct Scope<'a> {
+    variables: HashMap<&'a String, ast::Type>,
+    signed: Rc<RefCell<SignedOperations>>,
+    /// The name of the function the program starts from, which no variable may take.
+    entry: &'a str,
+}
+
+impl<'a> Scope<'a> {
+    fn note_signed(&self, expression: &ast::Expression) {
+        self.signed.borrow_mut().0.insert(expression);
+    }
+}
+
+impl<'a> From<HashMap<&'a String, ast::Type>> for Scope<'a> {
+    fn from(variables: HashMap<&'a String, ast::Type>) -> Self {
+        Scope {
+            variables,
+            signed: Rc::default(),
+            entry: "main",
+        }
+    }
+}
+
+impl<'a> Deref for Scope<'a> {
+    type Target = HashMap<&'a String, ast::Type>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.variables
+    }
+}
+
+impl DerefMut for Scope<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.variables
+    }
+}
+
+/// Errors start out without a location. `check_statement` gives them the span of
+/// the innermost statement they were found in.
+impl From<Error> for ast::Spanned<Error> {
+    fn from(node: Error) -> Self {
+        ast::Spanned {
+            node,
+            span: ast::Span::default(),
+        }
+    }
+}
+
+pub fn check(ast: &[ast::Declaration]) -> Result<(), ast::Spanned<Error>> {
+    check_with_builtins(ast, &builtins::standard())
+}
+
+pub fn check_with_builtins(
+    ast: &[ast::Declaration],
+    builtins: &[Builtin],
+) -> Result<(), ast::Spanned<Error>> {
+    check_all_with_builtins(ast, builtins).map_err(|errors| errors.into_iter().next().unwrap())
+}
+
+/// Like `check`, but for a program that starts from the function named `entry`
+/// instead of `main`, as compiled by `compile_with_entry`.
+pub fn check_with_entry(ast: &[ast::Declaration], entry: &str) -> Result<(), ast::Spanned<Error>> {
+    check_program(ast, &builtins::standard(), entry, &Rc::default())
+        .map_err(|errors| errors.into_iter().next().unwrap())
+}
+
+/// Like `check`, but keeps going after a function fails to typecheck, and returns
+/// the first error found in each function that has one.
+pub fn check_all(ast: &[ast::Declaration]) -> Result<(), Vec<ast::Spanned<Error>>> {
+    check_all_with_builtins(ast, &builtins::standard())
+}
+
+pub fn check_all_with_builtins(
+    ast: &[ast::Declaration],
+    builtins: &[Builtin],
+) -> Result<(), Vec<ast::Spanned<Error>>> {
+    check_program(ast, builtins, "main", &Rc::default())
+}
+
+/// Finds the `SignedOperations` of `ast`, in the functions that typecheck when the
+/// program starts from the function named `entry`.
+pub fn signed_operations(
+    ast: &[ast::Declaration],
+    builtins: &[Builtin],
+    entry: &str,
+) -> SignedOperations {
+    let signed = Rc::default();
+    let _ = check_program(ast, builtins, entry, &signed);
+    signed.take()
+}
+
+/// Checks every function in `ast`, noting the `SignedOperations` found in `signed`.
+fn check_program(
+    ast: &[ast::Declaration],
+    builtins: &[Builtin],
+    entry: &str,
+    signed: &Rc<RefCell<SignedOperations>>,
+) -> Result<(), Vec<ast::Spanned<Error>>> {
     let mut global_types: HashMap<&String, ast::Type> = HashMap::new();
+    for builtin in builtins {
+        global_types.insert(&builtin.name, builtin.signature());
+    }
     for declaration in ast {
         match declaration {
             ast::Declaration::Function {
                 name,
                 arguments,
                 return_type,
-                body: _,
+                ..
+            }
+            | ast::Declaration::Extern {
+                name,
+                arguments,
+                return_type,
+                ..
             } => {
                 global_types.insert(
                     name,
                     ast::Type::Fun(
-                        arguments.iter().map(|(_, t)| t.clone()).collect(),
-                        Box::new(return_type.clone()),
+                        arguments.iter().map(|(_, t)| t.resolved()).collect(),
+                        Box::new(return_type.resolved()),
                     ),
                 );
             }
+            // Uses of aliases are resolved where they are written.
+            ast::Declaration::TypeAlias { .. } => {}
         }
     }
+    let mut errors = Vec::new();
     for declaration in ast {
         match declaration {
             ast::Declaration::Function {
                 name: _,
+                doc: _,
+                arithmetic: _,
                 arguments,
                 return_type,
                 body,
+                span: _,
             } => {
-                let mut local_vars: HashMap<&String, ast::Type> = HashMap::new();
-
-                for (name, typ) in global_types.iter() {
-                    local_vars.insert(name, typ.clone());
-                }
-                for (name, typ) in arguments.iter() {
-                    local_vars.insert(name, typ.clone());
-                }
-
-                for statement in body {
-                    match statement {
-                        ast::Statement::Let(name, typ, expression) => {
-                            check_expression(expression, typ.as_ref().unwrap(), &local_vars)?;
-                            local_vars.insert(name, typ.clone().unwrap());
-                        }
-                        ast::Statement::Expr(_) => {
-                            todo!("Implement inference for standalone expressions")
-                        }
-                        ast::Statement::Return(expression) => {
-                            check_expression(expression, return_type, &local_vars)?
-                        }
-                    }
+                if let Err(error) =
+                    check_function(arguments, return_type, body, &global_types, entry, signed)
+                {
+                    errors.push(error);
                 }
             }
+            // Checked against the builtin that provides it when compiling.
+            ast::Declaration::Extern { .. } => {}
+            ast::Declaration::TypeAlias { .. } => {}
         }
     }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_function<'a>(
+    arguments: &'a [(String, ast::Type)],
+    return_type: &ast::Type,
+    body: &'a [ast::Spanned<ast::Statement>],
+    global_types: &HashMap<&'a String, ast::Type>,
+    entry: &'a str,
+    signed: &Rc<RefCell<SignedOperations>>,
+) -> Result<(), ast::Spanned<Error>> {
+    let mut local_vars = Scope {
+        variables: HashMap::new(),
+        signed: Rc::clone(signed),
+        entry,
+    };
+
+    for (name, typ) in global_types.iter() {
+        local_vars.insert(name, typ.clone());
+    }
+    for (name, typ) in arguments.iter() {
+        check_not_entry_point(name, entry)?;
+        local_vars.insert(name, typ.resolved());
+    }
+
+    let return_type = return_type.resolved();
+    for statement in body {
+        check_statement(statement, &return_type, &mut local_vars)?;
+    }
+    check_mutability(body, &mut HashMap::new(), &signed.borrow())
+}
+
+fn check_not_entry_point(name: &str, entry: &str) -> Result<(), Error> {
+    if name == entry {
+        return Err(Error::ShadowsEntryPoint);
+    }
     Ok(())
 }
 
-pub fn check_expression(
-    expression: &ast::Expression,
-    typ: &ast::Type,
-    env: &HashMap<&String, ast::Type>,
-) -> Result<(), Error> {
-    match expression {
-        ast::Expression::Call(function, expressions) => match env.get(function) {
-            Some(ast::Type::Fun(arg_types, return_type)) => {
-                for (expression, arg_type) in expressions.iter().zip(arg_types) {
-                    check_expression(expression, arg_type, env)?
+fn check_statement<'a>(
+    statement: &'a ast::Spanned<ast::Statement>,
+    return_type: &ast::Type,
+    local_vars: &mut Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    check_statement_node(&statement.node, return_type, local_vars).map_err(|error| {
+        // Errors from statements nested inside this one already have their span.
+        if error.span == ast::Span::default() {
+            ast::Spanned {
+                node: error.node,
+                span: statement.span,
+            }
+        } else {
+            error
+        }
+    })
+}
+
+fn check_statement_node<'a>(
+    statement: &'a ast::Statement,
+    return_type: &ast::Type,
+    local_vars: &mut Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    match statement {
+        ast::Statement::Let {
+            name,
+            typ,
+            value: expression,
+            ..
+        } => {
+            check_not_entry_point(name, local_vars.entry)?;
+            let typ = match typ {
+                Some(typ) => {
+                    let typ = typ.resolved();
+                    check_expression(expression, &typ, return_type, local_vars)?;
+                    typ
+                }
+                None => infer_expression(expression, return_type, local_vars)?,
+            };
+            local_vars.insert(name, typ);
+        }
+        ast::Statement::Const { name, typ, value } => {
+            check_not_entry_point(name, local_vars.entry)?;
+            let typ = typ.resolved();
+            check_expression(value, &typ, return_type, local_vars)?;
+            local_vars.insert(name, typ);
+        }
+        ast::Statement::LetTuple(names, expression) => {
+            for name in names {
+                check_not_entry_point(name, local_vars.entry)?;
+            }
+            let typ = match expression {
+                ast::Expression::Call(function, expressions) => {
+                    infer_call(function, expressions, return_type, local_vars)?
                 }
-                if typ != return_type.as_ref() {
-                    return Err(Error::NonMatchingTypes(
-                        typ.clone(),
-                        return_type.as_ref().clone(),
-                    ));
+                _ => infer_expression(expression, return_type, local_vars)?,
+            };
+            match typ {
+                ast::Type::Tuple(types) if types.len() == names.len() => {
+                    for (name, typ) in names.iter().zip(types) {
+                        local_vars.insert(name, typ);
+                    }
                 }
+                typ => return Err(Error::CannotDestructure(names.len(), typ).into()),
             }
-            Some(t) => return Err(Error::CallingNonFunction(function.clone(), t.clone())),
-            None => return Err(Error::UndeclaredVariable(function.clone())),
+        }
+        ast::Statement::Assign(name, expression) => match local_vars.get(name) {
+            Some(typ) => check_expression(expression, &typ.clone(), return_type, local_vars)?,
+            None => return Err(Error::UndeclaredVariable(name.clone()).into()),
         },
+        ast::Statement::IndexAssign(array, index, value) => {
+            let element_type = infer_element(array, index, return_type, local_vars)?;
+            check_expression(value, &element_type, return_type, local_vars)?;
+        }
+        ast::Statement::Expr(expression) => {
+            // Calls are evaluated for their effects, so their results may be tuples.
+            match expression {
+                ast::Expression::Call(function, expressions) => {
+                    infer_call(function, expressions, return_type, local_vars)?
+                }
+                _ => infer_expression(expression, return_type, local_vars)?,
+            };
+        }
+        ast::Statement::Return(expression) => {
+            check_expression(expression, return_type, return_type, local_vars)?
+        }
+        ast::Statement::If(condition, then_body, else_body) => {
+            check_expression(condition, &ast::Type::Bool, return_type, local_vars)?;
+            check_block(then_body, return_type, local_vars)?;
+            check_block(else_body, return_type, local_vars)?;
+        }
+        ast::Statement::While(condition, body) => {
+            check_expression(condition, &ast::Type::Bool, return_type, local_vars)?;
+            let mut env = local_vars.clone();
+            env.insert(&BREAK_TYPE, ast::Type::Unit);
+            check_block(body, return_type, &env)?;
+        }
+        ast::Statement::Break(value) => {
+            let Some(typ) = local_vars.get(&BREAK_TYPE).cloned() else {
+                return Err(Error::BreakOutsideLoop.into());
+            };
+            match value {
+                Some(value) => check_expression(value, &typ, return_type, local_vars)?,
+                None if typ != ast::Type::Unit => {
+                    return Err(Error::NonMatchingTypes(typ, ast::Type::Unit).into());
+                }
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The type of the value the first `break` in a loop's `body` gives, if it can
+/// be worked out. `break`s inside nested loops leave those loops instead.
+fn break_type<'a>(
+    body: &'a [ast::Spanned<ast::Statement>],
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Option<ast::Type> {
+    let mut local_vars = env.clone();
+    for statement in body {
+        match &statement.node {
+            ast::Statement::Break(Some(value)) => {
+                return infer_expression(value, return_type, &local_vars).ok();
+            }
+            ast::Statement::Break(None) => return Some(ast::Type::Unit),
+            ast::Statement::If(_, then_body, else_body) => {
+                let typ = break_type(then_body, return_type, &local_vars)
+                    .or_else(|| break_type(else_body, return_type, &local_vars));
+                if typ.is_some() {
+                    return typ;
+                }
+            }
+            _ => {}
+        }
+        check_statement(statement, return_type, &mut local_vars).ok()?;
+    }
+    None
+}
+
+/// How a local was declared, as far as assigning to it and knowing its value when
+/// compiling go.
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    Immutable,
+    Mutable,
+    Constant(Word),
+}
+
+/// Checks that only variables declared with `let mut` are assigned to, and that
+/// the value of each `const` can be worked out. `mutable` holds how each variable
+/// in scope was declared.
+fn check_mutability<'a>(
+    body: &'a [ast::Spanned<ast::Statement>],
+    mutable: &mut HashMap<&'a String, Binding>,
+    signed: &SignedOperations,
+) -> Result<(), ast::Spanned<Error>> {
+    for statement in body {
+        match &statement.node {
+            ast::Statement::Let {
+                name,
+                mutable: is_mutable,
+                value,
+                ..
+            } => {
+                check_expression_mutability(value, mutable, signed)?;
+                let binding = if *is_mutable {
+                    Binding::Mutable
+                } else {
+                    Binding::Immutable
+                };
+                mutable.insert(name, binding);
+            }
+            ast::Statement::Const { name, value, .. } => {
+                let constant = |name: &str| match mutable.get(&name.to_string()) {
+                    Some(Binding::Constant(value)) => Some(*value),
+                    _ => None,
+                };
+                let Some(value) = fold::evaluate(value, &constant, signed) else {
+                    return Err(ast::Spanned {
+                        node: Error::NotConstant(name.clone()),
+                        span: statement.span,
+                    });
+                };
+                mutable.insert(name, Binding::Constant(value));
+            }
+            ast::Statement::LetTuple(names, expression) => {
+                check_expression_mutability(expression, mutable, signed)?;
+                for name in names {
+                    mutable.insert(name, Binding::Immutable);
+                }
+            }
+            ast::Statement::Assign(name, expression) => {
+                check_expression_mutability(expression, mutable, signed)?;
+                // Arguments and functions can't be declared `mut`, so they aren't here.
+                if !matches!(mutable.get(name), Some(Binding::Mutable)) {
+                    return Err(ast::Spanned {
+                        node: Error::AssignToImmutable(name.clone()),
+                        span: statement.span,
+                    });
+                }
+            }
+            ast::Statement::IndexAssign(array, index, value) => {
+                check_expression_mutability(array, mutable, signed)?;
+                check_expression_mutability(index, mutable, signed)?;
+                check_expression_mutability(value, mutable, signed)?;
+                // Arrays are shared rather than copied, so writing to one through a
+                // variable that isn't `mut` would change it under every other name.
+                let mut target = array;
+                while let ast::Expression::Index(array, _) = target {
+                    target = array;
+                }
+                let node = match target {
+                    ast::Expression::Variable(name)
+                        if matches!(mutable.get(name), Some(Binding::Mutable)) =>
+                    {
+                        None
+                    }
+                    ast::Expression::Variable(name) => Some(Error::AssignToImmutable(name.clone())),
+                    _ => Some(Error::AssignToTemporary),
+                };
+                if let Some(node) = node {
+                    return Err(ast::Spanned {
+                        node,
+                        span: statement.span,
+                    });
+                }
+            }
+            ast::Statement::Expr(expression) | ast::Statement::Return(expression) => {
+                check_expression_mutability(expression, mutable, signed)?
+            }
+            ast::Statement::If(condition, then_body, else_body) => {
+                check_expression_mutability(condition, mutable, signed)?;
+                check_mutability(then_body, &mut mutable.clone(), signed)?;
+                check_mutability(else_body, &mut mutable.clone(), signed)?;
+            }
+            ast::Statement::While(condition, body) => {
+                check_expression_mutability(condition, mutable, signed)?;
+                check_mutability(body, &mut mutable.clone(), signed)?;
+            }
+            ast::Statement::Break(value) => {
+                if let Some(value) = value {
+                    check_expression_mutability(value, mutable, signed)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the assignments in block expressions nested in `expression`.
+fn check_expression_mutability<'a>(
+    expression: &'a ast::Expression,
+    mutable: &HashMap<&'a String, Binding>,
+    signed: &SignedOperations,
+) -> Result<(), ast::Spanned<Error>> {
+    match expression {
+        ast::Expression::Call(_, expressions) | ast::Expression::ArrayLiteral(expressions) => {
+            for expression in expressions {
+                check_expression_mutability(expression, mutable, signed)?;
+            }
+        }
+        ast::Expression::Add(a, b)
+        | ast::Expression::Sub(a, b)
+        | ast::Expression::Mul(a, b)
+        | ast::Expression::Div(a, b)
+        | ast::Expression::Mod(a, b)
+        | ast::Expression::Index(a, b)
+        | ast::Expression::Compare(_, a, b)
+        | ast::Expression::And(a, b)
+        | ast::Expression::Or(a, b) => {
+            check_expression_mutability(a, mutable, signed)?;
+            check_expression_mutability(b, mutable, signed)?;
+        }
+        ast::Expression::Block(body, tail) => {
+            let mut mutable = mutable.clone();
+            check_mutability(body, &mut mutable, signed)?;
+            check_expression_mutability(tail, &mutable, signed)?;
+        }
+        ast::Expression::Loop(body) => check_mutability(body, &mut mutable.clone(), signed)?,
+        ast::Expression::Negate(a) | ast::Expression::Not(a) => {
+            check_expression_mutability(a, mutable, signed)?
+        }
+        ast::Expression::Match(value, arms, wildcard) => {
+            check_expression_mutability(value, mutable, signed)?;
+            for arm in arms.iter().map(|(_, arm)| arm).chain(wildcard.as_deref()) {
+                check_expression_mutability(arm, mutable, signed)?;
+            }
+        }
+        ast::Expression::If(condition, then_branch, else_branch) => {
+            check_expression_mutability(condition, mutable, signed)?;
+            check_expression_mutability(then_branch, mutable, signed)?;
+            check_expression_mutability(else_branch, mutable, signed)?;
+        }
+        ast::Expression::Variable(_)
+        | ast::Expression::NumLiteral(_)
+        | ast::Expression::NegativeLiteral(_)
+        | ast::Expression::SuffixedLiteral(..)
+        | ast::Expression::BoolLiteral(_)
+        | ast::Expression::StrLiteral(_) => {}
+    }
+    Ok(())
+}
+
+fn check_block<'a>(
+    body: &'a [ast::Spanned<ast::Statement>],
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    let mut local_vars = env.clone();
+    for statement in body {
+        check_statement(statement, return_type, &mut local_vars)?;
+    }
+    Ok(())
+}
+
+/// Checks `expression` against the expected type `typ`. `return_type` is the return
+/// type of the enclosing function, needed for `return`s inside block expressions.
+pub fn check_expression<'a>(
+    expression: &'a ast::Expression,
+    typ: &ast::Type,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    match expression {
+        ast::Expression::Call(function, expressions) => {
+            let result_type = infer_call(function, expressions, return_type, env)?;
+            if typ != &result_type {
+                return Err(Error::NonMatchingTypes(typ.clone(), result_type).into());
+            }
+        }
         ast::Expression::Variable(name) => match env.get(name) {
             Some(var_type) => {
                 if var_type == typ {
                     return Ok(());
                 } else {
-                    return Err(Error::NonMatchingTypes(typ.clone(), var_type.clone()));
+                    return Err(Error::NonMatchingTypes(typ.clone(), var_type.clone()).into());
+                }
+            }
+            None => return Err(Error::UndeclaredVariable(name.clone()).into()),
+        },
+        ast::Expression::NumLiteral(n) => match typ {
+            ast::Type::U32 | ast::Type::I32 | ast::Type::U8 | ast::Type::U16 => {
+                check_literal_range(*n, typ)?
+            }
+            _ => return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::U32).into()),
+        },
+        ast::Expression::SuffixedLiteral(n, suffix) => {
+            if typ != suffix {
+                return Err(Error::NonMatchingTypes(typ.clone(), suffix.clone()).into());
+            }
+            check_literal_range(*n, suffix)?;
+        }
+        ast::Expression::NegativeLiteral(n) => match typ {
+            ast::Type::I32 if *n <= i32::MIN.unsigned_abs() => {}
+            ast::Type::I32 => return Err(Error::LiteralOutOfRange(ast::Type::I32).into()),
+            _ => return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::I32).into()),
+        },
+        ast::Expression::BoolLiteral(_) => {
+            if !matches!(typ, ast::Type::Bool) {
+                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::Bool).into());
+            }
+        }
+        ast::Expression::StrLiteral(_) => {
+            let found = infer_expression(expression, return_type, env)?;
+            if typ != &found {
+                return Err(Error::NonMatchingTypes(typ.clone(), found).into());
+            }
+        }
+        ast::Expression::Negate(a) => {
+            if typ != &ast::Type::I32 {
+                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::I32).into());
+            }
+            check_operand(a, typ, number_operand, return_type, env)?;
+        }
+        ast::Expression::Not(a) => {
+            if typ != &ast::Type::Bool {
+                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::Bool).into());
+            }
+            check_expression(a, typ, return_type, env)?;
+        }
+        ast::Expression::And(a, b) | ast::Expression::Or(a, b) => {
+            if typ != &ast::Type::Bool {
+                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::Bool).into());
+            }
+            check_expression(a, typ, return_type, env)?;
+            check_expression(b, typ, return_type, env)?;
+        }
+        ast::Expression::Add(a, b)
+        | ast::Expression::Sub(a, b)
+        | ast::Expression::Mul(a, b)
+        | ast::Expression::Div(a, b)
+        | ast::Expression::Mod(a, b) => {
+            check_arithmetic_type(expression, typ, env)?;
+            check_operand(a, typ, number_operand, return_type, env)?;
+            check_operand(b, typ, number_operand, return_type, env)?;
+        }
+        ast::Expression::ArrayLiteral(elements) => match typ {
+            ast::Type::Array(element_type, length) if *length as usize == elements.len() => {
+                for element in elements {
+                    check_expression(element, element_type, return_type, env)?;
                 }
             }
-            None => return Err(Error::UndeclaredVariable(name.clone())),
+            _ => {
+                let found = infer_expression(expression, return_type, env)?;
+                return Err(Error::NonMatchingTypes(typ.clone(), found).into());
+            }
         },
-        ast::Expression::NumLiteral(_) => {
-            if !matches!(typ, ast::Type::U32) {
-                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::U32));
+        ast::Expression::Index(array, index) => {
+            let element_type = infer_element(array, index, return_type, env)?;
+            if typ != &element_type {
+                return Err(Error::NonMatchingTypes(typ.clone(), element_type).into());
             }
         }
-        ast::Expression::Add(expression, expression1)
-        | ast::Expression::Sub(expression, expression1)
-        | ast::Expression::Mul(expression, expression1)
-        | ast::Expression::Div(expression, expression1)
-        | ast::Expression::Mod(expression, expression1) => {
-            if typ == &ast::Type::U32 {
-                return check_expression(expression, typ, env).and(check_expression(
-                    expression1,
-                    typ,
-                    env,
-                ));
-            } else {
-                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::U32));
+        ast::Expression::Block(body, tail) => {
+            let mut local_vars = env.clone();
+            for statement in body {
+                check_statement(statement, return_type, &mut local_vars)?;
             }
+            return check_expression(tail, typ, return_type, &local_vars);
+        }
+        ast::Expression::Loop(body) => {
+            let mut env = env.clone();
+            env.insert(&BREAK_TYPE, typ.clone());
+            check_block(body, return_type, &env)?;
+        }
+        ast::Expression::If(condition, then_branch, else_branch) => {
+            check_expression(condition, &ast::Type::Bool, return_type, env)?;
+            check_expression(then_branch, typ, return_type, env)?;
+            check_expression(else_branch, typ, return_type, env)?;
+        }
+        ast::Expression::Match(value, arms, wildcard) => {
+            check_expression(value, &ast::Type::U32, return_type, env)?;
+            let Some(wildcard) = wildcard else {
+                return Err(Error::NonExhaustiveMatch.into());
+            };
+            for (_, arm) in arms {
+                check_expression(arm, typ, return_type, env)?;
+            }
+            check_expression(wildcard, typ, return_type, env)?;
+        }
+        ast::Expression::Compare(comparison, a, b) => {
+            if typ != &ast::Type::Bool {
+                return Err(Error::NonMatchingTypes(typ.clone(), ast::Type::Bool).into());
+            }
+            check_comparison(expression, *comparison, a, b, return_type, env)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the arithmetic operator `expression` works on numbers of type `typ`,
+/// noting it in `env` when it works on signed numbers.
+fn check_arithmetic_type(
+    expression: &ast::Expression,
+    typ: &ast::Type,
+    env: &Scope,
+) -> Result<(), Error> {
+    match typ {
+        ast::Type::U32 => Ok(()),
+        ast::Type::I32 => {
+            env.note_signed(expression);
+            Ok(())
+        }
+        ast::Type::U8 | ast::Type::U16 => Err(Error::NarrowArithmetic(typ.clone())),
+        _ => Err(Error::NonMatchingTypes(typ.clone(), ast::Type::U32)),
+    }
+}
+
+/// Checks the operands of a comparison, which must be numbers of the same kind, or
+/// for `==` and `!=` also bools.
+fn check_comparison<'a>(
+    expression: &ast::Expression,
+    comparison: ast::Comparison,
+    a: &'a ast::Expression,
+    b: &'a ast::Expression,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    let equality = matches!(
+        comparison,
+        ast::Comparison::Equal | ast::Comparison::NotEqual
+    );
+    let operand = if equality {
+        equatable_operand
+    } else {
+        number_operand
+    };
+    let typ = infer_operands(a, b, operand, return_type, env)?;
+    if typ == ast::Type::I32 && !equality {
+        env.note_signed(expression);
+    }
+    Ok(())
+}
+
+fn number_operand(typ: &ast::Type) -> Result<(), Error> {
+    if is_number(typ) {
+        Ok(())
+    } else {
+        Err(Error::ArithmeticOnNonNumber(typ.clone()))
+    }
+}
+
+/// Bools are stored as 0 or 1, so they can be compared for equality like numbers.
+fn equatable_operand(typ: &ast::Type) -> Result<(), Error> {
+    match typ {
+        ast::Type::Bool => Ok(()),
+        typ => number_operand(typ),
+    }
+}
+
+/// Whether `expression` can only be given a type by checking it against the one
+/// expected of it, as it may be made of literals, which fit any kind of number.
+fn takes_expected_type(expression: &ast::Expression) -> bool {
+    matches!(
+        expression,
+        ast::Expression::NumLiteral(_)
+            | ast::Expression::Add(..)
+            | ast::Expression::Sub(..)
+            | ast::Expression::Mul(..)
+            | ast::Expression::Div(..)
+            | ast::Expression::Mod(..)
+            | ast::Expression::If(..)
+            | ast::Expression::Block(..)
+            | ast::Expression::Match(..)
+            | ast::Expression::Loop(_)
+    )
+}
+
+/// The type both operands of a binary operator have, which `operand` must accept.
+/// An operand that takes its type from the one expected of it is checked against
+/// the type of the other, so that each is only looked at once, unless both are
+/// like that and only the second turns out not to be a `u32`.
+fn infer_operands<'a>(
+    a: &'a ast::Expression,
+    b: &'a ast::Expression,
+    operand: fn(&ast::Type) -> Result<(), Error>,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<ast::Type, ast::Spanned<Error>> {
+    let (first, second) = if takes_expected_type(a) && !takes_expected_type(b) {
+        (b, a)
+    } else {
+        (a, b)
+    };
+    let typ = infer_expression(first, return_type, env)?;
+    operand(&typ)?;
+    // Then `second` takes its type from the one expected of it too.
+    if takes_expected_type(first) && typ == ast::Type::U32 {
+        let second_type = infer_expression(second, return_type, env)?;
+        operand(&second_type)?;
+        if second_type != ast::Type::U32 {
+            check_expression(first, &second_type, return_type, env)?;
+        }
+        return Ok(second_type);
+    }
+    check_operand(second, &typ, operand, return_type, env)?;
+    Ok(typ)
+}
+
+/// Checks that an operand of a binary operator, which `operand` must accept, has
+/// type `typ`.
+fn check_operand<'a>(
+    expression: &'a ast::Expression,
+    typ: &ast::Type,
+    operand: fn(&ast::Type) -> Result<(), Error>,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    if takes_expected_type(expression) {
+        return check_expression(expression, typ, return_type, env);
+    }
+    let found = infer_expression(expression, return_type, env)?;
+    operand(&found)?;
+    if &found != typ {
+        return Err(Error::NonMatchingTypes(typ.clone(), found).into());
+    }
+    Ok(())
+}
+
+fn is_number(typ: &ast::Type) -> bool {
+    matches!(
+        typ,
+        ast::Type::U32 | ast::Type::I32 | ast::Type::U8 | ast::Type::U16
+    )
+}
+
+/// Checks that the literal `n` can be stored in the number type `typ`.
+fn check_literal_range(n: u32, typ: &ast::Type) -> Result<(), Error> {
+    let max = match typ {
+        ast::Type::U8 => u8::MAX as u32,
+        ast::Type::U16 => u16::MAX as u32,
+        ast::Type::I32 => i32::MAX as u32,
+        _ => u32::MAX,
+    };
+    if n > max {
+        return Err(Error::LiteralOutOfRange(typ.clone()));
+    }
+    Ok(())
+}
+
+fn check_arguments<'a>(
+    function: &str,
+    expressions: &'a [ast::Expression],
+    arg_types: &[ast::Type],
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<(), ast::Spanned<Error>> {
+    if expressions.len() != arg_types.len() {
+        return Err(Error::ArgumentCountMismatch {
+            function: function.to_string(),
+            expected: arg_types.len(),
+            found: expressions.len(),
         }
+        .into());
+    }
+    for (index, (expression, arg_type)) in expressions.iter().zip(arg_types).enumerate() {
+        // Checked rather than inferred, so that literals fit any kind of number.
+        check_expression(expression, arg_type, return_type, env).map_err(|error| {
+            match error.node {
+                Error::NonMatchingTypes(expected, found) if &expected == arg_type => {
+                    Error::ArgumentTypeMismatch {
+                        function: function.to_string(),
+                        index,
+                        expected,
+                        found,
+                    }
+                    .into()
+                }
+                _ => error,
+            }
+        })?;
     }
     Ok(())
 }
+
+fn infer_call<'a>(
+    function: &String,
+    expressions: &'a [ast::Expression],
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<ast::Type, ast::Spanned<Error>> {
+    // `len` is an intrinsic rather than a builtin, as it takes any kind of array.
+    if function == "len" {
+        return infer_len(expressions, return_type, env);
+    }
+    match env.get(function) {
+        Some(ast::Type::Fun(arg_types, result_type)) => {
+            check_arguments(function, expressions, arg_types, return_type, env)?;
+            Ok(result_type.as_ref().clone())
+        }
+        Some(t) => Err(Error::CallingNonFunction(function.clone(), t.clone()).into()),
+        None => Err(Error::UndeclaredVariable(function.clone()).into()),
+    }
+}
+
+/// `len(array)`, which works on arrays of any element type and length.
+fn infer_len<'a>(
+    expressions: &'a [ast::Expression],
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<ast::Type, ast::Spanned<Error>> {
+    let [array] = expressions else {
+        return Err(Error::ArgumentCountMismatch {
+            function: "len".to_string(),
+            expected: 1,
+            found: expressions.len(),
+        }
+        .into());
+    };
+    match infer_expression(array, return_type, env)? {
+        ast::Type::Array(..) => Ok(ast::Type::U32),
+        typ => Err(Error::NotAnArray(typ).into()),
+    }
+}
+
+/// The type of the elements of `array`, which `index` must be a `u32` index into.
+fn infer_element<'a>(
+    array: &'a ast::Expression,
+    index: &'a ast::Expression,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<ast::Type, ast::Spanned<Error>> {
+    let element_type = match infer_expression(array, return_type, env)? {
+        ast::Type::Array(element_type, _) => *element_type,
+        typ => return Err(Error::NotAnArray(typ).into()),
+    };
+    check_expression(index, &ast::Type::U32, return_type, env)?;
+    Ok(element_type)
+}
+
+/// Works out the type of `expression` without an expected type to check against.
+pub fn infer_expression<'a>(
+    expression: &'a ast::Expression,
+    return_type: &ast::Type,
+    env: &Scope<'a>,
+) -> Result<ast::Type, ast::Spanned<Error>> {
+    match expression {
+        ast::Expression::Call(function, expressions) => {
+            match infer_call(function, expressions, return_type, env)? {
+                typ @ ast::Type::Tuple(_) => Err(Error::UndestructuredTuple(typ).into()),
+                typ => Ok(typ),
+            }
+        }
+        ast::Expression::Variable(name) => env
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UndeclaredVariable(name.clone()).into()),
+        ast::Expression::NumLiteral(_) => Ok(ast::Type::U32),
+        ast::Expression::SuffixedLiteral(_, suffix) => {
+            check_expression(expression, suffix, return_type, env)?;
+            Ok(suffix.clone())
+        }
+        ast::Expression::NegativeLiteral(_) => {
+            check_expression(expression, &ast::Type::I32, return_type, env)?;
+            Ok(ast::Type::I32)
+        }
+        ast::Expression::BoolLiteral(_) => Ok(ast::Type::Bool),
+        ast::Expression::Negate(_) => {
+            check_expression(expression, &ast::Type::I32, return_type, env)?;
+            Ok(ast::Type::I32)
+        }
+        ast::Expression::Not(_) | ast::Expression::And(..) | ast::Expression::Or(..) => {
+            check_expression(expression, &ast::Type::Bool, return_type, env)?;
+            Ok(ast::Type::Bool)
+        }
+        ast::Expression::Add(a, b)
+        | ast::Expression::Sub(a, b)
+        | ast::Expression::Mul(a, b)
+        | ast::Expression::Div(a, b)
+        | ast::Expression::Mod(a, b) => {
+            let typ = infer_operands(a, b, number_operand, return_type, env)?;
+            check_arithmetic_type(expression, &typ, env)?;
+            Ok(typ)
+        }
+        ast::Expression::StrLiteral(text) => Ok(ast::Type::Array(
+            Box::new(ast::Type::U32),
+            text.chars().count() as u32,
+        )),
+        ast::Expression::ArrayLiteral(elements) => {
+            let element_type = infer_expression(&elements[0], return_type, env)?;
+            for element in &elements[1..] {
+                check_expression(element, &element_type, return_type, env)?;
+            }
+            Ok(ast::Type::Array(
+                Box::new(element_type),
+                elements.len() as u32,
+            ))
+        }
+        ast::Expression::Index(array, index) => infer_element(array, index, return_type, env),
+        ast::Expression::Block(body, tail) => {
+            let mut local_vars = env.clone();
+            for statement in body {
+                check_statement(statement, return_type, &mut local_vars)?;
+            }
+            infer_expression(tail, return_type, &local_vars)
+        }
+        // A loop without a `break` never produces a value, so any type would do.
+        ast::Expression::Loop(body) => {
+            let mut env = env.clone();
+            env.remove(&BREAK_TYPE);
+            let typ = break_type(body, return_type, &env).unwrap_or(ast::Type::Unit);
+            check_expression(expression, &typ, return_type, &env)?;
+            Ok(typ)
+        }
+        ast::Expression::If(condition, then_branch, else_branch) => {
+            check_expression(condition, &ast::Type::Bool, return_type, env)?;
+            let typ = infer_expression(then_branch, return_type, env)?;
+            check_expression(else_branch, &typ, return_type, env)?;
+            Ok(typ)
+        }
+        // The arms must agree with the first one.
+        ast::Expression::Match(value, arms, wildcard) => {
+            check_expression(value, &ast::Type::U32, return_type, env)?;
+            let Some(wildcard) = wildcard else {
+                return Err(Error::NonExhaustiveMatch.into());
+            };
+            let mut arms = arms.iter().map(|(_, arm)| arm).chain([&**wildcard]);
+            let typ = infer_expression(arms.next().unwrap(), return_type, env)?;
+            for arm in arms {
+                check_expression(arm, &typ, return_type, env)?;
+            }
+            Ok(typ)
+        }
+        ast::Expression::Compare(comparison, a, b) => {
+            check_comparison(expression, *comparison, a, b, return_type, env)?;
+            Ok(ast::Type::Bool)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler, parser};
+
+    #[test]
+    fn integer_condition_is_rejected() {
+        let program = parser::parse("fn main(): u32 { if 1 {} return 0; }").unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NonMatchingTypes(ast::Type::Bool, ast::Type::U32))
+        ));
+
+        let program = parser::parse("fn main(): u32 { while 5 {} return 0; }").unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NonMatchingTypes(ast::Type::Bool, ast::Type::U32))
+        ));
+    }
+
+    #[test]
+    fn breaks_must_agree_with_their_loop() {
+        let error = |source| {
+            check(&parser::parse(source).unwrap())
+                .map_err(|error| error.node)
+                .err()
+        };
+        assert!(matches!(
+            error("fn main(): u32 { break; return 0; }"),
+            Some(Error::BreakOutsideLoop)
+        ));
+        assert!(matches!(
+            error("fn main(): u32 { return loop { if true { break 1; } break false; }; }"),
+            Some(Error::NonMatchingTypes(ast::Type::U32, ast::Type::Bool))
+        ));
+        assert!(matches!(
+            error("fn main(): u32 { while true { break 1; } return 0; }"),
+            Some(Error::NonMatchingTypes(ast::Type::Unit, ast::Type::U32))
+        ));
+        assert!(error("fn main(): u32 { let x = loop { break true; }; return 0; }").is_none());
+    }
+
+    #[test]
+    fn entry_point_cannot_be_shadowed() {
+        let error = |source| {
+            check(&parser::parse(source).unwrap())
+                .err()
+                .map(|error| error.node)
+        };
+        assert!(matches!(
+            error("fn f(main: u32): u32 { return main; } fn main(): u32 { return f(1); }"),
+            Some(Error::ShadowsEntryPoint)
+        ));
+        assert!(matches!(
+            error("fn main(): u32 { let main = 1; return main; }"),
+            Some(Error::ShadowsEntryPoint)
+        ));
+        assert!(error("fn main(): u32 { let mains = 1; return mains; }").is_none());
+    }
+
+    #[test]
+    fn other_entry_points_cannot_be_shadowed() {
+        let error = |source| {
+            check_with_entry(&parser::parse(source).unwrap(), "start")
+                .err()
+                .map(|error| error.node)
+        };
+        assert!(matches!(
+            error("fn f(start: u32): u32 { return start; } fn start(): u32 { return f(1); }"),
+            Some(Error::ShadowsEntryPoint)
+        ));
+        assert!(matches!(
+            error("fn start(): u32 { let start = 1; return start; }"),
+            Some(Error::ShadowsEntryPoint)
+        ));
+        assert!(error("fn start(): u32 { let main = 1; return main; }").is_none());
+    }
+
+    #[test]
+    fn match_arms_must_be_exhaustive_and_agree() {
+        let error = |body| {
+            let source = format!("fn main(): u32 {{ let x = 1; return {body}; }}");
+            check(&parser::parse(&source).unwrap())
+                .err()
+                .map(|error| error.node)
+        };
+        assert!(matches!(
+            error("match x { 0 => 1, 1 => 2 }"),
+            Some(Error::NonExhaustiveMatch)
+        ));
+        assert!(matches!(
+            error("match x { 0 => 1, _ => false }"),
+            Some(Error::NonMatchingTypes(..))
+        ));
+        assert!(matches!(
+            error("match true { 0 => 1, _ => 2 }"),
+            Some(Error::NonMatchingTypes(..))
+        ));
+        assert!(error("match x { 0 => 1, _ => 2 }").is_none());
+        assert!(error("match x { _ => 2, }").is_none());
+    }
+
+    #[test]
+    fn const_initializers_must_be_constant() {
+        let error = |body| {
+            let source = format!("fn two(): u32 {{ return 2; }} fn main(): u32 {{ {body} }}");
+            check(&parser::parse(&source).unwrap())
+                .err()
+                .map(|error| error.node)
+        };
+        assert!(error("const N: u32 = 2 + 3; const M: bool = N * 2 > 9; return N;").is_none());
+        for body in [
+            "let x = 1; const N: u32 = x + 1; return N;",
+            "const N: u32 = two(); return N;",
+            "const N: u32 = 1 / 0; return N;",
+            "const N: u32 = { 1 }; return N;",
+        ] {
+            assert!(
+                matches!(error(body), Some(Error::NotConstant(name)) if name == "N"),
+                "{body}"
+            );
+        }
+        assert!(matches!(
+            error("const N: u32 = 1; N = 2; return N;"),
+            Some(Error::AssignToImmutable(_))
+        ));
+        assert!(matches!(
+            error("const N: u32 = true; return N;"),
+            Some(Error::NonMatchingTypes(..))
+        ));
+    }
+
+    #[test]
+    fn bool_condition_is_accepted() {
+        let program = parser::parse("fn main(): u32 { if true {} return 0; }").unwrap();
+        assert!(check(&program).is_ok());
+    }
+
+    #[test]
+    fn let_initializer_is_inferred() {
+        let program = parser::parse(
+            "fn main(): u32 {
+                let c = true;
+                let y = if c { 1 } else { 2 };
+                return y;
+            }",
+        )
+        .unwrap();
+        assert!(check(&program).is_ok());
+
+        let program =
+            parser::parse("fn main(): u32 { let y = if true { 1 } else { false }; return 0; }")
+                .unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NonMatchingTypes(ast::Type::U32, ast::Type::Bool))
+        ));
+    }
+
+    #[test]
+    fn len_requires_an_array() {
+        let program =
+            parser::parse("fn main(): u32 { let a: [u32; 2] = [1, 2]; return len(a); }").unwrap();
+        assert!(check(&program).is_ok());
+
+        let program = parser::parse("fn main(): u32 { return len(5); }").unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NotAnArray(ast::Type::U32))
+        ));
+
+        let program =
+            parser::parse("fn main(): u32 { let a: [u32; 3] = [1, 2]; return 0; }").unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NonMatchingTypes(
+                ast::Type::Array(_, 3),
+                ast::Type::Array(_, 2)
+            ))
+        ));
+    }
+
+    #[test]
+    fn argument_mismatch_reports_index() {
+        let program = parser::parse(
+            "fn pick(a: u32, b: u32): u32 { return a; }
+            fn main(): u32 { return pick(1, true); }",
+        )
+        .unwrap();
+        match check(&program).map_err(|error| error.node) {
+            Err(Error::ArgumentTypeMismatch {
+                function,
+                index,
+                expected,
+                found,
+            }) => {
+                assert_eq!(function, "pick");
+                assert_eq!(index, 1);
+                assert_eq!(expected, ast::Type::U32);
+                assert_eq!(found, ast::Type::Bool);
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    #[test]
+    fn literal_arguments_fit_any_number() {
+        let program = parser::parse(
+            "fn signed(a: i32): i32 { return a; }
+            fn byte(a: u8): u8 { return a; }
+            fn main(): u32 { signed(5); signed(-5); byte(255); return 0; }",
+        )
+        .unwrap();
+        check(&program).unwrap();
+
+        let program = parser::parse(
+            "fn byte(a: u8): u8 { return a; }
+            fn main(): u32 { byte(256); return 0; }",
+        )
+        .unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::LiteralOutOfRange(ast::Type::U8))
+        ));
+    }
+
+    #[test]
+    fn function_in_arithmetic_is_reported() {
+        let program = parser::parse(
+            "fn one(): u32 { return 1; }
+            fn main(): u32 { return one + 1; }",
+        )
+        .unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::ArithmeticOnNonNumber(ast::Type::Fun(..)))
+        ));
+    }
+
+    #[test]
+    fn suffixed_literals_must_fit_their_type() {
+        let check_main = |body: &str| {
+            let source = format!("fn main(): u32 {{ {body} return 0; }}");
+            check(&parser::parse(&source).unwrap()).map_err(|error| error.node)
+        };
+        assert!(check_main("let a: u8 = 255u8; if a == 3 { return 1; }").is_ok());
+        assert!(check_main("let b = 42u32 + 1;").is_ok());
+        assert!(matches!(
+            check_main("let a = 256u8;"),
+            Err(Error::LiteralOutOfRange(ast::Type::U8))
+        ));
+        assert!(matches!(
+            check_main("let a: u16 = 1u8;"),
+            Err(Error::NonMatchingTypes(ast::Type::U16, ast::Type::U8))
+        ));
+        assert!(matches!(
+            check_main("let a = 1u8 + 1;"),
+            Err(Error::NarrowArithmetic(ast::Type::U8))
+        ));
+    }
+
+    #[test]
+    fn bools_compare_for_equality() {
+        let source = |condition: &str| {
+            format!("fn main(): u32 {{ if {condition} {{ return 1; }} return 0; }}")
+        };
+        let program = parser::parse(&source("true == false")).unwrap();
+        check(&program).unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 0);
+        let program = parser::parse(&source("true != false")).unwrap();
+        assert_eq!(compiler::compile(&program).unwrap().run().unwrap(), 1);
+
+        let check_source = |condition| {
+            check(&parser::parse(&source(condition)).unwrap()).map_err(|error| error.node)
+        };
+        assert!(matches!(
+            check_source("true == 1"),
+            Err(Error::NonMatchingTypes(ast::Type::Bool, ast::Type::U32))
+        ));
+        assert!(matches!(
+            check_source("true < false"),
+            Err(Error::ArithmeticOnNonNumber(ast::Type::Bool))
+        ));
+    }
+
+    #[test]
+    fn only_mut_variables_can_be_assigned() {
+        let program = parser::parse(
+            "fn main(): u32 {
+                let mut total = 0;
+                let mut i = 0;
+                while i < 4 {
+                    i = i + 1;
+                    total = total + i;
+                }
+                return total;
+            }",
+        )
+        .unwrap();
+        check(&program).unwrap();
+        assert_eq!(compiler::compile(&program).unwrap().run().unwrap(), 10);
+
+        let program = parser::parse(
+            "fn main(): u32 {
+                let x = 5;
+                if true { x = 6; }
+                return x;
+            }",
+        )
+        .unwrap();
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::AssignToImmutable(name)) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn only_arrays_in_mut_variables_can_be_written_to() {
+        let check_main = |body: &str| {
+            let source = format!(
+                "fn first(a: [u32; 2]): [u32; 2] {{ return a; }}
+                fn main(): u32 {{ {body} }}"
+            );
+            check(&parser::parse(&source).unwrap()).map_err(|error| error.node)
+        };
+        check_main("let mut a = [1, 2]; a[0] = 5; return a[0];").unwrap();
+        assert!(matches!(
+            check_main("let a = [1, 2]; a[0] = 5; return a[0];"),
+            Err(Error::AssignToImmutable(name)) if name == "a"
+        ));
+        // Arrays are shared, so this would change `a` too.
+        assert!(matches!(
+            check_main("let mut a = [1, 2]; let b = a; b[0] = 9; return a[0];"),
+            Err(Error::AssignToImmutable(name)) if name == "b"
+        ));
+        assert!(matches!(
+            check_main("let a = [1, 2]; first(a)[0] = 9; return a[0];"),
+            Err(Error::AssignToTemporary)
+        ));
+    }
+
+    #[test]
+    fn deep_nesting_checks_quickly() {
+        // Looking at each operand more than once per level would double the work
+        // at every level, and these would never finish.
+        let depth = 60;
+        let mut arithmetic = "x".to_string();
+        let mut comparison = "x == 1".to_string();
+        let mut arms = "1".to_string();
+        for level in 0..depth {
+            arithmetic = match level % 3 {
+                0 => format!("(1 + {arithmetic})"),
+                1 => format!("({arithmetic} * 2 - 3)"),
+                _ => format!("-({arithmetic})"),
+            };
+            comparison = format!("({comparison}) == (x < {level})");
+            arms = format!("match n {{ 0 => {arms}, _ => 2 }}");
+        }
+        let source = format!(
+            "fn main(): u32 {{
+                let x: i32 = 4;
+                let y = {arithmetic};
+                let n = 0;
+                if {} {{ return {arms}; }}
+                return 0;
+            }}",
+            comparison.replace("x", "n")
+        );
+        let program = parser::parse(&source).unwrap();
+        check(&program).unwrap();
+    }
+
+    #[test]
+    fn errors_in_every_function_are_reported() {
+        let program = parser::parse(
+            "fn f(): u32 { return true; }
+            fn g(): u32 { return 1 + false; }
+            fn main(): u32 { return f() + g(); }",
+        )
+        .unwrap();
+        let errors = check_all(&program).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0].node,
+            Error::NonMatchingTypes(ast::Type::U32, ast::Type::Bool)
+        ));
+        assert!(matches!(
+            errors[1].node,
+            Error::ArithmeticOnNonNumber(ast::Type::Bool)
+        ));
+        assert!(matches!(
+            check(&program).map_err(|error| error.node),
+            Err(Error::NonMatchingTypes(..))
+        ));
+    }
+}
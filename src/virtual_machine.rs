@@ -1,35 +1,142 @@
-use std::rc::Rc;
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    ops::Range,
+    rc::Rc,
+};
 
-#[derive(Debug)]
+use crate::ast::{Span, Type};
+
+/// The width of every stack and heap slot: 32 bits by default, or 64 bits with the
+/// `word64` feature.
+#[cfg(not(feature = "word64"))]
+pub type Word = u32;
+#[cfg(feature = "word64")]
+pub type Word = u64;
+
+/// A `Word` read as a two's complement number, which is how `i32` values are
+/// stored.
+#[cfg(not(feature = "word64"))]
+pub type SignedWord = i32;
+#[cfg(feature = "word64")]
+pub type SignedWord = i64;
+
+/// `a / b` with both read as signed numbers, or `None` when dividing by zero.
+pub(crate) fn signed_div(a: Word, b: Word) -> Option<Word> {
+    (b != 0).then(|| (a as SignedWord).wrapping_div(b as SignedWord) as Word)
+}
+
+/// `a % b` with both read as signed numbers, or `None` when dividing by zero.
+pub(crate) fn signed_rem(a: Word, b: Word) -> Option<Word> {
+    (b != 0).then(|| (a as SignedWord).wrapping_rem(b as SignedWord) as Word)
+}
+
+/// `a + b` with both read as signed numbers, or `None` when it overflows.
+pub(crate) fn signed_checked_add(a: Word, b: Word) -> Option<Word> {
+    (a as SignedWord)
+        .checked_add(b as SignedWord)
+        .map(|value| value as Word)
+}
+
+/// `a - b` with both read as signed numbers, or `None` when it overflows.
+pub(crate) fn signed_checked_sub(a: Word, b: Word) -> Option<Word> {
+    (a as SignedWord)
+        .checked_sub(b as SignedWord)
+        .map(|value| value as Word)
+}
+
+/// `a * b` with both read as signed numbers, or `None` when it overflows.
+pub(crate) fn signed_checked_mul(a: Word, b: Word) -> Option<Word> {
+    (a as SignedWord)
+        .checked_mul(b as SignedWord)
+        .map(|value| value as Word)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
+    Nop,
     // Stack Operations
-    Push(u32),
+    Push(Word),
+    PushBool(bool),
+    /// Pushes a run of values from the function's constants, starting at the
+    /// given index, whose slot holds how many values follow it. Keeping the values
+    /// out of the operation keeps every operation two words long.
+    PushMany(u32),
     Pop,
     Get(u32),
     Put(u32),
+    /// Exchanges the top two values.
+    Swap,
     // Heap Operations
     Store(u32),
     Load(u32),
     Allocate(u32),
     Free(u32),
+    /// Pops a value and then an address, and stores the value `offset` slots past
+    /// the address.
+    StoreIndirect(u32),
+    /// Pops an address and pushes the value `offset` slots past it.
+    LoadIndirect(u32),
+    /// Pushes the element with the given index of the array the given depth down
+    /// the stack, like `Get`, `Push`, `CheckIndex`, `Add` then `LoadIndirect(1)`
+    /// but without the copies. The operands are narrow so that the operation stays
+    /// two words long with 32 bit words.
+    LoadElement(u16, u16),
+    /// Stops with `IndexOutOfBounds` unless the top value is less than the length
+    /// of the array whose address is below it. Leaves both in place.
+    CheckIndex,
+    /// Pops an address and the `count` values below it, stores them at the address
+    /// with the deepest first, and pushes the address back.
+    StoreMany(u32),
     // Function Operations
     Call(u32),
+    /// Pops a function id and calls that function.
     CallFnPointer,
+    /// Pushes the id of a function, for `CallFnPointer` to call. It is the same
+    /// word as `Push` of the id, but tagged as a function when type tags are
+    /// enabled.
+    PushFunction(u32),
     Return,
     // Arithmatic Operations
-    AddImmediate(u32),
+    AddImmediate(Word),
     Add,
-    SubImmediate(u32),
-    SubImmediateBy(u32),
+    SubImmediate(Word),
+    SubImmediateBy(Word),
     Sub,
-    MulImmediate(u32),
+    MulImmediate(Word),
     Mul,
-    DivImmediate(u32),
-    DivImmediateBy(u32),
+    DivImmediate(Word),
+    DivImmediateBy(Word),
     Div,
-    ModImmediate(u32),
-    ModImmediateBy(u32),
+    ModImmediate(Word),
+    ModImmediateBy(Word),
     Mod,
+    /// Like `Add`, `Sub` and `Mul`, but clamped to `0..=Word::MAX` instead of
+    /// wrapping, even in strict mode.
+    AddSat,
+    SubSat,
+    MulSat,
+    /// Like `Add`, `Sub` and `Mul`, but stopping with `Overflow` instead of
+    /// wrapping, even outside strict mode.
+    AddChecked,
+    SubChecked,
+    MulChecked,
+    /// Like `AddChecked`, `SubChecked` and `MulChecked`, but reading the operands
+    /// as signed numbers.
+    AddCheckedSigned,
+    SubCheckedSigned,
+    MulCheckedSigned,
+    /// Like `Div` and `Mod`, but reading the operands as signed numbers.
+    DivSigned,
+    ModSigned,
+    // Comparison Operations, pushing a bool
+    Equal,
+    NotEqual,
+    // `a > b` is compiled as `b < a`, so there are no `Greater` operations.
+    Less,
+    LessEqual,
+    LessSigned,
+    LessEqualSigned,
     // Control Flow
     Jump(u32),
     JumpIf(u32),
@@ -39,6 +146,270 @@ pub enum Operation {
     GotoIfNot,
 }
 
+// An operation is already laid out as an opcode followed by one word of operand.
+// The `flat-dispatch` feature splits code into a byte per opcode and a separate
+// operand array instead; `dispatch_speed` compares the two. Grow this with care.
+const _: () = assert!(std::mem::size_of::<Operation>() == 2 * std::mem::size_of::<Word>());
+
+/// The kind of an `Operation`, without its operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Opcode {
+    Nop,
+    Push,
+    PushBool,
+    Pop,
+    Get,
+    Put,
+    Swap,
+    Store,
+    Load,
+    Allocate,
+    Free,
+    StoreIndirect,
+    LoadIndirect,
+    Call,
+    CallFnPointer,
+    Return,
+    AddImmediate,
+    Add,
+    SubImmediate,
+    SubImmediateBy,
+    Sub,
+    MulImmediate,
+    Mul,
+    DivImmediate,
+    DivImmediateBy,
+    Div,
+    ModImmediate,
+    ModImmediateBy,
+    Mod,
+    AddSat,
+    SubSat,
+    MulSat,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Jump,
+    JumpIf,
+    JumpIfNot,
+    Goto,
+    GotoIf,
+    GotoIfNot,
+    PushMany,
+    StoreMany,
+    LoadElement,
+    AddChecked,
+    SubChecked,
+    MulChecked,
+    DivSigned,
+    ModSigned,
+    LessSigned,
+    LessEqualSigned,
+    AddCheckedSigned,
+    SubCheckedSigned,
+    MulCheckedSigned,
+    CheckIndex,
+    PushFunction,
+}
+
+impl Operation {
+    /// The kind of operation, ignoring its operand.
+    #[inline(always)]
+    fn opcode(&self) -> Opcode {
+        use Operation::*;
+        match self {
+            Nop => Opcode::Nop,
+            Push(_) => Opcode::Push,
+            PushBool(_) => Opcode::PushBool,
+            Pop => Opcode::Pop,
+            Get(_) => Opcode::Get,
+            Put(_) => Opcode::Put,
+            Swap => Opcode::Swap,
+            Store(_) => Opcode::Store,
+            Load(_) => Opcode::Load,
+            Allocate(_) => Opcode::Allocate,
+            Free(_) => Opcode::Free,
+            StoreIndirect(_) => Opcode::StoreIndirect,
+            LoadIndirect(_) => Opcode::LoadIndirect,
+            Call(_) => Opcode::Call,
+            CallFnPointer => Opcode::CallFnPointer,
+            Return => Opcode::Return,
+            AddImmediate(_) => Opcode::AddImmediate,
+            Add => Opcode::Add,
+            SubImmediate(_) => Opcode::SubImmediate,
+            SubImmediateBy(_) => Opcode::SubImmediateBy,
+            Sub => Opcode::Sub,
+            MulImmediate(_) => Opcode::MulImmediate,
+            Mul => Opcode::Mul,
+            DivImmediate(_) => Opcode::DivImmediate,
+            DivImmediateBy(_) => Opcode::DivImmediateBy,
+            Div => Opcode::Div,
+            ModImmediate(_) => Opcode::ModImmediate,
+            ModImmediateBy(_) => Opcode::ModImmediateBy,
+            Mod => Opcode::Mod,
+            AddSat => Opcode::AddSat,
+            SubSat => Opcode::SubSat,
+            MulSat => Opcode::MulSat,
+            Equal => Opcode::Equal,
+            NotEqual => Opcode::NotEqual,
+            Less => Opcode::Less,
+            LessEqual => Opcode::LessEqual,
+            Jump(_) => Opcode::Jump,
+            JumpIf(_) => Opcode::JumpIf,
+            JumpIfNot(_) => Opcode::JumpIfNot,
+            Goto => Opcode::Goto,
+            GotoIf => Opcode::GotoIf,
+            GotoIfNot => Opcode::GotoIfNot,
+            PushMany(_) => Opcode::PushMany,
+            StoreMany(_) => Opcode::StoreMany,
+            LoadElement(..) => Opcode::LoadElement,
+            AddChecked => Opcode::AddChecked,
+            SubChecked => Opcode::SubChecked,
+            MulChecked => Opcode::MulChecked,
+            DivSigned => Opcode::DivSigned,
+            ModSigned => Opcode::ModSigned,
+            LessSigned => Opcode::LessSigned,
+            LessEqualSigned => Opcode::LessEqualSigned,
+            AddCheckedSigned => Opcode::AddCheckedSigned,
+            SubCheckedSigned => Opcode::SubCheckedSigned,
+            MulCheckedSigned => Opcode::MulCheckedSigned,
+            CheckIndex => Opcode::CheckIndex,
+            PushFunction(_) => Opcode::PushFunction,
+        }
+    }
+
+    /// The operand as one word, or 0 if there is none. `LoadElement` keeps its depth
+    /// above the low 16 bits and its index in them.
+    #[cfg(feature = "flat-dispatch")]
+    fn operand(&self) -> Word {
+        use Operation::*;
+        match *self {
+            Push(value)
+            | AddImmediate(value)
+            | SubImmediate(value)
+            | SubImmediateBy(value)
+            | MulImmediate(value)
+            | DivImmediate(value)
+            | DivImmediateBy(value)
+            | ModImmediate(value)
+            | ModImmediateBy(value) => value,
+            PushBool(value) => value as Word,
+            PushMany(value) | PushFunction(value) | Get(value) | Put(value) | Store(value)
+            | Load(value) | Allocate(value) | Free(value) | StoreIndirect(value)
+            | LoadIndirect(value) | StoreMany(value) | Call(value) | Jump(value)
+            | JumpIf(value) | JumpIfNot(value) => value as Word,
+            LoadElement(depth, index) => ((depth as Word) << 16) | index as Word,
+            _ => 0,
+        }
+    }
+}
+
+/// Which kinds of operation ran at least once, recorded when coverage is enabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Coverage(u64);
+
+impl Coverage {
+    /// Whether any operation of the same kind as `operation` ran, whatever its
+    /// operand.
+    pub fn contains(&self, operation: &Operation) -> bool {
+        self.0 & (1 << operation.opcode() as u32) != 0
+    }
+
+    fn insert(&mut self, opcode: Opcode) {
+        self.0 |= 1 << opcode as u32;
+    }
+}
+
+/// What kind of value a stack slot holds, tracked when type tags are enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Number,
+    Bool,
+    Address,
+    Function,
+}
+
+/// A call in progress, as listed by `backtrace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function_name: String,
+    /// The index of the operation it is at, which for any but the innermost call
+    /// is the `Call` it is waiting on.
+    pub pc: u32,
+}
+
+/// What `run_detailed` found out about a run, along with the value it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunResult {
+    pub value: Word,
+    /// The most calls that were in progress at once, not counting the function the
+    /// run started in.
+    pub max_call_depth: u32,
+    /// Which operations ran, if `enable_coverage` was called.
+    pub coverage: Option<Coverage>,
+    /// The declared return type of the function the run started in, if it was
+    /// given with `set_return_type`.
+    pub return_type: Option<Type>,
+}
+
+impl RunResult {
+    /// The value as an `i32`, if that is what the function returns.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn as_i32(&self) -> Option<i32> {
+        // i32s are sign extended to a full word, so the low 32 bits hold the value.
+        (self.return_type == Some(Type::I32)).then_some(self.value as u32 as i32)
+    }
+
+    /// The value as a `bool`, if that is what the function returns.
+    pub fn as_bool(&self) -> Option<bool> {
+        (self.return_type == Some(Type::Bool)).then_some(self.value != 0)
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    TypeTagMismatch {
+        expected: Tag,
+        found: Tag,
+    },
+    DivideByZero,
+    Overflow,
+    UnknownFunction(String),
+    /// `run` executed as many operations as `set_step_limit` allowed.
+    StepLimitExceeded,
+    /// A function ran past its last operation without returning.
+    MissingReturn,
+    /// `Free` of an address that isn't the start of a live allocation.
+    InvalidFree(Word),
+    /// A heap access outside every live allocation, caught when type tags are
+    /// enabled.
+    InvalidAddress(Word),
+    /// A write to a heap slot holding a constant, such as a pooled string literal.
+    WriteToReadOnly(Word),
+    /// The function the VM starts in was given a different number of arguments
+    /// than it takes.
+    WrongArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    /// `resume_with` was called when the last run hadn't stopped at an `Overflow`
+    /// or `DivideByZero`.
+    NotTrapped,
+    /// `assert_eq` was called with values that differ.
+    AssertionFailed {
+        left: Word,
+        right: Word,
+    },
+    /// An array of `length` elements was indexed at `index`.
+    IndexOutOfBounds {
+        index: Word,
+        length: Word,
+    },
+}
+
 enum FunctionData {
     Code(Vec<Operation>),
     Builtin(Rc<dyn Fn(&mut VirtualMachine)>),
@@ -47,16 +418,79 @@ enum FunctionData {
 pub struct Function {
     name: String,
     implementation: FunctionData,
+    /// The source span of each operation, if known.
+    spans: Vec<Span>,
+    /// How many arguments it takes, if it is code. Builtins pop their own.
+    arguments: usize,
+    /// The values `PushMany` reads from.
+    constants: Vec<Word>,
+    /// The most slots its frame holds at once, if known.
+    max_stack: Option<usize>,
+    /// The opcode and operand of each operation, which `run` dispatches on.
+    #[cfg(feature = "flat-dispatch")]
+    flat: FlatCode,
+}
+
+/// A function's operations laid out flat: the opcode of each in one array and its
+/// operand in another. Opcodes take a byte each, so more of them fit in the cache
+/// than whole operations do.
+#[cfg(feature = "flat-dispatch")]
+#[derive(Default)]
+struct FlatCode {
+    opcodes: Box<[Opcode]>,
+    operands: Box<[Word]>,
+}
+
+#[cfg(feature = "flat-dispatch")]
+impl FlatCode {
+    fn new(operations: &[Operation]) -> Self {
+        Self {
+            opcodes: operations.iter().map(Operation::opcode).collect(),
+            operands: operations.iter().map(Operation::operand).collect(),
+        }
+    }
 }
 
 impl Function {
     pub fn from_operations(name: impl Into<String>, operations: Vec<Operation>) -> Self {
         Self {
             name: name.into(),
+            #[cfg(feature = "flat-dispatch")]
+            flat: FlatCode::new(&operations),
             implementation: FunctionData::Code(operations),
+            spans: Vec::new(),
+            arguments: 0,
+            constants: Vec::new(),
+            max_stack: None,
         }
     }
 
+    /// Declares that the function takes `count` arguments.
+    pub fn with_arguments(mut self, count: usize) -> Self {
+        self.arguments = count;
+        self
+    }
+
+    /// Attaches the source span each operation was compiled from, so runtime errors
+    /// can be traced back to the source.
+    pub fn with_spans(mut self, spans: Vec<Span>) -> Self {
+        self.spans = spans;
+        self
+    }
+
+    /// Records the most slots the function's frame holds at once, above the return
+    /// address, as worked out from its code when it was compiled.
+    pub fn with_max_stack(mut self, max_stack: Option<usize>) -> Self {
+        self.max_stack = max_stack;
+        self
+    }
+
+    /// Gives the function the table of values its `PushMany` operations read.
+    pub fn with_constants(mut self, constants: Vec<Word>) -> Self {
+        self.constants = constants;
+        self
+    }
+
     pub fn from_builtin(
         name: impl Into<String>,
         function: impl Fn(&mut VirtualMachine) + 'static,
@@ -64,50 +498,648 @@ impl Function {
         Self {
             name: name.into(),
             implementation: FunctionData::Builtin(Rc::new(function)),
+            spans: Vec::new(),
+            arguments: 0,
+            constants: Vec::new(),
+            max_stack: None,
+            #[cfg(feature = "flat-dispatch")]
+            flat: FlatCode::default(),
+        }
+    }
+}
+
+/// A hash of the code in `functions`, for cache keys and spotting changes in what
+/// the compiler generates. Unlike one made with `DefaultHasher`, it stays the same
+/// across runs and Rust versions. Builtins are skipped.
+#[allow(clippy::unnecessary_cast)]
+pub fn program_hash(functions: &[Function]) -> u64 {
+    // 64 bit FNV-1a.
+    fn write(hash: &mut u64, bytes: &[u8]) {
+        for byte in bytes {
+            *hash ^= *byte as u64;
+            *hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    let mut hash = 0xcbf29ce484222325;
+    for function in functions {
+        let FunctionData::Code(operations) = &function.implementation else {
+            continue;
+        };
+        write(&mut hash, function.name.as_bytes());
+        write(&mut hash, &[0]);
+        for operation in operations {
+            write(&mut hash, format!("{operation:?};").as_bytes());
         }
+        for constant in &function.constants {
+            write(&mut hash, &(*constant as u64).to_le_bytes());
+        }
+        write(&mut hash, &[0]);
     }
+    hash
 }
 
 pub struct VirtualMachine {
     function_id: u32,
     program_counter: u32,
     functions: Vec<Function>,
-    stack: Vec<u32>,
-    heap: Vec<u32>,
+    stack: Vec<Word>,
+    heap: Vec<Word>,
+    /// The size of every live allocation, by the address it starts at.
+    allocations: BTreeMap<u32, u32>,
+    /// Freed blocks that can be handed out again, by size, most recently freed last.
+    free_blocks: BTreeMap<u32, Vec<u32>>,
+    /// The blocks allocated with `allocate_read_only`, which can't be written to
+    /// or freed.
+    read_only: Vec<Range<u32>>,
+    tags: Option<Vec<Tag>>,
+    /// The tag of every heap slot that holds something other than a number, kept
+    /// when type tags are enabled, so that loads push what was stored.
+    heap_tags: BTreeMap<u32, Tag>,
+    strict: bool,
+    /// How many more operations may run, if limited.
+    steps_left: Option<u64>,
+    trace: bool,
+    /// Where tracing writes to.
+    trace_output: Box<dyn Write>,
+    coverage: Option<Coverage>,
+    /// How many arguments were given with `set_main_arguments`.
+    main_arguments: usize,
+    /// What the function the VM starts in returns, for `RunResult`.
+    return_type: Option<Type>,
+    /// Whether the last run stopped at a checked operation that failed, which has
+    /// popped its operands but not pushed a result.
+    trapped: bool,
+    /// Whether the last run was stopped by `exit`, which leaves its code on top of
+    /// the stack rather than where the function's result goes.
+    exited: bool,
+    /// The error a builtin stopped the run with, until the run loop returns it.
+    failure: Option<RuntimeError>,
+    /// Where builtins read the program's input from.
+    input: Box<dyn Read>,
+    /// Where builtins write the program's output to.
+    output: Box<dyn Write>,
+    /// How many calls are in progress.
+    call_depth: u32,
+    /// Where on the stack each call in progress saved its return address, innermost
+    /// last. Only kept so `backtrace` can find them.
+    frames: Vec<usize>,
+    max_call_depth: u32,
 }
 
 impl VirtualMachine {
+    /// Starts at `main`, or the first function if there is none, without passing it
+    /// any arguments. `run` refuses to start a function that takes some until they
+    /// are given with `set_main_arguments`.
     pub fn from_functions(functions: Vec<Function>) -> Self {
         let main_index = functions.iter().position(|s| s.name == "main").unwrap_or(0) as u32;
         Self {
             function_id: main_index,
             program_counter: 0,
             functions,
-            stack: vec![0, 0, u32::MAX],
+            stack: vec![0, 0, u32::MAX as Word],
             heap: vec![],
+            allocations: BTreeMap::new(),
+            free_blocks: BTreeMap::new(),
+            read_only: Vec::new(),
+            tags: None,
+            heap_tags: BTreeMap::new(),
+            strict: false,
+            steps_left: None,
+            trace: false,
+            trace_output: Box::new(io::stdout()),
+            coverage: None,
+            main_arguments: 0,
+            return_type: None,
+            trapped: false,
+            exited: false,
+            failure: None,
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            call_depth: 0,
+            max_call_depth: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Like `from_functions`, but starts at the function named `entry`, which must
+    /// exist.
+    pub fn from_functions_with_entry(
+        functions: Vec<Function>,
+        entry: &str,
+    ) -> Result<Self, RuntimeError> {
+        let Some(entry_index) = functions.iter().position(|f| f.name == entry) else {
+            return Err(RuntimeError::UnknownFunction(entry.to_string()));
+        };
+        let mut vm = Self::from_functions(functions);
+        vm.function_id = entry_index as u32;
+        Ok(vm)
+    }
+
+    /// Tracks the kind of value in every stack slot, so that e.g. a bool used in
+    /// arithmetic is reported as a `RuntimeError` instead of silently computing.
+    /// This is a debugging aid and slows execution down.
+    pub fn enable_type_tags(&mut self) {
+        self.tags = Some(vec![Tag::Number; self.stack.len()]);
+    }
+
+    /// Makes overflowing arithmetic and division by zero stop the program with a
+    /// `RuntimeError` instead of wrapping around or producing 0.
+    pub fn enable_strict_arithmetic(&mut self) {
+        self.strict = true;
+    }
+
+    /// Makes `run` stop with `RuntimeError::StepLimitExceeded` after `steps` more
+    /// operations, so that programs which may never finish can be run safely.
+    pub fn set_step_limit(&mut self, steps: u64) {
+        self.steps_left = Some(steps);
+    }
+
+    /// Like `from_functions`, but passes `arguments` to the function the VM starts
+    /// in, which must take that many.
+    pub fn from_functions_with_arguments(
+        functions: Vec<Function>,
+        arguments: &[Word],
+    ) -> Result<Self, RuntimeError> {
+        let mut vm = Self::from_functions(functions);
+        vm.set_main_arguments(arguments)?;
+        Ok(vm)
+    }
+
+    /// Passes `arguments` to the function the VM starts in, following the calling
+    /// convention of `Call`. Must be done before running.
+    pub fn set_main_arguments(&mut self, arguments: &[Word]) -> Result<(), RuntimeError> {
+        self.check_argument_count(self.function_id, arguments.len())?;
+        self.stack = vec![0];
+        self.frames.clear();
+        self.stack.extend_from_slice(arguments);
+        self.stack.extend([0, u32::MAX as Word]);
+        self.main_arguments = arguments.len();
+        if self.tags.is_some() {
+            self.enable_type_tags();
+        }
+        Ok(())
+    }
+
+    /// Fails with `WrongArgumentCount` if the function isn't code taking `count`
+    /// arguments. Builtins pop as many as they need.
+    fn check_argument_count(&self, function_id: u32, count: usize) -> Result<(), RuntimeError> {
+        match self.functions.get(function_id as usize) {
+            Some(function)
+                if matches!(function.implementation, FunctionData::Code(_))
+                    && function.arguments != count =>
+            {
+                Err(RuntimeError::WrongArgumentCount {
+                    function: function.name.clone(),
+                    expected: function.arguments,
+                    found: count,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Declares what the function the VM starts in returns, so `RunResult` can
+    /// interpret the value.
+    pub fn set_return_type(&mut self, return_type: Type) {
+        self.return_type = Some(return_type);
+    }
+
+    /// Makes builtins read input from `input` instead of stdin.
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        self.input = Box::new(input);
+    }
+
+    /// Reads up to `max` bytes of input, fewer if it ends first or can't be read.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn read_input(&mut self, max: Word) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Whatever was read before an error is still returned.
+        let _ = (&mut self.input).take(max as u64).read_to_end(&mut bytes);
+        bytes
+    }
+
+    /// Makes builtins write output to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Writes `text` to the program's output. Output that can't be written is lost.
+    pub fn write_output(&mut self, text: &str) {
+        let _ = self.output.write_all(text.as_bytes());
+    }
+
+    /// Prints the stack and the operation about to run before every step.
+    pub fn enable_tracing(&mut self) {
+        self.trace = true;
+    }
+
+    /// Makes tracing write to `output` instead of stdout.
+    pub fn set_trace_output(&mut self, output: impl Write + 'static) {
+        self.trace_output = Box::new(output);
+    }
+
+    /// Records which kinds of operation run, reported by `run_detailed`. Meant for
+    /// checking that test programs exercise the operations they are written for.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::default());
+    }
+
+    /// Kept out of `run` so the dispatch loop has no formatting code in it when
+    /// tracing is off.
+    #[cold]
+    #[inline(never)]
+    fn trace_step(&mut self) {
+        let function = &self.functions[self.function_id as usize];
+        let output = &mut self.trace_output;
+        // Like the program's output, a trace that can't be written is lost.
+        let _ = writeln!(output, "stack: {:?}", self.stack);
+        let _ = writeln!(output, "function: {}", self.function_id);
+        let _ = writeln!(output, "pc: {}", self.program_counter);
+        match &function.implementation {
+            FunctionData::Builtin(_) => {
+                let _ = writeln!(output, "Running builtin {:?}", function.name);
+            }
+            FunctionData::Code(operations) => {
+                if let Some(operation) = operations.get(self.program_counter as usize) {
+                    let _ = writeln!(output, "op: {:?}", operation);
+                }
+            }
+        }
+    }
+
+    /// The source span of the operation about to run. After `run` returns an error
+    /// this is the operation that failed.
+    pub fn current_span(&self) -> Option<Span> {
+        self.functions
+            .get(self.function_id as usize)?
+            .spans
+            .get(self.program_counter as usize)
+            .copied()
+    }
+
+    /// The result of a checked operation, or in non-strict mode `fallback` when
+    /// the operation failed.
+    fn checked(
+        &mut self,
+        result: Option<Word>,
+        fallback: Word,
+        error: RuntimeError,
+    ) -> Result<Word, RuntimeError> {
+        match result {
+            Some(value) => Ok(value),
+            None if self.strict => {
+                self.trapped = true;
+                Err(error)
+            }
+            None => Ok(fallback),
+        }
+    }
+
+    /// After a run stopped with `Overflow` or `DivideByZero`, carries on as if the
+    /// failed operation had produced `value`. The rest of the VM's state can be
+    /// inspected or changed before resuming.
+    pub fn resume_with(&mut self, value: Word) -> Result<Word, RuntimeError> {
+        if !self.trapped {
+            return Err(RuntimeError::NotTrapped);
+        }
+        self.stack.push(value);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        self.run()
+    }
+
+    fn saturate(&mut self, operation: fn(Word, Word) -> Word) {
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        self.stack.push(operation(a, b));
+    }
+
+    /// Like `checked`, but stopping on overflow whether or not the VM is strict.
+    fn trap_overflow(
+        &mut self,
+        operation: fn(Word, Word) -> Option<Word>,
+    ) -> Result<(), RuntimeError> {
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        let Some(value) = operation(a, b) else {
+            self.trapped = true;
+            return Err(RuntimeError::Overflow);
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn compare(&mut self, comparison: impl Fn(Word, Word) -> bool) {
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        self.stack.push(comparison(a, b) as Word);
+    }
+
+    pub fn push(&mut self, value: Word) {
+        self.stack.push(value);
+        if let Some(tags) = &mut self.tags {
+            tags.push(Tag::Number);
+        }
+    }
+
+    pub fn push_bool(&mut self, value: bool) {
+        self.stack.push(value as Word);
+        if let Some(tags) = &mut self.tags {
+            tags.push(Tag::Bool);
+        }
+    }
+
+    pub fn pop(&mut self) -> Word {
+        if let Some(tags) = &mut self.tags {
+            tags.pop();
+        }
+        self.stack.pop().unwrap()
+    }
+
+    /// The value `depth` slots below the top of the stack, or `None` if the stack
+    /// isn't that deep. Unlike `Get`, it can see past the current frame into the
+    /// return address and function id each `Call` saved, for tools that walk the
+    /// calls in progress.
+    pub fn peek_frame(&self, depth: u32) -> Option<Word> {
+        let index = self.stack.len().checked_sub(depth as usize + 1)?;
+        Some(self.stack[index])
+    }
+
+    /// Number of heap slots currently in use, including freed ones the allocator
+    /// hasn't reclaimed.
+    pub fn heap_size(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Discards the whole heap. Addresses can't be told apart from numbers on the
+    /// stack, so live allocations can't be moved or tracked; this is only safe
+    /// between runs, once nothing refers to the heap any more.
+    pub fn reset_heap(&mut self) {
+        self.heap.clear();
+        self.allocations.clear();
+        self.free_blocks.clear();
+        self.read_only.clear();
+        self.heap_tags.clear();
+    }
+
+    /// Allocates a block of `size` slots, all 0, and returns its address.
+    ///
+    /// The address only depends on the allocations and frees made before: the most
+    /// recently freed block of exactly `size` slots is reused if there is one, and
+    /// otherwise the block is added to the end of the heap.
+    pub fn allocate(&mut self, size: u32) -> Word {
+        let address = match self.free_blocks.get_mut(&size).and_then(Vec::pop) {
+            Some(address) => {
+                self.heap[address as usize..(address + size) as usize].fill(0);
+                address
+            }
+            None => {
+                let address = self.heap.len() as u32;
+                self.heap.extend((0..size).map(|_| 0));
+                address
+            }
+        };
+        self.allocations.insert(address, size);
+        address as Word
+    }
+
+    /// Allocates a block holding `values` at the end of the heap, never reusing a
+    /// freed one, so that its address can be worked out in advance.
+    pub fn allocate_at_end(&mut self, values: &[Word]) -> Word {
+        let address = self.heap.len() as u32;
+        self.heap.extend_from_slice(values);
+        self.allocations.insert(address, values.len() as u32);
+        address as Word
+    }
+
+    /// Like `allocate_at_end`, but the program can only read the block. Constants
+    /// that every use shares, like pooled string literals, live in one of these so
+    /// that changing one use can't change the others.
+    pub fn allocate_read_only(&mut self, values: &[Word]) -> Word {
+        let address = self.allocate_at_end(values);
+        let start = to_index(address);
+        self.read_only.push(start..start + values.len() as u32);
+        address
+    }
+
+    /// Whether `address` is in a block allocated with `allocate_read_only`.
+    pub fn is_read_only(&self, address: Word) -> bool {
+        self.read_only
+            .iter()
+            .any(|block| block.contains(&to_index(address)))
+    }
+
+    /// Frees the block allocated at `address`. A block at the end of the heap is
+    /// given back, shrinking it; any other is kept for `allocate` to reuse.
+    pub fn free(&mut self, address: Word) -> Result<(), RuntimeError> {
+        if self.is_read_only(address) {
+            return Err(RuntimeError::InvalidFree(address));
+        }
+        let Some(size) = self.allocations.remove(&to_index(address)) else {
+            return Err(RuntimeError::InvalidFree(address));
+        };
+        let address = to_index(address);
+        // Whatever reuses the block starts out with numbers, all 0.
+        let tagged: Vec<u32> = self
+            .heap_tags
+            .range(address..address + size)
+            .map(|(&slot, _)| slot)
+            .collect();
+        for slot in tagged {
+            self.heap_tags.remove(&slot);
+        }
+        if address as usize + size as usize == self.heap.len() {
+            self.heap.truncate(address as usize);
+        } else {
+            self.free_blocks.entry(size).or_default().push(address);
+        }
+        Ok(())
+    }
+
+    pub fn read_heap(&self, address: Word) -> Word {
+        self.heap[to_index(address) as usize]
+    }
+
+    pub fn write_heap(&mut self, address: Word, value: Word) {
+        self.heap[to_index(address) as usize] = value;
+        self.heap_tags.remove(&to_index(address));
+    }
+
+    /// The size of the live allocation starting at `address`, if there is one.
+    pub fn allocation_size(&self, address: Word) -> Option<u32> {
+        self.allocations.get(&to_index(address)).copied()
+    }
+
+    /// The name and operations of every function made of code, in order. Builtins
+    /// are skipped.
+    pub fn iter_code(&self) -> impl Iterator<Item = (&str, &[Operation])> {
+        self.functions
+            .iter()
+            .filter_map(|function| match &function.implementation {
+                FunctionData::Code(operations) => Some((function.name.as_str(), &operations[..])),
+                FunctionData::Builtin(_) => None,
+            })
+    }
+
+    /// The most slots the frame of the function called `name` holds at once, so a
+    /// run's stack can be sized up front. `None` if there is no such function or
+    /// its code wasn't analysed.
+    pub fn max_stack_of(&self, name: &str) -> Option<usize> {
+        self.functions
+            .iter()
+            .find(|function| function.name == name)
+            .and_then(|function| function.max_stack)
+    }
+
+    /// Swaps in a new set of functions, keeping the stack and heap. Function ids
+    /// taken from the old set no longer mean anything.
+    pub fn replace_functions(&mut self, functions: Vec<Function>) {
+        self.functions = functions;
+    }
+
+    /// Stops the run with `error` once the builtin calling this returns, for
+    /// builtins that find they can't carry on.
+    pub fn fail(&mut self, error: RuntimeError) {
+        self.failure = Some(error);
+    }
+
+    /// The error a builtin stopped with through `fail`, clearing it.
+    pub(crate) fn take_failure(&mut self) -> Option<RuntimeError> {
+        self.failure.take()
+    }
+
+    /// Stops the program with `code` as its result, no matter how deep the call stack is.
+    pub fn exit(&mut self, code: Word) {
+        self.push(code);
+        self.function_id = u32::MAX;
+        self.exited = true;
+    }
+
+    /// Runs the function called `name` with `arguments`, following the same calling
+    /// convention as `Call`, and returns its result. The stack, and where the VM is
+    /// in its own program, are left as they were, even if the function fails. If
+    /// the function calls `exit`, its code is the result. Afterwards there is no
+    /// trap to `resume_with`, since the state a trap was found in is gone.
+    pub fn call(&mut self, name: &str, arguments: &[Word]) -> Result<Word, RuntimeError> {
+        self.trapped = false;
+        let function_id = self
+            .functions
+            .iter()
+            .position(|function| function.name == name)
+            .ok_or_else(|| RuntimeError::UnknownFunction(name.to_string()))?;
+        let builtin = matches!(
+            self.functions[function_id].implementation,
+            FunctionData::Builtin(_)
+        );
+        self.check_argument_count(function_id as u32, arguments.len())?;
+
+        let base = self.stack.len();
+        let frames = self.frames.len();
+        let caller = (self.function_id, self.program_counter, self.call_depth);
+        // The function's `Return` pops its own arguments rather than those of the
+        // one the VM starts in.
+        let main_arguments = std::mem::replace(&mut self.main_arguments, arguments.len());
+        // Builtins push their result rather than filling in a slot.
+        if !builtin {
+            self.push(0);
+        }
+        for argument in arguments {
+            self.push(*argument);
+        }
+        self.push(0);
+        self.push(u32::MAX as Word);
+        self.function_id = function_id as u32;
+        self.program_counter = 0;
+        let result = self.run().map(|_| {
+            if builtin || self.exited {
+                self.pop()
+            } else {
+                self.stack[base]
+            }
+        });
+
+        while self.stack.len() > base {
+            self.pop();
         }
+        self.frames.truncate(frames);
+        (self.function_id, self.program_counter, self.call_depth) = caller;
+        self.main_arguments = main_arguments;
+        self.trapped = false;
+        result
     }
 
-    pub fn run(&mut self) -> u32 {
+    pub fn run(&mut self) -> Result<Word, RuntimeError> {
+        self.run_detailed().map(|result| result.value)
+    }
+
+    /// Like `run`, but also reports statistics about the run.
+    pub fn run_detailed(&mut self) -> Result<RunResult, RuntimeError> {
+        // At the start of the function the VM starts in, nothing has run yet, and
+        // that function must have been given its arguments.
+        if self.frames.is_empty() && self.program_counter == 0 {
+            self.check_argument_count(self.function_id, self.main_arguments)?;
+        }
+        self.trapped = false;
+        self.exited = false;
+        self.max_call_depth = self.call_depth;
+        if self.coverage.is_some() {
+            self.coverage = Some(Coverage::default());
+        }
+        let start_depth = self.call_depth;
+        #[cfg(feature = "flat-dispatch")]
+        self.run_flat()?;
+        #[cfg(not(feature = "flat-dispatch"))]
         while self.function_id != u32::MAX {
-            println!("stack: {:?}", self.stack);
-            println!("function: {}", self.function_id);
-            println!("pc: {}", self.program_counter);
+            if let Some(steps_left) = &mut self.steps_left {
+                if *steps_left == 0 {
+                    return Err(RuntimeError::StepLimitExceeded);
+                }
+                *steps_left -= 1;
+            }
+            if self.trace {
+                self.trace_step();
+            }
             match &self.functions[self.function_id as usize].implementation {
                 FunctionData::Builtin(f) => {
-                    println!(
-                        "Running builtin {:?}",
-                        self.functions[self.function_id as usize].name
-                    );
-                    Rc::clone(f)(self);
-                    self.function_id = self.stack.pop().unwrap();
-                    self.program_counter = self.stack.pop().unwrap();
+                    let f = Rc::clone(f);
+                    self.leave_frame();
+                    self.call_depth = self.call_depth.saturating_sub(1);
+                    self.function_id = to_index(self.pop());
+                    self.program_counter = to_index(self.pop());
+                    f(self);
+                    if let Some(error) = self.failure.take() {
+                        return Err(error);
+                    }
                 }
                 FunctionData::Code(operations) => {
-                    println!("op: {:?}", operations[self.program_counter as usize]);
+                    let Some(operation) = operations.get(self.program_counter as usize) else {
+                        return Err(RuntimeError::MissingReturn);
+                    };
+                    if let Some(tags) = &mut self.tags {
+                        update_tags(
+                            tags,
+                            &mut self.heap_tags,
+                            operation,
+                            &self.stack,
+                            &self.functions[self.function_id as usize].constants,
+                        )?;
+                    }
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.insert(operation.opcode());
+                    }
                     use Operation::*;
-                    match operations[self.program_counter as usize] {
+                    match *operation {
+                        Nop => {}
                         Push(n) => self.stack.push(n),
+                        PushBool(b) => self.stack.push(b as Word),
+                        PushFunction(id) => self.stack.push(id as Word),
+                        PushMany(start) => {
+                            let values = constant_run(
+                                &self.functions[self.function_id as usize].constants,
+                                start,
+                            );
+                            self.stack.extend_from_slice(values);
+                        }
                         Pop => {
                             self.stack.pop();
                         }
@@ -120,91 +1152,226 @@ impl VirtualMachine {
                             let index = self.stack.len() - 1 - depth as usize;
                             self.stack[index] = v;
                         }
+                        Swap => {
+                            let len = self.stack.len();
+                            self.stack.swap(len - 1, len - 2);
+                        }
                         Store(address) => {
+                            self.check_address(address)?;
+                            self.check_writable(address)?;
                             self.heap[address as usize] = self.stack.pop().unwrap();
                         }
-                        Load(address) => self.stack.push(self.heap[address as usize]),
+                        Load(address) => {
+                            self.check_address(address)?;
+                            self.stack.push(self.heap[address as usize]);
+                        }
                         Allocate(size) => {
-                            self.stack.push(self.heap.len() as u32);
-                            self.heap.extend((0..size).map(|_| 0));
+                            let address = self.allocate(size);
+                            self.stack.push(address);
+                        }
+                        Free(_) => {
+                            let address = self.stack.pop().unwrap();
+                            self.free(address)?;
+                        }
+                        StoreIndirect(offset) => {
+                            let value = self.stack.pop().unwrap();
+                            let address = to_index(self.stack.pop().unwrap());
+                            self.check_writable(address + offset)?;
+                            *self.heap_slot(address + offset)? = value;
                         }
-                        Free(_address) => {
-                            self.stack.pop().unwrap();
-                            // Will eventually free memory properly, but as alloc is a simpl bump allocator for now, we can't do much.
+                        LoadIndirect(offset) => {
+                            let address = to_index(self.stack.pop().unwrap());
+                            let value = *self.heap_slot(address + offset)?;
+                            self.stack.push(value);
+                        }
+                        LoadElement(depth, index) => {
+                            let address =
+                                to_index(self.stack[self.stack.len() - 1 - depth as usize]);
+                            self.check_index(address, index as Word)?;
+                            let value = *self.heap_slot(address + 1 + index as u32)?;
+                            self.stack.push(value);
+                        }
+                        CheckIndex => {
+                            let index = self.stack[self.stack.len() - 1];
+                            let address = to_index(self.stack[self.stack.len() - 2]);
+                            self.check_index(address, index)?;
+                        }
+                        StoreMany(count) => {
+                            let address = to_index(self.stack.pop().unwrap());
+                            for offset in 0..count {
+                                self.check_address(address + offset)?;
+                                self.check_writable(address + offset)?;
+                            }
+                            let start = self.stack.len() - count as usize;
+                            self.heap[address as usize..(address + count) as usize]
+                                .copy_from_slice(&self.stack[start..]);
+                            self.stack.truncate(start);
+                            self.stack.push(address as Word);
                         }
                         Call(function_id) => {
-                            self.stack.push(self.program_counter);
-                            self.stack.push(self.function_id);
+                            self.enter_call();
+                            self.frames.push(self.stack.len());
+                            self.stack.push(self.program_counter as Word);
+                            self.stack.push(self.function_id as Word);
                             self.function_id = function_id;
                             self.program_counter = u32::MAX;
                         }
                         CallFnPointer => {
-                            let function_id = self.stack.pop().unwrap();
-                            self.stack.push(self.program_counter);
-                            self.stack.push(self.function_id);
+                            self.enter_call();
+                            let function_id = to_index(self.stack.pop().unwrap());
+                            self.frames.push(self.stack.len());
+                            self.stack.push(self.program_counter as Word);
+                            self.stack.push(self.function_id as Word);
                             self.function_id = function_id;
                             self.program_counter = u32::MAX;
                         }
                         Return => {
-                            self.function_id = self.stack.pop().unwrap();
-                            self.program_counter = self.stack.pop().unwrap();
+                            self.leave_frame();
+                            self.call_depth = self.call_depth.saturating_sub(1);
+                            self.function_id = to_index(self.stack.pop().unwrap());
+                            self.program_counter = to_index(self.stack.pop().unwrap());
+                            // There is no caller to pop the arguments of the function
+                            // the VM started in, so that its result ends up on top.
+                            if self.function_id == u32::MAX {
+                                for _ in 0..std::mem::take(&mut self.main_arguments) {
+                                    self.pop();
+                                }
+                            }
                         }
                         AddImmediate(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(b.wrapping_add(i));
+                            let value = self.checked(
+                                b.checked_add(i),
+                                b.wrapping_add(i),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         Add => {
                             let b = self.stack.pop().unwrap();
                             let a = self.stack.pop().unwrap();
-                            self.stack.push(a.wrapping_add(b));
+                            let value = self.checked(
+                                a.checked_add(b),
+                                a.wrapping_add(b),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         SubImmediate(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(b.wrapping_sub(i));
+                            let value = self.checked(
+                                b.checked_sub(i),
+                                b.wrapping_sub(i),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         SubImmediateBy(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(b.wrapping_sub(i));
+                            let value = self.checked(
+                                i.checked_sub(b),
+                                i.wrapping_sub(b),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         Sub => {
                             let b = self.stack.pop().unwrap();
                             let a = self.stack.pop().unwrap();
-                            self.stack.push(a.wrapping_sub(b));
+                            let value = self.checked(
+                                a.checked_sub(b),
+                                a.wrapping_sub(b),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         MulImmediate(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(b.wrapping_mul(i));
+                            let value = self.checked(
+                                b.checked_mul(i),
+                                b.wrapping_mul(i),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         Mul => {
                             let b = self.stack.pop().unwrap();
                             let a = self.stack.pop().unwrap();
-                            self.stack.push(a.wrapping_mul(b));
+                            let value = self.checked(
+                                a.checked_mul(b),
+                                a.wrapping_mul(b),
+                                RuntimeError::Overflow,
+                            )?;
+                            self.stack.push(value);
                         }
                         DivImmediate(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(if i != 0 { b / i } else { 0 });
+                            let value =
+                                self.checked(b.checked_div(i), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
                         }
                         DivImmediateBy(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(if b != 0 { b / i } else { 0 });
+                            let value =
+                                self.checked(i.checked_div(b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
                         }
                         Div => {
                             let b = self.stack.pop().unwrap();
                             let a = self.stack.pop().unwrap();
-                            self.stack.push(if b != 0 { a / b } else { 0 });
+                            let value =
+                                self.checked(a.checked_div(b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
+                        }
+                        DivSigned => {
+                            let b = self.stack.pop().unwrap();
+                            let a = self.stack.pop().unwrap();
+                            let value =
+                                self.checked(signed_div(a, b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
                         }
                         ModImmediate(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(if i != 0 { b % i } else { 0 });
+                            let value =
+                                self.checked(b.checked_rem(i), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
                         }
                         ModImmediateBy(i) => {
                             let b = self.stack.pop().unwrap();
-                            self.stack.push(if b != 0 { i % b } else { 0 });
+                            let value =
+                                self.checked(i.checked_rem(b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
                         }
                         Mod => {
                             let b = self.stack.pop().unwrap();
                             let a = self.stack.pop().unwrap();
-                            self.stack.push(if b != 0 { a % b } else { 0 });
+                            let value =
+                                self.checked(a.checked_rem(b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
+                        }
+                        ModSigned => {
+                            let b = self.stack.pop().unwrap();
+                            let a = self.stack.pop().unwrap();
+                            let value =
+                                self.checked(signed_rem(a, b), 0, RuntimeError::DivideByZero)?;
+                            self.stack.push(value);
+                        }
+                        AddSat => self.saturate(Word::saturating_add),
+                        SubSat => self.saturate(Word::saturating_sub),
+                        MulSat => self.saturate(Word::saturating_mul),
+                        AddChecked => self.trap_overflow(Word::checked_add)?,
+                        SubChecked => self.trap_overflow(Word::checked_sub)?,
+                        MulChecked => self.trap_overflow(Word::checked_mul)?,
+                        AddCheckedSigned => self.trap_overflow(signed_checked_add)?,
+                        SubCheckedSigned => self.trap_overflow(signed_checked_sub)?,
+                        MulCheckedSigned => self.trap_overflow(signed_checked_mul)?,
+                        Equal => self.compare(|a, b| a == b),
+                        NotEqual => self.compare(|a, b| a != b),
+                        Less => self.compare(|a, b| a < b),
+                        LessEqual => self.compare(|a, b| a <= b),
+                        LessSigned => self.compare(|a, b| (a as SignedWord) < (b as SignedWord)),
+                        LessEqualSigned => {
+                            self.compare(|a, b| (a as SignedWord) <= (b as SignedWord))
                         }
                         Jump(location) => {
                             self.program_counter = location.wrapping_sub(1);
@@ -220,17 +1387,17 @@ impl VirtualMachine {
                             }
                         }
                         Goto => {
-                            let location = self.stack.pop().unwrap();
+                            let location = to_index(self.stack.pop().unwrap());
                             self.program_counter = location.wrapping_sub(1);
                         }
                         GotoIf => {
-                            let location = self.stack.pop().unwrap();
+                            let location = to_index(self.stack.pop().unwrap());
                             if self.stack.pop().unwrap() != 0 {
                                 self.program_counter = location.wrapping_sub(1);
                             }
                         }
                         GotoIfNot => {
-                            let location = self.stack.pop().unwrap();
+                            let location = to_index(self.stack.pop().unwrap());
                             if self.stack.pop().unwrap() == 0 {
                                 self.program_counter = location.wrapping_sub(1);
                             }
@@ -241,51 +1408,1340 @@ impl VirtualMachine {
             self.program_counter = self.program_counter.wrapping_add(1);
         }
 
-        return self.stack.last().copied().unwrap_or(u32::MAX);
+        Ok(RunResult {
+            value: self.stack.last().copied().unwrap_or(Word::MAX),
+            max_call_depth: self.max_call_depth - start_depth,
+            coverage: self.coverage,
+            return_type: self.return_type.clone(),
+        })
+    }
+
+    /// Runs until the function the VM started in returns, dispatching on the flat
+    /// opcodes and operands of each function rather than on its operations.
+    #[cfg(feature = "flat-dispatch")]
+    fn run_flat(&mut self) -> Result<(), RuntimeError> {
+        while self.function_id != u32::MAX {
+            self.count_step()?;
+            let function = &self.functions[self.function_id as usize];
+            let operations = match &function.implementation {
+                FunctionData::Builtin(f) => {
+                    let f = Rc::clone(f);
+                    self.leave_frame();
+                    self.call_depth = self.call_depth.saturating_sub(1);
+                    self.function_id = to_index(self.pop());
+                    self.program_counter = to_index(self.pop());
+                    f(self);
+                    if let Some(error) = self.failure.take() {
+                        return Err(error);
+                    }
+                    self.program_counter = self.program_counter.wrapping_add(1);
+                    continue;
+                }
+                FunctionData::Code(operations) => operations,
+            };
+            let pc = self.program_counter as usize;
+            let (Some(&opcode), Some(&operand)) = (
+                function.flat.opcodes.get(pc),
+                function.flat.operands.get(pc),
+            ) else {
+                return Err(RuntimeError::MissingReturn);
+            };
+            if let Some(tags) = &mut self.tags {
+                update_tags(
+                    tags,
+                    &mut self.heap_tags,
+                    &operations[pc],
+                    &self.stack,
+                    &function.constants,
+                )?;
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.insert(opcode);
+            }
+            self.execute(opcode, operand)?;
+            self.program_counter = self.program_counter.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// Runs one operation, given as its opcode and operand.
+    #[cfg(feature = "flat-dispatch")]
+    #[inline(always)]
+    fn execute(&mut self, opcode: Opcode, operand: Word) -> Result<(), RuntimeError> {
+        use Opcode::*;
+        match opcode {
+            Nop => {}
+            Push | PushBool | PushFunction => self.stack.push(operand),
+            PushMany => {
+                let start = to_index(operand);
+                let values =
+                    constant_run(&self.functions[self.function_id as usize].constants, start);
+                self.stack.extend_from_slice(values);
+            }
+            Pop => {
+                self.stack.pop();
+            }
+            Get => {
+                let depth = to_index(operand);
+                let n = self.stack[self.stack.len() - 1 - depth as usize];
+                self.stack.push(n);
+            }
+            Put => {
+                let depth = to_index(operand);
+                let v = self.stack.pop().unwrap();
+                let index = self.stack.len() - 1 - depth as usize;
+                self.stack[index] = v;
+            }
+            Swap => {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            Store => {
+                let address = to_index(operand);
+                self.check_address(address)?;
+                self.check_writable(address)?;
+                self.heap[address as usize] = self.stack.pop().unwrap();
+            }
+            Load => {
+                let address = to_index(operand);
+                self.check_address(address)?;
+                self.stack.push(self.heap[address as usize]);
+            }
+            Allocate => {
+                let size = to_index(operand);
+                let address = self.allocate(size);
+                self.stack.push(address);
+            }
+            Free => {
+                let address = self.stack.pop().unwrap();
+                self.free(address)?;
+            }
+            StoreIndirect => {
+                let offset = to_index(operand);
+                let value = self.stack.pop().unwrap();
+                let address = to_index(self.stack.pop().unwrap());
+                self.check_writable(address + offset)?;
+                *self.heap_slot(address + offset)? = value;
+            }
+            LoadIndirect => {
+                let offset = to_index(operand);
+                let address = to_index(self.stack.pop().unwrap());
+                let value = *self.heap_slot(address + offset)?;
+                self.stack.push(value);
+            }
+            LoadElement => {
+                let (depth, index) = ((operand >> 16) as usize, operand & 0xffff);
+                let address = to_index(self.stack[self.stack.len() - 1 - depth]);
+                self.check_index(address, index)?;
+                let value = *self.heap_slot(address + 1 + to_index(index))?;
+                self.stack.push(value);
+            }
+            CheckIndex => {
+                let index = self.stack[self.stack.len() - 1];
+                let address = to_index(self.stack[self.stack.len() - 2]);
+                self.check_index(address, index)?;
+            }
+            StoreMany => {
+                let count = to_index(operand);
+                let address = to_index(self.stack.pop().unwrap());
+                for offset in 0..count {
+                    self.check_address(address + offset)?;
+                    self.check_writable(address + offset)?;
+                }
+                let start = self.stack.len() - count as usize;
+                self.heap[address as usize..(address + count) as usize]
+                    .copy_from_slice(&self.stack[start..]);
+                self.stack.truncate(start);
+                self.stack.push(address as Word);
+            }
+            Call => {
+                let function_id = to_index(operand);
+                self.enter_call();
+                self.frames.push(self.stack.len());
+                self.stack.push(self.program_counter as Word);
+                self.stack.push(self.function_id as Word);
+                self.function_id = function_id;
+                self.program_counter = u32::MAX;
+            }
+            CallFnPointer => {
+                self.enter_call();
+                let function_id = to_index(self.stack.pop().unwrap());
+                self.frames.push(self.stack.len());
+                self.stack.push(self.program_counter as Word);
+                self.stack.push(self.function_id as Word);
+                self.function_id = function_id;
+                self.program_counter = u32::MAX;
+            }
+            Return => {
+                self.leave_frame();
+                self.call_depth = self.call_depth.saturating_sub(1);
+                self.function_id = to_index(self.stack.pop().unwrap());
+                self.program_counter = to_index(self.stack.pop().unwrap());
+                // There is no caller to pop the arguments of the function
+                // the VM started in, so that its result ends up on top.
+                if self.function_id == u32::MAX {
+                    for _ in 0..std::mem::take(&mut self.main_arguments) {
+                        self.pop();
+                    }
+                }
+            }
+            AddImmediate => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value =
+                    self.checked(b.checked_add(i), b.wrapping_add(i), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            Add => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value =
+                    self.checked(a.checked_add(b), a.wrapping_add(b), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            SubImmediate => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value =
+                    self.checked(b.checked_sub(i), b.wrapping_sub(i), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            SubImmediateBy => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value =
+                    self.checked(i.checked_sub(b), i.wrapping_sub(b), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            Sub => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value =
+                    self.checked(a.checked_sub(b), a.wrapping_sub(b), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            MulImmediate => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value =
+                    self.checked(b.checked_mul(i), b.wrapping_mul(i), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            Mul => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value =
+                    self.checked(a.checked_mul(b), a.wrapping_mul(b), RuntimeError::Overflow)?;
+                self.stack.push(value);
+            }
+            DivImmediate => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value = self.checked(b.checked_div(i), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            DivImmediateBy => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value = self.checked(i.checked_div(b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            Div => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value = self.checked(a.checked_div(b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            DivSigned => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value = self.checked(signed_div(a, b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            ModImmediate => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value = self.checked(b.checked_rem(i), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            ModImmediateBy => {
+                let i = operand;
+                let b = self.stack.pop().unwrap();
+                let value = self.checked(i.checked_rem(b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            Mod => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value = self.checked(a.checked_rem(b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            ModSigned => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let value = self.checked(signed_rem(a, b), 0, RuntimeError::DivideByZero)?;
+                self.stack.push(value);
+            }
+            AddSat => self.saturate(Word::saturating_add),
+            SubSat => self.saturate(Word::saturating_sub),
+            MulSat => self.saturate(Word::saturating_mul),
+            AddChecked => self.trap_overflow(Word::checked_add)?,
+            SubChecked => self.trap_overflow(Word::checked_sub)?,
+            MulChecked => self.trap_overflow(Word::checked_mul)?,
+            AddCheckedSigned => self.trap_overflow(signed_checked_add)?,
+            SubCheckedSigned => self.trap_overflow(signed_checked_sub)?,
+            MulCheckedSigned => self.trap_overflow(signed_checked_mul)?,
+            Equal => self.compare(|a, b| a == b),
+            NotEqual => self.compare(|a, b| a != b),
+            Less => self.compare(|a, b| a < b),
+            LessEqual => self.compare(|a, b| a <= b),
+            LessSigned => self.compare(|a, b| (a as SignedWord) < (b as SignedWord)),
+            LessEqualSigned => self.compare(|a, b| (a as SignedWord) <= (b as SignedWord)),
+            Jump => {
+                let location = to_index(operand);
+                self.program_counter = location.wrapping_sub(1);
+            }
+            JumpIf => {
+                let location = to_index(operand);
+                if self.stack.pop().unwrap() != 0 {
+                    self.program_counter = location.wrapping_sub(1);
+                }
+            }
+            JumpIfNot => {
+                let location = to_index(operand);
+                if self.stack.pop().unwrap() == 0 {
+                    self.program_counter = location.wrapping_sub(1);
+                }
+            }
+            Goto => {
+                let location = to_index(self.stack.pop().unwrap());
+                self.program_counter = location.wrapping_sub(1);
+            }
+            GotoIf => {
+                let location = to_index(self.stack.pop().unwrap());
+                if self.stack.pop().unwrap() != 0 {
+                    self.program_counter = location.wrapping_sub(1);
+                }
+            }
+            GotoIfNot => {
+                let location = to_index(self.stack.pop().unwrap());
+                if self.stack.pop().unwrap() == 0 {
+                    self.program_counter = location.wrapping_sub(1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts an operation or builtin about to run against the step limit, and
+    /// traces it.
+    #[cfg(feature = "flat-dispatch")]
+    #[inline(always)]
+    fn count_step(&mut self) -> Result<(), RuntimeError> {
+        if let Some(steps_left) = &mut self.steps_left {
+            if *steps_left == 0 {
+                return Err(RuntimeError::StepLimitExceeded);
+            }
+            *steps_left -= 1;
+        }
+        if self.trace {
+            self.trace_step();
+        }
+        Ok(())
+    }
+
+    /// With type tags enabled, fails unless `address` is inside a live allocation,
+    /// catching uses of freed blocks and addresses that never came from `Allocate`.
+    fn check_address(&self, address: u32) -> Result<(), RuntimeError> {
+        if self.tags.is_none() {
+            return Ok(());
+        }
+        match self.allocations.range(..=address).next_back() {
+            Some((start, size)) if address < start + size => Ok(()),
+            _ => Err(RuntimeError::InvalidAddress(address as Word)),
+        }
+    }
+
+    fn check_writable(&self, address: u32) -> Result<(), RuntimeError> {
+        if self.is_read_only(address as Word) {
+            return Err(RuntimeError::WriteToReadOnly(address as Word));
+        }
+        Ok(())
+    }
+
+    /// The heap slot at `address`, which must be inside the heap even when type
+    /// tags are disabled.
+    fn heap_slot(&mut self, address: u32) -> Result<&mut Word, RuntimeError> {
+        self.check_address(address)?;
+        self.heap
+            .get_mut(address as usize)
+            .ok_or(RuntimeError::InvalidAddress(address as Word))
+    }
+
+    /// Checks `index` against the length in the header of the array at `address`.
+    fn check_index(&mut self, address: u32, index: Word) -> Result<(), RuntimeError> {
+        let length = *self.heap_slot(address)?;
+        if index >= length {
+            return Err(RuntimeError::IndexOutOfBounds { index, length });
+        }
+        Ok(())
+    }
+
+    fn enter_call(&mut self) {
+        self.call_depth += 1;
+        self.max_call_depth = self.max_call_depth.max(self.call_depth);
+    }
+
+    /// Forgets the innermost frame if its return address is in the top two slots,
+    /// which are about to be popped. The function the VM started in has no frame.
+    fn leave_frame(&mut self) {
+        if self.frames.last() == Some(&self.stack.len().wrapping_sub(2)) {
+            self.frames.pop();
+        }
+    }
+
+    /// The calls in progress, innermost first, read from the return addresses saved
+    /// on the stack. After a runtime error, the first is where it happened.
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        let saved = self.frames.iter().rev().filter_map(|&frame| {
+            let pc = *self.stack.get(frame)?;
+            let function_id = *self.stack.get(frame + 1)?;
+            Some((to_index(function_id), to_index(pc)))
+        });
+        [(self.function_id, self.program_counter)]
+            .into_iter()
+            .chain(saved)
+            .filter_map(|(function_id, pc)| {
+                let function = self.functions.get(function_id as usize)?;
+                Some(StackFrame {
+                    function_name: function.name.clone(),
+                    pc,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Code locations and function ids are always 32 bits, whatever the word size.
+#[allow(clippy::unnecessary_cast)]
+fn to_index(word: Word) -> u32 {
+    word as u32
+}
+
+/// The values a `PushMany(start)` pushes from `constants`.
+pub(crate) fn constant_run(constants: &[Word], start: u32) -> &[Word] {
+    let start = start as usize;
+    &constants[start + 1..][..constants[start] as usize]
+}
+
+/// Applies the effect `operation` is about to have on the stack to `tags`, checking
+/// that its operands have the expected kinds. `constants` are those of the function
+/// it is in.
+fn update_tags(
+    tags: &mut Vec<Tag>,
+    heap_tags: &mut BTreeMap<u32, Tag>,
+    operation: &Operation,
+    stack: &[Word],
+    constants: &[Word],
+) -> Result<(), RuntimeError> {
+    fn expect(tags: &mut Vec<Tag>, expected: Tag) -> Result<(), RuntimeError> {
+        match tags.pop() {
+            Some(found) if found != expected => {
+                Err(RuntimeError::TypeTagMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+    // Slots without a tag hold numbers.
+    fn load(heap_tags: &BTreeMap<u32, Tag>, address: u32) -> Tag {
+        heap_tags.get(&address).copied().unwrap_or(Tag::Number)
+    }
+    fn store(heap_tags: &mut BTreeMap<u32, Tag>, address: u32, tag: Tag) {
+        if tag == Tag::Number {
+            heap_tags.remove(&address);
+        } else {
+            heap_tags.insert(address, tag);
+        }
+    }
+    // The address `depth` slots down the stack, plus `offset`.
+    let address =
+        |depth: usize, offset: u32| to_index(stack[stack.len() - 1 - depth]).wrapping_add(offset);
+
+    use Operation::*;
+    match operation {
+        Push(_) => tags.push(Tag::Number),
+        PushBool(_) => tags.push(Tag::Bool),
+        PushFunction(_) => tags.push(Tag::Function),
+        Load(address) => tags.push(load(heap_tags, *address)),
+        Store(address) => {
+            let tag = tags.pop().unwrap();
+            store(heap_tags, *address, tag);
+        }
+        PushMany(start) => {
+            let count = constant_run(constants, *start).len();
+            tags.extend(std::iter::repeat_n(Tag::Number, count));
+        }
+        Allocate(_) => tags.push(Tag::Address),
+        Pop | JumpIf(_) | JumpIfNot(_) | Goto => {
+            tags.pop();
+        }
+        GotoIf | GotoIfNot => {
+            tags.pop();
+            tags.pop();
+        }
+        Get(depth) => tags.push(tags[tags.len() - 1 - *depth as usize]),
+        LoadElement(depth, index) => {
+            let found = tags[tags.len() - 1 - *depth as usize];
+            if found != Tag::Address {
+                return Err(RuntimeError::TypeTagMismatch {
+                    expected: Tag::Address,
+                    found,
+                });
+            }
+            let address = address(*depth as usize, 1 + *index as u32);
+            tags.push(load(heap_tags, address));
+        }
+        Swap => {
+            let len = tags.len();
+            tags.swap(len - 1, len - 2);
+        }
+        Put(depth) => {
+            let tag = tags.pop().unwrap();
+            let index = tags.len() - 1 - *depth as usize;
+            tags[index] = tag;
+        }
+        Free(_) => expect(tags, Tag::Address)?,
+        StoreIndirect(offset) => {
+            let tag = tags.pop().unwrap();
+            expect(tags, Tag::Address)?;
+            store(heap_tags, address(1, *offset), tag);
+        }
+        LoadIndirect(offset) => {
+            expect(tags, Tag::Address)?;
+            tags.push(load(heap_tags, address(0, *offset)));
+        }
+        CheckIndex => {
+            expect(tags, Tag::Number)?;
+            expect(tags, Tag::Address)?;
+            tags.extend([Tag::Address, Tag::Number]);
+        }
+        StoreMany(count) => {
+            expect(tags, Tag::Address)?;
+            let start = tags.len() - *count as usize;
+            for (offset, tag) in tags.drain(start..).enumerate() {
+                store(heap_tags, address(0, offset as u32), tag);
+            }
+            tags.push(Tag::Address);
+        }
+        Call(_) => tags.extend([Tag::Number, Tag::Number]),
+        CallFnPointer => {
+            expect(tags, Tag::Function)?;
+            tags.extend([Tag::Number, Tag::Number]);
+        }
+        Return => {
+            tags.pop();
+            tags.pop();
+        }
+        AddImmediate(_) | SubImmediate(_) | SubImmediateBy(_) | MulImmediate(_)
+        | DivImmediate(_) | DivImmediateBy(_) | ModImmediate(_) | ModImmediateBy(_) => {
+            expect(tags, Tag::Number)?;
+            tags.push(Tag::Number);
+        }
+        // Indexing adds a number to an address, giving an address.
+        Add if tags.len() >= 2 && tags[tags.len() - 2] == Tag::Address => {
+            expect(tags, Tag::Number)?;
+            expect(tags, Tag::Address)?;
+            tags.push(Tag::Address);
+        }
+        Add | Sub | Mul | Div | Mod | AddSat | SubSat | MulSat | AddChecked | SubChecked
+        | MulChecked | AddCheckedSigned | SubCheckedSigned | MulCheckedSigned | DivSigned
+        | ModSigned => {
+            expect(tags, Tag::Number)?;
+            expect(tags, Tag::Number)?;
+            tags.push(Tag::Number);
+        }
+        // Bools are compared for equality too, but not with numbers.
+        Equal | NotEqual if tags.last() == Some(&Tag::Bool) => {
+            expect(tags, Tag::Bool)?;
+            expect(tags, Tag::Bool)?;
+            tags.push(Tag::Bool);
+        }
+        Equal | NotEqual | Less | LessEqual | LessSigned | LessEqualSigned => {
+            expect(tags, Tag::Number)?;
+            expect(tags, Tag::Number)?;
+            tags.push(Tag::Bool);
+        }
+        Nop | Jump(_) => {}
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// Computes the `n`th Fibonacci number with a loop.
+    fn fibonacci_program(n: Word) -> VirtualMachine {
+        use Operation::*;
+        VirtualMachine::from_functions(vec![Function::from_operations(
+            "main",
+            vec![
+                Push(0),         // 0
+                Push(1),         // 1
+                Push(n),         // 2
+                Get(0),          // 3
+                JumpIfNot(13),   // 4
+                Get(2),          // 5
+                Get(2),          // 6
+                Add,             // 7
+                Get(2),          // 8
+                Put(3),          // 9
+                Put(1),          // 10
+                SubImmediate(1), // 11
+                Jump(3),         // 12
+                Pop,             // 13
+                Put(3),          // 14
+                Pop,             // 15
+                Return,          // 16
+            ],
+        )])
+    }
+
     #[test]
     fn fibonacci() {
-        fn fib(n: u32) -> u32 {
+        fn fib(n: Word) -> Word {
             let mut a = 0;
             let mut b = 1;
             for _ in 0..n {
                 std::mem::swap(&mut a, &mut b);
                 b += a;
             }
-            return b;
+            b
         }
 
         for i in 0..30 {
-            use Operation::*;
-            let mut program = VirtualMachine::from_functions(vec![Function::from_operations(
+            assert_eq!(fib(i), fibonacci_program(i).run().unwrap());
+        }
+    }
+
+    #[test]
+    fn resume_after_a_trap() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn main(): u32 { let a = 10; let b = 0; let c = a / b; return c * 2 + a; }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.enable_strict_arithmetic();
+        assert!(matches!(vm.run(), Err(RuntimeError::DivideByZero)));
+        assert_eq!(vm.resume_with(5).unwrap(), 20);
+        assert!(matches!(vm.resume_with(5), Err(RuntimeError::NotTrapped)));
+    }
+
+    #[test]
+    fn run_result_reads_the_declared_type() {
+        use crate::{compiler, parser, typechecker};
+
+        let run = |source| {
+            let program = parser::parse(source).unwrap();
+            typechecker::check(&program).unwrap();
+            compiler::compile(&program).unwrap().run_detailed().unwrap()
+        };
+        let result = run("fn main(): i32 { return -3 + -4; }");
+        assert_eq!(result.as_i32(), Some(-7));
+        assert_eq!(result.as_bool(), None);
+        let result = run("fn main(): bool { return 2 < 3; }");
+        assert_eq!(result.as_bool(), Some(true));
+        assert_eq!(result.as_i32(), None);
+        let result = run("fn main(): u32 { return 1; }");
+        assert_eq!((result.as_i32(), result.as_bool()), (None, None));
+    }
+
+    #[test]
+    fn coverage_records_the_operations_that_ran() {
+        use Operation::*;
+
+        let mut program = fibonacci_program(5);
+        assert_eq!(program.run_detailed().unwrap().coverage, None);
+
+        let mut program = fibonacci_program(5);
+        program.enable_coverage();
+        let coverage = program.run_detailed().unwrap().coverage.unwrap();
+        let mut expected = Coverage::default();
+        for operation in [
+            Push(0),
+            Get(0),
+            JumpIfNot(0),
+            Add,
+            Put(0),
+            SubImmediate(0),
+            Jump(0),
+            Pop,
+            Return,
+        ] {
+            expected.insert(operation.opcode());
+        }
+        assert_eq!(coverage, expected);
+        assert!(coverage.contains(&Push(7)));
+        assert!(!coverage.contains(&Mul));
+    }
+
+    /// Run with `cargo test --release -- --ignored dispatch_speed --nocapture`, and
+    /// again with `--features flat-dispatch` to compare the two ways of dispatching.
+    #[test]
+    #[ignore]
+    fn dispatch_speed() {
+        use std::time::Instant;
+
+        let mut program = fibonacci_program(10_000_000);
+        let start = Instant::now();
+        program.run().unwrap();
+        // Each pass through the loop runs 10 operations.
+        let operations = 10 * 10_000_000;
+        println!(
+            "{:.2}ns per operation {}",
+            start.elapsed().as_nanos() as f64 / operations as f64,
+            if cfg!(feature = "flat-dispatch") {
+                "flat"
+            } else {
+                "from operations"
+            }
+        );
+
+        let mut traced = fibonacci_program(100_000);
+        traced.enable_tracing();
+        traced.set_trace_output(io::sink());
+        let start = Instant::now();
+        traced.run().unwrap();
+        println!(
+            "{:.2}ns per operation traced",
+            start.elapsed().as_nanos() as f64 / (10 * 100_000) as f64
+        );
+    }
+
+    #[test]
+    fn tracing_off_skips_formatting() {
+        use std::{cell::Cell, rc::Rc};
+
+        /// Counts the writes made to it.
+        struct Writes(Rc<Cell<usize>>);
+        impl Write for Writes {
+            fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+                self.0.set(self.0.get() + 1);
+                Ok(buffer.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let run = |trace: bool| {
+            let writes = Rc::new(Cell::new(0));
+            let mut program = fibonacci_program(40);
+            program.set_trace_output(Writes(Rc::clone(&writes)));
+            if trace {
+                program.enable_tracing();
+            }
+            (program.run().unwrap(), writes.get())
+        };
+        let (traced_result, traced_writes) = run(true);
+        let (result, writes) = run(false);
+        assert_eq!(result, traced_result);
+        assert_eq!(writes, 0);
+        assert!(traced_writes > 0);
+    }
+
+    #[test]
+    fn early_return_leaves_the_stack_balanced() {
+        use crate::{ast, builtins::Builtin, compiler, parser};
+
+        // Pushes how many slots the caller has on the stack.
+        let depth = Builtin::new("depth", vec![], ast::Type::U32, |vm| {
+            let depth = vm.stack.len() as Word;
+            vm.push(depth);
+        });
+        let program = parser::parse(
+            "fn f(c: bool): u32 {
+                let a = 1;
+                let b = 2;
+                if c {
+                    let d = 3;
+                    return a + b + d;
+                }
+                return 0;
+            }
+            fn main(): u32 {
+                let before = depth();
+                let result = f(true);
+                let after = depth();
+                return (after - before) * 100 + result;
+            }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile_with_builtins(&program, &[depth]).unwrap();
+
+        // `after` sees exactly `before` and `result` on top of what `before` saw.
+        assert_eq!(vm.run().unwrap(), 206);
+        // Only main's result is left once it returns.
+        assert_eq!(vm.stack, vec![206]);
+    }
+
+    #[test]
+    fn return_from_nested_blocks_pops_every_enclosing_local() {
+        use crate::{ast, builtins::Builtin, compiler, parser};
+
+        let depth = Builtin::new("depth", vec![], ast::Type::U32, |vm| {
+            let depth = vm.stack.len() as Word;
+            vm.push(depth);
+        });
+        let program = parser::parse(
+            "fn f(n: u32): u32 {
+                let a = 1;
+                while true {
+                    let b = 2;
+                    let c = {
+                        let d = 3;
+                        if eq(n, 5) {
+                            let e = 4;
+                            return a + b + d + e + n;
+                        }
+                        d
+                    };
+                    return c;
+                }
+                return 0;
+            }
+            fn main(): u32 {
+                let before = depth();
+                let x = f(5);
+                let y = f(1);
+                let after = depth();
+                return (after - before) * 100 + x * 10 + y;
+            }",
+        )
+        .unwrap();
+        let mut builtins = crate::builtins::standard();
+        builtins.push(depth);
+        let mut vm = compiler::compile_with_builtins(&program, &builtins).unwrap();
+
+        assert_eq!(vm.run().unwrap(), 3 * 100 + 150 + 3);
+        assert_eq!(vm.stack, vec![453]);
+    }
+
+    #[test]
+    fn backtrace_lists_the_calls_in_progress() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn inner(x: u32): u32 { return 10 / x; }
+            fn outer(x: u32): u32 { let y = 1; return inner(x - 1) + y; }
+            fn main(): u32 { return outer(1); }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.enable_strict_arithmetic();
+        assert!(matches!(vm.run(), Err(RuntimeError::DivideByZero)));
+        let backtrace = vm.backtrace();
+        let names: Vec<_> = backtrace
+            .iter()
+            .map(|frame| frame.function_name.as_str())
+            .collect();
+        assert_eq!(names, ["inner", "outer", "main"]);
+        let code: BTreeMap<_, _> = vm.iter_code().collect();
+        assert!(matches!(
+            code["inner"][backtrace[0].pc as usize],
+            Operation::Div
+        ));
+        for frame in &backtrace[1..] {
+            let operation = &code[frame.function_name.as_str()][frame.pc as usize];
+            assert!(matches!(operation, Operation::Call(_)));
+        }
+
+        // Finished calls are gone from it.
+        let mut vm = compiler::compile(&program).unwrap();
+        assert_eq!(vm.run().unwrap(), 1);
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn saved_frames_can_be_peeked() {
+        use Operation::*;
+
+        let mut vm = VirtualMachine::from_functions(vec![
+            Function::from_operations("main", vec![Nop, Push(0), Call(1), Return]),
+            Function::from_operations("callee", vec![Push(5), Put(2), Return]),
+        ]);
+        vm.set_step_limit(3);
+        assert!(matches!(vm.run(), Err(RuntimeError::StepLimitExceeded)));
+        // The callee hasn't run yet, so the caller's function id and the index of
+        // its `Call` are on top.
+        assert_eq!(vm.peek_frame(0), Some(0));
+        assert_eq!(vm.peek_frame(1), Some(2));
+        assert_eq!(vm.peek_frame(2), Some(0));
+        assert_eq!(vm.peek_frame(3), Some(u32::MAX as Word));
+        assert_eq!(vm.peek_frame(5), Some(0));
+        assert_eq!(vm.peek_frame(6), None);
+        assert_eq!(vm.peek_frame(u32::MAX), None);
+    }
+
+    #[test]
+    fn max_stack_matches_the_peak_while_running() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn fib(n: u32): u32 {
+                let mut a = 0;
+                let mut b = 1;
+                let mut i = 0;
+                while i < n {
+                    let next = a + b;
+                    a = b;
+                    b = next;
+                    i = i + 1;
+                }
+                return a;
+            }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile_with_entry(&program, "fib").unwrap();
+        vm.set_main_arguments(&[10]).unwrap();
+        let expected = vm.max_stack_of("fib").unwrap();
+
+        // Step through one operation at a time, watching how far the frame grows.
+        let base = vm.stack.len();
+        let mut peak = 0;
+        vm.set_step_limit(1);
+        loop {
+            peak = peak.max(vm.stack.len() - base);
+            match vm.run() {
+                Err(RuntimeError::StepLimitExceeded) => vm.set_step_limit(1),
+                result => {
+                    assert_eq!(result.unwrap(), 55);
+                    break;
+                }
+            }
+        }
+        assert_eq!(peak, expected);
+        assert_eq!(vm.max_stack_of("print"), None);
+        assert_eq!(vm.max_stack_of("missing"), None);
+    }
+
+    #[test]
+    fn call_function_by_name() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn double(x: u32): u32 { return x * 2; }
+            fn main(): u32 { return 0; }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        let depth = vm.stack.len();
+
+        assert_eq!(vm.call("double", &[21]).unwrap(), 42);
+        assert_eq!(vm.call("double", &[5]).unwrap(), 10);
+        assert_eq!(vm.call("eq", &[3, 3]).unwrap(), 1);
+        assert_eq!(vm.stack.len(), depth);
+        assert!(matches!(
+            vm.call("triple", &[1]),
+            Err(RuntimeError::UnknownFunction(_))
+        ));
+    }
+
+    #[test]
+    fn failed_calls_leave_the_vm_usable() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn check(x: u32): u32 { assert_eq(x, 1); return x; }
+            fn double(x: u32): u32 { return x * 2; }
+            fn main(): u32 { return double(4); }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        let depth = vm.stack.len();
+
+        assert!(matches!(
+            vm.call("check", &[2]),
+            Err(RuntimeError::AssertionFailed { left: 2, right: 1 })
+        ));
+        assert_eq!(vm.stack.len(), depth);
+        assert_eq!(vm.call("double", &[21]).unwrap(), 42);
+        assert_eq!(vm.run().unwrap(), 8);
+    }
+
+    #[test]
+    fn calls_that_exit_return_the_exit_code() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn stop(x: u32): u32 { let y = x + 1; exit(y); return 0; }
+            fn main(): u32 { return 3; }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        let depth = vm.stack.len();
+
+        assert_eq!(vm.call("stop", &[6]).unwrap(), 7);
+        assert_eq!(vm.call("exit", &[9]).unwrap(), 9);
+        assert_eq!(vm.stack.len(), depth);
+        assert_eq!(vm.run().unwrap(), 3);
+    }
+
+    #[test]
+    fn calls_leave_no_trap_to_resume() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn divide(a: u32, b: u32): u32 { return a / b; }
+            fn main(): u32 { let a = 10; let b = 0; return a / b; }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.enable_strict_arithmetic();
+
+        // A trap inside the call is unwound with the rest of the call.
+        assert!(matches!(
+            vm.call("divide", &[1, 0]),
+            Err(RuntimeError::DivideByZero)
+        ));
+        assert!(matches!(vm.resume_with(5), Err(RuntimeError::NotTrapped)));
+
+        // So is one from before the call, even when the call fails before running.
+        assert!(matches!(vm.run(), Err(RuntimeError::DivideByZero)));
+        assert!(vm.call("divide", &[1]).is_err());
+        assert!(matches!(vm.resume_with(5), Err(RuntimeError::NotTrapped)));
+    }
+
+    #[test]
+    fn calls_keep_the_arguments_of_main() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn double(x: u32): u32 { return x * 2; }
+            fn seven(): u32 { return 7; }
+            fn main(argc: u32): u32 { return argc + 100; }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.set_main_arguments(&[3]).unwrap();
+
+        assert_eq!(vm.call("double", &[21]).unwrap(), 42);
+        assert_eq!(vm.call("seven", &[]).unwrap(), 7);
+        assert_eq!(vm.run().unwrap(), 103);
+    }
+
+    #[test]
+    fn reset_heap_shrinks_it() {
+        use Operation::*;
+        let mut program = VirtualMachine::from_functions(vec![Function::from_operations(
+            "main",
+            vec![Allocate(4), Allocate(2), Pop, Put(2), Return],
+        )]);
+        program.run().unwrap();
+        assert_eq!(program.heap_size(), 6);
+
+        program.reset_heap();
+        assert_eq!(program.heap_size(), 0);
+    }
+
+    #[test]
+    fn allocations_remember_their_size() {
+        use Operation::*;
+        let mut vm = VirtualMachine::from_functions(vec![Function::from_operations(
+            "main",
+            vec![
+                Allocate(4),
+                Allocate(2),
+                Allocate(3),
+                Get(1),
+                Free(0),
+                Allocate(1),
+                Free(0),
+                Pop,
+                Pop,
+                Put(2),
+                Return,
+            ],
+        )]);
+        vm.run().unwrap();
+        assert_eq!(vm.allocation_size(0), Some(4));
+        assert_eq!(vm.allocation_size(4), None);
+        assert_eq!(vm.allocation_size(6), Some(3));
+        // The last block was at the end of the heap, so its space was reclaimed.
+        assert_eq!(vm.allocation_size(9), None);
+        assert_eq!(vm.heap_size(), 9);
+
+        let mut vm = VirtualMachine::from_functions(vec![Function::from_operations(
+            "main",
+            vec![Push(5), Free(0)],
+        )]);
+        assert!(matches!(vm.run(), Err(RuntimeError::InvalidFree(5))));
+    }
+
+    #[test]
+    fn tags_catch_accesses_outside_allocations() {
+        use Operation::*;
+        // Frees the first of two blocks, then stores to and loads from `address`.
+        let run = |address| {
+            let mut vm = VirtualMachine::from_functions(vec![Function::from_operations(
                 "main",
                 vec![
-                    Push(0),         // 0
-                    Push(1),         // 1
-                    Push(i),         // 2
-                    Get(0),          // 3
-                    JumpIfNot(13),   // 4
-                    Get(2),          // 5
-                    Get(2),          // 6
-                    Add,             // 7
-                    Get(2),          // 8
-                    Put(3),          // 9
-                    Put(1),          // 10
-                    SubImmediate(1), // 11
-                    Jump(3),         // 12
-                    Pop,             // 13
-                    Put(3),          // 14
-                    Pop,             // 15
-                    Return,          // 16
+                    Allocate(2),
+                    Allocate(2),
+                    Get(1),
+                    Free(0),
+                    Push(7),
+                    Store(address),
+                    Load(address),
+                    Put(4),
+                    Pop,
+                    Pop,
+                    Return,
                 ],
             )]);
-            assert_eq!(fib(i), program.run());
-        }
+            vm.enable_type_tags();
+            vm.run()
+        };
+        assert_eq!(run(3).unwrap(), 7);
+        assert!(matches!(run(1), Err(RuntimeError::InvalidAddress(1))));
+    }
+
+    #[test]
+    fn freed_blocks_are_reused_most_recent_first() {
+        let addresses = || {
+            let mut vm = VirtualMachine::from_functions(vec![]);
+            let a = vm.allocate(2);
+            let b = vm.allocate(2);
+            let c = vm.allocate(3);
+            vm.write_heap(b, 7);
+            vm.free(a).unwrap();
+            vm.free(b).unwrap();
+            let mut addresses = vec![a, b, c];
+            addresses.extend([vm.allocate(2), vm.allocate(2), vm.allocate(2)]);
+            // Reused blocks start out zeroed like new ones.
+            assert_eq!(vm.read_heap(b), 0);
+            vm.free(c).unwrap();
+            addresses.push(vm.allocate(3));
+            addresses
+        };
+        assert_eq!(addresses(), [0, 2, 4, 2, 0, 7, 4]);
+        assert_eq!(addresses(), addresses());
+    }
+
+    #[test]
+    fn bool_in_arithmetic_is_caught_by_tags() {
+        use Operation::*;
+        let operations = || vec![Push(1), PushBool(true), Add, Put(2), Return];
+
+        let mut program =
+            VirtualMachine::from_functions(vec![Function::from_operations("main", operations())]);
+        assert_eq!(program.run().unwrap(), 2);
+
+        let mut program =
+            VirtualMachine::from_functions(vec![Function::from_operations("main", operations())]);
+        program.enable_type_tags();
+        assert!(matches!(
+            program.run(),
+            Err(RuntimeError::TypeTagMismatch {
+                expected: Tag::Number,
+                found: Tag::Bool
+            })
+        ));
+    }
+
+    #[test]
+    fn function_pointers_are_tagged() {
+        use Operation::*;
+        let double = || {
+            Function::from_operations("double", vec![Get(2), Get(0), Add, Put(3), Return])
+                .with_arguments(1)
+        };
+        let main = |operations| Function::from_operations("main", operations);
+
+        // Calls `double(21)` through a pointer.
+        let mut vm = VirtualMachine::from_functions(vec![
+            main(vec![
+                Push(0),
+                Push(21),
+                PushFunction(1),
+                CallFnPointer,
+                Pop,
+                Put(2),
+                Return,
+            ]),
+            double(),
+        ]);
+        vm.enable_type_tags();
+        assert_eq!(vm.run().unwrap(), 42);
+
+        // The same id pushed as a number isn't a function.
+        let mut vm = VirtualMachine::from_functions(vec![
+            main(vec![
+                Push(0),
+                Push(21),
+                Push(1),
+                CallFnPointer,
+                Pop,
+                Put(2),
+                Return,
+            ]),
+            double(),
+        ]);
+        vm.enable_type_tags();
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::TypeTagMismatch {
+                expected: Tag::Function,
+                found: Tag::Number
+            })
+        ));
+    }
+
+    #[test]
+    fn step_limit_stops_endless_loop() {
+        let mut vm = VirtualMachine::from_functions(vec![Function::from_operations(
+            "main",
+            vec![Operation::Nop, Operation::Jump(0)],
+        )]);
+        vm.set_step_limit(100);
+        assert!(matches!(vm.run(), Err(RuntimeError::StepLimitExceeded)));
+    }
+
+    #[test]
+    fn iter_code_skips_builtins() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn double(x: u32): u32 { return x * 2; }
+            fn main(): u32 { return double(2); }",
+        )
+        .unwrap();
+        let vm = compiler::compile(&program).unwrap();
+        let code: Vec<_> = vm
+            .iter_code()
+            .map(|(name, operations)| (name, operations.len()))
+            .collect();
+        assert_eq!(code, [("double", 5), ("main", 6)]);
+    }
+
+    #[test]
+    fn peak_call_depth_is_reported() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse(
+            "fn down(n: u32): u32 {
+                if n == 0 { return 0; }
+                return down(n - 1);
+            }
+            fn main(): u32 { return down(5); }",
+        )
+        .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        let result = vm.run_detailed().unwrap();
+        assert_eq!(result.value, 0);
+        // `down` is entered with 5, 4, 3, 2, 1 and 0.
+        assert_eq!(result.max_call_depth, 6);
+    }
+
+    #[test]
+    fn loop_breaks_with_its_value() {
+        use crate::{compiler, parser, typechecker};
+
+        let program = parser::parse(
+            "fn main(): u32 {
+                let mut i = 0;
+                let mut total = 0;
+                let found = loop {
+                    i = i + 1;
+                    let square = i * i;
+                    if square > 50 { break square + total; }
+                    total = total + square;
+                };
+                while true {
+                    let j = i;
+                    if j == 10 { break; }
+                    i = i + 1;
+                }
+                return found * 100 + i;
+            }",
+        )
+        .unwrap();
+        typechecker::check(&program).unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        // 1 + 4 + ... + 49 = 140, and 64 is the first square over 50.
+        assert_eq!(vm.run().unwrap(), 20410);
+    }
+
+    #[test]
+    fn main_receives_its_arguments() {
+        use crate::{compiler, parser};
+
+        let program =
+            parser::parse("fn main(argc: u32): u32 { let doubled = argc * 2; return doubled; }")
+                .unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        vm.set_main_arguments(&[3]).unwrap();
+        assert_eq!(vm.run().unwrap(), 6);
+    }
+
+    #[test]
+    fn entry_argument_count_is_checked() {
+        // Returns its argument.
+        let main = || {
+            Function::from_operations(
+                "main",
+                vec![Operation::Get(2), Operation::Put(3), Operation::Return],
+            )
+            .with_arguments(1)
+        };
+        let mut vm = VirtualMachine::from_functions_with_arguments(vec![main()], &[5]).unwrap();
+        assert_eq!(vm.run().unwrap(), 5);
+        assert!(matches!(
+            VirtualMachine::from_functions_with_arguments(vec![main()], &[]),
+            Err(RuntimeError::WrongArgumentCount {
+                expected: 1,
+                found: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn runs_refuse_to_start_without_the_entry_arguments() {
+        let main = Function::from_operations(
+            "main",
+            vec![Operation::Get(2), Operation::Put(3), Operation::Return],
+        )
+        .with_arguments(1);
+        let mut vm = VirtualMachine::from_functions(vec![main]);
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::WrongArgumentCount {
+                expected: 1,
+                found: 0,
+                ..
+            })
+        ));
+        vm.set_main_arguments(&[5]).unwrap();
+        assert_eq!(vm.run().unwrap(), 5);
+    }
+
+    #[test]
+    fn compiled_entry_arguments_are_checked_when_running() {
+        use crate::{compiler, parser};
+
+        let program = parser::parse("fn main(argc: u32): u32 { return argc + 7; }").unwrap();
+        let mut vm = compiler::compile(&program).unwrap();
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::WrongArgumentCount { .. })
+        ));
+        assert!(matches!(
+            vm.call("main", &[]),
+            Err(RuntimeError::WrongArgumentCount { .. })
+        ));
+        assert_eq!(vm.call("main", &[1]).unwrap(), 8);
     }
 }